@@ -0,0 +1,196 @@
+use crate::class::{DFUClass, DFUMemIO};
+use core::ops::ControlFlow;
+use usb_device::bus::{UsbBus, UsbBusAllocator};
+use usb_device::device::{StringDescriptors, UsbDevice, UsbDeviceBuilder, UsbVidPid};
+use usb_device::LangID;
+
+pub use usb_device::prelude::BuilderError;
+
+/// USB device configuration of a bootloader, see [`run_dfu()`].
+#[derive(Clone, Copy, Debug)]
+pub struct BootloaderConfig<'a> {
+    /// USB Vendor ID
+    pub vid: u16,
+    /// USB Product ID
+    pub pid: u16,
+    /// Manufacturer string, not reported if empty
+    pub manufacturer: &'a str,
+    /// Product string, not reported if empty
+    pub product: &'a str,
+    /// Serial number string, not reported if empty
+    pub serial_number: &'a str,
+    /// Maximum packet size of the control endpoint: 8, 16, 32, or 64
+    pub max_packet_size_0: u8,
+}
+
+impl<'a> BootloaderConfig<'a> {
+    /// Creates a configuration with `vid` and `pid`, "DFU Bootloader" product string,
+    /// and 64 byte control endpoint packets.
+    pub const fn new(vid: u16, pid: u16) -> Self {
+        Self {
+            vid,
+            pid,
+            manufacturer: "",
+            product: "DFU Bootloader",
+            serial_number: "",
+            max_packet_size_0: 64,
+        }
+    }
+}
+
+/// Result of a boot decision, which is made by a bootloader before USB is enabled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BootDecision {
+    /// Don't start DFU, the bootloader should run the main firmware
+    Application,
+    /// Start DFU in `dfuIDLE` state
+    Dfu,
+    /// Start DFU in `dfuERROR` state with `errPOR` status,
+    /// see [`DFUClass::set_unexpected_reset_state()`]
+    DfuUnexpectedReset,
+    /// Start DFU in `dfuERROR` state with `errFIRMWARE` status,
+    /// see [`DFUClass::set_firmware_corrupted_state()`]
+    DfuFirmwareCorrupted,
+}
+
+/// Runs a DFU device until `idle` breaks the loop, returns the memory.
+///
+/// Creates [`DFUClass`] with `mem`, builds [`UsbDevice`] as described by `config`,
+/// and polls it in a loop. This covers the common part of a bootloader, target-specific
+/// parts are left to the caller:
+///
+/// * Boot decision. `boot` is usually decided from a button, a magic value in RAM left
+///   by the main firmware, or a reset cause, before USB peripheral and `alloc` are created.
+///   If `boot` is [`BootDecision::Application`], returns `mem` immediately, USB device is
+///   not created.
+///
+/// * Idle loop body. `idle` is called after every `usb_dev.poll()`. It may feed a watchdog,
+///   wait for an interrupt, or check the device state. Returning [`ControlFlow::Break`]
+///   leaves the loop, for example, to reset the device after a manifestation.
+///
+/// `usb_dev.poll()` is called from the loop, not from USB interrupt handlers,
+/// so memory functions are called from the thread context. [`DFUClass::update()`]
+/// is called after every poll, so [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+/// may have any value.
+///
+/// ```no_run
+/// use core::ops::ControlFlow;
+/// use usbd_dfu::bootloader::*;
+/// # use usbd_dfu::*;
+/// # use usb_device::bus::UsbBusAllocator;
+/// #
+/// # pub struct DummyUsbBus { }
+/// # impl usb_device::bus::UsbBus for DummyUsbBus {
+/// #     fn alloc_ep(&mut self, _: usb_device::UsbDirection, _: Option<usb_device::endpoint::EndpointAddress>,
+/// #                 _: usb_device::endpoint::EndpointType, _: u16, _: u8) -> usb_device::Result<usb_device::endpoint::EndpointAddress> { todo!() }
+/// #     fn enable(&mut self) { todo!() }
+/// #     fn reset(&self) { todo!() }
+/// #     fn set_device_address(&self, _: u8) { todo!() }
+/// #     fn write(&self, _: usb_device::endpoint::EndpointAddress, _: &[u8]) -> usb_device::Result<usize> { todo!() }
+/// #     fn read(&self, _: usb_device::endpoint::EndpointAddress, _: &mut [u8]) -> usb_device::Result<usize> { todo!() }
+/// #     fn set_stalled(&self, _: usb_device::endpoint::EndpointAddress, _: bool) { todo!() }
+/// #     fn is_stalled(&self, _: usb_device::endpoint::EndpointAddress) -> bool { todo!() }
+/// #     fn suspend(&self) { todo!() }
+/// #     fn resume(&self) { todo!() }
+/// #     fn poll(&self) -> usb_device::bus::PollResult { todo!() }
+/// # }
+/// # struct MyMem {}
+/// # impl DFUMemIO for MyMem {
+/// #   const MEM_INFO_STRING: &'static str = "@Flash/0x00000000/1*1Kg";
+/// #   const INITIAL_ADDRESS_POINTER: u32 = 0x0;
+/// #   const PROGRAM_TIME_MS: u32 = 8;
+/// #   const ERASE_TIME_MS: u32 = 50;
+/// #   const FULL_ERASE_TIME_MS: u32 = 50;
+/// #   fn read(&mut self, _: u32, _: usize) -> Result<&[u8], DFUMemError> { todo!() }
+/// #   fn erase(&mut self, _: u32) -> Result<(), DFUMemError> { todo!() }
+/// #   fn erase_all(&mut self) -> Result<(), DFUMemError> { todo!() }
+/// #   fn store_write_buffer(&mut self, _: &[u8]) -> Result<(), ()> { todo!() }
+/// #   fn program(&mut self, _: u32, _: usize) -> Result<(), DFUMemError> { todo!() }
+/// #   fn manifestation(&mut self) -> Result<(), DFUManifestationError> { todo!() }
+/// # }
+/// # fn button_pressed() -> bool { true }
+/// # fn jump_to_application() -> ! { todo!() }
+/// # fn reset() -> ! { todo!() }
+/// # let my_mem = MyMem {};
+///
+/// const CONFIG: BootloaderConfig = BootloaderConfig {
+///     manufacturer: "ACME",
+///     ..BootloaderConfig::new(0x1209, 0x0001)
+/// };
+///
+/// // Boot decision
+/// let boot = if button_pressed() {
+///     BootDecision::Dfu
+/// } else {
+///     BootDecision::Application
+/// };
+///
+/// if boot == BootDecision::Application {
+///     jump_to_application();
+/// }
+///
+/// // let usb_bus_alloc = UsbBus::new(peripheral);
+/// # let usb_bus_alloc: UsbBusAllocator<DummyUsbBus> = unsafe { core::mem::MaybeUninit::<UsbBusAllocator<DummyUsbBus>>::uninit().assume_init() };
+///
+/// run_dfu(&usb_bus_alloc, my_mem, &CONFIG, boot, |usb_dev, dfu| {
+///     // Idle loop body
+///     if dfu.failed_manifestations() > 0 {
+///         return ControlFlow::Break(());
+///     }
+///     ControlFlow::Continue(())
+/// })
+/// .unwrap();
+///
+/// reset();
+/// ```
+pub fn run_dfu<'a, B, M, F>(
+    alloc: &'a UsbBusAllocator<B>,
+    mem: M,
+    config: &BootloaderConfig<'a>,
+    boot: BootDecision,
+    mut idle: F,
+) -> Result<M, BuilderError>
+where
+    B: UsbBus,
+    M: DFUMemIO,
+    F: FnMut(&mut UsbDevice<'a, B>, &mut DFUClass<B, M>) -> ControlFlow<()>,
+{
+    if boot == BootDecision::Application {
+        return Ok(mem);
+    }
+
+    // interfaces are allocated before the device is built
+    let mut dfu = DFUClass::new(alloc, mem);
+
+    match boot {
+        BootDecision::DfuUnexpectedReset => dfu.set_unexpected_reset_state(),
+        BootDecision::DfuFirmwareCorrupted => dfu.set_firmware_corrupted_state(),
+        _ => {}
+    }
+
+    let mut strings = StringDescriptors::new(LangID::EN_US);
+    if !config.manufacturer.is_empty() {
+        strings = strings.manufacturer(config.manufacturer);
+    }
+    if !config.product.is_empty() {
+        strings = strings.product(config.product);
+    }
+    if !config.serial_number.is_empty() {
+        strings = strings.serial_number(config.serial_number);
+    }
+
+    let mut usb_dev = UsbDeviceBuilder::new(alloc, UsbVidPid(config.vid, config.pid))
+        .strings(&[strings])?
+        .max_packet_size_0(config.max_packet_size_0)?
+        .build();
+
+    loop {
+        usb_dev.poll(&mut [&mut dfu]);
+        dfu.update();
+        if idle(&mut usb_dev, &mut dfu).is_break() {
+            break;
+        }
+    }
+
+    Ok(dfu.release())
+}
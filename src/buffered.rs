@@ -0,0 +1,363 @@
+use crate::class::{
+    DFUManifestationError, DFUMemError, DFUMemIO, DFUState, DFUStatusCode, DfuProgress,
+    EraseProgress, ManifestationProgress, ResetContext, SegmentLimits, UsbResetPolicy,
+    VendorCommandOutcome,
+};
+use crate::suffix::DfuSuffix;
+use core::cmp::min;
+use core::ops::Range;
+
+/// Size of the stack buffer for reading back programmed blocks.
+const VERIFY_CHUNK_SIZE: usize = 32;
+
+/// [`DFUMemIO`] wrapper that owns a download buffer of `N` bytes.
+///
+/// Download data is collected in the wrapper's buffer, and programmed with
+/// the wrapped memory's [`program_block()`](DFUMemIO::program_block), so the
+/// wrapped implementation doesn't need a buffer and doesn't need to implement
+/// [`store_write_buffer()`](DFUMemIO::store_write_buffer) and [`program()`](DFUMemIO::program).
+///
+/// `N` must not be smaller than [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE), this is checked
+/// at compile time.
+///
+/// If [`VERIFY_AFTER_PROGRAM`](DFUMemIO::VERIFY_AFTER_PROGRAM) is set, every programmed
+/// block is read back with the wrapped memory's [`read_block()`](DFUMemIO::read_block)
+/// in small chunks and compared with the buffer. If the write continues in background
+/// (see [`operation_busy()`](DFUMemIO::operation_busy)), the block is read back
+/// when it completes, in [`operation_result()`](DFUMemIO::operation_result).
+/// `BufferedMem` reports `VERIFY_AFTER_PROGRAM` as `false`, blocks are already verified.
+///
+/// All other constants and functions are forwarded to the wrapped implementation.
+/// Implementations that program directly from their own buffers, for example,
+/// a DMA region, should implement [`store_write_buffer()`](DFUMemIO::store_write_buffer)
+/// and [`program()`](DFUMemIO::program) and don't need `BufferedMem`.
+pub struct BufferedMem<M: DFUMemIO, const N: usize> {
+    mem: M,
+    buf: [u8; N],
+    len: usize,
+    /// Block to read back when a background write completes
+    verify_pending: Option<(u32, usize)>,
+}
+
+impl<M: DFUMemIO, const N: usize> BufferedMem<M, N> {
+    const SIZE_OK: () = assert!(
+        N >= M::TRANSFER_SIZE as usize,
+        "BufferedMem buffer is smaller than DFUMemIO::TRANSFER_SIZE"
+    );
+
+    /// Creates a new `BufferedMem` wrapping `mem`.
+    pub fn new(mem: M) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::SIZE_OK;
+
+        Self {
+            mem,
+            buf: [0; N],
+            len: 0,
+            verify_pending: None,
+        }
+    }
+
+    /// Returns a reference to the wrapped memory.
+    pub fn inner(&self) -> &M {
+        &self.mem
+    }
+
+    /// Returns a mutable reference to the wrapped memory.
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.mem
+    }
+
+    /// Consumes `BufferedMem` and returns the wrapped memory.
+    pub fn into_inner(self) -> M {
+        self.mem
+    }
+
+    /// Read back `length` bytes at `address` and compare them with the buffer
+    fn verify(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        let mut chunk = [0; VERIFY_CHUNK_SIZE];
+        let mut pos = 0;
+        while pos < length {
+            let len = min(length - pos, VERIFY_CHUNK_SIZE);
+            let read = self
+                .mem
+                .read_block(address.wrapping_add(pos as u32), &mut chunk[..len])
+                .map_err(|_| DFUMemError::Verify)?;
+            if read == 0 || read > len || chunk[..read] != self.buf[pos..pos + read] {
+                return Err(DFUMemError::Verify);
+            }
+            pos += read;
+        }
+        Ok(())
+    }
+}
+
+impl<M: DFUMemIO, const N: usize> DFUMemIO for BufferedMem<M, N> {
+    const INITIAL_ADDRESS_POINTER: u32 = M::INITIAL_ADDRESS_POINTER;
+    const MEM_INFO_STRING: &'static str = M::MEM_INFO_STRING;
+    const ALT_COUNT: u8 = M::ALT_COUNT;
+    const HAS_DOWNLOAD: bool = M::HAS_DOWNLOAD;
+    const HAS_UPLOAD: bool = M::HAS_UPLOAD;
+    const MANIFESTATION_TOLERANT: bool = M::MANIFESTATION_TOLERANT;
+    const WILL_DETACH: bool = M::WILL_DETACH;
+    const HAS_READ_UNPROTECT: bool = M::HAS_READ_UNPROTECT;
+    const HAS_CRC_COMMAND: bool = M::HAS_CRC_COMMAND;
+    const HAS_DEVICE_INFO: bool = M::HAS_DEVICE_INFO;
+    const PROGRAM_TIME_MS: u32 = M::PROGRAM_TIME_MS;
+    const ERASE_TIME_MS: u32 = M::ERASE_TIME_MS;
+    const FULL_ERASE_TIME_MS: u32 = M::FULL_ERASE_TIME_MS;
+    const READ_UNPROTECT_TIME_MS: u32 = M::READ_UNPROTECT_TIME_MS;
+    const MANIFESTATION_TIME_MS: u32 = M::MANIFESTATION_TIME_MS;
+    const DETACH_TIMEOUT: u16 = M::DETACH_TIMEOUT;
+    const TRANSFER_SIZE: u16 = M::TRANSFER_SIZE;
+    const DFU_VERSION: u16 = M::DFU_VERSION;
+    const FIRST_DATA_BLOCK: u16 = M::FIRST_DATA_BLOCK;
+    const REDACTED_RANGES: &'static [Range<u32>] = M::REDACTED_RANGES;
+    const REDACTED_FILL: u8 = M::REDACTED_FILL;
+    const BLOCK_CRC: bool = M::BLOCK_CRC;
+    // programmed blocks are verified here
+    const VERIFY_AFTER_PROGRAM: bool = false;
+    const IMAGE_HEADER_SIZE: usize = M::IMAGE_HEADER_SIZE;
+    const PROGRAM_IMAGE_HEADER: bool = M::PROGRAM_IMAGE_HEADER;
+    const ERROR_AUTOCLEAR_MS: u32 = M::ERROR_AUTOCLEAR_MS;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = M::LAYOUT_SEGMENTS;
+    const SEGMENT_LIMITS: &'static [SegmentLimits] = M::SEGMENT_LIMITS;
+    const OPERATION_BUDGET_FACTOR: u32 = M::OPERATION_BUDGET_FACTOR;
+    const MANIFESTATION_CONSTANT_TIME: bool = M::MANIFESTATION_CONSTANT_TIME;
+    const MAX_FAILED_MANIFESTATIONS: u8 = M::MAX_FAILED_MANIFESTATIONS;
+    const IMAGE_MAGIC: Option<(usize, &'static [u8])> = M::IMAGE_MAGIC;
+    const CHECK_SUFFIX: bool = M::CHECK_SUFFIX;
+    const ALLOW_UPLOAD_DURING_DNLOAD_IDLE: bool = M::ALLOW_UPLOAD_DURING_DNLOAD_IDLE;
+    const ADVANCE_ON_GETSTATE: bool = M::ADVANCE_ON_GETSTATE;
+    const ON_USB_RESET: UsbResetPolicy = M::ON_USB_RESET;
+    const HAS_VENDOR_ERROR_STRING: bool = M::HAS_VENDOR_ERROR_STRING;
+    const MEMIO_IN_USB_INTERRUPT: bool = M::MEMIO_IN_USB_INTERRUPT;
+    const READ_CHUNK_SIZE: usize = M::READ_CHUNK_SIZE;
+    const CHECK_PERMISSIONS: bool = M::CHECK_PERMISSIONS;
+    const WINUSB_VENDOR_CODE: u8 = M::WINUSB_VENDOR_CODE;
+    const WINUSB_GUID: &'static str = M::WINUSB_GUID;
+    const WINUSB_COMPOSITE: bool = M::WINUSB_COMPOSITE;
+    const WEBUSB_VENDOR_CODE: u8 = M::WEBUSB_VENDOR_CODE;
+    const WEBUSB_LANDING_PAGE: Option<&'static str> = M::WEBUSB_LANDING_PAGE;
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        if src.len() > N {
+            return Err(());
+        }
+        self.buf[..src.len()].copy_from_slice(src);
+        self.len = src.len();
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        self.mem.read_block(address, dest)
+    }
+
+    fn read_chunk(
+        &mut self,
+        address: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DFUMemError> {
+        self.mem.read_chunk(address, offset, buf)
+    }
+
+    fn is_end_of_region(&self, address: u32) -> bool {
+        self.mem.is_end_of_region(address)
+    }
+
+    fn upload_end_address(&self) -> Option<u32> {
+        self.mem.upload_end_address()
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        let length = min(length, self.len);
+        self.verify_pending = None;
+        self.mem.program_block(address, &self.buf[..length])?;
+        if M::VERIFY_AFTER_PROGRAM {
+            if self.mem.operation_busy() {
+                self.verify_pending = Some((address, length));
+            } else {
+                self.verify(address, length)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        self.mem.program_block(address, data)
+    }
+
+    fn program_time_ms(&self, length: usize) -> u32 {
+        self.mem.program_time_ms(length)
+    }
+
+    fn erase_time_ms(&self, address: u32) -> u32 {
+        self.mem.erase_time_ms(address)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn is_write_protected(&self, address: u32, length: usize) -> bool {
+        self.mem.is_write_protected(address, length)
+    }
+
+    fn is_blank(&mut self, address: u32, length: usize) -> Result<bool, DFUMemError> {
+        self.mem.is_blank(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.verify_pending = None;
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.verify_pending = None;
+        self.mem.erase_all()
+    }
+
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        self.verify_pending = None;
+        self.mem.erase_all_next()
+    }
+
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        self.verify_pending = None;
+        self.mem.read_unprotect()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn manifestation_start(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation_start()
+    }
+
+    fn manifestation_poll(&mut self) -> ManifestationProgress {
+        self.mem.manifestation_poll()
+    }
+
+    fn manifestation_allowed(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation_allowed()
+    }
+
+    fn validate_suffix(&mut self, suffix: Option<DfuSuffix>) -> Result<(), DFUManifestationError> {
+        self.mem.validate_suffix(suffix)
+    }
+
+    fn usb_reset(&mut self) {
+        self.mem.usb_reset()
+    }
+
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        self.mem.on_usb_reset(ctx)
+    }
+
+    fn on_manifest_reset(&mut self) {
+        self.mem.on_manifest_reset()
+    }
+
+    fn locate_image(&mut self, header: &[u8]) -> Result<u32, DFUMemError> {
+        self.mem.locate_image(header)
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        self.mem.now_ms()
+    }
+
+    fn profile_ticks(&mut self) -> u32 {
+        self.mem.profile_ticks()
+    }
+
+    fn on_abort(&mut self) {
+        self.mem.on_abort()
+    }
+
+    fn on_configured(&mut self) {
+        self.mem.on_configured()
+    }
+
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        self.mem.on_detach_request(timeout_ms)
+    }
+
+    fn on_detach_expired(&mut self) {
+        self.mem.on_detach_expired()
+    }
+
+    fn on_progress(&mut self, progress: DfuProgress) {
+        self.mem.on_progress(progress)
+    }
+
+    fn on_state_change(&mut self, old: DFUState, new: DFUState, status: DFUStatusCode) {
+        self.mem.on_state_change(old, new, status)
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        self.mem.operation_busy()
+    }
+
+    fn operation_result(&mut self) -> Result<(), DFUMemError> {
+        let verify = self.verify_pending.take();
+        self.mem.operation_result()?;
+        match verify {
+            Some((address, length)) => self.verify(address, length),
+            None => Ok(()),
+        }
+    }
+
+    fn on_error(&mut self) {
+        self.mem.on_error()
+    }
+
+    fn persist_lockout(&mut self, failures: Option<u8>) -> u8 {
+        self.mem.persist_lockout(failures)
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.mem.mem_info_string()
+    }
+
+    fn initial_address_pointer(&self) -> u32 {
+        self.mem.initial_address_pointer()
+    }
+
+    fn mem_info_string_for(&self, alt: u8) -> &str {
+        self.mem.mem_info_string_for(alt)
+    }
+
+    fn select_alt(&mut self, alt: u8) {
+        self.mem.select_alt(alt)
+    }
+
+    fn vendor_error_string(&self) -> Option<&str> {
+        self.mem.vendor_error_string()
+    }
+
+    fn vendor_commands(&self) -> &[u8] {
+        self.mem.vendor_commands()
+    }
+
+    fn vendor_dnload_command(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+    ) -> Result<VendorCommandOutcome, DFUMemError> {
+        self.mem.vendor_dnload_command(cmd, payload)
+    }
+
+    fn vendor_command_execute(&mut self, cmd: u8) -> Result<(), DFUMemError> {
+        self.mem.vendor_command_execute(cmd)
+    }
+
+    fn device_info(&mut self, buf: &mut [u8]) -> usize {
+        self.mem.device_info(buf)
+    }
+}
@@ -1,35 +1,43 @@
+use crate::crc::{crc32, crc32_update};
+use crate::dfuse::{DfuseCommand, DfuseCommandError, DnloadCommand};
+use crate::meminfo::{areas, Perms};
+#[cfg(feature = "profiling")]
+use crate::profile::DfuProfile;
+use crate::suffix::{DfuSuffix, SuffixTail};
 use core::cmp::min;
 use core::marker::PhantomData;
-use usb_device::{class_prelude::*, control::Request};
+use core::ops::Range;
+use usb_device::{class_prelude::*, control::Request, device::CONFIGURATION_VALUE};
 
-const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
-const USB_SUBCLASS_DFU: u8 = 0x01;
+pub(crate) const USB_CLASS_APPLICATION_SPECIFIC: u8 = 0xFE;
+pub(crate) const USB_SUBCLASS_DFU: u8 = 0x01;
 
-#[allow(dead_code)]
-const USB_PROTOCOL_RUN_TIME: u8 = 0x01;
-const USB_PROTOCOL_DFU_MODE: u8 = 0x02;
+pub(crate) const USB_PROTOCOL_RUN_TIME: u8 = 0x01;
+pub(crate) const USB_PROTOCOL_DFU_MODE: u8 = 0x02;
 
-#[allow(dead_code)]
-const DFU_DETACH: u8 = 0x00;
+pub(crate) const DFU_DETACH: u8 = 0x00;
 const DFU_DNLOAD: u8 = 0x01;
 const DFU_UPLOAD: u8 = 0x02;
-const DFU_GETSTATUS: u8 = 0x03;
+pub(crate) const DFU_GETSTATUS: u8 = 0x03;
 const DFU_CLRSTATUS: u8 = 0x04;
-const DFU_GETSTATE: u8 = 0x05;
+pub(crate) const DFU_GETSTATE: u8 = 0x05;
 const DFU_ABORT: u8 = 0x06;
 
-const DESC_DESCTYPE_DFU: u8 = 0x21;
+pub(crate) const DESC_DESCTYPE_DFU: u8 = 0x21;
 
-const HAS_READ_UNPROTECT: bool = false;
+/// `bcdDFUVersion` of DfuSe, DFU 1.1 with ST extensions, see [`DFUMemIO::DFU_VERSION`].
+pub const DFU_VERSION_DFUSE: u16 = 0x011A;
+/// `bcdDFUVersion` of standard DFU 1.1, see [`DFUMemIO::DFU_VERSION`].
+pub const DFU_VERSION_1_1: u16 = 0x0110;
 
+/// DFU device state, `bState` field of `DFU_GETSTATUS` reply and `DFU_GETSTATE` reply.
 #[repr(u8)]
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum DFUState {
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DFUState {
     /// Device is running its normal application.
-    #[allow(dead_code)]
     AppIdle = 0,
     /// Device is running its normal application, has received the DFU_DETACH request, and is waiting for a USB reset.
-    #[allow(dead_code)]
     AppDetach = 1,
     /// Device is operating in the DFU mode and is waiting for requests.
     DfuIdle = 2,
@@ -51,9 +59,11 @@ enum DFUState {
     DfuError = 10,
 }
 
+/// DFU status code, `bStatus` field of `DFU_GETSTATUS` reply.
 #[repr(u8)]
-#[derive(Clone, Copy, PartialEq, Eq)]
-enum DFUStatusCode {
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DFUStatusCode {
     /// No error condition is present.
     OK = 0x00,
     /// File is not targeted for use by this device.
@@ -88,17 +98,72 @@ enum DFUStatusCode {
     ErrStalledPkt = 0x0F,
 }
 
-#[repr(u8)]
-enum DnloadCommand {
-    GetCommands = 0x00,
-    SetAddressPointer = 0x21,
-    Erase = 0x41,
-    ReadUnprotect = 0x92,
+impl From<DFUState> for u8 {
+    fn from(state: DFUState) -> u8 {
+        state as u8
+    }
+}
+
+impl TryFrom<u8> for DFUState {
+    /// Unknown state value
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
+            0 => DFUState::AppIdle,
+            1 => DFUState::AppDetach,
+            2 => DFUState::DfuIdle,
+            3 => DFUState::DfuDnloadSync,
+            4 => DFUState::DfuDnBusy,
+            5 => DFUState::DfuDnloadIdle,
+            6 => DFUState::DfuManifestSync,
+            7 => DFUState::DfuManifest,
+            8 => DFUState::DfuManifestWaitReset,
+            9 => DFUState::DfuUploadIdle,
+            10 => DFUState::DfuError,
+            v => return Err(v),
+        })
+    }
+}
+
+impl From<DFUStatusCode> for u8 {
+    fn from(status: DFUStatusCode) -> u8 {
+        status as u8
+    }
+}
+
+impl TryFrom<u8> for DFUStatusCode {
+    /// Unknown status code
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, u8> {
+        Ok(match value {
+            0x00 => DFUStatusCode::OK,
+            0x01 => DFUStatusCode::ErrTarget,
+            0x02 => DFUStatusCode::ErrFile,
+            0x03 => DFUStatusCode::ErrWrite,
+            0x04 => DFUStatusCode::ErrErase,
+            0x05 => DFUStatusCode::ErrCheckErased,
+            0x06 => DFUStatusCode::ErrProg,
+            0x07 => DFUStatusCode::ErrVerify,
+            0x08 => DFUStatusCode::ErrAddress,
+            0x09 => DFUStatusCode::ErrNotdone,
+            0x0A => DFUStatusCode::ErrFirmware,
+            0x0B => DFUStatusCode::ErrVendor,
+            0x0C => DFUStatusCode::ErrUsbr,
+            0x0D => DFUStatusCode::ErrPOR,
+            0x0E => DFUStatusCode::ErrUnknown,
+            0x0F => DFUStatusCode::ErrStalledPkt,
+            v => return Err(v),
+        })
+    }
 }
 
 /// Errors that may happen when working with the memory
 /// (reading, erasing, writting). These will be translated
 /// to a corresponding error codes in DFU protocol.
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum DFUMemError {
     /// File is not targeted for use by this device.
@@ -119,11 +184,14 @@ pub enum DFUMemError {
     Unknown = DFUStatusCode::ErrUnknown as u8,
     /// Cannot program memory due to received address that is out of range.
     Address = DFUStatusCode::ErrAddress as u8,
-    /// A vendor-specific error. iString in DFU_GETSTATUS reply will always be 0.
+    /// A vendor-specific error. iString in DFU_GETSTATUS reply is 0 unless
+    /// [`HAS_VENDOR_ERROR_STRING`](DFUMemIO::HAS_VENDOR_ERROR_STRING) is set.
     ErrVendor = DFUStatusCode::ErrVendor as u8,
 }
 
 /// Errors that may happen when device enter Manifestation phase
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 #[repr(u8)]
 pub enum DFUManifestationError {
     /// File is not targeted for use by this device.
@@ -134,12 +202,220 @@ pub enum DFUManifestationError {
     NotDone = DFUStatusCode::ErrNotdone as u8,
     /// Device’s firmware is corrupt. It cannot return to run-time (non-DFU) operations.
     Firmware = DFUStatusCode::ErrFirmware as u8,
-    /// A vendor-specific error. iString in DFU_GETSTATUS reply will always be 0.
+    /// A vendor-specific error. iString in DFU_GETSTATUS reply is 0 unless
+    /// [`HAS_VENDOR_ERROR_STRING`](DFUMemIO::HAS_VENDOR_ERROR_STRING) is set.
     ErrVendor = DFUStatusCode::ErrVendor as u8,
     /// Something went wrong, but the device does not know what it was.
     Unknown = DFUStatusCode::ErrUnknown as u8,
 }
 
+/// DFU state of a new [`DFUClass`], see [`DFUClass::new_with_state()`].
+#[derive(Debug)]
+pub enum InitialState {
+    /// `dfuIDLE` state, the usual one.
+    Idle,
+    /// `dfuIDLE` state, Address Pointer is set to the value instead of
+    /// [`initial_address_pointer()`](DFUMemIO::initial_address_pointer).
+    IdleAt(u32),
+    /// `dfuERROR` state with `errPOR` status, "Device detected unexpected power on reset".
+    UnexpectedReset,
+    /// `dfuERROR` state with `errFIRMWARE` status, "Device’s firmware is corrupt.
+    /// It cannot return to run-time (non-DFU) operations".
+    FirmwareCorrupted,
+    /// `dfuERROR` state with status of a memory error, for example,
+    /// [`DFUMemError::Verify`] if a firmware signature check has failed.
+    Error(DFUMemError),
+}
+
+/// Memory work done by [`DFUClass`] from `usb_dev.poll([])`,
+/// see [`DFUClass::take_poll_activity()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PollActivity {
+    /// No memory functions were called and no operation is pending.
+    None,
+    /// Erase, program, or manifestation function was called.
+    ExecutedCommand,
+    /// An operation is waiting to be executed, or is still running.
+    CommandPending,
+}
+
+/// DFU Functional descriptor parameters of a [`DFUClass`], see
+/// [`DFUClass::new_with_options()`].
+///
+/// Defaults are [`DFUMemIO`] constants, options allow to choose them at run time,
+/// for example, enable upload only if a jumper is set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DfuOptions {
+    /// *bitCanDnload* bit, replaces [`HAS_DOWNLOAD`](DFUMemIO::HAS_DOWNLOAD)
+    pub has_download: bool,
+    /// *bitCanUpload* bit, replaces [`HAS_UPLOAD`](DFUMemIO::HAS_UPLOAD)
+    pub has_upload: bool,
+    /// *bitWillDetach* bit, replaces [`WILL_DETACH`](DFUMemIO::WILL_DETACH)
+    pub will_detach: bool,
+    /// *bitManifestationTolerant* bit and the state after manifestation,
+    /// replaces [`MANIFESTATION_TOLERANT`](DFUMemIO::MANIFESTATION_TOLERANT)
+    pub manifestation_tolerant: bool,
+    /// *wDetachTimeOut* field, replaces [`DETACH_TIMEOUT`](DFUMemIO::DETACH_TIMEOUT)
+    pub detach_timeout: u16,
+    /// *wTransferSize* field and the size of upload and download blocks, replaces
+    /// [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE), must not be `0` or larger than it
+    pub transfer_size: u16,
+}
+
+impl DfuOptions {
+    /// Creates options with values of `M` constants.
+    pub const fn new<M: DFUMemIO>() -> Self {
+        Self {
+            has_download: M::HAS_DOWNLOAD,
+            has_upload: M::HAS_UPLOAD,
+            will_detach: M::WILL_DETACH,
+            manifestation_tolerant: M::MANIFESTATION_TOLERANT,
+            detach_timeout: M::DETACH_TIMEOUT,
+            transfer_size: M::TRANSFER_SIZE,
+        }
+    }
+
+    /// Run-time part of [`ConfigCheck`], constants are already checked
+    fn check<M: DFUMemIO>(&self) {
+        assert!(
+            self.transfer_size > 0 && self.transfer_size <= M::TRANSFER_SIZE,
+            "DfuOptions::transfer_size must not be 0 or larger than DFUMemIO::TRANSFER_SIZE"
+        );
+        assert!(
+            !self.has_download
+                || (M::PROGRAM_TIME_MS > 0
+                    && M::ERASE_TIME_MS > 0
+                    && M::FULL_ERASE_TIME_MS > 0
+                    && M::MANIFESTATION_TIME_MS > 0),
+            "DFUMemIO::*_TIME_MS values must not be 0 if has_download is true"
+        );
+
+        let crc = if M::BLOCK_CRC { 4 } else { 0 };
+        assert!(
+            self.transfer_size > crc && M::IMAGE_HEADER_SIZE <= (self.transfer_size - crc) as usize,
+            "DfuOptions::transfer_size is too small for BLOCK_CRC or IMAGE_HEADER_SIZE"
+        );
+
+        let download_block = (self.transfer_size - crc) as u32;
+        for r in M::LAYOUT_SEGMENTS {
+            assert!(
+                (r.end - r.start) % self.transfer_size as u32 == 0
+                    && (r.end - r.start) % download_block == 0,
+                "DFUMemIO::LAYOUT_SEGMENTS sizes must be multiples of a block size"
+            );
+        }
+        for l in M::SEGMENT_LIMITS {
+            assert!(
+                l.max_block as u32 <= download_block,
+                "DFUMemIO::SEGMENT_LIMITS block size must not be larger than a download block"
+            );
+        }
+    }
+}
+
+/// Download operation reported by [`DFUMemIO::on_progress()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DfuPhase {
+    /// Page or mass erase
+    Erase,
+    /// Download block program
+    Program,
+    /// Manifestation
+    Manifest,
+}
+
+/// Download progress, see [`DFUMemIO::on_progress()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DfuProgress {
+    /// Completed operation
+    pub phase: DfuPhase,
+    /// Data block number in the download session, starting from `0`,
+    /// `0` for erase and manifestation
+    pub block_num: u32,
+    /// Address of an erased page or a programmed block, Address Pointer
+    /// for mass erase and manifestation
+    pub address: u32,
+    /// Number of bytes programmed since the download session has started
+    pub bytes_written: u32,
+}
+
+/// Class state at the moment of USB reset, see [`DFUMemIO::on_usb_reset()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ResetContext {
+    /// DFU state, `dfuMANIFEST-WAIT-RESET` after a complete download if
+    /// [`MANIFESTATION_TOLERANT`](DFUMemIO::MANIFESTATION_TOLERANT) is `false`
+    pub state: DFUState,
+    /// A manifestation has completed successfully since the previous USB reset
+    pub manifested: bool,
+    /// Data blocks were downloaded since the previous USB reset
+    pub downloaded: bool,
+}
+
+/// What USB reset does to a download or upload in progress,
+/// see [`DFUMemIO::ON_USB_RESET`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UsbResetPolicy {
+    /// Stay in `dfuDNLOAD-IDLE`, `dfuUPLOAD-IDLE`, or `dfuERROR` state, a host may
+    /// continue the download or upload. Other states are handled as with
+    /// [`ErrorUsbr`](UsbResetPolicy::ErrorUsbr), the operation in progress is
+    /// cancelled. The state is not kept if an alternate setting other than `0` was
+    /// selected, USB reset selects setting `0`.
+    KeepState,
+    /// Enter `dfuERROR` state with `errUSBR` status, "Device detected unexpected
+    /// USB reset signaling".
+    ErrorUsbr,
+    /// Enter `dfuIDLE` state, the download or upload is cancelled silently.
+    ReturnToIdle,
+}
+
+/// State of a manifestation, returned by [`DFUMemIO::manifestation_poll()`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Debug)]
+pub enum ManifestationProgress {
+    /// Still running, expected remaining time in milliseconds
+    Busy(u32),
+    /// Completed
+    Done,
+    /// Failed
+    Err(DFUManifestationError),
+}
+
+/// State of an incremental mass erase, returned by [`DFUMemIO::erase_all_next()`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EraseProgress {
+    /// More to erase, expected remaining time in milliseconds
+    Busy(u32),
+    /// Completed
+    Done,
+}
+
+/// Result of a vendor-specific DfuSe command, returned by
+/// [`DFUMemIO::vendor_dnload_command()`].
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VendorCommandOutcome {
+    /// Not a vendor command, rejected with `errSTALLEDPKT` status
+    Unsupported,
+    /// Completed
+    Done,
+    /// Execute later with [`DFUMemIO::vendor_command_execute()`], expected execution
+    /// time in milliseconds is reported as `bwPollTimeout`
+    Deferred(u32),
+}
+
+/// Download block size and timing of a memory segment,
+/// see [`SEGMENT_LIMITS`](DFUMemIO::SEGMENT_LIMITS).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SegmentLimits {
+    /// Largest download block data size, not larger than a download block
+    pub max_block: u16,
+    /// Program time, replaces [`PROGRAM_TIME_MS`](DFUMemIO::PROGRAM_TIME_MS)
+    pub program_time_ms: u32,
+    /// Erase time, replaces [`ERASE_TIME_MS`](DFUMemIO::ERASE_TIME_MS)
+    pub erase_time_ms: u32,
+}
+
 /// Trait that describes the abstraction used to access memory on a device. [`DFUClass`] will call corresponding
 /// functions and will use provided constants to tailor DFU features and, for example time interval values that
 /// are used in the protocol.
@@ -150,6 +426,7 @@ pub trait DFUMemIO {
     /// Specifies the default value of Address Pointer
     ///
     /// Usually, it's start address of a memory region.
+    /// See also [`initial_address_pointer()`](DFUMemIO::initial_address_pointer).
     ///
     const INITIAL_ADDRESS_POINTER: u32;
 
@@ -191,8 +468,22 @@ pub trait DFUMemIO {
     /// Denotes a memory region named "Flash", with a starting address `0x08000000`,
     /// the first 16 pages with a size 1K are available only for reading, and the next
     /// 48 1K-pages are avaiable for reading, erase, and write operations.
+    ///
+    /// A string built at runtime may be returned by [`mem_info_string()`](DFUMemIO::mem_info_string).
+    /// The syntax can be checked at compile time with
+    /// [`dfu_assert_mem_info!`](crate::dfu_assert_mem_info).
     const MEM_INFO_STRING: &'static str;
 
+    /// Number of DFU interface alternate settings, from `1` to `8`. Default is `1`.
+    ///
+    /// Each alternate setting describes a separate memory, for example, internal flash,
+    /// option bytes, and an external flash, and has its own memory layout string,
+    /// see [`mem_info_string_for()`](DFUMemIO::mem_info_string_for). A host selects
+    /// an alternate setting with `SET_INTERFACE` request (`dfu-util -a <alt>`),
+    /// [`select_alt()`](DFUMemIO::select_alt) is called, and following memory operations
+    /// are for the selected memory.
+    const ALT_COUNT: u8 = 1;
+
     /// If set, DFU descriptor will have *bitCanDnload* bit set. Default is `true`.
     ///
     /// Should be set to true if firmware download (host to device) is supported.
@@ -208,9 +499,63 @@ pub trait DFUMemIO {
     /// See also [`MANIFESTATION_TIME_MS`](DFUMemIO::MANIFESTATION_TIME_MS).
     const MANIFESTATION_TOLERANT: bool = true;
 
-    // /// Remove device's flash read protection. This operation should erase
-    // /// memory contents.
-    // const HAS_READ_UNPROTECT : bool = false;
+    /// If set, DFU descriptor will have *bitWillDetach* bit set. Default is `true`.
+    ///
+    /// Should be cleared if the device can't detach from USB bus by itself after
+    /// `DFU_DETACH` request, a host resets USB bus instead. In this case
+    /// [`on_detach_request()`](DFUMemIO::on_detach_request) is not called, and the request
+    /// is pending until the bus reset, which is reported by [`usb_reset()`](DFUMemIO::usb_reset).
+    const WILL_DETACH: bool = true;
+
+    /// If set, DfuSe `Read Unprotect` command is accepted and listed in `Get Commands`
+    /// reply, see [`read_unprotect()`](DFUMemIO::read_unprotect). Default is `false`.
+    const HAS_READ_UNPROTECT: bool = false;
+
+    /// If set, vendor-specific [`DfuseCommand::Crc`] command is accepted and listed in
+    /// `Get Commands` reply. Default is `false`, the command code is passed to
+    /// [`vendor_dnload_command()`](DFUMemIO::vendor_dnload_command).
+    ///
+    /// The range is read with [`read_block()`](DFUMemIO::read_block), or
+    /// [`read_chunk()`](DFUMemIO::read_chunk), a part of it on every poll while the device
+    /// is in `dfuDNBUSY` state, and the digest is returned by the next upload block.
+    /// The digest of a short range reveals memory contents, so the range is checked like
+    /// upload blocks are: it must be readable, see [`CHECK_PERMISSIONS`](DFUMemIO::CHECK_PERMISSIONS),
+    /// and [`REDACTED_RANGES`](DFUMemIO::REDACTED_RANGES) are replaced with
+    /// [`REDACTED_FILL`](DFUMemIO::REDACTED_FILL).
+    const HAS_CRC_COMMAND: bool = false;
+
+    /// If set, `DFU_UPLOAD` request with `wBlockNum` `1` returns device information
+    /// written by [`device_info()`](DFUMemIO::device_info), for example, chip UID or
+    /// bootloader version. Default is `false`, the request is rejected.
+    ///
+    /// Only in DfuSe mode with [`FIRST_DATA_BLOCK`](DFUMemIO::FIRST_DATA_BLOCK) `2`,
+    /// otherwise block `1` is a data block. `Get Commands` reply is not changed.
+    const HAS_DEVICE_INFO: bool = false;
+
+    /// bcdDFUVersion field in DFU descriptor. Default is [`DFU_VERSION_DFUSE`] (`0x011A`).
+    ///
+    /// With [`DFU_VERSION_1_1`] (`0x0110`) the device is a standard DFU 1.1 device:
+    /// `wBlockNum` of `DFU_DNLOAD` and `DFU_UPLOAD` requests is a data block number
+    /// starting from `0`, DfuSe commands and `Get Commands` are not available, and blocks
+    /// are at [`INITIAL_ADDRESS_POINTER`](DFUMemIO::INITIAL_ADDRESS_POINTER) or
+    /// [`DFUClass::set_address_pointer()`] address. Erasing is left to
+    /// [`program()`](DFUMemIO::program). A session is limited to `65534` blocks.
+    ///
+    /// Some hosts, for example, fwupd, treat DfuSe devices as ST bootloaders.
+    const DFU_VERSION: u16 = DFU_VERSION_DFUSE;
+
+    /// `wBlockNum` of the first data block of `DFU_DNLOAD` and `DFU_UPLOAD` requests
+    /// in DfuSe mode. Default is `2`, as in DfuSe.
+    ///
+    /// Some hosts follow DFU 1.1 examples and start data blocks from `1` or `0`
+    /// while still talking to a DfuSe device. With `1`, `wBlockNum` `0` is still
+    /// a DfuSe command, with `0` DfuSe commands and `Get Commands` are not available.
+    /// Data blocks are addressed from the Address Pointer the same way, a session
+    /// is limited to `65536 - (2 - FIRST_DATA_BLOCK)` blocks.
+    ///
+    /// Must be `0`, `1`, or `2`. Not used if [`DFU_VERSION`](DFUMemIO::DFU_VERSION)
+    /// is [`DFU_VERSION_1_1`], data blocks start from `0`.
+    const FIRST_DATA_BLOCK: u16 = 2;
 
     /// Time in milliseconds host must wait before issuing the next command after
     /// block program request.
@@ -235,6 +580,10 @@ pub trait DFUMemIO {
     /// Similar to [`PROGRAM_TIME_MS`](DFUMemIO::PROGRAM_TIME_MS), but for a full erase operation.
     const FULL_ERASE_TIME_MS: u32;
 
+    /// Similar to [`PROGRAM_TIME_MS`](DFUMemIO::PROGRAM_TIME_MS), but for `Read Unprotect`
+    /// command. Default is [`FULL_ERASE_TIME_MS`](DFUMemIO::FULL_ERASE_TIME_MS).
+    const READ_UNPROTECT_TIME_MS: u32 = Self::FULL_ERASE_TIME_MS;
+
     /// Time in milliseconds host must wait after submitting the final firware download
     /// (host to device) command. Default is `1` ms.
     ///
@@ -270,28 +619,328 @@ pub trait DFUMemIO {
     ///
     /// All DFU transfers use Control endpoint only.
     ///
-    /// **Warning**: must be less or equal of `usb-device`'s control endpoint buffer size (usually `128` bytes),
-    /// otherwise data transfers may fail for no obvious reason.
+    /// Must be less or equal of `usb-device`'s control endpoint buffer size (`128` bytes, or `256` bytes
+    /// if `control-buffer-256` feature is enabled), this is checked at compile time.
+    /// `usb-device` receives the whole data stage of a request in this buffer before passing
+    /// it to [`DFUClass`], and stalls requests with a longer data stage, so a download block
+    /// can't be received in chunks.
     const TRANSFER_SIZE: u16 = 128;
 
-    // /// Not supported, implementation would probably need some
-    // /// non-trivial locking.
-    // const MEMIO_IN_USB_INTERRUPT: bool = true;
+    /// Address ranges that are never returned to a host. Default is an empty list.
+    ///
+    /// When an upload (device to host) block overlaps any of these ranges,
+    /// the overlapping bytes are replaced with [`REDACTED_FILL`](DFUMemIO::REDACTED_FILL),
+    /// and [`read()`](DFUMemIO::read) is called only for the remaining parts of the block.
+    /// Block size and addresses of the surrounding data are not affected.
+    ///
+    /// Ranges must lie within the memory region served by [`read()`](DFUMemIO::read).
+    ///
+    /// For example:
+    /// ```text
+    /// const REDACTED_RANGES: &'static [Range<u32>] = &[0x0800_fc00..0x0800_fc40];
+    /// ```
+    const REDACTED_RANGES: &'static [Range<u32>] = &[];
+
+    /// Value that replaces the contents of [`REDACTED_RANGES`](DFUMemIO::REDACTED_RANGES)
+    /// in upload (device to host) blocks. Default is `0xff`.
+    const REDACTED_FILL: u8 = 0xff;
+
+    /// If set, every download (host to device) block ends with a 4-byte CRC-32 trailer. Default is `false`.
+    ///
+    /// The trailer is a little-endian [`crc32()`](crate::crc::crc32) of the block's payload.
+    /// [`DFUClass`] verifies it before [`store_write_buffer()`](DFUMemIO::store_write_buffer) is called,
+    /// and the trailer is not passed to [`store_write_buffer()`](DFUMemIO::store_write_buffer)
+    /// or [`program()`](DFUMemIO::program).
+    ///
+    /// A block with a CRC mismatch is rejected, device enters `dfuERROR` state with `errFILE`
+    /// status and the host may clear the status and send the same block again.
+    ///
+    /// [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE) includes the trailer, so each
+    /// download block carries `TRANSFER_SIZE - 4` bytes of data, and block
+    /// addresses are calculated accordingly. Upload blocks are not affected.
+    const BLOCK_CRC: bool = false;
+
+    /// If set, every block programmed with [`program_block()`](DFUMemIO::program_block)
+    /// is read back with [`read_block()`](DFUMemIO::read_block) and compared with
+    /// the download data. Default is `false`.
+    ///
+    /// Only [`BufferedMem`](crate::buffered::BufferedMem) keeps the download data after
+    /// the block is programmed, so the check is done by `BufferedMem`, which reports this
+    /// constant as `false`. Setting it for a memory that is not wrapped in `BufferedMem`
+    /// fails at compile time, see [`dfu_assert_config!`](crate::dfu_assert_config).
+    /// A mismatch or a failed read fails the request with `errVERIFY`.
+    /// [`PROGRAM_TIME_MS`](DFUMemIO::PROGRAM_TIME_MS) should include the read time.
+    const VERIFY_AFTER_PROGRAM: bool = false;
+
+    /// Size of an image header in bytes, `0` disables image location. Default is `0`.
+    ///
+    /// If set, the first [`IMAGE_HEADER_SIZE`](DFUMemIO::IMAGE_HEADER_SIZE) bytes of the first
+    /// download block of a session are passed to [`locate_image()`](DFUMemIO::locate_image),
+    /// which decides where the image is programmed. A download session ends when device
+    /// returns to `dfuIDLE` state (after abort, clear status, or manifestation).
+    ///
+    /// The first download block must contain the whole header, otherwise the block is rejected
+    /// with `errFILE` status. Must not be larger than a download block.
+    const IMAGE_HEADER_SIZE: usize = 0;
+
+    /// If set, image header is programmed to memory together with the image. Default is `true`.
+    ///
+    /// If not set, header bytes are skipped, and the image data after the header is
+    /// programmed starting at the address returned by [`locate_image()`](DFUMemIO::locate_image).
+    const PROGRAM_IMAGE_HEADER: bool = true;
+
+    /// Time in milliseconds after which `dfuERROR` state is cleared automatically,
+    /// `0` disables this. Default is `0`.
+    ///
+    /// Some hosts do not send `DFU_CLRSTATUS` after an error and retry the
+    /// operation instead. If the device stays in `dfuERROR` state for this long,
+    /// it returns to `dfuIDLE` state by itself and calls [`on_abort()`](DFUMemIO::on_abort).
+    /// Every `DFU_GETSTATUS` request in `dfuERROR` state restarts the timer.
+    ///
+    /// The timeout is checked when a request arrives and from `usb_dev.poll([])`,
+    /// using the time from [`now_ms()`](DFUMemIO::now_ms), which must be implemented.
+    const ERROR_AUTOCLEAR_MS: u32 = 0;
+
+    /// Memory segments described by [`MEM_INFO_STRING`](DFUMemIO::MEM_INFO_STRING),
+    /// sorted by address. Default is an empty list.
+    ///
+    /// If not empty, sequential upload and download blocks are mapped onto these segments:
+    /// when a block would start past the end of a segment, it continues at the start
+    /// of the next segment instead, so a host that assumes contiguous address space
+    /// doesn't touch gaps between segments. Address Pointer must point into one of
+    /// the segments, otherwise blocks are not mapped.
+    ///
+    /// Segment sizes must be multiples of upload and download block sizes, this is checked
+    /// at compile time.
+    ///
+    /// For example, for `"@Flash/0x08000000/2*1Kg,4*1Kg"` with a reserved area in between:
+    /// ```text
+    /// const LAYOUT_SEGMENTS: &'static [Range<u32>] = &[
+    ///     0x0800_0000..0x0800_0800,
+    ///     0x0800_1000..0x0800_2000,
+    /// ];
+    /// ```
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = &[];
+
+    /// Download block size and timing of each of [`LAYOUT_SEGMENTS`](DFUMemIO::LAYOUT_SEGMENTS),
+    /// in the same order. Default is an empty list, all segments use global values.
+    ///
+    /// For memories with different properties in one address space, for example, internal
+    /// flash and an external EEPROM. A download block that starts in a segment and is
+    /// larger than its `max_block` is rejected with `errSTALLEDPKT` status, and
+    /// `bwPollTimeout` of program and erase operations is taken from the segment that
+    /// contains the address. [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE) advertised to a host
+    /// should be the largest block size, a host is expected to send smaller blocks to
+    /// segments with smaller limits, for example, using `Set Address Pointer` command
+    /// before each block.
+    ///
+    /// Must be empty or have as many elements as `LAYOUT_SEGMENTS`, this is checked
+    /// at compile time.
+    const SEGMENT_LIMITS: &'static [SegmentLimits] = &[];
+
+    /// Time budget of program and erase operations that continue after
+    /// [`program()`](DFUMemIO::program), [`erase()`](DFUMemIO::erase), or
+    /// [`erase_all()`](DFUMemIO::erase_all) return, as a multiple of
+    /// [`PROGRAM_TIME_MS`](DFUMemIO::PROGRAM_TIME_MS), [`ERASE_TIME_MS`](DFUMemIO::ERASE_TIME_MS),
+    /// or [`FULL_ERASE_TIME_MS`](DFUMemIO::FULL_ERASE_TIME_MS). `0` disables the limit. Default is `0`.
+    ///
+    /// If [`operation_busy()`](DFUMemIO::operation_busy) still returns `true` when the budget
+    /// is exceeded, device enters `dfuERROR` state with `errPROG` or `errERASE` status
+    /// and [`on_error()`](DFUMemIO::on_error) is called. Time is taken from
+    /// [`now_ms()`](DFUMemIO::now_ms), which must be implemented.
+    const OPERATION_BUDGET_FACTOR: u32 = 0;
+
+    /// Report manifestation result only after a fixed time. Default is `false`.
+    ///
+    /// If `true`, device stays in `dfuMANIFEST` state until [`MANIFESTATION_TIME_MS`](DFUMemIO::MANIFESTATION_TIME_MS)
+    /// elapses since [`manifestation()`](DFUMemIO::manifestation) was called, whether it succeeded
+    /// or failed, and any manifestation error is reported as `errFILE`. A host can't
+    /// tell why an image was rejected, for example, by a signature check, or learn
+    /// how long the check took. `MANIFESTATION_TIME_MS` must cover the longest
+    /// manifestation.
+    ///
+    /// Time is taken from [`now_ms()`](DFUMemIO::now_ms), which must be implemented.
+    const MANIFESTATION_CONSTANT_TIME: bool = false;
+
+    /// Number of consecutive failed manifestations after which downloads are refused,
+    /// `0` disables this. Default is `0`.
+    ///
+    /// When the limit is reached, every `DFU_DNLOAD` request is rejected and device enters
+    /// `dfuERROR` state with `errVENDOR` status, so a host can't try other images, for example,
+    /// to get past a signature check. A successful manifestation resets the counter.
+    /// The counter is not affected by USB reset, it's cleared when [`DFUClass`] is created,
+    /// unless [`persist_lockout()`](DFUMemIO::persist_lockout) restores it.
+    const MAX_FAILED_MANIFESTATIONS: u8 = 0;
+
+    /// Offset and expected bytes of a magic value in a downloaded image, `None` disables
+    /// the check. Default is `None`.
+    ///
+    /// The offset is counted from the start of the first data block of a download session,
+    /// data blocks are expected in order. A block that contains bytes of the magic value
+    /// that don't match is rejected before it's programmed, device enters `dfuERROR` state
+    /// with `errTARGET` status. The magic value may span several blocks, blocks before it
+    /// are programmed as usual. Manifestation is rejected with `errTARGET` if the image
+    /// ends before the whole magic value is received.
+    ///
+    /// The check is done before [`locate_image()`](DFUMemIO::locate_image), on block data
+    /// without [`BLOCK_CRC`](DFUMemIO::BLOCK_CRC) trailers.
+    const IMAGE_MAGIC: Option<(usize, &'static [u8])> = None;
+
+    /// If set, the last [`DfuSuffix::LEN`] bytes of a download session are checked
+    /// for a DFU file suffix, and [`validate_suffix()`](DFUMemIO::validate_suffix) is
+    /// called before manifestation. Default is `false`.
+    ///
+    /// The suffix is appended by `dfu-suffix` tool, it's programmed as a part of
+    /// the image. Suffix bytes may come in several blocks. Bytes are counted from
+    /// the first data block of a session, on block data without
+    /// [`BLOCK_CRC`](DFUMemIO::BLOCK_CRC) trailers, blocks are expected in order.
+    const CHECK_SUFFIX: bool = false;
+
+    /// Allow `DFU_UPLOAD` requests in `dfuDNLOAD-IDLE` state. Default is `false`.
+    ///
+    /// Non-standard: some hosts verify every block right after it's written, without
+    /// leaving the download session with `DFU_ABORT`. If set, an upload in `dfuDNLOAD-IDLE`
+    /// state reads a block back from the address the download block with the same number
+    /// was programmed at, the state doesn't change, and the following download blocks
+    /// continue the session. Get Commands upload is not allowed in this state.
+    const ALLOW_UPLOAD_DURING_DNLOAD_IDLE: bool = false;
+
+    /// Make state transitions on `DFU_GETSTATE` the same way as on `DFU_GETSTATUS`.
+    /// Default is `false`.
+    ///
+    /// DFU specification changes the state only on `DFU_GETSTATUS`, and `DFU_GETSTATE`
+    /// just returns it. Some hosts wait for a download block to be programmed by polling
+    /// `DFU_GETSTATE` until the state is not `dfuDNLOAD-SYNC` or `dfuDNBUSY`. If set,
+    /// `DFU_GETSTATE` moves the device from `dfuDNLOAD-SYNC` to `dfuDNBUSY` or
+    /// `dfuDNLOAD-IDLE`, and from `dfuMANIFEST-SYNC` to `dfuMANIFEST` or `dfuIDLE`,
+    /// and returns the new state. Such hosts don't see `bwPollTimeout`, and should
+    /// poll until the operation is complete.
+    const ADVANCE_ON_GETSTATE: bool = false;
+
+    /// What USB reset does in `dfuDNLOAD-IDLE`, `dfuUPLOAD-IDLE`, `dfuERROR`, and
+    /// states with an operation in progress. Default is [`UsbResetPolicy::ErrorUsbr`].
+    ///
+    /// Some hosts or hubs reset the bus once more right after the device is opened,
+    /// with `ErrorUsbr` the device is already in `dfuERROR` state when the update starts.
+    /// `dfuIDLE` state is not changed by USB reset with any policy, and
+    /// `dfuMANIFEST-WAIT-RESET` state is always left for `dfuIDLE` if
+    /// [`on_manifest_reset()`](DFUMemIO::on_manifest_reset) returns.
+    const ON_USB_RESET: UsbResetPolicy = UsbResetPolicy::ErrorUsbr;
+
+    /// Report vendor-specific error descriptions. Default is `false`.
+    ///
+    /// If set, [`DFUClass::new()`] allocates a string descriptor index, and `iString` field
+    /// of `DFU_GETSTATUS` reply refers to it when the status is `errVENDOR` and
+    /// [`vendor_error_string()`](DFUMemIO::vendor_error_string) returns a string.
+    /// Hosts may show the string, for example, `dfu-util -v`.
+    const HAS_VENDOR_ERROR_STRING: bool = false;
+
+    /// Call erase, program, and manifestation functions from `usb_dev.poll([])`.
+    /// Default is `true`.
+    ///
+    /// If `false`, these operations are not executed from `usb_dev.poll([])`, which is
+    /// usually called from USB interrupt handler, and [`DFUClass::update()`] must be called
+    /// instead, for example, from the main loop. A host sees `dfuDNBUSY` or `dfuMANIFEST`
+    /// state until the operation is executed. [`DFUClass`] is shared between the interrupt
+    /// handler and the main loop, so both calls should be done with the same lock held.
+    ///
+    /// Either way, an operation is executed after the `DFU_GETSTATUS` reply that has
+    /// started it is sent, on the next `usb_dev.poll([])` that reports a USB event, so
+    /// a long operation doesn't delay the reply and its `bwPollTimeout`.
+    ///
+    /// Manifestation that is pending when a host resets USB bus stays pending, and
+    /// [`on_usb_reset()`](DFUMemIO::on_usb_reset) is called after `update()` has
+    /// executed it. If `true`, it's executed from USB reset handler, before `on_usb_reset()`.
+    const MEMIO_IN_USB_INTERRUPT: bool = true;
+
+    /// Read upload blocks in chunks of this size with [`read_chunk()`](DFUMemIO::read_chunk).
+    /// Default is `0`, blocks are read with [`read()`](DFUMemIO::read) at once.
+    ///
+    /// Chunks are read directly to `usb-device`'s control buffer, so the implementation
+    /// doesn't need a read buffer of [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE) bytes.
+    const READ_CHUNK_SIZE: usize = 0;
+
+    /// Check operations against permissions of the memory layout string, see
+    /// [`MEM_INFO_STRING`](DFUMemIO::MEM_INFO_STRING). Default is `true`.
+    ///
+    /// If set, [`DFUClass`] checks the layout of the selected alternate setting before
+    /// memory functions are called, and the memory is not touched if an operation
+    /// overlaps an area without the permission: an upload that starts in an area that is
+    /// not readable fails with `errADDRESS`, otherwise it ends before such area;
+    /// a download block fails with `errWRITE` if it overlaps an area that is not writable,
+    /// and a page erase fails with `errERASE` if it's not in an erasable area.
+    ///
+    /// Addresses that are not in any area are not checked, memory functions
+    /// are expected to fail for them.
+    const CHECK_PERMISSIONS: bool = true;
+
+    /// `bMS_VendorCode`, request code of the vendor request that reads
+    /// Microsoft OS 2.0 descriptor set. Only used with `winusb` feature. Default is `0x20`.
+    ///
+    /// With `winusb` feature, [`DFUClass`] adds MS OS 2.0 platform capability to the BOS
+    /// descriptor, and answers the vendor request with this `bRequest` and
+    /// [`MS_OS_20_DESCRIPTOR_INDEX`](crate::winusb::MS_OS_20_DESCRIPTOR_INDEX) `wIndex`
+    /// with a descriptor set that makes Windows bind WinUSB driver to the DFU interface,
+    /// so no driver installation is needed. Windows reads BOS descriptor only if `bcdUSB`
+    /// is `0x0210` or higher, the default of `usb-device`.
+    ///
+    /// Must not be used by vendor requests of other classes of the device.
+    const WINUSB_VENDOR_CODE: u8 = 0x20;
+
+    /// Device interface GUID that WinUSB registers for the DFU interface, in
+    /// `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}` format. Only used with `winusb` feature.
+    ///
+    /// Default is the same for all devices, host software that looks for a device by its
+    /// interface GUID needs a device-specific value.
+    const WINUSB_GUID: &'static str = "{1d54789b-592e-41b3-9967-126f32f62c96}";
+
+    /// Set for composite devices, WinUSB is bound only to the DFU interface instead of
+    /// the whole device. Only used with `winusb` feature. Default is `false`.
+    ///
+    /// Windows treats a device as composite if it has several interfaces and its device
+    /// class is `0` or an Interface Association Descriptor class.
+    const WINUSB_COMPOSITE: bool = false;
+
+    /// `bVendorCode` of WebUSB platform capability, request code of WebUSB vendor
+    /// requests. Only used with `webusb` feature. Default is `0x21`.
+    ///
+    /// With `webusb` feature, [`DFUClass`] adds WebUSB platform capability to the BOS
+    /// descriptor, so browsers allow web pages to access the device, for example, to
+    /// update firmware with WebDFU. Must not be used by vendor requests of other classes
+    /// of the device, or by [`WINUSB_VENDOR_CODE`](DFUMemIO::WINUSB_VENDOR_CODE).
+    const WEBUSB_VENDOR_CODE: u8 = 0x21;
+
+    /// WebUSB landing page URL, `None` if the device has no landing page. Only used with
+    /// `webusb` feature. Default is `None`.
+    ///
+    /// Browsers may suggest to open the page when the device is connected. The URL is
+    /// returned by `GET_URL` vendor request, `http://` and `https://` prefixes are encoded
+    /// as `bScheme`, other URLs are sent as is.
+    const WEBUSB_LANDING_PAGE: Option<&'static str> = None;
 
     /// Collect data which comes from USB, possibly in chunks, to a buffer in RAM.
     ///
     /// [`DFUClass`] does not have an internal memory buffer for a read/write operations,
     /// incoming data should be stored in a buffer managed by this trait's implementation.
+    /// Alternatively, an implementation may be wrapped in [`BufferedMem`](crate::buffered::BufferedMem),
+    /// which owns the buffer and calls [`program_block()`](DFUMemIO::program_block) instead.
+    /// Default implementation returns an error.
     ///
     /// This function should not write data to Flash or trigger memory Erase.
     ///
     /// The same buffer may be shared for both write and read operations.
     /// DFU protocol will not trigger block write while sending data to host, and
     /// will ensure that buffer has valid data before program operation is requested.
+    /// `src` is never longer than [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE), larger
+    /// blocks are refused with `errSTALLEDPKT` status.
     ///
     /// This function is called from `usb_dev.poll([])` (USB interrupt context).
     ///
-    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()>;
+    #[allow(clippy::result_unit_err)]
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        let _ = src;
+        Err(())
+    }
 
     /// Read memory and return it to device.
     ///
@@ -301,39 +950,287 @@ pub trait DFUMemIO {
     /// Implementation must check that address is in a target region and that the
     /// whole block fits in this region too.
     ///
+    /// Implementations should implement either this function or
+    /// [`read_block()`](DFUMemIO::read_block). Default implementation returns
+    /// [`DFUMemError::Unknown`].
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        let _ = (address, length);
+        Err(DFUMemError::Unknown)
+    }
+
+    /// Read memory at `address` to `dest`.
+    ///
+    /// Same as [`read()`](DFUMemIO::read), but the data is written to `dest`,
+    /// which is a part of `usb-device`'s control buffer, so the implementation doesn't need
+    /// to own a buffer. Returns the number of bytes read, a short read ends the upload,
+    /// unless [`is_end_of_region()`](DFUMemIO::is_end_of_region) returns `false`.
+    /// Default implementation calls [`read()`](DFUMemIO::read) and copies the data.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        let data = self.read(address, dest.len())?;
+        let len = min(data.len(), dest.len());
+        dest[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    /// Read a part of an upload block at `address` to `buf`, used if
+    /// [`READ_CHUNK_SIZE`](DFUMemIO::READ_CHUNK_SIZE) is not `0`.
+    ///
+    /// `offset` is the offset of the chunk in the block, `buf` is at most `READ_CHUNK_SIZE`
+    /// bytes long. Returns the number of bytes read, a short read ends the upload block,
+    /// unless [`is_end_of_region()`](DFUMemIO::is_end_of_region) returns `false`.
+    /// Default implementation calls [`read_block()`](DFUMemIO::read_block).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn read_chunk(
+        &mut self,
+        address: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DFUMemError> {
+        self.read_block(address.wrapping_add(offset as u32), buf)
+    }
+
+    /// Returns `true` if readable memory ends at `address`, checked after a short
+    /// [`read_block()`](DFUMemIO::read_block) or [`read_chunk()`](DFUMemIO::read_chunk)
+    /// that stopped at `address`. Default implementation returns `true`.
+    ///
+    /// If it returns `false`, the read returned less data than requested, but there is more,
+    /// and the rest of the upload block is read from `address` with more calls, for example,
+    /// if memory is read by ECC codewords smaller than [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE).
+    /// Otherwise the upload block is short, and the upload ends. A read of `0` bytes
+    /// always ends the upload.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn is_end_of_region(&self, address: u32) -> bool {
+        let _ = address;
+        true
+    }
+
+    /// Returns the address where uploads end, for example, the end of a firmware image
+    /// that is shorter than its memory region. Default implementation returns `None`.
+    ///
+    /// If set, upload blocks are cut at this address: the block that contains it is
+    /// short, or the next block is empty if it's on a block boundary, a host sees
+    /// the end of the upload, and the device returns to `dfuIDLE` state. Blocks
+    /// that start at or after the address are empty. Blocks read back during
+    /// a download, see [`ALLOW_UPLOAD_DURING_DNLOAD_IDLE`](DFUMemIO::ALLOW_UPLOAD_DURING_DNLOAD_IDLE),
+    /// are not cut.
+    ///
     /// This function is called from `usb_dev.poll([])` (USB interrupt context).
     ///
-    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError>;
+    fn upload_end_address(&self) -> Option<u32> {
+        None
+    }
 
     /// Trigger block program.
     ///
     /// Implementation must check that address is in a target region and that the
     /// whole block fits in this region too.
     ///
-    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
-    // / This function by default is called from USB interrupt context, depending on
-    // / [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT) value.
+    /// Data is in the buffer filled by [`store_write_buffer()`](DFUMemIO::store_write_buffer).
+    /// Default implementation returns [`DFUMemError::Unknown`].
+    ///
+    /// A standard DFU 1.1 host (see [`DFU_VERSION`](DFUMemIO::DFU_VERSION)) does not send
+    /// erase commands, the implementation should erase a page before its first block
+    /// is programmed.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
+    ///
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        let _ = (address, length);
+        Err(DFUMemError::Unknown)
+    }
+
+    /// Program `data` at `address`.
+    ///
+    /// Called by [`BufferedMem`](crate::buffered::BufferedMem) instead of
+    /// [`store_write_buffer()`](DFUMemIO::store_write_buffer) and
+    /// [`program()`](DFUMemIO::program), `data` is in the wrapper's buffer.
+    /// Implementation must check that address is in a target region and that the
+    /// whole block fits in this region too. Default implementation returns
+    /// [`DFUMemError::Unknown`].
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
+    ///
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        let _ = (address, data);
+        Err(DFUMemError::Unknown)
+    }
+
+    /// Time in milliseconds to program a block of `length` bytes, reported to a host
+    /// in `bwPollTimeout`. Default is [`PROGRAM_TIME_MS`](DFUMemIO::PROGRAM_TIME_MS).
+    ///
+    /// `length` is the number of bytes stored by the download request, the last block
+    /// of an image is usually shorter than [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE).
+    /// [`SEGMENT_LIMITS`](DFUMemIO::SEGMENT_LIMITS) program time takes precedence.
+    /// Values larger than 24 bits are truncated to `0xFFFFFF`.
+    fn program_time_ms(&self, length: usize) -> u32 {
+        let _ = length;
+        Self::PROGRAM_TIME_MS
+    }
+
+    /// Time in milliseconds to erase a page at `address`, reported to a host
+    /// in `bwPollTimeout`. Default is [`ERASE_TIME_MS`](DFUMemIO::ERASE_TIME_MS).
+    ///
+    /// Useful for memories with pages of different sizes.
+    /// [`SEGMENT_LIMITS`](DFUMemIO::SEGMENT_LIMITS) erase time takes precedence.
+    /// Values larger than 24 bits are truncated to `0xFFFFFF`.
+    fn erase_time_ms(&self, address: u32) -> u32 {
+        let _ = address;
+        Self::ERASE_TIME_MS
+    }
+
+    /// Size of an erasable page that contains `address`, `None` if `address` is
+    /// outside of erasable memory.
+    ///
+    /// DfuSe `Erase Page` command address is checked before [`erase()`](DFUMemIO::erase)
+    /// is called: an address outside of erasable memory fails with `errADDRESS`, an
+    /// address that is not a multiple of the page size fails with `errERASE`.
+    /// Default implementation returns `Some(1)`, any address is accepted.
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        let _ = address;
+        Some(1)
+    }
+
+    /// Returns `true` if any of `length` bytes at `address` must not be programmed
+    /// or erased over DFU, for example, the bootloader itself.
+    ///
+    /// Checked before every [`program()`](DFUMemIO::program) call with the block address
+    /// and length, and before every [`erase()`](DFUMemIO::erase) call with the page returned
+    /// by [`page_size_at()`](DFUMemIO::page_size_at). If the range is protected, memory is not
+    /// touched and the request fails with `errWRITE` or `errERASE`.
+    /// [`erase_all()`](DFUMemIO::erase_all) is not checked, it should skip protected pages.
+    /// Default implementation returns `false`.
+    fn is_write_protected(&self, address: u32, length: usize) -> bool {
+        let _ = (address, length);
+        false
+    }
+
+    /// Returns `true` if `length` bytes at `address` are erased and can be programmed.
     ///
-    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError>;
+    /// Called before every [`program()`](DFUMemIO::program) call with the block address
+    /// and length, after [`is_write_protected()`](DFUMemIO::is_write_protected).
+    /// If the range is not blank, for example, a host did not erase it first, the block
+    /// is not programmed and the request fails with `errCHECK_ERASED`. An error is
+    /// reported to the host as is.
+    /// Default implementation returns `Ok(true)`, memory is not checked.
+    fn is_blank(&mut self, address: u32, length: usize) -> Result<bool, DFUMemError> {
+        let _ = (address, length);
+        Ok(true)
+    }
 
     /// Trigger page erase.
     ///
     /// Implementation must ensure that address is valid, or return an error.
     ///
-    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
-    // / This function by default is called from USB interrupt context, depending on
-    // / [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT) value.
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
     ///
     fn erase(&mut self, address: u32) -> Result<(), DFUMemError>;
 
     /// Trigger full erase.
     ///
-    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
-    // / This function by default is called from USB interrupt context, depending on
-    // / [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT) value.
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
     ///
     fn erase_all(&mut self) -> Result<(), DFUMemError>;
 
+    /// Erase the next part of memory for a mass erase, called until it returns
+    /// [`EraseProgress::Done`]. Default implementation calls [`erase_all()`](DFUMemIO::erase_all).
+    ///
+    /// This is useful if a full erase takes longer than a host waits for a status.
+    /// Device stays in `dfuDNBUSY` state and reports the remaining time returned in
+    /// [`EraseProgress::Busy`] in `bwPollTimeout`. The function is called once per
+    /// `usb_dev.poll([])`, or [`DFUClass::update()`] call.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
+    ///
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        self.erase_all()?;
+        Ok(EraseProgress::Done)
+    }
+
+    /// Remove read protection of the memory, called for DfuSe `Read Unprotect` command
+    /// if [`HAS_READ_UNPROTECT`](DFUMemIO::HAS_READ_UNPROTECT) is `true`.
+    ///
+    /// This operation should erase memory contents. On many devices, for example, STM32
+    /// with RDP level 1, the device is mass-erased and reset, in this case this function
+    /// should not return. If it returns `Ok()`, the device reports the command as completed.
+    /// Default implementation returns [`DFUMemError::Unknown`].
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
+    ///
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        Err(DFUMemError::Unknown)
+    }
+
+    /// Called for a DfuSe command block with an unknown command code `cmd`,
+    /// `payload` is the rest of the block.
+    ///
+    /// A short command may be executed here and return [`VendorCommandOutcome::Done`].
+    /// A command that takes longer should save its arguments and return
+    /// [`VendorCommandOutcome::Deferred`], it's executed later by
+    /// [`vendor_command_execute()`](DFUMemIO::vendor_command_execute). An error is
+    /// reported to the host. Default implementation returns
+    /// [`VendorCommandOutcome::Unsupported`], the command is rejected.
+    ///
+    /// Commands may be advertised with [`vendor_commands()`](DFUMemIO::vendor_commands).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn vendor_dnload_command(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+    ) -> Result<VendorCommandOutcome, DFUMemError> {
+        let _ = (cmd, payload);
+        Ok(VendorCommandOutcome::Unsupported)
+    }
+
+    /// Executes a vendor-specific command deferred by
+    /// [`vendor_dnload_command()`](DFUMemIO::vendor_dnload_command).
+    /// Default implementation returns [`DFUMemError::Unknown`].
+    ///
+    /// Not called in dry run mode (see [`DFUClass::set_dry_run()`]).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
+    ///
+    fn vendor_command_execute(&mut self, cmd: u8) -> Result<(), DFUMemError> {
+        let _ = cmd;
+        Err(DFUMemError::Unknown)
+    }
+
+    /// Writes implementation-defined device information to `buf`, returns its length,
+    /// see [`HAS_DEVICE_INFO`](DFUMemIO::HAS_DEVICE_INFO). `buf` is limited by `wLength`
+    /// of the request. Default implementation returns `0`.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn device_info(&mut self, buf: &mut [u8]) -> usize {
+        let _ = buf;
+        0
+    }
+
     /// Finish writing firmware to a persistent storage, and optionally activate it.
     ///
     /// This funciton should return if [`MANIFESTATION_TOLERANT`](DFUMemIO::MANIFESTATION_TOLERANT) is `true`.
@@ -341,12 +1238,82 @@ pub trait DFUMemIO {
     /// This funciton should not return `Ok()` if `MANIFESTATION_TOLERANT` is `false`.
     /// Instead device should activate and start new main firmware.
     ///
-    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
-    // / This function by default is called from USB interrupt context, depending on
-    // / [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT) value.
+    /// If a host resets USB bus after the final download request, but before manifestation
+    /// has started, this function is still called, and USB reset is reported to
+    /// [`on_usb_reset()`](DFUMemIO::on_usb_reset) after it.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
     ///
     fn manifestation(&mut self) -> Result<(), DFUManifestationError>;
 
+    /// Start manifestation, it continues until [`manifestation_poll()`](DFUMemIO::manifestation_poll)
+    /// reports it's done. Default implementation calls [`manifestation()`](DFUMemIO::manifestation).
+    ///
+    /// This is useful if manifestation takes much longer than a host waits for a status,
+    /// for example, an image is copied from a staging area. Device stays in `dfuMANIFEST`
+    /// state and reports the remaining time returned by `manifestation_poll()` in
+    /// `bwPollTimeout`. If a host resets USB bus meanwhile, the USB reset handler
+    /// doesn't wait, `manifestation_poll()` is still called from `usb_dev.poll([])`
+    /// or [`DFUClass::update()`], and [`on_usb_reset()`](DFUMemIO::on_usb_reset) is
+    /// called when manifestation is complete.
+    ///
+    /// `manifestation()` should still do the whole manifestation, it's used by
+    /// wrappers that can't poll, like [`DualSlot`](crate::DualSlot).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
+    ///
+    fn manifestation_start(&mut self) -> Result<(), DFUManifestationError> {
+        self.manifestation()
+    }
+
+    /// Returns the state of a manifestation started by
+    /// [`manifestation_start()`](DFUMemIO::manifestation_start).
+    /// Default implementation returns [`ManifestationProgress::Done`].
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn manifestation_poll(&mut self) -> ManifestationProgress {
+        ManifestationProgress::Done
+    }
+
+    /// Check if manifestation may start.
+    ///
+    /// Called when a host finishes a download with a zero-length `DFU_DNLOAD` request,
+    /// before device enters `dfuMANIFEST-SYNC` state. On error, the request is rejected
+    /// and device enters `dfuERROR` state with a corresponding status, a host may clear
+    /// the status and finish the download again later. Default implementation returns `Ok(())`.
+    ///
+    /// May be used to veto the update if the device is not ready to activate
+    /// a new firmware.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn manifestation_allowed(&mut self) -> Result<(), DFUManifestationError> {
+        Ok(())
+    }
+
+    /// Check DFU file suffix of a downloaded image,
+    /// see [`CHECK_SUFFIX`](DFUMemIO::CHECK_SUFFIX).
+    ///
+    /// Called when a host finishes a download with a zero-length `DFU_DNLOAD` request,
+    /// before [`manifestation_allowed()`](DFUMemIO::manifestation_allowed). `suffix` is
+    /// `None` if the image does not end with a suffix. On error, the request is rejected
+    /// and device enters `dfuERROR` state with a corresponding status, usually
+    /// [`DFUManifestationError::Target`] if the image is for another device, or
+    /// [`DFUManifestationError::File`] if [`crc_matches()`](DfuSuffix::crc_matches)
+    /// is `false`. Default implementation returns `Ok(())`.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn validate_suffix(&mut self, suffix: Option<DfuSuffix>) -> Result<(), DFUManifestationError> {
+        let _ = suffix;
+        Ok(())
+    }
+
     /// Called every time when USB is reset.
     ///
     /// After firmware update is done, device should switch to an application
@@ -360,18 +1327,295 @@ pub trait DFUMemIO {
     /// to ERROR state so host could try to recover. This is the default.
     ///
     /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    /// See also [`on_usb_reset()`](DFUMemIO::on_usb_reset), which calls it.
     ///
     fn usb_reset(&mut self) {}
-}
 
-impl From<DFUMemError> for DFUStatusCode {
-    fn from(e: DFUMemError) -> Self {
-        match e {
-            DFUMemError::File => DFUStatusCode::ErrFile,
-            DFUMemError::Target => DFUStatusCode::ErrTarget,
-            DFUMemError::Address => DFUStatusCode::ErrAddress,
-            DFUMemError::CheckErased => DFUStatusCode::ErrCheckErased,
-            DFUMemError::Erase => DFUStatusCode::ErrErase,
+    /// Called every time when USB is reset, `ctx` describes what the class was doing.
+    ///
+    /// Default implementation calls [`usb_reset()`](DFUMemIO::usb_reset). May be
+    /// overridden instead of it, for example, to switch to the application only if
+    /// `ctx.state` is `dfuMANIFEST-WAIT-RESET` or `ctx.manifested` is `true`,
+    /// and stay in DFU mode when the device connects the first time at startup.
+    /// The same rules apply as for `usb_reset()`.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or
+    /// from [`DFUClass::update()`] if USB reset has waited for a pending manifestation,
+    /// see [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT).
+    ///
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        let _ = ctx;
+        self.usb_reset()
+    }
+
+    /// Called when USB is reset in `dfuMANIFEST-WAIT-RESET` state, after a complete
+    /// download if [`MANIFESTATION_TOLERANT`](DFUMemIO::MANIFESTATION_TOLERANT) is `false`.
+    ///
+    /// The device should start the new application firmware and this function should
+    /// not return. Unlike [`on_usb_reset()`](DFUMemIO::on_usb_reset), it's not called for
+    /// other resets, like the one when the device connects the first time at startup.
+    ///
+    /// If it returns, for example, if firmware is corrupt, `on_usb_reset()` is called
+    /// and the device stays in DFU mode in `dfuIDLE` state. Default implementation does nothing.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn on_manifest_reset(&mut self) {}
+
+    /// Inspect an image header and return the address where the image should be programmed.
+    ///
+    /// Called with the first [`IMAGE_HEADER_SIZE`](DFUMemIO::IMAGE_HEADER_SIZE) bytes of
+    /// the first download block of a session if `IMAGE_HEADER_SIZE` is not `0`.
+    /// Returned address replaces Address Pointer for the rest of the session, and
+    /// the host can't change it with `Set Address Pointer` command until the session ends.
+    ///
+    /// Implementation should check that the image fits in a target region, error is reported
+    /// to the host and the block is not programmed. Default implementation returns
+    /// [`DFUMemError::Target`].
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn locate_image(&mut self, header: &[u8]) -> Result<u32, DFUMemError> {
+        let _ = header;
+        Err(DFUMemError::Target)
+    }
+
+    /// Returns the current time in milliseconds.
+    ///
+    /// The value should increase monotonically and may wrap around. Used if
+    /// [`ERROR_AUTOCLEAR_MS`](DFUMemIO::ERROR_AUTOCLEAR_MS) is not `0`, and to expire
+    /// `DFU_DETACH` requests. Default implementation returns `0`.
+    ///
+    /// Alternatively, the application may keep the default and report elapsed time
+    /// with [`DFUClass::tick()`], which is added to this value.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn now_ms(&mut self) -> u32 {
+        0
+    }
+
+    /// Returns a timestamp for profiling, in any units, for example, a cycle counter value.
+    ///
+    /// The value should increase monotonically and may wrap around. Only used with
+    /// `profiling` feature, see `DFUClass::profile()`. Default implementation returns
+    /// [`now_ms()`](DFUMemIO::now_ms).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn profile_ticks(&mut self) -> u32 {
+        self.now_ms()
+    }
+
+    /// Called when a download or upload operation is aborted, either by
+    /// `DFU_ABORT` request, or when `dfuERROR` state is cleared automatically
+    /// (see [`ERROR_AUTOCLEAR_MS`](DFUMemIO::ERROR_AUTOCLEAR_MS)).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn on_abort(&mut self) {}
+
+    /// Called when a host selects the device configuration with DFU interface
+    /// (`SET_CONFIGURATION` request), once per enumeration.
+    ///
+    /// May be used to indicate that the device is ready for an update.
+    /// USB reset is reported by [`usb_reset()`](DFUMemIO::usb_reset).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn on_configured(&mut self) {}
+
+    /// Called when a host sends `DFU_DETACH` request to the device in DFU mode,
+    /// for example, `dfu-util --detach`, after the request is accepted.
+    ///
+    /// `timeout_ms` is `wTimeout` value of the request. The implementation may reset
+    /// the device to run the application, in this case this function should not return.
+    /// If it returns, DFU state is not changed, and the request expires after `timeout_ms`
+    /// (see [`DFUClass::detach_pending()`], requires [`now_ms()`](DFUMemIO::now_ms)
+    /// or [`DFUClass::tick()`]).
+    ///
+    /// Not called if [`WILL_DETACH`](DFUMemIO::WILL_DETACH) is `false`.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        let _ = timeout_ms;
+    }
+
+    /// Called when `DFU_DETACH` request has expired without USB reset,
+    /// see [`on_detach_request()`](DFUMemIO::on_detach_request). DFU state is not changed.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context),
+    /// or from [`DFUClass::tick()`].
+    ///
+    fn on_detach_expired(&mut self) {}
+
+    /// Called after erase, program, or manifestation function has returned `Ok()`,
+    /// for example, to show download progress. Default implementation does nothing.
+    ///
+    /// An operation that continues in background (see
+    /// [`operation_busy()`](DFUMemIO::operation_busy)) is reported when it's started.
+    /// Not called in dry run mode (see [`DFUClass::set_dry_run()`]).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
+    ///
+    fn on_progress(&mut self, progress: DfuProgress) {
+        let _ = progress;
+    }
+
+    /// Called when DFU state or status has changed, for example, to show the state
+    /// with a LED. `status` is the new status. Default implementation does nothing.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context), or from
+    /// [`DFUClass::update()`] if [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
+    /// is `false`.
+    ///
+    fn on_state_change(&mut self, old: DFUState, new: DFUState, status: DFUStatusCode) {
+        let _ = (old, new, status);
+    }
+
+    /// Returns `true` while an operation started by the last [`program()`](DFUMemIO::program),
+    /// [`erase()`](DFUMemIO::erase), or [`erase_all()`](DFUMemIO::erase_all) call is still running.
+    ///
+    /// This allows these functions to start an operation and return without waiting for it
+    /// to complete, device stays in `dfuDNBUSY` state until this function returns `false`,
+    /// see [`OPERATION_BUDGET_FACTOR`](DFUMemIO::OPERATION_BUDGET_FACTOR).
+    /// Default implementation returns `false`, operations complete before functions return.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn operation_busy(&mut self) -> bool {
+        false
+    }
+
+    /// Returns the result of an operation that was running in background, called once
+    /// after [`operation_busy()`](DFUMemIO::operation_busy) returns `false`.
+    ///
+    /// If an error is returned, device enters `dfuERROR` state with the corresponding status.
+    /// Default implementation returns `Ok(())`.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn operation_result(&mut self) -> Result<(), DFUMemError> {
+        Ok(())
+    }
+
+    /// Called when a program or erase operation exceeds its time budget
+    /// (see [`OPERATION_BUDGET_FACTOR`](DFUMemIO::OPERATION_BUDGET_FACTOR)).
+    ///
+    /// The implementation should stop the operation, for example, by resetting
+    /// memory controller.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn on_error(&mut self) {}
+
+    /// Store or load the number of consecutive failed manifestations,
+    /// see [`MAX_FAILED_MANIFESTATIONS`](DFUMemIO::MAX_FAILED_MANIFESTATIONS).
+    ///
+    /// Called with `None` when [`DFUClass`] is created, and with a new value every time
+    /// it changes. Returns the value [`DFUClass`] will use. Implementation may keep the
+    /// counter in flash or backup registers so a lockout survives device reset,
+    /// and may return `0` to clear a lockout. Default implementation returns
+    /// `failures`, or `0` for `None`.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context),
+    /// and from [`DFUClass::new()`].
+    ///
+    fn persist_lockout(&mut self, failures: Option<u8>) -> u8 {
+        failures.unwrap_or(0)
+    }
+
+    /// Returns memory layout string reported in the DFU interface string descriptor.
+    ///
+    /// Default implementation returns [`MEM_INFO_STRING`](DFUMemIO::MEM_INFO_STRING).
+    /// May be overridden if the layout is known only at runtime, for example, if it
+    /// depends on a flash size register, see [`MemInfoString`](crate::meminfo::MemInfoString).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn mem_info_string(&self) -> &str {
+        Self::MEM_INFO_STRING
+    }
+
+    /// Returns the default value of Address Pointer.
+    ///
+    /// Default implementation returns [`INITIAL_ADDRESS_POINTER`](DFUMemIO::INITIAL_ADDRESS_POINTER).
+    /// May be overridden if the start address is known only at runtime, for example,
+    /// if it's the currently inactive flash bank.
+    ///
+    /// This function is called when [`DFUClass`] is created, and again every time
+    /// `DFU_CLRSTATUS` request returns it from `dfuERROR` to `dfuIDLE` state. Address Pointer
+    /// is moved only if the returned value differs from the previous one, for example,
+    /// after a bank swap. It's not called if the address is set by
+    /// [`DFUClass::new_with_address()`].
+    ///
+    fn initial_address_pointer(&self) -> u32 {
+        Self::INITIAL_ADDRESS_POINTER
+    }
+
+    /// Returns memory layout string of alternate setting `alt`, see [`ALT_COUNT`](DFUMemIO::ALT_COUNT).
+    ///
+    /// The string is reported in `iInterface` string descriptor of the alternate setting,
+    /// [`DFUClass`] allocates one string index per setting. Default implementation returns
+    /// [`mem_info_string()`](DFUMemIO::mem_info_string).
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn mem_info_string_for(&self, alt: u8) -> &str {
+        let _ = alt;
+        self.mem_info_string()
+    }
+
+    /// Called when a host selects alternate setting `alt`, see [`ALT_COUNT`](DFUMemIO::ALT_COUNT).
+    ///
+    /// Alternate setting may be changed only in `dfuIDLE` state, and is set back to `0`
+    /// on USB reset. Read, erase, and program functions that are called later
+    /// must operate on the memory of `alt`. Default implementation does nothing.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn select_alt(&mut self, alt: u8) {
+        let _ = alt;
+    }
+
+    /// Returns a description of the last `errVENDOR` error, see
+    /// [`HAS_VENDOR_ERROR_STRING`](DFUMemIO::HAS_VENDOR_ERROR_STRING).
+    ///
+    /// The implementation may save the description when a function returns
+    /// [`DFUMemError::ErrVendor`] or [`DFUManifestationError::ErrVendor`].
+    /// `iString` is `0` if `None` is returned. Default implementation returns `None`.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn vendor_error_string(&self) -> Option<&str> {
+        None
+    }
+
+    /// Returns command codes appended to DfuSe `Get Commands` reply, after the standard
+    /// commands. Default implementation returns an empty list.
+    ///
+    /// Some host tools check the list before sending a command. The commands are
+    /// executed by [`vendor_dnload_command()`](DFUMemIO::vendor_dnload_command).
+    /// The reply is truncated to the control buffer size.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn vendor_commands(&self) -> &[u8] {
+        &[]
+    }
+}
+
+impl From<DFUMemError> for DFUStatusCode {
+    fn from(e: DFUMemError) -> Self {
+        match e {
+            DFUMemError::File => DFUStatusCode::ErrFile,
+            DFUMemError::Target => DFUStatusCode::ErrTarget,
+            DFUMemError::Address => DFUStatusCode::ErrAddress,
+            DFUMemError::CheckErased => DFUStatusCode::ErrCheckErased,
+            DFUMemError::Erase => DFUStatusCode::ErrErase,
             DFUMemError::Write => DFUStatusCode::ErrWrite,
             DFUMemError::Prog => DFUStatusCode::ErrProg,
             DFUMemError::Verify => DFUStatusCode::ErrVerify,
@@ -394,15 +1638,411 @@ impl From<DFUManifestationError> for DFUStatusCode {
     }
 }
 
+/// Platform device capability type of BOS descriptor
+#[cfg(any(feature = "winusb", feature = "webusb"))]
+const CAPABILITY_PLATFORM: u8 = 0x05;
+
+/// Maximum length of `usb-device`'s control transfer buffer.
+#[cfg(not(feature = "control-buffer-256"))]
+const CONTROL_BUF_LEN: usize = 128;
+#[cfg(feature = "control-buffer-256")]
+const CONTROL_BUF_LEN: usize = 256;
+
+/// Echo self-test mode state, see [`DfuseCommand::Echo`]
+#[cfg(feature = "echo-test")]
+struct Echo {
+    enabled: bool,
+    len: usize,
+    buf: [u8; CONTROL_BUF_LEN],
+}
+
+/// Maximum value of 24-bit bwPollTimeout field in DFU_GETSTATUS reply.
+const MAX_POLL_TIMEOUT: u32 = 0xff_ffff;
+
+/// Size of the stack buffer for memory reads of CRC command.
+const CRC_CHUNK_SIZE: usize = 64;
+
+/// Number of bytes CRC command reads in one poll.
+const CRC_BYTES_PER_POLL: u32 = 1024;
+
+/// Maximum number of interface alternate settings, see `ALT_COUNT`
+pub(crate) const MAX_ALT_COUNT: usize = 8;
+
+/// Compile-time checks of [`DFUMemIO`] constants, see [`dfu_assert_config!`](crate::dfu_assert_config).
+#[doc(hidden)]
+pub struct ConfigCheck<M: DFUMemIO>(PhantomData<M>);
+
+impl<M: DFUMemIO> ConfigCheck<M> {
+    #[doc(hidden)]
+    pub const OK: () = {
+        assert!(
+            M::TRANSFER_SIZE > 0,
+            "DFUMemIO::TRANSFER_SIZE must not be 0"
+        );
+        assert!(
+            M::TRANSFER_SIZE as usize <= CONTROL_BUF_LEN,
+            "DFUMemIO::TRANSFER_SIZE is larger than usb-device control buffer, \
+            consider enabling \"control-buffer-256\" feature"
+        );
+
+        assert!(
+            M::DFU_VERSION == DFU_VERSION_DFUSE || M::DFU_VERSION == DFU_VERSION_1_1,
+            "DFUMemIO::DFU_VERSION must be DFU_VERSION_DFUSE or DFU_VERSION_1_1"
+        );
+
+        assert!(
+            M::FIRST_DATA_BLOCK <= 2,
+            "DFUMemIO::FIRST_DATA_BLOCK must be 0, 1, or 2"
+        );
+
+        assert!(
+            M::ALT_COUNT > 0 && M::ALT_COUNT as usize <= MAX_ALT_COUNT,
+            "DFUMemIO::ALT_COUNT must be from 1 to 8"
+        );
+
+        assert!(
+            M::PROGRAM_TIME_MS <= MAX_POLL_TIMEOUT
+                && M::ERASE_TIME_MS <= MAX_POLL_TIMEOUT
+                && M::FULL_ERASE_TIME_MS <= MAX_POLL_TIMEOUT
+                && M::READ_UNPROTECT_TIME_MS <= MAX_POLL_TIMEOUT
+                && M::MANIFESTATION_TIME_MS <= MAX_POLL_TIMEOUT,
+            "DFUMemIO::*_TIME_MS values must fit in 24 bits"
+        );
+
+        assert!(
+            !M::HAS_DOWNLOAD
+                || (M::PROGRAM_TIME_MS > 0
+                    && M::ERASE_TIME_MS > 0
+                    && M::FULL_ERASE_TIME_MS > 0
+                    && M::MANIFESTATION_TIME_MS > 0),
+            "DFUMemIO::*_TIME_MS values must not be 0 if HAS_DOWNLOAD is true"
+        );
+
+        assert!(
+            !M::HAS_READ_UNPROTECT || M::READ_UNPROTECT_TIME_MS > 0,
+            "DFUMemIO::READ_UNPROTECT_TIME_MS must not be 0 if HAS_READ_UNPROTECT is true"
+        );
+
+        assert!(
+            !M::BLOCK_CRC || M::TRANSFER_SIZE > 4,
+            "DFUMemIO::TRANSFER_SIZE must be larger than CRC trailer if BLOCK_CRC is true"
+        );
+
+        assert!(
+            !M::VERIFY_AFTER_PROGRAM,
+            "DFUMemIO::VERIFY_AFTER_PROGRAM requires the memory to be wrapped in BufferedMem"
+        );
+
+        assert!(
+            M::IMAGE_HEADER_SIZE <= M::TRANSFER_SIZE as usize - if M::BLOCK_CRC { 4 } else { 0 },
+            "DFUMemIO::IMAGE_HEADER_SIZE must fit in a download block"
+        );
+
+        let mut i = 0;
+        while i < M::REDACTED_RANGES.len() {
+            let r = &M::REDACTED_RANGES[i];
+            assert!(
+                r.start < r.end,
+                "DFUMemIO::REDACTED_RANGES must not contain empty ranges"
+            );
+            i += 1;
+        }
+
+        let download_block = M::TRANSFER_SIZE as u32 - if M::BLOCK_CRC { 4 } else { 0 };
+        let mut i = 0;
+        while i < M::LAYOUT_SEGMENTS.len() {
+            let r = &M::LAYOUT_SEGMENTS[i];
+            assert!(
+                r.start < r.end,
+                "DFUMemIO::LAYOUT_SEGMENTS must not contain empty ranges"
+            );
+            assert!(
+                i == 0 || M::LAYOUT_SEGMENTS[i - 1].end <= r.start,
+                "DFUMemIO::LAYOUT_SEGMENTS must be sorted and must not overlap"
+            );
+            assert!(
+                (r.end - r.start) % M::TRANSFER_SIZE as u32 == 0
+                    && (r.end - r.start) % download_block == 0,
+                "DFUMemIO::LAYOUT_SEGMENTS sizes must be multiples of a block size"
+            );
+            i += 1;
+        }
+
+        if let Some((_, magic)) = M::IMAGE_MAGIC {
+            assert!(!magic.is_empty(), "DFUMemIO::IMAGE_MAGIC must not be empty");
+        }
+
+        assert!(
+            M::SEGMENT_LIMITS.is_empty() || M::SEGMENT_LIMITS.len() == M::LAYOUT_SEGMENTS.len(),
+            "DFUMemIO::SEGMENT_LIMITS must be empty or match LAYOUT_SEGMENTS"
+        );
+        let mut i = 0;
+        while i < M::SEGMENT_LIMITS.len() {
+            let l = &M::SEGMENT_LIMITS[i];
+            assert!(
+                l.max_block > 0 && l.max_block as u32 <= download_block,
+                "DFUMemIO::SEGMENT_LIMITS block size must not be 0 or larger than a download block"
+            );
+            assert!(
+                l.program_time_ms <= MAX_POLL_TIMEOUT && l.erase_time_ms <= MAX_POLL_TIMEOUT,
+                "DFUMemIO::SEGMENT_LIMITS times must fit in 24 bits"
+            );
+            assert!(
+                !M::HAS_DOWNLOAD || (l.program_time_ms > 0 && l.erase_time_ms > 0),
+                "DFUMemIO::SEGMENT_LIMITS times must not be 0 if HAS_DOWNLOAD is true"
+            );
+            i += 1;
+        }
+
+        #[cfg(feature = "winusb")]
+        assert!(
+            crate::winusb::is_valid_guid(M::WINUSB_GUID),
+            "DFUMemIO::WINUSB_GUID must be in {{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}} format"
+        );
+
+        #[cfg(feature = "webusb")]
+        if let Some(url) = M::WEBUSB_LANDING_PAGE {
+            assert!(
+                crate::webusb::is_valid_url(url),
+                "DFUMemIO::WEBUSB_LANDING_PAGE must not be empty or longer than 252 bytes \
+                without a scheme"
+            );
+        }
+
+        #[cfg(all(feature = "winusb", feature = "webusb"))]
+        assert!(
+            M::WINUSB_VENDOR_CODE != M::WEBUSB_VENDOR_CODE,
+            "DFUMemIO::WINUSB_VENDOR_CODE and WEBUSB_VENDOR_CODE must be different"
+        );
+    };
+}
+
+/// Checks [`DFUMemIO`] implementation's constants at compile time.
+///
+/// The same checks are done by [`DFUClass::new()`], however, with this macro
+/// errors are reported by `cargo check` too, and a configuration can be
+/// checked without creating a [`DFUClass`].
+///
+/// These configurations are rejected:
+///
+/// * [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE) is `0`, or larger than
+///   `usb-device` control buffer size (`128` bytes, or `256` bytes if
+///   `control-buffer-256` feature is enabled).
+/// * [`ALT_COUNT`](DFUMemIO::ALT_COUNT) is `0` or larger than `8`.
+/// * [`DFU_VERSION`](DFUMemIO::DFU_VERSION) is neither DfuSe nor DFU 1.1 version.
+/// * [`FIRST_DATA_BLOCK`](DFUMemIO::FIRST_DATA_BLOCK) is larger than `2`.
+/// * Any of `*_TIME_MS` values does not fit in 24-bit `bwPollTimeout`.
+/// * [`HAS_DOWNLOAD`](DFUMemIO::HAS_DOWNLOAD) is `true` and any of `*_TIME_MS`
+///   values is `0`. The host would not wait for the device to leave a busy state.
+/// * [`HAS_READ_UNPROTECT`](DFUMemIO::HAS_READ_UNPROTECT) is `true` and
+///   [`READ_UNPROTECT_TIME_MS`](DFUMemIO::READ_UNPROTECT_TIME_MS) is `0`.
+/// * [`REDACTED_RANGES`](DFUMemIO::REDACTED_RANGES) contains an empty range.
+/// * [`BLOCK_CRC`](DFUMemIO::BLOCK_CRC) is `true` and `TRANSFER_SIZE` leaves
+///   no space for data.
+/// * [`IMAGE_HEADER_SIZE`](DFUMemIO::IMAGE_HEADER_SIZE) is larger than a download block.
+/// * [`LAYOUT_SEGMENTS`](DFUMemIO::LAYOUT_SEGMENTS) are not sorted, overlap, are empty,
+///   or their sizes are not multiples of a block size.
+/// * [`SEGMENT_LIMITS`](DFUMemIO::SEGMENT_LIMITS) don't match `LAYOUT_SEGMENTS`, or
+///   contain invalid block sizes or times.
+/// * [`IMAGE_MAGIC`](DFUMemIO::IMAGE_MAGIC) value is empty.
+/// * [`WINUSB_GUID`](DFUMemIO::WINUSB_GUID) is not a GUID, if `winusb` feature is enabled.
+/// * [`WEBUSB_LANDING_PAGE`](DFUMemIO::WEBUSB_LANDING_PAGE) does not fit in a URL descriptor,
+///   or vendor codes of `winusb` and `webusb` features are the same.
+///
+/// Whether [`manifestation()`](DFUMemIO::manifestation) returns is not known at compile time,
+/// [`MANIFESTATION_TOLERANT`](DFUMemIO::MANIFESTATION_TOLERANT) can't be checked.
+///
+/// ```
+/// # use usbd_dfu::*;
+/// struct MyMem {}
+///
+/// impl DFUMemIO for MyMem {
+///     const MEM_INFO_STRING: &'static str = "@Flash/0x00000000/1*1Kg";
+///     const INITIAL_ADDRESS_POINTER: u32 = 0x0;
+///     const PROGRAM_TIME_MS: u32 = 8;
+///     const ERASE_TIME_MS: u32 = 50;
+///     const FULL_ERASE_TIME_MS: u32 = 50;
+///     const TRANSFER_SIZE: u16 = 64;
+/// #   fn read(&mut self, _: u32, _: usize) -> Result<&[u8], DFUMemError> { todo!() }
+/// #   fn erase(&mut self, _: u32) -> Result<(), DFUMemError> { todo!() }
+/// #   fn erase_all(&mut self) -> Result<(), DFUMemError> { todo!() }
+/// #   fn store_write_buffer(&mut self, _: &[u8]) -> Result<(), ()> { todo!() }
+/// #   fn program(&mut self, _: u32, _: usize) -> Result<(), DFUMemError> { todo!() }
+/// #   fn manifestation(&mut self) -> Result<(), DFUManifestationError> { todo!() }
+///     // ...
+/// }
+///
+/// usbd_dfu::dfu_assert_config!(MyMem);
+/// ```
+///
+/// Transfer size does not fit in a control buffer:
+///
+/// ```compile_fail
+/// # use usbd_dfu::*;
+/// struct MyMem {}
+///
+/// impl DFUMemIO for MyMem {
+///     const MEM_INFO_STRING: &'static str = "@Flash/0x00000000/1*1Kg";
+///     const INITIAL_ADDRESS_POINTER: u32 = 0x0;
+///     const PROGRAM_TIME_MS: u32 = 8;
+///     const ERASE_TIME_MS: u32 = 50;
+///     const FULL_ERASE_TIME_MS: u32 = 50;
+///     const TRANSFER_SIZE: u16 = 1024;
+/// #   fn read(&mut self, _: u32, _: usize) -> Result<&[u8], DFUMemError> { todo!() }
+/// #   fn erase(&mut self, _: u32) -> Result<(), DFUMemError> { todo!() }
+/// #   fn erase_all(&mut self) -> Result<(), DFUMemError> { todo!() }
+/// #   fn store_write_buffer(&mut self, _: &[u8]) -> Result<(), ()> { todo!() }
+/// #   fn program(&mut self, _: u32, _: usize) -> Result<(), DFUMemError> { todo!() }
+/// #   fn manifestation(&mut self) -> Result<(), DFUManifestationError> { todo!() }
+///     // ...
+/// }
+///
+/// usbd_dfu::dfu_assert_config!(MyMem);
+/// ```
+///
+/// Download is supported, but program time is `0`:
+///
+/// ```compile_fail
+/// # use usbd_dfu::*;
+/// struct MyMem {}
+///
+/// impl DFUMemIO for MyMem {
+///     const MEM_INFO_STRING: &'static str = "@Flash/0x00000000/1*1Kg";
+///     const INITIAL_ADDRESS_POINTER: u32 = 0x0;
+///     const PROGRAM_TIME_MS: u32 = 0;
+///     const ERASE_TIME_MS: u32 = 50;
+///     const FULL_ERASE_TIME_MS: u32 = 50;
+/// #   fn read(&mut self, _: u32, _: usize) -> Result<&[u8], DFUMemError> { todo!() }
+/// #   fn erase(&mut self, _: u32) -> Result<(), DFUMemError> { todo!() }
+/// #   fn erase_all(&mut self) -> Result<(), DFUMemError> { todo!() }
+/// #   fn store_write_buffer(&mut self, _: &[u8]) -> Result<(), ()> { todo!() }
+/// #   fn program(&mut self, _: u32, _: usize) -> Result<(), DFUMemError> { todo!() }
+/// #   fn manifestation(&mut self) -> Result<(), DFUManifestationError> { todo!() }
+///     // ...
+/// }
+///
+/// usbd_dfu::dfu_assert_config!(MyMem);
+/// ```
+///
+/// Erase time does not fit in 24 bits:
+///
+/// ```compile_fail
+/// # use usbd_dfu::*;
+/// struct MyMem {}
+///
+/// impl DFUMemIO for MyMem {
+///     const MEM_INFO_STRING: &'static str = "@Flash/0x00000000/1*1Kg";
+///     const INITIAL_ADDRESS_POINTER: u32 = 0x0;
+///     const PROGRAM_TIME_MS: u32 = 8;
+///     const ERASE_TIME_MS: u32 = 0x100_0000;
+///     const FULL_ERASE_TIME_MS: u32 = 50;
+/// #   fn read(&mut self, _: u32, _: usize) -> Result<&[u8], DFUMemError> { todo!() }
+/// #   fn erase(&mut self, _: u32) -> Result<(), DFUMemError> { todo!() }
+/// #   fn erase_all(&mut self) -> Result<(), DFUMemError> { todo!() }
+/// #   fn store_write_buffer(&mut self, _: &[u8]) -> Result<(), ()> { todo!() }
+/// #   fn program(&mut self, _: u32, _: usize) -> Result<(), DFUMemError> { todo!() }
+/// #   fn manifestation(&mut self) -> Result<(), DFUManifestationError> { todo!() }
+///     // ...
+/// }
+///
+/// usbd_dfu::dfu_assert_config!(MyMem);
+/// ```
+///
+/// Programmed blocks are verified, but the memory is not wrapped in `BufferedMem`:
+///
+/// ```compile_fail
+/// # use usbd_dfu::*;
+/// struct MyMem {}
+///
+/// impl DFUMemIO for MyMem {
+///     const MEM_INFO_STRING: &'static str = "@Flash/0x00000000/1*1Kg";
+///     const INITIAL_ADDRESS_POINTER: u32 = 0x0;
+///     const PROGRAM_TIME_MS: u32 = 8;
+///     const ERASE_TIME_MS: u32 = 50;
+///     const FULL_ERASE_TIME_MS: u32 = 50;
+///     const VERIFY_AFTER_PROGRAM: bool = true;
+/// #   fn read(&mut self, _: u32, _: usize) -> Result<&[u8], DFUMemError> { todo!() }
+/// #   fn erase(&mut self, _: u32) -> Result<(), DFUMemError> { todo!() }
+/// #   fn erase_all(&mut self) -> Result<(), DFUMemError> { todo!() }
+/// #   fn store_write_buffer(&mut self, _: &[u8]) -> Result<(), ()> { todo!() }
+/// #   fn program(&mut self, _: u32, _: usize) -> Result<(), DFUMemError> { todo!() }
+/// #   fn manifestation(&mut self) -> Result<(), DFUManifestationError> { todo!() }
+///     // ...
+/// }
+///
+/// usbd_dfu::dfu_assert_config!(MyMem);
+/// ```
+#[macro_export]
+macro_rules! dfu_assert_config {
+    ($mem:ty) => {
+        const _: () = $crate::class::ConfigCheck::<$mem>::OK;
+    };
+}
+
 /// DFU protocol USB class implementation for usb-device library.
 pub struct DFUClass<B: UsbBus, M: DFUMemIO> {
     if_num: InterfaceNumber,
-    status: DFUStatus,
-    interface_string: StringIndex,
+    /// Memory layout string descriptors of alternate settings
+    interface_strings: [Option<StringIndex>; MAX_ALT_COUNT],
+    /// String descriptor of vendor-specific errors, see `HAS_VENDOR_ERROR_STRING`
+    vendor_string: Option<StringIndex>,
     _bus: PhantomData<B>,
-    mem: M,
+    core: DfuCore<M>,
+}
+
+/// DFU state machine, USB stack specific parts are left to [`DFUClass`]
+/// and other front-ends.
+pub(crate) struct DfuCore<M: DFUMemIO> {
+    status: DFUStatus,
+    /// Selected alternate setting
+    alt: u8,
+    /// String descriptor index of vendor-specific errors, see `HAS_VENDOR_ERROR_STRING`
+    pub(crate) vendor_string: Option<u8>,
+    pub(crate) mem: M,
+    /// Last `initial_address_pointer()` value, `None` if the application has set the address
+    initial_address: Option<u32>,
+    /// Time when dfuERROR state was noticed, see `ERROR_AUTOCLEAR_MS`
+    error_since: Option<u32>,
+    /// Program or erase command that is still running, and its start time
+    in_progress: Option<(Command, u32)>,
+    /// Don't call erase, program, and manifestation functions
+    dry_run: bool,
+    /// Manifestation result that is not reported yet, and manifestation start time
+    manifest_result: Option<(Result<(), DFUManifestationError>, u32)>,
+    /// Remaining time and start time of a manifestation that is still running
+    manifesting: Option<(u32, u32)>,
+    /// Remaining time of a mass erase that is still running
+    erasing: Option<u32>,
+    /// Bytes read and CRC value of a CRC command that is still running
+    crc_progress: Option<(u32, u32)>,
+    /// CRC command result that is not uploaded yet
+    crc_result: Option<u32>,
+    /// Polls before a pending operation may run, see `hold_pending()`
+    hold_polls: u8,
+    /// USB reset is waiting for a pending manifestation, see `finish_reset()`
+    reset_deferred: bool,
+    /// Number of consecutive failed manifestations, see `MAX_FAILED_MANIFESTATIONS`
+    failed_manifestations: u8,
+    /// Data blocks were accepted since USB reset
+    downloaded: bool,
+    /// Manifestation has succeeded since USB reset
+    manifested: bool,
+    /// Memory work since the last `take_poll_activity()` call
+    poll_activity: PollActivity,
+    /// Timeout and receipt time of the last `DFU_DETACH` request that has not expired
+    detach: Option<(u16, u32)>,
+    /// Time reported by `DFUClass::tick()`, added to `now_ms()`
+    ticks: u32,
+    /// Descriptor parameters, `DFUMemIO` constants or `DfuOptions`
+    pub(crate) options: DfuOptions,
+    #[cfg(feature = "echo-test")]
+    echo: Echo,
+    #[cfg(feature = "profiling")]
+    profile: DfuProfile,
 }
 
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[cfg_attr(feature = "log", derive(Debug))]
 #[derive(Clone, Copy, PartialEq, Eq)]
 enum Command {
     None,
@@ -410,8 +2050,10 @@ enum Command {
     Erase(u32),
     SetAddressPointer(u32),
     ReadUnprotect,
-    WriteMemory { block_num: u16, len: u16 },
+    WriteMemory { block_num: u32, len: u16 },
     LeaveDFU,
+    Vendor { cmd: u8, time_ms: u32 },
+    Crc { address: u32, length: u32 },
 }
 
 #[derive(Clone, Copy)]
@@ -422,6 +2064,23 @@ struct DFUStatus {
     address_pointer: u32,
     command: Command,
     pending: Command,
+    /// Block number of the first block of a located image in the current download session
+    image_block: Option<u32>,
+    /// Download session was started with a data block or an erase command
+    download_session: bool,
+    /// Number of bytes programmed in the current download session
+    download_bytes: u32,
+    /// Address Pointer value latched by the first block of the current upload session
+    upload_base: Option<u32>,
+    /// `wBlockNum` and logical block number of the last accepted data block,
+    /// logical block numbers don't wrap
+    last_block: Option<(u16, u32)>,
+    /// Number of data bytes received in the current download session, see `IMAGE_MAGIC`
+    magic_offset: usize,
+    /// Number of `IMAGE_MAGIC` bytes verified in the current download session
+    magic_checked: usize,
+    /// The last bytes of the current download session, see `CHECK_SUFFIX`
+    suffix: SuffixTail,
 }
 
 impl DFUStatus {
@@ -433,18 +2092,44 @@ impl DFUStatus {
             address_pointer: addr,
             command: Command::None,
             pending: Command::None,
+            image_block: None,
+            download_session: false,
+            download_bytes: 0,
+            upload_base: None,
+            last_block: None,
+            magic_offset: 0,
+            magic_checked: 0,
+            suffix: SuffixTail::new(),
         }
     }
 
-    fn new_state_ok(&mut self, state: DFUState) {
-        self.new_state_status(state, DFUStatusCode::OK);
-    }
-
     fn new_state_status(&mut self, state: DFUState, status: DFUStatusCode) {
+        if state != self.state || status != self.status {
+            if status == DFUStatusCode::OK {
+                debug!("dfu: {:?} -> {:?}", self.state, state);
+            } else {
+                warn!("dfu: {:?} -> {:?}, status {:?}", self.state, state, status);
+            }
+        }
+        if state == DFUState::DfuIdle {
+            // download session is over
+            self.end_session();
+        }
         self.status = status;
         self.state = state;
     }
 
+    fn end_session(&mut self) {
+        self.image_block = None;
+        self.download_session = false;
+        self.download_bytes = 0;
+        self.upload_base = None;
+        self.last_block = None;
+        self.magic_offset = 0;
+        self.magic_checked = 0;
+        self.suffix = SuffixTail::new();
+    }
+
     fn state(&self) -> DFUState {
         self.state
     }
@@ -452,13 +2137,14 @@ impl DFUStatus {
 
 impl From<DFUStatus> for [u8; 6] {
     fn from(dfu: DFUStatus) -> Self {
+        let poll_timeout = min(dfu.poll_timeout, MAX_POLL_TIMEOUT);
         [
             // bStatus
             dfu.status as u8,
-            // bwPollTimeout
-            (dfu.poll_timeout & 0xff) as u8,
-            ((dfu.poll_timeout >> 8) & 0xff) as u8,
-            ((dfu.poll_timeout >> 16) & 0xff) as u8,
+            // bwPollTimeout, saturated to 24 bits
+            (poll_timeout & 0xff) as u8,
+            ((poll_timeout >> 8) & 0xff) as u8,
+            ((poll_timeout >> 16) & 0xff) as u8,
             // bState
             dfu.state as u8,
             // iString: Index of status description in string table.
@@ -467,504 +2153,2088 @@ impl From<DFUStatus> for [u8; 6] {
     }
 }
 
+/// DFU Functional descriptor body, without length and type,
+/// it's the same in run-time and DFU modes
+#[allow(clippy::identity_op)]
+pub(crate) fn functional_descriptor(
+    will_detach: bool,
+    manifestation_tolerant: bool,
+    can_upload: bool,
+    can_download: bool,
+    detach_timeout: u16,
+    transfer_size: u16,
+    dfu_version: u16,
+) -> [u8; 7] {
+    [
+        // bmAttributes
+        // Bit 7: bitAcceleratedST
+        (if false {0x80} else {0}) |
+            // Bit 4-6: Reserved
+            // Bit 3: bitWillDetach
+            (if will_detach {0x8} else {0}) |
+            // Bit 2: bitManifestationTolerant
+            (if manifestation_tolerant {0x4} else {0}) |
+            // Bit 1: bitCanUpload
+            (if can_upload {0x2} else {0}) |
+            // Bit 0: bitCanDnload
+            (if can_download {0x1} else {0}),
+        // wDetachTimeOut
+        (detach_timeout & 0xff) as u8,
+        (detach_timeout >> 8) as u8,
+        // wTransferSize
+        (transfer_size & 0xff) as u8,
+        (transfer_size >> 8) as u8,
+        // bcdDFUVersion
+        (dfu_version & 0xff) as u8,
+        (dfu_version >> 8) as u8,
+    ]
+}
+
+/// Response to a control IN request, implemented for USB stack transfer types
+pub(crate) trait InXfer {
+    /// Send `data`
+    fn accept_with(self, data: &[u8]);
+
+    /// Send data written by `f` to the control buffer, `f` returns the length,
+    /// or `None` to stall the request
+    fn accept(self, f: impl FnOnce(&mut [u8]) -> Option<usize>);
+
+    /// Stall the request
+    fn reject(self);
+}
+
+/// Response to a control OUT request, implemented for USB stack transfer types
+pub(crate) trait OutXfer {
+    /// Data stage of the request
+    fn data(&self) -> &[u8];
+
+    /// Accept the request
+    fn accept(self);
+
+    /// Stall the request
+    fn reject(self);
+}
+
+impl<B: UsbBus> InXfer for ControlIn<'_, '_, '_, B> {
+    fn accept_with(self, data: &[u8]) {
+        ControlIn::accept_with(self, data).ok();
+    }
+
+    fn accept(self, f: impl FnOnce(&mut [u8]) -> Option<usize>) {
+        // an error makes usb-device stall the request
+        ControlIn::accept(self, |buf| f(buf).ok_or(UsbError::InvalidState)).ok();
+    }
+
+    fn reject(self) {
+        ControlIn::reject(self).ok();
+    }
+}
+
+impl<B: UsbBus> OutXfer for ControlOut<'_, '_, '_, B> {
+    fn data(&self) -> &[u8] {
+        ControlOut::data(self)
+    }
+
+    fn accept(self) {
+        ControlOut::accept(self).ok();
+    }
+
+    fn reject(self) {
+        ControlOut::reject(self).ok();
+    }
+}
+
 impl<B: UsbBus, M: DFUMemIO> UsbClass<B> for DFUClass<B, M> {
     fn get_configuration_descriptors(
         &self,
         writer: &mut DescriptorWriter,
     ) -> usb_device::Result<()> {
-        writer.interface_alt(
-            self.if_num,
-            0,
-            USB_CLASS_APPLICATION_SPECIFIC,
-            USB_SUBCLASS_DFU,
-            USB_PROTOCOL_DFU_MODE,
-            Some(self.interface_string),
-        )?;
+        for alt in 0..M::ALT_COUNT {
+            writer.interface_alt(
+                self.if_num,
+                alt,
+                USB_CLASS_APPLICATION_SPECIFIC,
+                USB_SUBCLASS_DFU,
+                USB_PROTOCOL_DFU_MODE,
+                self.interface_strings[alt as usize],
+            )?;
+        }
 
-        // DFU Functional descriptor
+        let options = &self.core.options;
         writer.write(
             DESC_DESCTYPE_DFU,
-            &[
-                // bmAttributes
-                // Bit 7: bitAcceleratedST
-                (if false {0x80} else {0}) |
-                    // Bit 4-6: Reserved
-                    // Bit 3: bitWillDetach
-                    (if true {0x8} else {0}) |
-                    // Bit 2: bitManifestationTolerant
-                    (if M::MANIFESTATION_TOLERANT {0x4} else {0}) |
-                    // Bit 1: bitCanUpload
-                    (if M::HAS_UPLOAD {0x2} else {0}) |
-                    // Bit 0: bitCanDnload
-                    (if M::HAS_DOWNLOAD {0x1} else {0}),
-                // wDetachTimeOut
-                (M::DETACH_TIMEOUT & 0xff) as u8,
-                (M::DETACH_TIMEOUT >> 8) as u8,
-                // wTransferSize
-                (M::TRANSFER_SIZE & 0xff) as u8,
-                (M::TRANSFER_SIZE >> 8) as u8,
-                // bcdDFUVersion
-                0x1a,
-                0x01,
-            ],
+            &functional_descriptor(
+                options.will_detach,
+                options.manifestation_tolerant,
+                options.has_upload,
+                options.has_download,
+                options.detach_timeout,
+                options.transfer_size,
+                M::DFU_VERSION,
+            ),
+        )
+    }
+
+    #[cfg(any(feature = "winusb", feature = "webusb"))]
+    fn get_bos_descriptors(&self, writer: &mut BosWriter) -> usb_device::Result<()> {
+        #[cfg(feature = "winusb")]
+        writer.capability(
+            CAPABILITY_PLATFORM,
+            &crate::winusb::platform_capability(M::WINUSB_COMPOSITE, M::WINUSB_VENDOR_CODE),
+        )?;
+        #[cfg(feature = "webusb")]
+        writer.capability(
+            CAPABILITY_PLATFORM,
+            &crate::webusb::platform_capability(
+                M::WEBUSB_VENDOR_CODE,
+                M::WEBUSB_LANDING_PAGE.is_some(),
+            ),
         )?;
+        Ok(())
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        if let Some(alt) = self
+            .interface_strings
+            .iter()
+            .position(|&s| s == Some(index))
+        {
+            return self.core.get_string(alt as u8, u16::from(lang_id));
+        }
+        if Some(index) == self.vendor_string {
+            return self.core.get_vendor_string(u16::from(lang_id));
+        }
+        None
+    }
+
+    fn get_alt_setting(&mut self, interface: InterfaceNumber) -> Option<u8> {
+        if interface != self.if_num {
+            return None;
+        }
+        Some(self.core.alt)
+    }
+
+    fn set_alt_setting(&mut self, interface: InterfaceNumber, alternative: u8) -> bool {
+        if interface != self.if_num {
+            return false;
+        }
+        self.core.set_alt_setting(alternative)
+    }
+
+    // Handle control requests to the host.
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        #[cfg(feature = "winusb")]
+        if self.is_winusb_request(&req) {
+            let first_interface = M::WINUSB_COMPOSITE.then_some(u8::from(self.if_num));
+            InXfer::accept(xfer, |buf| {
+                crate::winusb::write_descriptor_set(buf, M::WINUSB_GUID, first_interface)
+            });
+            return;
+        }
+
+        #[cfg(feature = "webusb")]
+        if self.is_webusb_url_request(&req) {
+            match M::WEBUSB_LANDING_PAGE {
+                Some(url) => {
+                    InXfer::accept(xfer, |buf| crate::webusb::write_url_descriptor(buf, url))
+                }
+                None => InXfer::reject(xfer),
+            }
+            return;
+        }
+
+        if !self.is_dfu_request(&req) {
+            return;
+        }
+
+        self.core.control_in(req, xfer);
+        self.core.hold_pending();
+    }
+
+    // Handle a control request from the host.
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        if req.request_type == control::RequestType::Standard
+            && req.recipient == control::Recipient::Device
+            && req.request == Request::SET_CONFIGURATION
+            && req.value == CONFIGURATION_VALUE as u16
+        {
+            // request is handled by usb-device
+            self.core.mem.on_configured();
+            return;
+        }
+
+        if !self.is_dfu_request(&req) {
+            return;
+        }
+
+        self.core.control_out(req, xfer);
+    }
+
+    fn reset(&mut self) {
+        self.core.reset();
+    }
+
+    fn poll(&mut self) {
+        self.core.poll(M::MEMIO_IN_USB_INTERRUPT);
+    }
+}
+
+impl<B: UsbBus, M: DFUMemIO> DFUClass<B, M> {
+    /// Creates a new DFUClass with the provided UsbBus and
+    /// DFUMemIO
+    ///
+    /// `DFUMemIO` constants are checked at compile time,
+    /// see [`dfu_assert_config!`](crate::dfu_assert_config).
+    pub fn new(alloc: &UsbBusAllocator<B>, mem: M) -> Self {
+        Self::new_with_config(alloc, mem, None, DfuOptions::new::<M>())
+    }
+
+    /// Creates a new DFUClass like [`new()`](DFUClass::new), in `initial` state
+    /// instead of the usual `dfuIDLE`.
+    ///
+    /// `DFU_GETSTATUS` request reports this state until a host clears the error.
+    pub fn new_with_state(alloc: &UsbBusAllocator<B>, mem: M, initial: InitialState) -> Self {
+        let mut dfu = Self::new(alloc, mem);
+        dfu.core.set_initial_state(initial);
+        dfu
+    }
+
+    /// Creates a new DFUClass like [`new()`](DFUClass::new), Address Pointer is
+    /// set to `initial_address` instead of
+    /// [`initial_address_pointer()`](DFUMemIO::initial_address_pointer).
+    ///
+    /// This allows to choose the start address at run time, for example,
+    /// from a value stored in option bytes.
+    pub fn new_with_address(alloc: &UsbBusAllocator<B>, mem: M, initial_address: u32) -> Self {
+        Self::new_with_config(alloc, mem, Some(initial_address), DfuOptions::new::<M>())
+    }
+
+    /// Creates a new DFUClass like [`new()`](DFUClass::new), DFU Functional descriptor
+    /// parameters are taken from `options` instead of `DFUMemIO` constants.
+    ///
+    /// # Panics
+    ///
+    /// Panics if [`transfer_size`](DfuOptions::transfer_size) is `0`, larger than
+    /// [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE), or breaks other `DFUMemIO` constants
+    /// the same way as listed in [`dfu_assert_config!`](crate::dfu_assert_config).
+    pub fn new_with_options(alloc: &UsbBusAllocator<B>, mem: M, options: DfuOptions) -> Self {
+        Self::new_with_config(alloc, mem, None, options)
+    }
+
+    fn new_with_config(
+        alloc: &UsbBusAllocator<B>,
+        mem: M,
+        initial_address: Option<u32>,
+        options: DfuOptions,
+    ) -> Self {
+        let if_num = alloc.interface();
+        let interface_strings = core::array::from_fn(|i| {
+            if i < M::ALT_COUNT as usize {
+                Some(alloc.string())
+            } else {
+                None
+            }
+        });
+        let vendor_string = if M::HAS_VENDOR_ERROR_STRING {
+            Some(alloc.string())
+        } else {
+            None
+        };
+
+        Self {
+            if_num,
+            interface_strings,
+            vendor_string,
+            _bus: PhantomData,
+            core: DfuCore::new(mem, vendor_string.map(u8::from), initial_address, options),
+        }
+    }
+
+    /// This function will consume self and return the owned memory
+    /// argument that was moved in the call to new()
+    pub fn release(self) -> M {
+        self.core.mem
+    }
+
+    /// Consumes `DFUClass` and returns the memory, for example, to use it from
+    /// the application after a download session is over.
+    ///
+    /// Same as [`release()`](DFUClass::release).
+    pub fn into_inner(self) -> M {
+        self.core.mem
+    }
+
+    /// This function may be called just after `DFUClass::new()` to
+    /// set DFU error state to "Device detected unexpected power on reset"
+    /// instead of the usual `dfuIdle`.
+    ///
+    /// Same as [`InitialState::UnexpectedReset`].
+    pub fn set_unexpected_reset_state(&mut self) {
+        self.core.set_initial_state(InitialState::UnexpectedReset);
+    }
+
+    /// This function may be called just after `DFUClass::new()` to
+    /// set DFU error state to "Device’s firmware is corrupt. It cannot return to run-time (non-DFU) operations"
+    /// instead of the usual `dfuIdle`.
+    ///
+    /// Same as [`InitialState::FirmwareCorrupted`].
+    pub fn set_firmware_corrupted_state(&mut self) {
+        self.core.set_initial_state(InitialState::FirmwareCorrupted);
+    }
+
+    /// Return current Address Pointer value.
+    pub fn get_address_pointer(&self) -> u32 {
+        self.core.status.address_pointer
+    }
+
+    /// Set Address Pointer value.
+    ///
+    /// Upload blocks are read relative to the Address Pointer value at the time
+    /// the first block of an upload session was requested, an upload in progress
+    /// is not affected, the new value is used from the next upload session.
+    /// Download blocks that follow use the new value.
+    pub fn set_address_pointer(&mut self, address: u32) {
+        self.core.status.address_pointer = address;
+    }
+
+    /// Returns `true` if a host has started a download session and has not finished it.
+    ///
+    /// A session starts with the first accepted download block or an erase command,
+    /// and ends after successful manifestation, abort, clearing an error status, or USB reset.
+    /// Unlike a pending operation, the session lasts between download blocks, and the
+    /// application may use it to avoid writing the same memory or powering down.
+    pub fn download_in_progress(&self) -> bool {
+        self.core.status.download_session
+    }
+
+    /// Return the number of bytes programmed in the current download session.
+    pub fn downloaded_bytes(&self) -> u32 {
+        self.core.status.download_bytes
+    }
+
+    /// Enable or disable dry-run mode.
+    ///
+    /// In dry-run mode download blocks and commands are accepted, and a host sees
+    /// the usual states and poll timeouts, but [`erase()`](DFUMemIO::erase),
+    /// [`erase_all()`](DFUMemIO::erase_all), [`program()`](DFUMemIO::program),
+    /// and [`manifestation()`](DFUMemIO::manifestation) are not called, and
+    /// succeed. Block checks, such as [`BLOCK_CRC`](DFUMemIO::BLOCK_CRC), are still
+    /// performed, and [`downloaded_bytes()`](DFUClass::downloaded_bytes) is updated.
+    ///
+    /// This allows to test a host and USB connection without modifying memory.
+    pub fn set_dry_run(&mut self, dry_run: bool) {
+        self.core.dry_run = dry_run;
+    }
+
+    /// Returns `true` if dry-run mode is enabled, see [`set_dry_run()`](DFUClass::set_dry_run).
+    pub fn is_dry_run(&self) -> bool {
+        self.core.dry_run
+    }
+
+    /// Returns memory work done from `usb_dev.poll([])` since the last call, and clears it.
+    ///
+    /// [`PollActivity::ExecutedCommand`] is reported if erase, program, or manifestation
+    /// function was called by any of the polls, otherwise [`PollActivity::CommandPending`]
+    /// is reported if an operation was pending after a poll. Together with
+    /// [`is_busy()`](DFUClass::is_busy) this may be used to decide if the device
+    /// may enter a low power mode.
+    pub fn take_poll_activity(&mut self) -> PollActivity {
+        self.core.take_poll_activity()
+    }
+
+    /// Returns `true` if an operation is waiting for the next `usb_dev.poll([])` call,
+    /// or is still running in background (see [`operation_busy()`](DFUMemIO::operation_busy)
+    /// and [`manifestation_poll()`](DFUMemIO::manifestation_poll)).
+    pub fn is_busy(&self) -> bool {
+        self.core.is_busy()
+    }
+
+    /// Executes a pending erase, program, or manifestation operation if
+    /// [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT) is `false`,
+    /// does nothing otherwise.
+    ///
+    /// Should be called periodically, or when [`update_pending()`](DFUClass::update_pending)
+    /// returns `true`, from a context where memory functions may run for a long time.
+    /// Manifestation may not return.
+    pub fn update(&mut self) {
+        if M::MEMIO_IN_USB_INTERRUPT {
+            return;
+        }
+        if self.core.command_ready() {
+            self.core.update_impl();
+        }
+        if self.core.reset_deferred {
+            self.core.check_manifestation_poll();
+            self.core.finish_reset();
+        }
+    }
+
+    /// Returns `true` if [`update()`](DFUClass::update) needs to be called to
+    /// process a pending operation.
+    ///
+    /// An operation requested by `DFU_GETSTATUS` is pending after the reply is sent,
+    /// on the next `usb_dev.poll()` that reports a USB event.
+    pub fn update_pending(&self) -> bool {
+        !M::MEMIO_IN_USB_INTERRUPT && (self.core.command_ready() || self.core.reset_deferred)
+    }
+
+    /// Returns time spent in `control_in()`, `control_out()`, and `poll()`,
+    /// measured with [`profile_ticks()`](DFUMemIO::profile_ticks).
+    ///
+    /// Requires `profiling` feature.
+    #[cfg(feature = "profiling")]
+    pub fn profile(&self) -> &DfuProfile {
+        &self.core.profile
+    }
+
+    /// Clears collected [`profile()`](DFUClass::profile) data.
+    ///
+    /// Requires `profiling` feature.
+    #[cfg(feature = "profiling")]
+    pub fn reset_profile(&mut self) {
+        self.core.profile = DfuProfile::default();
+    }
+
+    /// Returns `true` if a host has sent `DFU_DETACH` request and its timeout has not
+    /// expired yet, see [`on_detach_request()`](DFUMemIO::on_detach_request).
+    ///
+    /// The timeout is checked from `usb_dev.poll([])` and [`tick()`](DFUClass::tick),
+    /// USB reset cancels the request.
+    pub fn detach_pending(&self) -> bool {
+        self.core.detach_pending()
+    }
+
+    /// Advances the class clock by `elapsed_ms` milliseconds and expires a pending
+    /// `DFU_DETACH` request, see [`on_detach_expired()`](DFUMemIO::on_detach_expired).
+    ///
+    /// May be called from SysTick or a scheduler instead of implementing
+    /// [`now_ms()`](DFUMemIO::now_ms), the elapsed time is added to its value.
+    pub fn tick(&mut self, elapsed_ms: u32) {
+        self.core.tick(elapsed_ms);
+    }
+
+    /// Selected interface alternate setting, see [`ALT_COUNT`](DFUMemIO::ALT_COUNT).
+    pub fn alt_setting(&self) -> u8 {
+        self.core.alt
+    }
+
+    /// Number of consecutive failed manifestations,
+    /// see [`MAX_FAILED_MANIFESTATIONS`](DFUMemIO::MAX_FAILED_MANIFESTATIONS).
+    pub fn failed_manifestations(&self) -> u8 {
+        self.core.failed_manifestations
+    }
+
+    /// Returns `true` if downloads are refused after too many failed manifestations,
+    /// see [`MAX_FAILED_MANIFESTATIONS`](DFUMemIO::MAX_FAILED_MANIFESTATIONS).
+    pub fn is_locked_out(&self) -> bool {
+        self.core.is_locked_out()
+    }
+
+    /// Interface number allocated for this DFU interface.
+    ///
+    /// Class requests are handled only if their `wIndex` matches it, several
+    /// `DFUClass` instances can be used on the same composite device.
+    pub fn interface_number(&self) -> InterfaceNumber {
+        self.if_num
+    }
+
+    /// Class request for this interface
+    fn is_dfu_request(&self, req: &Request) -> bool {
+        req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.if_num) as u16
+    }
+
+    /// Vendor request that reads MS OS 2.0 descriptor set
+    #[cfg(feature = "winusb")]
+    fn is_winusb_request(&self, req: &Request) -> bool {
+        req.request_type == control::RequestType::Vendor
+            && req.recipient == control::Recipient::Device
+            && req.request == M::WINUSB_VENDOR_CODE
+            && req.index == crate::winusb::MS_OS_20_DESCRIPTOR_INDEX
+    }
+
+    /// WebUSB `GET_URL` vendor request for the landing page
+    #[cfg(feature = "webusb")]
+    fn is_webusb_url_request(&self, req: &Request) -> bool {
+        req.request_type == control::RequestType::Vendor
+            && req.recipient == control::Recipient::Device
+            && req.request == M::WEBUSB_VENDOR_CODE
+            && req.index == crate::webusb::WEBUSB_GET_URL
+            && req.value == crate::webusb::LANDING_PAGE_INDEX
+    }
+}
+
+impl<M: DFUMemIO> DfuCore<M> {
+    pub(crate) fn new(
+        mut mem: M,
+        vendor_string: Option<u8>,
+        initial_address: Option<u32>,
+        options: DfuOptions,
+    ) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = ConfigCheck::<M>::OK;
+        options.check::<M>();
+
+        let failed_manifestations = if M::MAX_FAILED_MANIFESTATIONS > 0 {
+            mem.persist_lockout(None)
+        } else {
+            0
+        };
+
+        let (address, mem_address) = match initial_address {
+            Some(address) => (address, None),
+            None => {
+                let address = mem.initial_address_pointer();
+                (address, Some(address))
+            }
+        };
+
+        Self {
+            status: DFUStatus::new(address),
+            initial_address: mem_address,
+            alt: 0,
+            vendor_string,
+            mem,
+            error_since: None,
+            in_progress: None,
+            dry_run: false,
+            manifest_result: None,
+            manifesting: None,
+            erasing: None,
+            crc_progress: None,
+            crc_result: None,
+            hold_polls: 0,
+            reset_deferred: false,
+            failed_manifestations,
+            downloaded: false,
+            manifested: false,
+            poll_activity: PollActivity::None,
+            detach: None,
+            ticks: 0,
+            options,
+            #[cfg(feature = "echo-test")]
+            echo: Echo {
+                enabled: false,
+                len: 0,
+                buf: [0; CONTROL_BUF_LEN],
+            },
+            #[cfg(feature = "profiling")]
+            profile: DfuProfile::default(),
+        }
+    }
+
+    pub(crate) fn set_initial_state(&mut self, initial: InitialState) {
+        match initial {
+            InitialState::Idle => self.new_state_ok(DFUState::DfuIdle),
+            InitialState::IdleAt(address) => {
+                self.status.address_pointer = address;
+                self.initial_address = None;
+                self.new_state_ok(DFUState::DfuIdle)
+            }
+            InitialState::UnexpectedReset => {
+                self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrPOR)
+            }
+            InitialState::FirmwareCorrupted => {
+                self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrFirmware)
+            }
+            InitialState::Error(e) => self.new_state_status(DFUState::DfuError, e.into()),
+        }
+    }
+
+    fn new_state_ok(&mut self, state: DFUState) {
+        self.new_state_status(state, DFUStatusCode::OK);
+    }
+
+    fn new_state_status(&mut self, state: DFUState, status: DFUStatusCode) {
+        let old = self.status.state;
+        let old_status = self.status.status;
+        self.status.new_state_status(state, status);
+        if state != old || status != old_status {
+            self.mem.on_state_change(old, state, status);
+        }
+    }
+
+    pub(crate) fn take_poll_activity(&mut self) -> PollActivity {
+        core::mem::replace(&mut self.poll_activity, PollActivity::None)
+    }
+
+    pub(crate) fn tick(&mut self, elapsed_ms: u32) {
+        self.ticks = self.ticks.wrapping_add(elapsed_ms);
+        self.check_detach_timeout();
+    }
+
+    /// Current time, `now_ms()` and time reported by `tick()`
+    fn now_ms(&mut self) -> u32 {
+        self.mem.now_ms().wrapping_add(self.ticks)
+    }
+
+    pub(crate) fn detach_pending(&self) -> bool {
+        self.detach.is_some()
+    }
+
+    pub(crate) fn is_busy(&self) -> bool {
+        self.status.pending != Command::None
+            || self.in_progress.is_some()
+            || self.manifesting.is_some()
+    }
+
+    /// An operation is waiting for `update_impl()`
+    pub(crate) fn command_pending(&self) -> bool {
+        self.status.pending != Command::None
+    }
+
+    /// An operation is waiting for `update_impl()`, and the reply that has
+    /// requested it is sent
+    pub(crate) fn command_ready(&self) -> bool {
+        self.command_pending() && self.hold_polls == 0
+    }
+
+    /// `usb-device` calls `poll()` just after a control IN request is handled, before
+    /// the reply is sent, and again on the next USB event. Don't run a pending
+    /// operation until then, a host would not get the reply to wait for it.
+    pub(crate) fn hold_pending(&mut self) {
+        if self.command_pending() {
+            self.hold_polls = 2;
+        }
+    }
+
+    pub(crate) fn is_locked_out(&self) -> bool {
+        M::MAX_FAILED_MANIFESTATIONS > 0
+            && self.failed_manifestations >= M::MAX_FAILED_MANIFESTATIONS
+    }
+
+    /// Memory layout string of alternate setting `alt`
+    pub(crate) fn get_string(&self, alt: u8, lang_id: u16) -> Option<&str> {
+        if lang_id == u16::from(LangID::EN_US) || lang_id == 0 {
+            return Some(self.mem.mem_info_string_for(alt));
+        }
+        None
+    }
+
+    /// Vendor-specific error string
+    pub(crate) fn get_vendor_string(&self, lang_id: u16) -> Option<&str> {
+        if lang_id == u16::from(LangID::EN_US) || lang_id == 0 {
+            return self.mem.vendor_error_string();
+        }
+        None
+    }
+
+    pub(crate) fn set_alt_setting(&mut self, alternative: u8) -> bool {
+        if alternative >= M::ALT_COUNT {
+            return false;
+        }
+        if alternative != self.alt {
+            // memory can't change during a transfer
+            if self.status.state() != DFUState::DfuIdle {
+                return false;
+            }
+            self.alt = alternative;
+            self.mem.select_alt(alternative);
+        }
+        true
+    }
+
+    /// Class request `req` to the DFU interface, direction is device to host
+    pub(crate) fn control_in(&mut self, req: Request, xfer: impl InXfer) {
+        #[cfg(feature = "profiling")]
+        let start = self.mem.profile_ticks();
+        trace!(
+            "dfu: control_in request {} value {} length {}",
+            req.request,
+            req.value,
+            req.length
+        );
+
+        self.handle_control_in(req, xfer);
+
+        #[cfg(feature = "profiling")]
+        {
+            let ticks = self.mem.profile_ticks().wrapping_sub(start);
+            self.profile.control_in.record(ticks);
+        }
+    }
+
+    /// Class request `req` to the DFU interface, direction is host to device
+    pub(crate) fn control_out(&mut self, req: Request, xfer: impl OutXfer) {
+        #[cfg(feature = "profiling")]
+        let start = self.mem.profile_ticks();
+        trace!(
+            "dfu: control_out request {} value {} length {}",
+            req.request,
+            req.value,
+            req.length
+        );
+
+        self.handle_control_out(req, xfer);
+
+        #[cfg(feature = "profiling")]
+        {
+            let ticks = self.mem.profile_ticks().wrapping_sub(start);
+            self.profile.control_out.record(ticks);
+        }
+    }
+
+    /// Checks timeouts, and executes a pending operation if `execute` is `true`
+    pub(crate) fn poll(&mut self, execute: bool) {
+        #[cfg(feature = "profiling")]
+        let start = self.mem.profile_ticks();
+
+        self.handle_poll(execute);
+
+        #[cfg(feature = "profiling")]
+        {
+            let ticks = self.mem.profile_ticks().wrapping_sub(start);
+            self.profile.poll.record(ticks);
+        }
+    }
+
+    pub(crate) fn reset(&mut self) {
+        let leave =
+            self.status.command == Command::LeaveDFU || self.status.pending == Command::LeaveDFU;
+        if leave {
+            // Host has finished the download, but reset the bus before
+            // manifestation has started, don't leave the image inactive.
+            self.status.command = Command::None;
+            self.status.pending = Command::LeaveDFU;
+            if !M::MEMIO_IN_USB_INTERRUPT {
+                // manifestation runs in update(), the rest is done after it
+                self.reset_deferred = true;
+                self.hold_polls = 0;
+                return;
+            }
+            // may not return
+            self.update_impl();
+        }
+        if self.manifesting.is_some() {
+            // manifestation continues in poll() or update(), the rest is done after it
+            self.reset_deferred = true;
+            self.hold_polls = 0;
+            return;
+        }
+        if leave || self.reset_deferred {
+            if let Some((mr, _)) = self.manifest_result.take() {
+                self.manifestation_done(mr);
+            }
+            if self.status.state() == DFUState::DfuManifestSync {
+                // manifestation is complete
+                self.new_state_ok(DFUState::DfuIdle);
+            }
+        }
+
+        let ctx = ResetContext {
+            state: self.status.state(),
+            manifested: self.manifested,
+            downloaded: self.downloaded,
+        };
+        if ctx.state == DFUState::DfuManifestWaitReset {
+            // may not return
+            self.mem.on_manifest_reset();
+        }
+        // may not return
+        self.mem.on_usb_reset(ctx);
+        self.downloaded = false;
+        self.manifested = false;
+
+        let keep = M::ON_USB_RESET == UsbResetPolicy::KeepState
+            && self.alt == 0
+            && matches!(
+                self.status.state(),
+                DFUState::DfuUploadIdle | DFUState::DfuDnloadIdle | DFUState::DfuError
+            );
+
+        // Operations that were in progress must not continue after reset
+        self.status.command = Command::None;
+        self.status.pending = Command::None;
+        if !keep {
+            self.status.end_session();
+            self.error_since = None;
+        }
+        self.in_progress = None;
+        self.manifest_result = None;
+        self.manifesting = None;
+        self.erasing = None;
+        self.crc_progress = None;
+        self.crc_result = None;
+        self.hold_polls = 0;
+        self.reset_deferred = false;
+        self.detach = None;
+        if self.alt != 0 {
+            self.alt = 0;
+            self.mem.select_alt(0);
+        }
+        #[cfg(feature = "echo-test")]
+        {
+            self.echo.enabled = false;
+        }
+
+        // Try to signal possible error to a host.
+        // Not exactly clear what status should be.
+        match self.status.state() {
+            DFUState::DfuUploadIdle
+            | DFUState::DfuDnloadIdle
+            | DFUState::DfuDnloadSync
+            | DFUState::DfuDnBusy
+            | DFUState::DfuError
+            | DFUState::DfuManifest
+            | DFUState::DfuManifestSync => {
+                if keep {
+                    // a host may continue where it has stopped
+                } else if M::ON_USB_RESET == UsbResetPolicy::ReturnToIdle {
+                    self.new_state_ok(DFUState::DfuIdle);
+                } else {
+                    self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrUsbr);
+                }
+            }
+            DFUState::DfuManifestWaitReset => {
+                // on_usb_reset() returned, the device stays in DFU mode
+                self.new_state_ok(DFUState::DfuIdle);
+            }
+            DFUState::DfuIdle | DFUState::AppDetach | DFUState::AppIdle => {}
+        }
+    }
+
+    /// Completes USB reset that has waited for a pending manifestation
+    pub(crate) fn finish_reset(&mut self) {
+        if self.reset_deferred && !self.command_pending() && self.manifesting.is_none() {
+            self.reset();
+        }
+    }
+
+    fn detach(&mut self, xfer: impl OutXfer, req: Request) {
+        // DFU state is not changed, the application decides what to do
+        self.detach = Some((req.value, self.now_ms()));
+        xfer.accept();
+        if self.options.will_detach {
+            // may not return
+            self.mem.on_detach_request(req.value);
+        }
+        // otherwise wait for a host to reset the bus
+    }
+
+    fn clear_status(&mut self, xfer: impl OutXfer) {
+        match self.status.state() {
+            DFUState::DfuError => {
+                self.status.command = Command::None;
+                self.status.pending = Command::None;
+                self.new_state_ok(DFUState::DfuIdle);
+                self.requery_initial_address();
+                xfer.accept();
+            }
+            _ => {
+                // nothing to clear, the state is not changed
+                xfer.reject();
+            }
+        }
+    }
+
+    /// Moves Address Pointer if `initial_address_pointer()` value has changed
+    fn requery_initial_address(&mut self) {
+        if let Some(prev) = self.initial_address {
+            let address = self.mem.initial_address_pointer();
+            if address != prev {
+                self.initial_address = Some(address);
+                self.status.address_pointer = address;
+            }
+        }
+    }
+
+    fn abort(&mut self, xfer: impl OutXfer) {
+        match self.status.state() {
+            DFUState::DfuIdle
+            | DFUState::DfuUploadIdle
+            | DFUState::DfuDnloadIdle
+            | DFUState::DfuDnloadSync
+            | DFUState::DfuManifestSync => {
+                self.status.command = Command::None;
+                self.status.pending = Command::None;
+                self.new_state_ok(DFUState::DfuIdle);
+                self.mem.on_abort();
+                xfer.accept();
+            }
+            DFUState::AppDetach
+            | DFUState::AppIdle
+            | DFUState::DfuDnBusy
+            | DFUState::DfuManifest
+            | DFUState::DfuManifestWaitReset
+            | DFUState::DfuError => {
+                xfer.reject();
+            }
+        }
+    }
+
+    fn handle_control_in(&mut self, req: Request, xfer: impl InXfer) {
+        self.check_in_progress();
+        self.check_manifestation_poll();
+        self.check_manifestation_time();
+        self.check_error_autoclear();
+        self.check_detach_timeout();
+        self.finish_reset();
+
+        match req.request {
+            DFU_UPLOAD => match Self::dfuse_block_num(req) {
+                Some(req) => self.upload(xfer, req),
+                None => {
+                    self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+                    xfer.reject();
+                }
+            },
+            DFU_GETSTATUS => {
+                self.get_status(xfer, req);
+            }
+            DFU_GETSTATE => {
+                self.get_state(xfer, req);
+            }
+            _ => {
+                xfer.reject();
+            }
+        }
+    }
+
+    fn handle_control_out(&mut self, req: Request, xfer: impl OutXfer) {
+        self.check_in_progress();
+        self.check_manifestation_poll();
+        self.check_manifestation_time();
+        self.check_error_autoclear();
+        self.check_detach_timeout();
+        self.finish_reset();
+
+        match req.request {
+            DFU_DETACH => {
+                self.detach(xfer, req);
+            }
+            DFU_DNLOAD => match Self::dfuse_block_num(req) {
+                Some(req) => self.download(xfer, req),
+                None => {
+                    self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+                    xfer.reject();
+                }
+            },
+            DFU_CLRSTATUS => {
+                self.clear_status(xfer);
+            }
+            DFU_ABORT => {
+                self.abort(xfer);
+            }
+            _ => {
+                xfer.reject();
+            }
+        }
+    }
+
+    fn handle_poll(&mut self, execute: bool) {
+        self.hold_polls = self.hold_polls.saturating_sub(1);
+        let execute = execute && self.hold_polls == 0;
+
+        let executed = execute
+            && !self.dry_run
+            && matches!(
+                self.status.pending,
+                Command::EraseAll
+                    | Command::Erase(_)
+                    | Command::ReadUnprotect
+                    | Command::Vendor { cmd: _, time_ms: _ }
+                    | Command::Crc {
+                        address: _,
+                        length: _
+                    }
+                    | Command::WriteMemory {
+                        block_num: _,
+                        len: _
+                    }
+                    | Command::LeaveDFU
+            );
+
+        if execute {
+            self.update_impl();
+        }
+        self.check_in_progress();
+        self.check_manifestation_poll();
+        self.check_manifestation_time();
+        self.check_error_autoclear();
+        self.check_detach_timeout();
+        self.finish_reset();
+
+        if executed {
+            self.poll_activity = PollActivity::ExecutedCommand;
+        } else if self.is_busy() && self.poll_activity == PollActivity::None {
+            self.poll_activity = PollActivity::CommandPending;
+        }
+    }
+
+    fn download(&mut self, xfer: impl OutXfer, req: Request) {
+        let initial_state = self.status.state();
+
+        if initial_state != DFUState::DfuIdle && initial_state != DFUState::DfuDnloadIdle {
+            self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+            xfer.reject();
+            return;
+        }
+
+        // a digest that was not uploaded is stale now
+        self.crc_result = None;
+
+        if self.is_locked_out() {
+            self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrVendor);
+            xfer.reject();
+            return;
+        }
+
+        if xfer.data().len() != req.length as usize {
+            // data stage doesn't match the setup packet
+            warn!(
+                "dfu: download length mismatch: {:?} != {:?}",
+                xfer.data().len(),
+                req.length
+            );
+            self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+            xfer.reject();
+            return;
+        }
+
+        if req.length > self.options.transfer_size {
+            // host ignores wTransferSize, data doesn't fit memory buffer
+            warn!(
+                "dfu: download block is too large: {:?} > {:?}",
+                req.length, self.options.transfer_size
+            );
+            self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+            xfer.reject();
+            return;
+        }
+
+        if req.length == 0 {
+            if let Some((_, magic)) = M::IMAGE_MAGIC {
+                if self.status.magic_offset > 0 && self.status.magic_checked < magic.len() {
+                    // image is too short
+                    self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrTarget);
+                    xfer.reject();
+                    return;
+                }
+            }
+            if M::CHECK_SUFFIX {
+                if let Err(e) = self.mem.validate_suffix(self.status.suffix.suffix()) {
+                    warn!("dfu: suffix rejected: {:?}", e);
+                    self.new_state_status(DFUState::DfuError, e.into());
+                    xfer.reject();
+                    return;
+                }
+            }
+            if let Err(e) = self.mem.manifestation_allowed() {
+                self.new_state_status(DFUState::DfuError, e.into());
+                xfer.reject();
+                return;
+            }
+            self.status.command = Command::LeaveDFU;
+            self.status.last_block = None;
+            self.new_state_ok(DFUState::DfuManifestSync);
+            xfer.accept();
+            return;
+        }
+
+        #[cfg(feature = "echo-test")]
+        if req.value > 1 && self.echo.enabled {
+            // capture the block, memory is not touched
+            let data = xfer.data();
+            self.echo.buf[..data.len()].copy_from_slice(data);
+            self.echo.len = data.len();
+            self.status.command = Command::None;
+            self.new_state_ok(DFUState::DfuDnloadSync);
+            xfer.accept();
+            return;
+        }
+
+        if req.value > 1 {
+            let block_num = self.logical_block(req.value);
+            let mut data = xfer.data();
+            if M::BLOCK_CRC && !data.is_empty() {
+                if data.len() <= 4 {
+                    self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+                    xfer.reject();
+                    return;
+                }
+
+                let (payload, trailer) = data.split_at(data.len() - 4);
+                let crc = u32::from_le_bytes([trailer[0], trailer[1], trailer[2], trailer[3]]);
+                if crc != crc32(payload) {
+                    self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrFile);
+                    xfer.reject();
+                    return;
+                }
+                data = payload;
+            }
+
+            // file data, as sent by a host
+            let file_data = data;
+
+            if !self.check_magic(data) {
+                self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrTarget);
+                xfer.reject();
+                return;
+            }
+
+            if M::IMAGE_HEADER_SIZE > 0 && self.status.image_block.is_none() && !data.is_empty() {
+                if data.len() < M::IMAGE_HEADER_SIZE {
+                    self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrFile);
+                    xfer.reject();
+                    return;
+                }
+
+                match self.mem.locate_image(&data[..M::IMAGE_HEADER_SIZE]) {
+                    Err(e) => {
+                        self.new_state_status(DFUState::DfuError, e.into());
+                        xfer.reject();
+                        return;
+                    }
+                    Ok(address) => {
+                        self.status.address_pointer = address;
+                        self.status.image_block = Some(block_num);
+                    }
+                }
+
+                if !M::PROGRAM_IMAGE_HEADER {
+                    data = &data[M::IMAGE_HEADER_SIZE..];
+                    if data.is_empty() {
+                        // header only, nothing to program
+                        self.push_suffix(file_data);
+                        self.status.command = Command::None;
+                        self.status.download_session = true;
+                        self.downloaded = true;
+                        self.status.last_block = Some((req.value, block_num));
+                        self.new_state_ok(DFUState::DfuDnloadSync);
+                        xfer.accept();
+                        return;
+                    }
+                }
+            }
+
+            if !data.is_empty() {
+                if let Some(l) = self.block_address(block_num).and_then(Self::segment_limits) {
+                    if data.len() > l.max_block as usize {
+                        // too large for this segment
+                        self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+                        xfer.reject();
+                        return;
+                    }
+                }
+
+                // store the whole buffer, chunked operation in not supported
+                match self.mem.store_write_buffer(data) {
+                    Err(_) => {
+                        self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+                        xfer.reject();
+                    }
+                    Ok(_) => {
+                        self.push_suffix(file_data);
+                        self.status.command = Command::WriteMemory {
+                            block_num,
+                            len: data.len() as u16,
+                        };
+                        self.status.download_session = true;
+                        self.downloaded = true;
+                        self.status.last_block = Some((req.value, block_num));
+                        self.new_state_ok(DFUState::DfuDnloadSync);
+                        xfer.accept();
+                    }
+                }
+                return;
+            }
+        } else if req.value == 0 {
+            #[cfg(feature = "echo-test")]
+            if let Ok(DfuseCommand::Echo(enable)) = DfuseCommand::decode(xfer.data()) {
+                self.echo.enabled = enable;
+                self.echo.len = 0;
+                self.status.command = Command::None;
+                self.new_state_ok(DFUState::DfuDnloadSync);
+                xfer.accept();
+                return;
+            }
+
+            let decoded = match xfer.data().first() {
+                Some(&cmd) if cmd == DnloadCommand::Crc as u8 && !M::HAS_CRC_COMMAND => {
+                    Err(DfuseCommandError::UnknownCommand(cmd))
+                }
+                _ => DfuseCommand::decode(xfer.data()),
+            };
+
+            let command = match decoded {
+                // image location can't be changed by host
+                Ok(DfuseCommand::SetAddressPointer(addr)) if self.status.image_block.is_none() => {
+                    Some(Command::SetAddressPointer(addr))
+                }
+                Ok(DfuseCommand::ErasePage(addr)) => Some(Command::Erase(addr)),
+                Ok(DfuseCommand::MassErase) => Some(Command::EraseAll),
+                Ok(DfuseCommand::ReadUnprotect) if M::HAS_READ_UNPROTECT => {
+                    Some(Command::ReadUnprotect)
+                }
+                Ok(DfuseCommand::Crc { address, length }) => {
+                    self.crc_progress = None;
+                    Some(Command::Crc { address, length })
+                }
+                Err(DfuseCommandError::UnknownCommand(cmd)) => {
+                    match self.mem.vendor_dnload_command(cmd, &xfer.data()[1..]) {
+                        Err(e) => {
+                            self.new_state_status(DFUState::DfuError, e.into());
+                            xfer.reject();
+                            return;
+                        }
+                        Ok(VendorCommandOutcome::Unsupported) => None,
+                        Ok(VendorCommandOutcome::Done) => Some(Command::None),
+                        Ok(VendorCommandOutcome::Deferred(time_ms)) => {
+                            Some(Command::Vendor { cmd, time_ms })
+                        }
+                    }
+                }
+                _ => None,
+            };
+
+            if let Some(command) = command {
+                if let Command::Erase(_) | Command::EraseAll | Command::ReadUnprotect = command {
+                    self.status.download_session = true;
+                }
+                self.status.command = command;
+                self.new_state_ok(DFUState::DfuDnloadSync);
+                xfer.accept();
+                return;
+            }
+        }
+
+        self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+        xfer.reject();
+    }
+
+    fn upload(&mut self, xfer: impl InXfer, req: Request) {
+        let initial_state = self.status.state();
+        let read_back =
+            M::ALLOW_UPLOAD_DURING_DNLOAD_IDLE && initial_state == DFUState::DfuDnloadIdle;
+
+        if initial_state != DFUState::DfuIdle
+            && initial_state != DFUState::DfuUploadIdle
+            && !read_back
+        {
+            self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+            xfer.reject();
+            return;
+        }
+
+        if req.value == 0 && !read_back {
+            // Get command
+            let commands = [
+                (DnloadCommand::GetCommands as u8, true),
+                (DnloadCommand::SetAddressPointer as u8, true),
+                (DnloadCommand::Erase as u8, true),
+                (DnloadCommand::ReadUnprotect as u8, M::HAS_READ_UNPROTECT),
+                (DnloadCommand::Crc as u8, M::HAS_CRC_COMMAND),
+            ];
+            let commands = commands
+                .iter()
+                .filter(|(_, enabled)| *enabled)
+                .map(|(cmd, _)| cmd);
+
+            let len = commands.clone().count() + self.mem.vendor_commands().len();
+            if req.length as usize >= len {
+                self.new_state_ok(DFUState::DfuIdle);
+            }
+            // otherwise, short probe for DfuSe support, state is unchanged
+
+            let list = commands
+                .chain(self.mem.vendor_commands())
+                .take(req.length as usize);
+            xfer.accept(|buf| Some(buf.iter_mut().zip(list).map(|(b, c)| *b = *c).count()));
+            return;
+        } else if req.value == 1 && M::HAS_DEVICE_INFO && !read_back {
+            // device information
+            self.new_state_ok(DFUState::DfuIdle);
+            let mem = &mut self.mem;
+            xfer.accept(|buf| {
+                let len = min(req.length as usize, buf.len());
+                Some(min(mem.device_info(&mut buf[..len]), len))
+            });
+            return;
+        } else if req.value > 1 {
+            // upload command
+            let block_num = req.value - 2;
+            let transfer_size = if read_back {
+                min(self.download_block_size() as u16, req.length)
+            } else {
+                min(self.options.transfer_size, req.length)
+            };
+
+            #[cfg(feature = "echo-test")]
+            if self.echo.enabled {
+                let len = min(self.echo.len, transfer_size as usize);
+                if len < self.options.transfer_size as usize {
+                    // short frame, back to idle
+                    self.new_state_ok(DFUState::DfuIdle);
+                } else {
+                    self.new_state_ok(DFUState::DfuUploadIdle);
+                }
+                xfer.accept_with(&self.echo.buf[..len]);
+                return;
+            }
+
+            if let Some(crc) = self.crc_result.filter(|_| !read_back) {
+                // CRC command result instead of memory, short frame
+                self.crc_result = None;
+                self.new_state_ok(DFUState::DfuIdle);
+                let digest = crc.to_le_bytes();
+                xfer.accept_with(&digest[..min(digest.len(), transfer_size as usize)]);
+                return;
+            }
+
+            let address = if read_back {
+                // read a downloaded block back, addressed as in the download session
+                self.block_address(self.logical_block(req.value))
+            } else {
+                // all blocks of a session are read relative to the same base
+                let base = match self.status.upload_base {
+                    Some(base) => base,
+                    None => self.status.address_pointer,
+                };
+                self.status.upload_base = Some(base);
+                Self::layout_address(
+                    base,
+                    (block_num as u32) * (self.options.transfer_size as u32),
+                )
+            };
+
+            // the end of the block must fit too
+            let address = address.filter(|a| a.checked_add(transfer_size as u32).is_some());
+
+            if let Some(address) = address {
+                let length = match self.mem.upload_end_address() {
+                    Some(end) if !read_back => {
+                        min(transfer_size as u32, end.saturating_sub(address))
+                    }
+                    _ => transfer_size as u32,
+                };
+                if length == 0 {
+                    // nothing left, empty frame ends the upload
+                    self.upload_in_place(xfer, address, 0);
+                    return;
+                }
+                let length = match self.check_perms(
+                    address,
+                    length,
+                    Perms::readable,
+                    DFUMemError::Address,
+                ) {
+                    Ok(()) => length,
+                    // the block ends before an area that is not readable
+                    Err((n, _)) if n > 0 => n,
+                    Err((_, e)) => {
+                        self.new_state_status(DFUState::DfuError, e.into());
+                        xfer.reject();
+                        return;
+                    }
+                };
+                self.upload_in_place(xfer, address, length as usize);
+                return;
+            } else {
+                // overflow
+                self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrAddress);
+                xfer.reject();
+                return;
+            }
+        }
+
+        self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+        xfer.reject();
+    }
+
+    /// Converts `wBlockNum` of a data request to DfuSe one, where data blocks
+    /// start from 2, `None` if it does not fit
+    fn dfuse_block_num(mut req: Request) -> Option<Request> {
+        let first = if M::DFU_VERSION == DFU_VERSION_DFUSE {
+            M::FIRST_DATA_BLOCK
+        } else {
+            0
+        };
+        if first == 2 || req.value < first {
+            // DfuSe block numbers, or a DfuSe command
+            return Some(req);
+        }
+        let shift = 2 - first;
+        if req.length > 0 && req.value > u16::MAX - shift {
+            return None;
+        }
+        // zero-length download ends a session with any wBlockNum
+        req.value = req.value.wrapping_add(shift);
+        Some(req)
+    }
+
+    /// Logical block number of a download block with `wBlockNum` of `value`.
+    ///
+    /// Data blocks are numbered from 2 to 0xFFFF, a host continues from 2 after 0xFFFF.
+    /// A block number that is more than half of the range behind the last accepted
+    /// block is a wraparound, other block numbers are relative to the last block.
+    fn logical_block(&self, value: u16) -> u32 {
+        const DATA_BLOCKS: u32 = 0xFFFF - 1;
+
+        let (last_value, last) = match self.status.last_block {
+            Some(b) => b,
+            None => return (value - 2) as u32,
+        };
+
+        if value >= last_value {
+            last.saturating_add((value - last_value) as u32)
+        } else if last_value - value > 0x8000 {
+            last.saturating_add(DATA_BLOCKS - (last_value - value) as u32)
+        } else {
+            // a logical block number is never less than its wBlockNum - 2
+            last - (last_value - value) as u32
+        }
+    }
+
+    /// Address of a download block data, `None` on overflow
+    fn block_address(&self, block_num: u32) -> Option<u32> {
+        let (first, skip) = match self.status.image_block {
+            Some(first) if M::PROGRAM_IMAGE_HEADER => (first, 0),
+            Some(first) if block_num == first => (first, 0),
+            Some(first) => (first, M::IMAGE_HEADER_SIZE as u32),
+            None => (0, 0),
+        };
+
+        let offset = block_num
+            .checked_sub(first)?
+            .checked_mul(self.download_block_size())?
+            .checked_sub(skip)?;
+        Self::layout_address(self.status.address_pointer, offset)
+    }
+
+    /// Keep the last bytes of a download session, see `CHECK_SUFFIX`
+    fn push_suffix(&mut self, data: &[u8]) {
+        if M::CHECK_SUFFIX {
+            self.status.suffix.push(data);
+        }
+    }
+
+    /// Check bytes of `IMAGE_MAGIC` in the next data block of a download session
+    fn check_magic(&mut self, data: &[u8]) -> bool {
+        let (offset, magic) = match M::IMAGE_MAGIC {
+            Some(m) => m,
+            None => return true,
+        };
+
+        let start = self.status.magic_offset;
+        self.status.magic_offset = start.saturating_add(data.len());
+
+        let checked = self.status.magic_checked;
+        // image offset of the next byte to check
+        let pos = offset.saturating_add(checked);
+        if checked == magic.len() || pos < start || pos - start >= data.len() {
+            return true;
+        }
+
+        let from = pos - start;
+        let n = min(magic.len() - checked, data.len() - from);
+        if data[from..from + n] != magic[checked..checked + n] {
+            return false;
+        }
+        self.status.magic_checked += n;
+        true
+    }
+
+    /// Limits of the segment that contains `address`, see `SEGMENT_LIMITS`
+    fn segment_limits(address: u32) -> Option<SegmentLimits> {
+        if M::SEGMENT_LIMITS.is_empty() {
+            return None;
+        }
+        M::LAYOUT_SEGMENTS
+            .iter()
+            .position(|r| r.contains(&address))
+            .map(|i| M::SEGMENT_LIMITS[i])
+    }
+
+    /// Address `offset` bytes after `base`, skipping gaps between layout segments,
+    /// `None` on overflow
+    fn layout_address(base: u32, offset: u32) -> Option<u32> {
+        let first = match M::LAYOUT_SEGMENTS.iter().position(|r| r.contains(&base)) {
+            Some(i) => i,
+            None => return base.checked_add(offset),
+        };
+
+        let mut address = base;
+        let mut remaining = offset;
+        let mut end = M::LAYOUT_SEGMENTS[first].end;
+        for next in M::LAYOUT_SEGMENTS[first + 1..].iter() {
+            let available = end - address;
+            if remaining < available {
+                break;
+            }
+            remaining -= available;
+            address = next.start;
+            end = next.end;
+        }
+        address.checked_add(remaining)
+    }
+
+    /// Number of data bytes in a download block, without a CRC trailer
+    fn download_block_size(&self) -> u32 {
+        if M::BLOCK_CRC {
+            self.options.transfer_size as u32 - 4
+        } else {
+            self.options.transfer_size as u32
+        }
+    }
+
+    fn upload_in_place(&mut self, xfer: impl InXfer, address: u32, length: usize) {
+        // Build the block directly in the control buffer: redacted spans are filled,
+        // everything else is read from memory, in chunks if READ_CHUNK_SIZE is set.
+        // A short read ends the block at the end of a memory region.
+        let mem = &mut self.mem;
+        let mut result = Ok(0);
+
+        xfer.accept(|buf| {
+            let length = min(length, buf.len());
+            let mut pos = 0;
+
+            while pos < length {
+                let addr = address.wrapping_add(pos as u32);
+                let left = length - pos;
+
+                if let Some(r) = M::REDACTED_RANGES.iter().find(|r| r.contains(&addr)) {
+                    let n = min(left, (r.end - addr) as usize);
+                    buf[pos..pos + n].fill(M::REDACTED_FILL);
+                    pos += n;
+                    continue;
+                }
+
+                let n = M::REDACTED_RANGES
+                    .iter()
+                    .filter(|r| r.start > addr)
+                    .map(|r| (r.start - addr) as usize)
+                    .fold(left, min);
+
+                let (n, read) = if M::READ_CHUNK_SIZE > 0 {
+                    let n = min(n, M::READ_CHUNK_SIZE);
+                    (n, mem.read_chunk(address, pos, &mut buf[pos..pos + n]))
+                } else {
+                    (n, mem.read_block(addr, &mut buf[pos..pos + n]))
+                };
+
+                match read {
+                    Ok(len) => {
+                        let len = min(len, n);
+                        pos += len;
+                        if len < n
+                            && (len == 0 || mem.is_end_of_region(address.wrapping_add(pos as u32)))
+                        {
+                            // short read, end of memory
+                            break;
+                        }
+                    }
+                    Err(e) => {
+                        result = Err(e);
+                        return None;
+                    }
+                }
+            }
 
-        //
+            result = Ok(pos);
+            Some(pos)
+        });
 
-        Ok(())
+        match result {
+            Ok(len) => self.upload_block_done(len),
+            Err(e) => {
+                self.new_state_status(DFUState::DfuError, e.into());
+            }
+        }
     }
 
-    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
-        if index == self.interface_string && (lang_id == LangID::EN_US || u16::from(lang_id) == 0) {
-            return Some(M::MEM_INFO_STRING);
+    /// State transition after an upload block of `len` bytes is sent
+    fn upload_block_done(&mut self, len: usize) {
+        if self.status.state() == DFUState::DfuDnloadIdle {
+            // read-back, download session continues
+        } else if len < self.options.transfer_size as usize {
+            // short frame, back to idle
+            self.new_state_ok(DFUState::DfuIdle);
+        } else {
+            self.new_state_ok(DFUState::DfuUploadIdle);
         }
-        None
     }
 
-    // Handle control requests to the host.
-    fn control_in(&mut self, xfer: ControlIn<B>) {
-        let req = *xfer.request();
-
-        if req.request_type != control::RequestType::Class {
-            return;
+    fn get_state(&mut self, xfer: impl InXfer, req: Request) {
+        // return current state, without any state transition unless configured
+        if req.length > 0 {
+            if M::ADVANCE_ON_GETSTATE {
+                self.process();
+            }
+            let v = self.status.state() as u8;
+            xfer.accept_with(&[v]);
+        } else {
+            self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+            xfer.reject();
         }
+    }
 
-        if req.recipient != control::Recipient::Interface {
-            return;
+    fn get_status(&mut self, xfer: impl InXfer, req: Request) {
+        if M::ERROR_AUTOCLEAR_MS > 0 && self.status.state() == DFUState::DfuError {
+            // host is aware of the error, restart the timer
+            self.error_since = Some(self.now_ms());
         }
 
-        if req.index != u8::from(self.if_num) as u16 {
+        if req.length >= 6 && self.process() {
+            self.status.poll_timeout = self.expected_timeout();
+            let mut v: [u8; 6] = self.status.into();
+            if let Some(index) = self.vendor_string {
+                if self.status.status == DFUStatusCode::ErrVendor
+                    && self.mem.vendor_error_string().is_some()
+                {
+                    v[5] = index;
+                }
+            }
+            xfer.accept_with(&v);
             return;
         }
 
-        match req.request {
-            DFU_UPLOAD => {
-                self.upload(xfer, req);
-            }
-            DFU_GETSTATUS => {
-                self.get_status(xfer, req);
-            }
-            DFU_GETSTATE => {
-                self.get_state(xfer, req);
-            }
-            _ => {
-                xfer.reject().ok();
-            }
-        }
+        self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
+        xfer.reject();
     }
 
-    // Handle a control request from the host.
-    fn control_out(&mut self, xfer: ControlOut<B>) {
-        let req = *xfer.request();
-
-        if req.request_type != control::RequestType::Class {
-            return;
-        }
-
-        if req.recipient != control::Recipient::Interface {
+    fn check_error_autoclear(&mut self) {
+        if M::ERROR_AUTOCLEAR_MS == 0 {
             return;
         }
 
-        if req.index != u8::from(self.if_num) as u16 {
+        if self.status.state() != DFUState::DfuError {
+            self.error_since = None;
             return;
         }
 
-        match req.request {
-            //DFU_DETACH => {},
-            DFU_DNLOAD => {
-                self.download(xfer, req);
-            }
-            DFU_CLRSTATUS => {
-                self.clear_status(xfer);
-            }
-            DFU_ABORT => {
-                self.abort(xfer);
-            }
-            _ => {
-                xfer.reject().ok();
+        let now = self.now_ms();
+        match self.error_since {
+            None => self.error_since = Some(now),
+            Some(since) if now.wrapping_sub(since) >= M::ERROR_AUTOCLEAR_MS => {
+                self.error_since = None;
+                self.status.command = Command::None;
+                self.status.pending = Command::None;
+                self.new_state_ok(DFUState::DfuIdle);
+                self.mem.on_abort();
             }
+            Some(_) => {}
         }
     }
 
-    fn reset(&mut self) {
-        // may not return
-        self.mem.usb_reset();
-
-        // Try to signal possible error to a host.
-        // Not exactly clear what status should be.
-        match self.status.state() {
-            DFUState::DfuUploadIdle
-            | DFUState::DfuDnloadIdle
-            | DFUState::DfuDnloadSync
-            | DFUState::DfuDnBusy
-            | DFUState::DfuError
-            | DFUState::DfuManifest
-            | DFUState::DfuManifestSync => {
-                self.status
-                    .new_state_status(DFUState::DfuError, DFUStatusCode::ErrUsbr);
+    fn check_detach_timeout(&mut self) {
+        if let Some((timeout, since)) = self.detach {
+            if self.now_ms().wrapping_sub(since) >= timeout as u32 {
+                self.detach = None;
+                self.mem.on_detach_expired();
             }
-            DFUState::DfuIdle
-            | DFUState::AppDetach
-            | DFUState::AppIdle
-            | DFUState::DfuManifestWaitReset => {}
         }
     }
 
-    fn poll(&mut self) {
-        self.update_impl();
-    }
-}
-
-impl<B: UsbBus, M: DFUMemIO> DFUClass<B, M> {
-    /// Creates a new DFUClass with the provided UsbBus and
-    /// DFUMemIO
-    pub fn new(alloc: &UsbBusAllocator<B>, mem: M) -> Self {
-        Self {
-            if_num: alloc.interface(),
-            status: DFUStatus::new(M::INITIAL_ADDRESS_POINTER),
-            interface_string: alloc.string(),
-            _bus: PhantomData,
-            mem,
+    fn expected_timeout(&self) -> u32 {
+        if let Some((remaining, _)) = self.manifesting {
+            return remaining;
+        }
+        if self.manifest_result.is_some() {
+            return M::MANIFESTATION_TIME_MS;
+        }
+        match self.in_progress {
+            Some((command, _)) => self.command_time(command),
+            None => self.command_time(self.status.pending),
         }
     }
 
-    /// This function will consume self and return the owned memory
-    /// argument that was moved in the call to new()
-    pub fn release(self) -> M {
-        self.mem
-    }
-
-    /// This function may be called just after `DFUClass::new()` to
-    /// set DFU error state to "Device detected unexpected power on reset"
-    /// instead of the usual `dfuIdle`.
-    pub fn set_unexpected_reset_state(&mut self) {
-        self.status
-            .new_state_status(DFUState::DfuError, DFUStatusCode::ErrPOR);
-    }
-
-    /// This function may be called just after `DFUClass::new()` to
-    /// set DFU error state to "Device’s firmware is corrupt. It cannot return to run-time (non-DFU) operations"
-    /// instead of the usual `dfuIdle`.
-    pub fn set_firmware_corrupted_state(&mut self) {
-        self.status
-            .new_state_status(DFUState::DfuError, DFUStatusCode::ErrFirmware);
-    }
-
-    /// Return current Address Pointer value.
-    pub fn get_address_pointer(&self) -> u32 {
-        self.status.address_pointer
+    fn command_time(&self, command: Command) -> u32 {
+        match command {
+            Command::WriteMemory { block_num, len } => {
+                match self.block_address(block_num).and_then(Self::segment_limits) {
+                    Some(l) => l.program_time_ms,
+                    None => min(self.mem.program_time_ms(len as usize), MAX_POLL_TIMEOUT),
+                }
+            }
+            Command::EraseAll => self.erasing.unwrap_or(M::FULL_ERASE_TIME_MS),
+            Command::ReadUnprotect => M::READ_UNPROTECT_TIME_MS,
+            Command::Erase(address) => match Self::segment_limits(address) {
+                Some(l) => l.erase_time_ms,
+                None => min(self.mem.erase_time_ms(address), MAX_POLL_TIMEOUT),
+            },
+            Command::LeaveDFU => M::MANIFESTATION_TIME_MS,
+            Command::Vendor { cmd: _, time_ms } => min(time_ms, MAX_POLL_TIMEOUT),
+            _ => 0,
+        }
     }
 
-    fn clear_status(&mut self, xfer: ControlOut<B>) {
-        match self.status.state() {
-            DFUState::DfuError => {
-                self.status.command = Command::None;
-                self.status.pending = Command::None;
-                self.status.new_state_ok(DFUState::DfuIdle);
-                xfer.accept().ok();
-            }
-            _ => {
-                self.status
-                    .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
-                xfer.reject().ok();
-            }
+    /// Program or erase function returned, wait for the operation if it's still running
+    fn operation_started(&mut self, command: Command) {
+        if self.mem.operation_busy() {
+            self.in_progress = Some((command, self.now_ms()));
+        } else {
+            self.operation_done();
         }
     }
 
-    fn abort(&mut self, xfer: ControlOut<B>) {
-        match self.status.state() {
-            DFUState::DfuIdle
-            | DFUState::DfuUploadIdle
-            | DFUState::DfuDnloadIdle
-            | DFUState::DfuDnloadSync
-            | DFUState::DfuManifestSync => {
-                self.status.command = Command::None;
-                self.status.pending = Command::None;
-                self.status.new_state_ok(DFUState::DfuIdle);
-                xfer.accept().ok();
-            }
-            DFUState::AppDetach
-            | DFUState::AppIdle
-            | DFUState::DfuDnBusy
-            | DFUState::DfuManifest
-            | DFUState::DfuManifestWaitReset
-            | DFUState::DfuError => {
-                xfer.reject().ok();
+    /// Operation is not running anymore, report its result
+    fn operation_done(&mut self) {
+        match self.mem.operation_result() {
+            Ok(_) => self.new_state_ok(DFUState::DfuDnloadSync),
+            Err(e) => {
+                warn!("dfu: background operation failed: {:?}", e);
+                self.new_state_status(DFUState::DfuError, e.into())
             }
         }
     }
 
-    fn download(&mut self, xfer: ControlOut<B>, req: Request) {
-        let initial_state = self.status.state();
+    fn check_in_progress(&mut self) {
+        let (command, since) = match self.in_progress {
+            Some(op) => op,
+            None => return,
+        };
 
-        if initial_state != DFUState::DfuIdle && initial_state != DFUState::DfuDnloadIdle {
-            self.status
-                .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
-            xfer.reject().ok();
+        if !self.mem.operation_busy() {
+            self.in_progress = None;
+            self.operation_done();
             return;
         }
 
-        if req.length == 0 {
-            self.status.command = Command::LeaveDFU;
-            self.status.new_state_ok(DFUState::DfuManifestSync);
-            xfer.accept().ok();
+        if M::OPERATION_BUDGET_FACTOR == 0 {
             return;
         }
 
-        if req.value > 1 {
-            let data = xfer.data();
-            if !data.is_empty() {
-                // store the whole buffer, chunked operation in not supported
-                match self.mem.store_write_buffer(data) {
-                    Err(_) => {
-                        self.status
-                            .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
-                        xfer.reject().ok();
-                    }
-                    Ok(_) => {
-                        let block_num = req.value - 2;
-                        self.status.command = Command::WriteMemory {
-                            block_num,
-                            len: data.len() as u16,
-                        };
-                        self.status.new_state_ok(DFUState::DfuDnloadSync);
-                        xfer.accept().ok();
-                    }
-                }
-                return;
+        let budget = self
+            .command_time(command)
+            .saturating_mul(M::OPERATION_BUDGET_FACTOR);
+        if self.now_ms().wrapping_sub(since) > budget {
+            self.in_progress = None;
+            let status = match command {
+                Command::WriteMemory {
+                    block_num: _,
+                    len: _,
+                } => DFUStatusCode::ErrProg,
+                Command::Vendor { cmd: _, time_ms: _ } => DFUStatusCode::ErrUnknown,
+                _ => DFUStatusCode::ErrErase,
+            };
+            self.new_state_status(DFUState::DfuError, status);
+            self.mem.on_error();
+        }
+    }
+
+    fn manifestation_done(&mut self, mr: Result<(), DFUManifestationError>) {
+        match mr {
+            Err(e) => {
+                warn!("dfu: manifestation failed: {:?}", e);
+                self.new_state_status(DFUState::DfuError, e.into())
             }
-        } else if req.value == 0 {
-            let data = xfer.data();
-            if req.length >= 1 {
-                let command = data[0];
-
-                if command == DnloadCommand::SetAddressPointer as u8 {
-                    if req.length == 5 {
-                        let addr = (data[1] as u32)
-                            | ((data[2] as u32) << 8)
-                            | ((data[3] as u32) << 16)
-                            | ((data[4] as u32) << 24);
-                        self.status.command = Command::SetAddressPointer(addr);
-                        self.status.new_state_ok(DFUState::DfuDnloadSync);
-                        xfer.accept().ok();
-                        return;
-                    }
-                } else if command == DnloadCommand::Erase as u8 {
-                    if req.length == 5 {
-                        let addr = (data[1] as u32)
-                            | ((data[2] as u32) << 8)
-                            | ((data[3] as u32) << 16)
-                            | ((data[4] as u32) << 24);
-                        self.status.command = Command::Erase(addr);
-                        self.status.new_state_ok(DFUState::DfuDnloadSync);
-                        xfer.accept().ok();
-                        return;
-                    } else if req.length == 1 {
-                        self.status.command = Command::EraseAll;
-                        self.status.new_state_ok(DFUState::DfuDnloadSync);
-                        xfer.accept().ok();
-                        return;
-                    }
-                } else if HAS_READ_UNPROTECT && command == DnloadCommand::ReadUnprotect as u8 {
-                    self.status.command = Command::ReadUnprotect;
-                    self.status.new_state_ok(DFUState::DfuDnloadSync);
-                    xfer.accept().ok();
-                    return;
+            Ok(_) => {
+                self.status.end_session();
+                self.manifested = true;
+                if self.options.manifestation_tolerant {
+                    self.new_state_ok(DFUState::DfuManifestSync)
+                } else {
+                    self.new_state_ok(DFUState::DfuManifestWaitReset)
                 }
             }
         }
-
-        self.status
-            .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
-        xfer.reject().ok();
     }
 
-    fn upload(&mut self, xfer: ControlIn<B>, req: Request) {
-        let initial_state = self.status.state();
+    /// Manifestation has completed or failed, report it
+    fn manifestation_finished(&mut self, mr: Result<(), DFUManifestationError>, since: u32) {
+        if mr.is_ok() && !self.dry_run {
+            self.progress(DfuPhase::Manifest, 0, self.status.address_pointer);
+        }
 
-        if initial_state != DFUState::DfuIdle && initial_state != DFUState::DfuUploadIdle {
-            self.status
-                .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
-            xfer.reject().ok();
-            return;
+        if M::MAX_FAILED_MANIFESTATIONS > 0 {
+            let failures = match mr {
+                Ok(_) => 0,
+                Err(_) => self.failed_manifestations.saturating_add(1),
+            };
+            if failures != self.failed_manifestations {
+                self.failed_manifestations = self.mem.persist_lockout(Some(failures));
+            }
         }
 
-        if req.value == 0 {
-            // Get command
-            let commands = [
-                DnloadCommand::GetCommands as u8,
-                DnloadCommand::SetAddressPointer as u8,
-                DnloadCommand::Erase as u8,
-                // XXX read unprotect
-            ];
+        if M::MANIFESTATION_CONSTANT_TIME {
+            // don't tell a host why and how fast it failed
+            let mr = mr.map_err(|_| DFUManifestationError::File);
+            self.manifest_result = Some((mr, since));
+        } else {
+            self.manifestation_done(mr);
+        }
+    }
 
-            if req.length as usize >= commands.len() {
-                self.status.new_state_ok(DFUState::DfuIdle);
-                xfer.accept_with(&commands).ok();
-                return;
-            }
-        } else if req.value > 1 {
-            // upload command
-            let block_num = req.value - 2;
-            let transfer_size = min(M::TRANSFER_SIZE, req.length);
+    fn check_manifestation_poll(&mut self) {
+        let since = match self.manifesting {
+            Some((_, since)) => since,
+            None => return,
+        };
 
-            if let Some(address) = self
-                .status
-                .address_pointer
-                .checked_add((block_num as u32) * (M::TRANSFER_SIZE as u32))
-            {
-                match self.mem.read(address, transfer_size as usize) {
-                    Ok(b) => {
-                        if b.len() < M::TRANSFER_SIZE as usize {
-                            // short frame, back to idle
-                            self.status.new_state_ok(DFUState::DfuIdle);
-                        } else {
-                            self.status.new_state_ok(DFUState::DfuUploadIdle);
-                        }
-                        xfer.accept_with(b).ok();
-                        return;
-                    }
-                    Err(e) => {
-                        self.status.new_state_status(DFUState::DfuError, e.into());
-                        xfer.reject().ok();
-                        return;
-                    }
-                }
-            } else {
-                // overflow
-                self.status
-                    .new_state_status(DFUState::DfuError, DFUStatusCode::ErrAddress);
-                xfer.reject().ok();
+        let mr = match self.mem.manifestation_poll() {
+            ManifestationProgress::Busy(remaining) => {
+                self.manifesting = Some((min(remaining, MAX_POLL_TIMEOUT), since));
                 return;
             }
-        }
+            ManifestationProgress::Done => Ok(()),
+            ManifestationProgress::Err(e) => Err(e),
+        };
 
-        self.status
-            .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
-        xfer.reject().ok();
+        self.manifesting = None;
+        self.manifestation_finished(mr, since);
     }
 
-    fn get_state(&mut self, xfer: ControlIn<B>, req: Request) {
-        // return current state, without any state transition
-        if req.length > 0 {
-            let v = self.status.state() as u8;
-            xfer.accept_with(&[v]).ok();
-        } else {
-            self.status
-                .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
-            xfer.reject().ok();
-        }
-    }
+    fn check_manifestation_time(&mut self) {
+        let since = match self.manifest_result {
+            Some((_, since)) => since,
+            None => return,
+        };
 
-    fn get_status(&mut self, xfer: ControlIn<B>, req: Request) {
-        if req.length >= 6 && self.process() {
-            self.status.poll_timeout = self.expected_timeout();
-            let v: [u8; 6] = self.status.into();
-            xfer.accept_with(&v).ok();
+        if self.now_ms().wrapping_sub(since) < M::MANIFESTATION_TIME_MS {
             return;
         }
 
-        self.status
-            .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt);
-        xfer.reject().ok();
+        if let Some((mr, _)) = self.manifest_result.take() {
+            self.manifestation_done(mr);
+        }
     }
 
-    fn expected_timeout(&self) -> u32 {
-        match self.status.pending {
-            Command::WriteMemory {
-                block_num: _,
-                len: _,
-            } => M::PROGRAM_TIME_MS,
-            Command::EraseAll => M::FULL_ERASE_TIME_MS,
-            Command::Erase(_) => M::ERASE_TIME_MS,
-            Command::LeaveDFU => M::MANIFESTATION_TIME_MS,
-            _ => 0,
+    /// Memory function of a pending command returned an error
+    fn operation_failed(&mut self, e: DFUMemError) {
+        warn!("dfu: {:?} failed: {:?}", self.status.pending, e);
+        self.new_state_status(DFUState::DfuError, e.into());
+    }
+
+    /// Checks `length` bytes at `address` against permissions of the memory layout,
+    /// see `CHECK_PERMISSIONS`. Fails with `denied` and the number of bytes from `address`
+    /// before the first area without the permission, everything is denied if the layout
+    /// is malformed.
+    fn check_perms(
+        &self,
+        address: u32,
+        length: u32,
+        allowed: fn(Perms) -> bool,
+        denied: DFUMemError,
+    ) -> Result<(), (u32, DFUMemError)> {
+        if !M::CHECK_PERMISSIONS {
+            return Ok(());
+        }
+
+        let end = address.saturating_add(length.max(1));
+        let mut first: Option<u32> = None;
+        for area in areas(self.mem.mem_info_string_for(self.alt)) {
+            // nothing is allowed by a malformed layout
+            let (r, perms) = area.map_err(|_| (0, denied))?;
+            if !allowed(perms) && r.start < end && address < r.end {
+                let n = r.start.saturating_sub(address);
+                first = Some(first.map_or(n, |f| f.min(n)));
+            }
+        }
+        match first {
+            Some(n) => Err((n, denied)),
+            None => Ok(()),
         }
     }
 
-    // ///
-    // /// Handle some DFU state transitions, and call `DFUMemIO`'s erase, program,
-    // /// and manifestation functions.
-    // ///
-    // /// This function will be called internally by if [`M::MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT)
-    // /// is `true` (default) as one of a final steps of `usb_dev.poll([...])` which is itself usually called
-    // /// from USB interrupt.
-    // ///
-    // /// This function must be called if [`M::MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT) is `false`
-    // /// and erase, program, and manifestation should be called from a different context than `usb_dev.poll([...])`.
-    // ///
-    // pub fn update(&mut self) {
-    //     debug_assert!(!M::MEMIO_IN_USB_INTERRUPT, "not requried with MEMIO_IN_USB_INTERRUPT");
-    //     if !M::MEMIO_IN_USB_INTERRUPT {
-    //         self.update_impl()
-    //     }
-    // }
-
-    // /// Returns `true` if [`update()`](DFUClass::update) needs to be called to
-    // /// process a pending operation.
-    // pub fn update_pending(&self) -> bool {
-    //     match self.status.pending {
-    //         Command::None => false,
-    //         _ => true,
-    //     }
-    // }
+    /// Checks that `address` is the start of an erasable page that is not write-protected
+    fn check_page(&self, address: u32) -> Result<(), DFUMemError> {
+        self.check_perms(address, 1, Perms::erasable, DFUMemError::Erase)
+            .map_err(|(_, e)| e)?;
+        match self.mem.page_size_at(address) {
+            None => Err(DFUMemError::Address),
+            Some(size) if !address.is_multiple_of(size) => Err(DFUMemError::Erase),
+            Some(size) if self.mem.is_write_protected(address, size as usize) => {
+                Err(DFUMemError::Erase)
+            }
+            Some(_) => Ok(()),
+        }
+    }
 
     fn update_impl(&mut self) {
+        if self.status.pending != Command::None {
+            debug!("dfu: execute {:?}", self.status.pending);
+        }
         match self.status.pending {
-            Command::EraseAll => match self.mem.erase_all() {
-                Err(e) => self.status.new_state_status(DFUState::DfuError, e.into()),
-                Ok(_) => self.status.new_state_ok(DFUState::DfuDnloadSync),
+            Command::EraseAll
+            | Command::Erase(_)
+            | Command::ReadUnprotect
+            | Command::Vendor { cmd: _, time_ms: _ }
+                if self.dry_run =>
+            {
+                self.new_state_ok(DFUState::DfuDnloadSync)
+            }
+            Command::WriteMemory { block_num: _, len } if self.dry_run => {
+                self.status.download_bytes = self.status.download_bytes.saturating_add(len as u32);
+                self.new_state_ok(DFUState::DfuDnloadSync)
+            }
+            Command::EraseAll => match self.mem.erase_all_next() {
+                Err(e) => {
+                    self.erasing = None;
+                    self.operation_failed(e)
+                }
+                Ok(EraseProgress::Busy(remaining)) => {
+                    // continue on the next poll
+                    self.erasing = Some(min(remaining, MAX_POLL_TIMEOUT));
+                    return;
+                }
+                Ok(EraseProgress::Done) => {
+                    self.erasing = None;
+                    self.progress(DfuPhase::Erase, 0, self.status.address_pointer);
+                    self.operation_started(Command::EraseAll)
+                }
             },
-            Command::Erase(b) => match self.mem.erase(b) {
-                Err(e) => self.status.new_state_status(DFUState::DfuError, e.into()),
-                Ok(_) => self.status.new_state_ok(DFUState::DfuDnloadSync),
+            Command::Erase(b) => match self.check_page(b).and_then(|_| self.mem.erase(b)) {
+                Err(e) => self.operation_failed(e),
+                Ok(_) => {
+                    self.progress(DfuPhase::Erase, 0, b);
+                    self.operation_started(Command::Erase(b))
+                }
             },
             Command::LeaveDFU => {
+                let since = if M::MANIFESTATION_CONSTANT_TIME {
+                    self.now_ms()
+                } else {
+                    0
+                };
+
                 // may not return
-                let mr = self.mem.manifestation();
+                let mr = if self.dry_run {
+                    Ok(())
+                } else {
+                    self.mem.manifestation_start()
+                };
 
-                match mr {
-                    Err(e) => self.status.new_state_status(DFUState::DfuError, e.into()),
-                    Ok(_) => {
-                        if M::MANIFESTATION_TOLERANT {
-                            self.status.new_state_ok(DFUState::DfuManifestSync)
-                        } else {
-                            self.status.new_state_ok(DFUState::DfuManifestWaitReset)
-                        }
-                    }
+                if mr.is_ok() && !self.dry_run {
+                    self.manifesting = Some((0, since));
+                    self.check_manifestation_poll();
+                } else {
+                    self.manifestation_finished(mr, since);
                 }
             }
-            Command::ReadUnprotect => {
-                // XXX not implemented
-                // self.status.state = DFUState::DfuDnloadSync;
-                self.status
-                    .new_state_status(DFUState::DfuError, DFUStatusCode::ErrStalledPkt)
-            }
+            // may not return
+            Command::ReadUnprotect => match self.mem.read_unprotect() {
+                Err(e) => self.operation_failed(e),
+                Ok(_) => self.operation_started(Command::ReadUnprotect),
+            },
+            Command::Vendor { cmd, time_ms: _ } => match self.mem.vendor_command_execute(cmd) {
+                Err(e) => self.operation_failed(e),
+                Ok(_) => self.operation_started(self.status.pending),
+            },
+            Command::Crc { address, length } => match self.crc_next(address, length) {
+                Err(e) => {
+                    self.crc_progress = None;
+                    self.operation_failed(e)
+                }
+                Ok(false) => {
+                    // continue on the next poll
+                    return;
+                }
+                Ok(true) => self.new_state_ok(DFUState::DfuDnloadSync),
+            },
             Command::WriteMemory { block_num, len } => {
-                if let Some(pointer) = self
-                    .status
-                    .address_pointer
-                    .checked_add((block_num as u32) * (M::TRANSFER_SIZE as u32))
-                {
-                    match self.mem.program(pointer, len as usize) {
-                        Err(e) => self.status.new_state_status(DFUState::DfuError, e.into()),
-                        Ok(_) => self.status.new_state_ok(DFUState::DfuDnloadSync),
+                // the end of the block must fit too
+                let pointer = self
+                    .block_address(block_num)
+                    .filter(|p| p.checked_add(len as u32).is_some());
+                if let Some(pointer) = pointer {
+                    let perms =
+                        self.check_perms(pointer, len as u32, Perms::writable, DFUMemError::Write);
+                    let r = if let Err((_, e)) = perms {
+                        Err(e)
+                    } else if self.mem.is_write_protected(pointer, len as usize) {
+                        Err(DFUMemError::Write)
+                    } else {
+                        match self.mem.is_blank(pointer, len as usize) {
+                            Ok(true) => self.mem.program(pointer, len as usize),
+                            Ok(false) => Err(DFUMemError::CheckErased),
+                            Err(e) => Err(e),
+                        }
+                    };
+                    match r {
+                        Err(e) => self.operation_failed(e),
+                        Ok(_) => {
+                            self.status.download_bytes =
+                                self.status.download_bytes.saturating_add(len as u32);
+                            self.progress(DfuPhase::Program, block_num, pointer);
+                            self.operation_started(self.status.pending)
+                        }
                     }
                 } else {
                     // overflow
-                    self.status
-                        .new_state_status(DFUState::DfuError, DFUStatusCode::ErrAddress);
+                    self.new_state_status(DFUState::DfuError, DFUStatusCode::ErrAddress);
                 }
             }
             Command::SetAddressPointer(p) => {
                 self.status.address_pointer = p;
-                self.status.new_state_ok(DFUState::DfuDnloadSync)
+                self.status.last_block = None;
+                self.new_state_ok(DFUState::DfuDnloadSync)
             }
             Command::None => {}
         }
         self.status.pending = Command::None;
     }
 
+    /// Reads the next part of CRC command range, returns `true` when the digest is ready
+    fn crc_next(&mut self, address: u32, length: u32) -> Result<bool, DFUMemError> {
+        let (mut pos, mut crc) = match self.crc_progress {
+            Some(progress) => progress,
+            None => {
+                if address.checked_add(length).is_none() {
+                    return Err(DFUMemError::Address);
+                }
+                self.check_perms(address, length, Perms::readable, DFUMemError::Address)
+                    .map_err(|(_, e)| e)?;
+                (0, 0xffff_ffff)
+            }
+        };
+
+        let mut buf = [0; CRC_CHUNK_SIZE];
+        let end = min(length, pos.saturating_add(CRC_BYTES_PER_POLL));
+        while pos < end {
+            let addr = address + pos;
+            let left = min((end - pos) as usize, CRC_CHUNK_SIZE);
+
+            let n = if let Some(r) = M::REDACTED_RANGES.iter().find(|r| r.contains(&addr)) {
+                let n = min(left, (r.end - addr) as usize);
+                buf[..n].fill(M::REDACTED_FILL);
+                n
+            } else {
+                let n = M::REDACTED_RANGES
+                    .iter()
+                    .filter(|r| r.start > addr)
+                    .map(|r| (r.start - addr) as usize)
+                    .fold(left, min);
+                let read = if M::READ_CHUNK_SIZE > 0 {
+                    let n = min(n, M::READ_CHUNK_SIZE);
+                    self.mem.read_chunk(address, pos as usize, &mut buf[..n])?
+                } else {
+                    self.mem.read_block(addr, &mut buf[..n])?
+                };
+                min(read, n)
+            };
+
+            if n == 0 {
+                // end of memory
+                return Err(DFUMemError::Address);
+            }
+            crc = crc32_update(crc, &buf[..n]);
+            pos += n as u32;
+        }
+
+        if pos < length {
+            self.crc_progress = Some((pos, crc));
+            Ok(false)
+        } else {
+            self.crc_progress = None;
+            self.crc_result = Some(crc ^ 0xffff_ffff);
+            Ok(true)
+        }
+    }
+
+    fn progress(&mut self, phase: DfuPhase, block_num: u32, address: u32) {
+        self.mem.on_progress(DfuProgress {
+            phase,
+            block_num,
+            address,
+            bytes_written: self.status.download_bytes,
+        });
+    }
+
     fn process(&mut self) -> bool {
         let initial_state = self.status.state();
         if initial_state == DFUState::DfuDnloadSync {
@@ -975,35 +4245,41 @@ impl<B: UsbBus, M: DFUMemIO> DFUClass<B, M> {
                 }
                 | Command::SetAddressPointer(_)
                 | Command::ReadUnprotect
+                | Command::Vendor { cmd: _, time_ms: _ }
+                | Command::Crc {
+                    address: _,
+                    length: _,
+                }
                 | Command::EraseAll
                 | Command::Erase(_) => {
                     self.status.pending = self.status.command;
                     self.status.command = Command::None;
-                    self.status.new_state_ok(DFUState::DfuDnBusy);
+                    self.new_state_ok(DFUState::DfuDnBusy);
                 }
                 //Command::None => {}
                 _ => {
-                    self.status.new_state_ok(DFUState::DfuDnloadIdle);
+                    self.new_state_ok(DFUState::DfuDnloadIdle);
                 }
             }
         } else if initial_state == DFUState::DfuManifestSync {
             match self.status.command {
                 Command::None => {
-                    if M::MANIFESTATION_TOLERANT {
+                    if self.options.manifestation_tolerant {
                         // Leave manifestation, back to Idle
                         self.status.command = Command::None;
-                        self.status.new_state_ok(DFUState::DfuIdle);
+                        self.new_state_ok(DFUState::DfuIdle);
                     }
                 }
                 _ => {
                     // Start manifestation
                     self.status.pending = self.status.command;
                     self.status.command = Command::None;
-                    self.status.new_state_ok(DFUState::DfuManifest);
+                    self.new_state_ok(DFUState::DfuManifest);
                 }
             }
         } else if initial_state == DFUState::DfuDnBusy {
-            return false;
+            // report the operation that is still running, or is waiting for update()
+            return self.in_progress.is_some() || self.status.pending != Command::None;
         }
 
         true
@@ -0,0 +1,24 @@
+/// Calculates CRC-32 (IEEE 802.3, the one used by zlib and DFU file suffix)
+/// of `data`.
+///
+/// This is a small bitwise implementation without a lookup table.
+///
+/// When [`BLOCK_CRC`](crate::DFUMemIO::BLOCK_CRC) is enabled, a host
+/// appends `crc32(payload).to_le_bytes()` to every download block.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0xffff_ffff, data) ^ 0xffff_ffff
+}
+
+/// Updates CRC-32 `crc` value with `data` without the final inversion.
+///
+/// Start with `0xffff_ffff`, and invert the final value, this allows
+/// to calculate CRC-32 of data that comes in chunks.
+pub fn crc32_update(mut crc: u32, data: &[u8]) -> u32 {
+    for b in data {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+    crc
+}
@@ -0,0 +1,163 @@
+/// DfuSe command codes, the first byte of a command block
+#[repr(u8)]
+pub(crate) enum DnloadCommand {
+    GetCommands = 0x00,
+    SetAddressPointer = 0x21,
+    Erase = 0x41,
+    ReadUnprotect = 0x92,
+    Echo = 0xe0,
+    Crc = 0xe4,
+}
+
+/// DfuSe command sent by a host in a download block `0`.
+///
+/// Command blocks consist of a command code and optional
+/// little-endian arguments:
+///
+/// | Command                                        | Block                     |
+/// |------------------------------------------------|---------------------------|
+/// | [`SetAddressPointer`](Self::SetAddressPointer) | `0x21`, address (4 bytes) |
+/// | [`ErasePage`](Self::ErasePage)                 | `0x41`, address (4 bytes) |
+/// | [`MassErase`](Self::MassErase)                 | `0x41`                    |
+/// | [`ReadUnprotect`](Self::ReadUnprotect)         | `0x92`                    |
+/// | [`Echo`](Self::Echo)                           | `0xe0`, `0` or `1`        |
+/// | [`Crc`](Self::Crc)                             | `0xe4`, address, length   |
+///
+/// The same type can be used by a host to build command blocks:
+///
+/// ```
+/// use usbd_dfu::DfuseCommand;
+///
+/// let mut buf = [0u8; DfuseCommand::MAX_LEN];
+/// let len = DfuseCommand::SetAddressPointer(0x0800_4000).encode(&mut buf);
+/// assert_eq!(buf[..len], [0x21, 0x00, 0x40, 0x00, 0x08]);
+///
+/// let cmd = DfuseCommand::decode(&buf[..len]);
+/// assert_eq!(cmd, Ok(DfuseCommand::SetAddressPointer(0x0800_4000)));
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DfuseCommand {
+    /// Set Address Pointer for the following upload and download blocks
+    SetAddressPointer(u32),
+    /// Erase a page (block) of memory that contains the address
+    ErasePage(u32),
+    /// Erase the whole memory
+    MassErase,
+    /// Disable read protection
+    ReadUnprotect,
+    /// Enter (`true`) or leave (`false`) echo self-test mode, vendor-specific.
+    ///
+    /// In echo mode a download block is stored in a buffer instead of memory, and
+    /// upload blocks return the last downloaded block. [`DFUMemIO`](crate::DFUMemIO) is
+    /// not used. USB reset leaves echo mode. Requires `echo-test` feature on the device.
+    Echo(bool),
+    /// Calculate CRC-32 of `length` bytes of memory at `address`, vendor-specific.
+    ///
+    /// The 4-byte little-endian digest is returned by the next upload block.
+    /// Requires [`HAS_CRC_COMMAND`](crate::DFUMemIO::HAS_CRC_COMMAND) on the device.
+    Crc {
+        /// Start address of the range
+        address: u32,
+        /// Length of the range in bytes
+        length: u32,
+    },
+}
+
+/// Errors that may happen when decoding a [`DfuseCommand`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DfuseCommandError {
+    /// Command block is empty
+    Empty,
+    /// Unknown command code
+    UnknownCommand(u8),
+    /// Command block length is invalid for the command
+    Length,
+    /// Command argument is invalid
+    Argument,
+}
+
+impl DfuseCommand {
+    /// Maximum length of an encoded command block
+    pub const MAX_LEN: usize = 9;
+
+    /// Writes the command block to `buf`, returns its length.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buf` is too short for the command,
+    /// [`MAX_LEN`](Self::MAX_LEN) bytes are always enough.
+    pub fn encode(&self, buf: &mut [u8]) -> usize {
+        let (code, address) = match *self {
+            DfuseCommand::SetAddressPointer(a) => (DnloadCommand::SetAddressPointer, Some(a)),
+            DfuseCommand::ErasePage(a) => (DnloadCommand::Erase, Some(a)),
+            DfuseCommand::MassErase => (DnloadCommand::Erase, None),
+            DfuseCommand::ReadUnprotect => (DnloadCommand::ReadUnprotect, None),
+            DfuseCommand::Echo(enable) => {
+                buf[0] = DnloadCommand::Echo as u8;
+                buf[1] = enable as u8;
+                return 2;
+            }
+            DfuseCommand::Crc { address, length } => {
+                buf[0] = DnloadCommand::Crc as u8;
+                buf[1..5].copy_from_slice(&address.to_le_bytes());
+                buf[5..9].copy_from_slice(&length.to_le_bytes());
+                return 9;
+            }
+        };
+
+        buf[0] = code as u8;
+        match address {
+            Some(a) => {
+                buf[1..5].copy_from_slice(&a.to_le_bytes());
+                5
+            }
+            None => 1,
+        }
+    }
+
+    /// Decodes a command block.
+    pub fn decode(data: &[u8]) -> Result<Self, DfuseCommandError> {
+        let (&code, args) = data.split_first().ok_or(DfuseCommandError::Empty)?;
+
+        let address = || -> Result<u32, DfuseCommandError> {
+            match args {
+                [a0, a1, a2, a3] => Ok(u32::from_le_bytes([*a0, *a1, *a2, *a3])),
+                _ => Err(DfuseCommandError::Length),
+            }
+        };
+
+        match code {
+            c if c == DnloadCommand::SetAddressPointer as u8 => {
+                Ok(DfuseCommand::SetAddressPointer(address()?))
+            }
+            c if c == DnloadCommand::Erase as u8 => {
+                if args.is_empty() {
+                    Ok(DfuseCommand::MassErase)
+                } else {
+                    Ok(DfuseCommand::ErasePage(address()?))
+                }
+            }
+            c if c == DnloadCommand::ReadUnprotect as u8 => {
+                if args.is_empty() {
+                    Ok(DfuseCommand::ReadUnprotect)
+                } else {
+                    Err(DfuseCommandError::Length)
+                }
+            }
+            c if c == DnloadCommand::Echo as u8 => match args {
+                [0] => Ok(DfuseCommand::Echo(false)),
+                [1] => Ok(DfuseCommand::Echo(true)),
+                [_] => Err(DfuseCommandError::Argument),
+                _ => Err(DfuseCommandError::Length),
+            },
+            c if c == DnloadCommand::Crc as u8 => match args {
+                [a0, a1, a2, a3, l0, l1, l2, l3] => Ok(DfuseCommand::Crc {
+                    address: u32::from_le_bytes([*a0, *a1, *a2, *a3]),
+                    length: u32::from_le_bytes([*l0, *l1, *l2, *l3]),
+                }),
+                _ => Err(DfuseCommandError::Length),
+            },
+            c => Err(DfuseCommandError::UnknownCommand(c)),
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use crate::class::{
+    DFUManifestationError, DFUMemError, DFUMemIO, DFUState, DFUStatusCode, DfuProgress,
+    EraseProgress, ResetContext, SegmentLimits, UsbResetPolicy, VendorCommandOutcome,
+};
+use crate::suffix::DfuSuffix;
+use core::ops::Range;
+
+/// Firmware slot of a [`DualSlot`] memory
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slot {
+    /// Slot at `slot_a` address
+    A,
+    /// Slot at `slot_b` address
+    B,
+}
+
+impl Slot {
+    /// Returns the other slot.
+    pub fn other(self) -> Slot {
+        match self {
+            Slot::A => Slot::B,
+            Slot::B => Slot::A,
+        }
+    }
+}
+
+/// [`DFUMemIO`] wrapper for a memory with two firmware slots, a new firmware is
+/// downloaded to the inactive slot and slots are swapped on manifestation.
+///
+/// A host sees one slot of `slot_size` bytes at the `slot_a` address.
+/// [`program()`](DFUMemIO::program), [`program_block()`](DFUMemIO::program_block),
+/// and [`erase()`](DFUMemIO::erase) addresses are translated to the inactive slot,
+/// reads are served from the active slot. The active slot is returned by
+/// the `active_slot` function, it is called for every operation.
+/// An operation that is outside of the slot, or crosses the slot end, fails
+/// with [`DFUMemError::Address`]. An erased page must fit in the slot, see
+/// [`page_size_at()`](DFUMemIO::page_size_at).
+///
+/// [`erase_all()`](DFUMemIO::erase_all) erases the inactive slot page by page,
+/// the active slot and write-protected pages are never erased.
+/// [`erase_all_next()`](DFUMemIO::erase_all_next) erases one page per call,
+/// a mass erase interrupted by USB reset starts over.
+///
+/// [`manifestation()`](DFUMemIO::manifestation) of the wrapped memory is called
+/// first, then `commit_swap` is called with the slot that holds the new firmware,
+/// it should make this slot active, for example, by changing option bytes or
+/// writing a boot record. `DualSlot` does not poll manifestation,
+/// [`manifestation_start()`](DFUMemIO::manifestation_start) of the wrapped memory
+/// is not used.
+///
+/// Reads serve the running image, so `DualSlot` can't read back what was just
+/// written: [`VERIFY_AFTER_PROGRAM`](DFUMemIO::VERIFY_AFTER_PROGRAM) and
+/// [`HAS_CRC_COMMAND`](DFUMemIO::HAS_CRC_COMMAND) are always `false`. To verify the
+/// inactive slot, wrap the memory in [`BufferedMem`](crate::BufferedMem) inside
+/// `DualSlot`, as `DualSlot<BufferedMem<M, N>>`, it reads back translated addresses.
+/// A wrapped memory that sets `VERIFY_AFTER_PROGRAM` fails to compile.
+///
+/// Other constants are forwarded from the wrapped memory, its layout string and
+/// address ranges should describe the slot as the host sees it, at `slot_a`.
+pub struct DualSlot<M: DFUMemIO> {
+    mem: M,
+    slot_a: u32,
+    slot_b: u32,
+    slot_size: u32,
+    active_slot: fn(&M) -> Slot,
+    commit_swap: fn(&mut M, Slot) -> Result<(), DFUManifestationError>,
+    /// Slot offset of the next page of a mass erase, see `erase_all_next()`
+    erase_offset: u32,
+}
+
+impl<M: DFUMemIO> DualSlot<M> {
+    const CONFIG_OK: () = assert!(
+        !M::VERIFY_AFTER_PROGRAM,
+        "DualSlot can't verify the inactive slot, use DualSlot<BufferedMem<M, N>>"
+    );
+
+    /// Creates a new `DualSlot` wrapping `mem`, slots of `slot_size` bytes
+    /// start at `slot_a` and `slot_b` addresses.
+    ///
+    /// `active_slot` returns the slot the device runs from, `commit_swap` is called
+    /// from [`manifestation()`](DFUMemIO::manifestation) with the slot to activate.
+    pub fn new(
+        mem: M,
+        slot_a: u32,
+        slot_b: u32,
+        slot_size: u32,
+        active_slot: fn(&M) -> Slot,
+        commit_swap: fn(&mut M, Slot) -> Result<(), DFUManifestationError>,
+    ) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::CONFIG_OK;
+
+        Self {
+            mem,
+            slot_a,
+            slot_b,
+            slot_size,
+            active_slot,
+            commit_swap,
+            erase_offset: 0,
+        }
+    }
+
+    /// Returns the slot the device runs from.
+    pub fn active_slot(&self) -> Slot {
+        (self.active_slot)(&self.mem)
+    }
+
+    /// Returns the slot a new firmware is written to.
+    pub fn inactive_slot(&self) -> Slot {
+        self.active_slot().other()
+    }
+
+    /// Returns start address of `slot`.
+    pub fn slot_base(&self, slot: Slot) -> u32 {
+        match slot {
+            Slot::A => self.slot_a,
+            Slot::B => self.slot_b,
+        }
+    }
+
+    /// Returns a reference to the wrapped memory.
+    pub fn inner(&self) -> &M {
+        &self.mem
+    }
+
+    /// Returns a mutable reference to the wrapped memory.
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.mem
+    }
+
+    /// Consumes `DualSlot` and returns the wrapped memory.
+    pub fn into_inner(self) -> M {
+        self.mem
+    }
+
+    /// Translate a host `address` of `length` bytes block to `slot`
+    fn translate(&self, address: u32, length: usize, slot: Slot) -> Result<u32, DFUMemError> {
+        let offset = address
+            .checked_sub(self.slot_a)
+            .ok_or(DFUMemError::Address)?;
+        let end = (offset as u64) + (length as u64);
+        if offset >= self.slot_size || end > self.slot_size as u64 {
+            return Err(DFUMemError::Address);
+        }
+        Ok(self.slot_base(slot).wrapping_add(offset))
+    }
+
+    fn read_address(&self, address: u32, length: usize) -> Result<u32, DFUMemError> {
+        self.translate(address, length, self.active_slot())
+    }
+
+    fn write_address(&self, address: u32, length: usize) -> Result<u32, DFUMemError> {
+        self.translate(address, length, self.inactive_slot())
+    }
+
+    /// Erases the page at `offset` of the inactive slot unless it's write-protected,
+    /// returns the page size
+    fn erase_slot_page(&mut self, offset: u32) -> Result<u32, DFUMemError> {
+        let slot = self.inactive_slot();
+        let address = self.slot_base(slot).wrapping_add(offset);
+        let page = self.slot_page_size(address, slot)?;
+        if !self.mem.is_write_protected(address, page as usize) {
+            self.mem.erase(address)?;
+        }
+        Ok(page)
+    }
+
+    /// Page size at a translated `address`, the page must end within the slot
+    fn slot_page_size(&self, address: u32, slot: Slot) -> Result<u32, DFUMemError> {
+        let page = self
+            .mem
+            .page_size_at(address)
+            .filter(|page| *page > 0)
+            .ok_or(DFUMemError::Address)?;
+        let offset = address.wrapping_sub(self.slot_base(slot));
+        if (offset as u64) + (page as u64) > self.slot_size as u64 {
+            return Err(DFUMemError::Address);
+        }
+        Ok(page)
+    }
+}
+
+impl<M: DFUMemIO> DFUMemIO for DualSlot<M> {
+    const INITIAL_ADDRESS_POINTER: u32 = M::INITIAL_ADDRESS_POINTER;
+    const MEM_INFO_STRING: &'static str = M::MEM_INFO_STRING;
+    const ALT_COUNT: u8 = M::ALT_COUNT;
+    const HAS_DOWNLOAD: bool = M::HAS_DOWNLOAD;
+    const HAS_UPLOAD: bool = M::HAS_UPLOAD;
+    const MANIFESTATION_TOLERANT: bool = M::MANIFESTATION_TOLERANT;
+    const WILL_DETACH: bool = M::WILL_DETACH;
+    const HAS_READ_UNPROTECT: bool = M::HAS_READ_UNPROTECT;
+    const HAS_CRC_COMMAND: bool = false;
+    const HAS_DEVICE_INFO: bool = M::HAS_DEVICE_INFO;
+    const PROGRAM_TIME_MS: u32 = M::PROGRAM_TIME_MS;
+    const ERASE_TIME_MS: u32 = M::ERASE_TIME_MS;
+    const FULL_ERASE_TIME_MS: u32 = M::FULL_ERASE_TIME_MS;
+    const READ_UNPROTECT_TIME_MS: u32 = M::READ_UNPROTECT_TIME_MS;
+    const MANIFESTATION_TIME_MS: u32 = M::MANIFESTATION_TIME_MS;
+    const DETACH_TIMEOUT: u16 = M::DETACH_TIMEOUT;
+    const TRANSFER_SIZE: u16 = M::TRANSFER_SIZE;
+    const DFU_VERSION: u16 = M::DFU_VERSION;
+    const FIRST_DATA_BLOCK: u16 = M::FIRST_DATA_BLOCK;
+    const REDACTED_RANGES: &'static [Range<u32>] = M::REDACTED_RANGES;
+    const REDACTED_FILL: u8 = M::REDACTED_FILL;
+    const BLOCK_CRC: bool = M::BLOCK_CRC;
+    const VERIFY_AFTER_PROGRAM: bool = false;
+    const IMAGE_HEADER_SIZE: usize = M::IMAGE_HEADER_SIZE;
+    const PROGRAM_IMAGE_HEADER: bool = M::PROGRAM_IMAGE_HEADER;
+    const ERROR_AUTOCLEAR_MS: u32 = M::ERROR_AUTOCLEAR_MS;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = M::LAYOUT_SEGMENTS;
+    const SEGMENT_LIMITS: &'static [SegmentLimits] = M::SEGMENT_LIMITS;
+    const OPERATION_BUDGET_FACTOR: u32 = M::OPERATION_BUDGET_FACTOR;
+    const MANIFESTATION_CONSTANT_TIME: bool = M::MANIFESTATION_CONSTANT_TIME;
+    const MAX_FAILED_MANIFESTATIONS: u8 = M::MAX_FAILED_MANIFESTATIONS;
+    const IMAGE_MAGIC: Option<(usize, &'static [u8])> = M::IMAGE_MAGIC;
+    const CHECK_SUFFIX: bool = M::CHECK_SUFFIX;
+    const ALLOW_UPLOAD_DURING_DNLOAD_IDLE: bool = M::ALLOW_UPLOAD_DURING_DNLOAD_IDLE;
+    const ADVANCE_ON_GETSTATE: bool = M::ADVANCE_ON_GETSTATE;
+    const ON_USB_RESET: UsbResetPolicy = M::ON_USB_RESET;
+    const HAS_VENDOR_ERROR_STRING: bool = M::HAS_VENDOR_ERROR_STRING;
+    const MEMIO_IN_USB_INTERRUPT: bool = M::MEMIO_IN_USB_INTERRUPT;
+    const READ_CHUNK_SIZE: usize = M::READ_CHUNK_SIZE;
+    const CHECK_PERMISSIONS: bool = M::CHECK_PERMISSIONS;
+    const WINUSB_VENDOR_CODE: u8 = M::WINUSB_VENDOR_CODE;
+    const WINUSB_GUID: &'static str = M::WINUSB_GUID;
+    const WINUSB_COMPOSITE: bool = M::WINUSB_COMPOSITE;
+    const WEBUSB_VENDOR_CODE: u8 = M::WEBUSB_VENDOR_CODE;
+    const WEBUSB_LANDING_PAGE: Option<&'static str> = M::WEBUSB_LANDING_PAGE;
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        let address = self.read_address(address, length)?;
+        self.mem.read(address, length)
+    }
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        let address = self.read_address(address, dest.len())?;
+        self.mem.read_block(address, dest)
+    }
+
+    fn read_chunk(
+        &mut self,
+        address: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DFUMemError> {
+        let address = self.read_address(address, offset + buf.len())?;
+        self.mem.read_chunk(address, offset, buf)
+    }
+
+    fn is_end_of_region(&self, address: u32) -> bool {
+        match self.read_address(address, 1) {
+            Ok(address) => self.mem.is_end_of_region(address),
+            Err(_) => true,
+        }
+    }
+
+    fn upload_end_address(&self) -> Option<u32> {
+        // the end in the active slot, as the host sees it
+        let base = self.slot_base(self.active_slot());
+        let end = self.mem.upload_end_address()?.saturating_sub(base);
+        Some(self.slot_a.saturating_add(end.min(self.slot_size)))
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        let address = self.write_address(address, length)?;
+        self.mem.program(address, length)
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        let address = self.write_address(address, data.len())?;
+        self.mem.program_block(address, data)
+    }
+
+    fn program_time_ms(&self, length: usize) -> u32 {
+        self.mem.program_time_ms(length)
+    }
+
+    fn erase_time_ms(&self, address: u32) -> u32 {
+        match self.write_address(address, 1) {
+            Ok(address) => self.mem.erase_time_ms(address),
+            Err(_) => M::ERASE_TIME_MS,
+        }
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        let address = self.write_address(address, 1).ok()?;
+        self.mem.page_size_at(address)
+    }
+
+    fn is_write_protected(&self, address: u32, length: usize) -> bool {
+        match self.write_address(address, length) {
+            Ok(address) => self.mem.is_write_protected(address, length),
+            Err(_) => false,
+        }
+    }
+
+    fn is_blank(&mut self, address: u32, length: usize) -> Result<bool, DFUMemError> {
+        match self.write_address(address, length) {
+            Ok(address) => self.mem.is_blank(address, length),
+            Err(_) => Ok(true),
+        }
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        let slot = self.inactive_slot();
+        let address = self.translate(address, 1, slot)?;
+        self.slot_page_size(address, slot)?;
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.erase_offset = 0;
+        let mut offset = 0;
+        while offset < self.slot_size {
+            offset += self.erase_slot_page(offset)?;
+        }
+        Ok(())
+    }
+
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        let page = match self.erase_slot_page(self.erase_offset) {
+            Ok(page) => page,
+            Err(e) => {
+                self.erase_offset = 0;
+                return Err(e);
+            }
+        };
+        self.erase_offset += page;
+        if self.erase_offset >= self.slot_size {
+            self.erase_offset = 0;
+            return Ok(EraseProgress::Done);
+        }
+        let pages = (self.slot_size - self.erase_offset).div_ceil(page);
+        Ok(EraseProgress::Busy(pages.saturating_mul(M::ERASE_TIME_MS)))
+    }
+
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        self.mem.read_unprotect()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        let slot = self.inactive_slot();
+        self.mem.manifestation()?;
+        (self.commit_swap)(&mut self.mem, slot)
+    }
+
+    fn manifestation_allowed(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation_allowed()
+    }
+
+    fn validate_suffix(&mut self, suffix: Option<DfuSuffix>) -> Result<(), DFUManifestationError> {
+        self.mem.validate_suffix(suffix)
+    }
+
+    fn usb_reset(&mut self) {
+        self.mem.usb_reset()
+    }
+
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        self.erase_offset = 0;
+        self.mem.on_usb_reset(ctx)
+    }
+
+    fn on_manifest_reset(&mut self) {
+        self.mem.on_manifest_reset()
+    }
+
+    fn locate_image(&mut self, header: &[u8]) -> Result<u32, DFUMemError> {
+        self.mem.locate_image(header)
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        self.mem.now_ms()
+    }
+
+    fn profile_ticks(&mut self) -> u32 {
+        self.mem.profile_ticks()
+    }
+
+    fn on_abort(&mut self) {
+        self.mem.on_abort()
+    }
+
+    fn on_configured(&mut self) {
+        self.mem.on_configured()
+    }
+
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        self.mem.on_detach_request(timeout_ms)
+    }
+
+    fn on_detach_expired(&mut self) {
+        self.mem.on_detach_expired()
+    }
+
+    fn on_progress(&mut self, progress: DfuProgress) {
+        self.mem.on_progress(progress)
+    }
+
+    fn on_state_change(&mut self, old: DFUState, new: DFUState, status: DFUStatusCode) {
+        self.mem.on_state_change(old, new, status)
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        self.mem.operation_busy()
+    }
+
+    fn operation_result(&mut self) -> Result<(), DFUMemError> {
+        self.mem.operation_result()
+    }
+
+    fn on_error(&mut self) {
+        self.mem.on_error()
+    }
+
+    fn persist_lockout(&mut self, failures: Option<u8>) -> u8 {
+        self.mem.persist_lockout(failures)
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.mem.mem_info_string()
+    }
+
+    fn initial_address_pointer(&self) -> u32 {
+        self.mem.initial_address_pointer()
+    }
+
+    fn mem_info_string_for(&self, alt: u8) -> &str {
+        self.mem.mem_info_string_for(alt)
+    }
+
+    fn select_alt(&mut self, alt: u8) {
+        self.mem.select_alt(alt)
+    }
+
+    fn vendor_error_string(&self) -> Option<&str> {
+        self.mem.vendor_error_string()
+    }
+
+    fn vendor_commands(&self) -> &[u8] {
+        self.mem.vendor_commands()
+    }
+
+    fn vendor_dnload_command(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+    ) -> Result<VendorCommandOutcome, DFUMemError> {
+        self.mem.vendor_dnload_command(cmd, payload)
+    }
+
+    fn vendor_command_execute(&mut self, cmd: u8) -> Result<(), DFUMemError> {
+        self.mem.vendor_command_execute(cmd)
+    }
+
+    fn device_info(&mut self, buf: &mut [u8]) -> usize {
+        self.mem.device_info(buf)
+    }
+}
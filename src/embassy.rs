@@ -0,0 +1,372 @@
+use crate::class::{
+    functional_descriptor, DFUMemIO, DfuCore, DfuOptions, InXfer, InitialState, OutXfer,
+    PollActivity, DESC_DESCTYPE_DFU, MAX_ALT_COUNT, USB_CLASS_APPLICATION_SPECIFIC,
+    USB_PROTOCOL_DFU_MODE, USB_SUBCLASS_DFU,
+};
+use core::cell::{Cell, RefCell};
+use core::cmp::min;
+use core::future::poll_fn;
+use core::task::{Poll, Waker};
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Direction, Driver};
+use embassy_usb::types::{InterfaceNumber, StringIndex};
+use embassy_usb::{Builder, Handler};
+use usb_device::{control, UsbDirection};
+
+/// Longest string descriptor, in characters
+const STRING_LEN: usize = 126;
+
+/// DFU state shared by [`DfuHandler`] and a task that runs [`run()`](DfuState::run).
+///
+/// This is the same state machine as in [`DFUClass`](crate::DFUClass), requests,
+/// states, and [`DFUMemIO`] calls are the same. Erase, program, and manifestation
+/// are always executed by [`run()`](DfuState::run), regardless of
+/// [`MEMIO_IN_USB_INTERRUPT`](DFUMemIO::MEMIO_IN_USB_INTERRUPT), a host sees
+/// `dfuDNBUSY` or `dfuMANIFEST` state meanwhile.
+///
+/// The state is not `Sync`, USB device task and the task that runs
+/// [`run()`](DfuState::run) must use the same executor.
+pub struct DfuState<M: DFUMemIO> {
+    core: RefCell<DfuCore<M>>,
+    waker: Cell<Option<Waker>>,
+}
+
+impl<M: DFUMemIO> DfuState<M> {
+    /// Creates a new `DfuState` with the provided `DFUMemIO`.
+    ///
+    /// `DFUMemIO` constants are checked at compile time,
+    /// see [`dfu_assert_config!`](crate::dfu_assert_config).
+    pub fn new(mem: M) -> Self {
+        Self {
+            core: RefCell::new(DfuCore::new(mem, None, None, DfuOptions::new::<M>())),
+            waker: Cell::new(None),
+        }
+    }
+
+    /// Executes pending erase, program, and manifestation operations, never completes.
+    ///
+    /// Manifestation may not return.
+    pub async fn run(&self) -> ! {
+        loop {
+            poll_fn(|cx| {
+                if self.core.borrow().command_pending() {
+                    return Poll::Ready(());
+                }
+                self.waker.set(Some(cx.waker().clone()));
+                Poll::Pending
+            })
+            .await;
+
+            self.core.borrow_mut().poll(true);
+
+            if self.core.borrow().command_pending() {
+                // incremental mass erase continues, let USB task run meanwhile
+                yield_now().await;
+            }
+        }
+    }
+
+    /// Checks error auto-clear and detach timeouts.
+    ///
+    /// embassy-usb has no periodic callback, this should be called periodically if
+    /// [`ERROR_AUTOCLEAR_MS`](DFUMemIO::ERROR_AUTOCLEAR_MS) is used, or a detach
+    /// timeout should expire without host requests.
+    pub fn poll(&self) {
+        self.core.borrow_mut().poll(false);
+    }
+
+    /// This function may be called just after `DfuState::new()` to
+    /// set DFU error state to "Device detected unexpected power on reset"
+    /// instead of the usual `dfuIdle`.
+    pub fn set_unexpected_reset_state(&self) {
+        self.core
+            .borrow_mut()
+            .set_initial_state(InitialState::UnexpectedReset);
+    }
+
+    /// This function may be called just after `DfuState::new()` to
+    /// set DFU error state to "Device’s firmware is corrupt. It cannot return to run-time (non-DFU) operations"
+    /// instead of the usual `dfuIdle`.
+    pub fn set_firmware_corrupted_state(&self) {
+        self.core
+            .borrow_mut()
+            .set_initial_state(InitialState::FirmwareCorrupted);
+    }
+
+    /// Returns `true` if an operation is waiting for [`run()`](DfuState::run),
+    /// or is still running in background (see [`operation_busy()`](DFUMemIO::operation_busy)
+    /// and [`manifestation_poll()`](DFUMemIO::manifestation_poll)).
+    pub fn is_busy(&self) -> bool {
+        self.core.borrow().is_busy()
+    }
+
+    /// Returns memory work done since the last call, and clears it,
+    /// see [`DFUClass::take_poll_activity()`](crate::DFUClass::take_poll_activity).
+    pub fn take_poll_activity(&self) -> PollActivity {
+        self.core.borrow_mut().take_poll_activity()
+    }
+
+    /// Returns `true` if a host has sent `DFU_DETACH` request and its timeout has not
+    /// expired yet, see [`on_detach_request()`](DFUMemIO::on_detach_request).
+    pub fn detach_pending(&self) -> bool {
+        self.core.borrow().detach_pending()
+    }
+
+    /// Calls `f` with the memory.
+    pub fn with_mem<R>(&self, f: impl FnOnce(&mut M) -> R) -> R {
+        f(&mut self.core.borrow_mut().mem)
+    }
+
+    /// Consumes `DfuState` and returns the memory.
+    pub fn into_inner(self) -> M {
+        self.core.into_inner().mem
+    }
+
+    fn wake(&self) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Completes on the second poll, other tasks run meanwhile
+async fn yield_now() {
+    let mut yielded = false;
+    poll_fn(|cx| {
+        if yielded {
+            return Poll::Ready(());
+        }
+        yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    })
+    .await
+}
+
+/// DFU mode interface for `embassy-usb`.
+///
+/// Writes the same descriptors as [`DFUClass`](crate::DFUClass): an interface alternate
+/// setting with a memory layout string for each of [`ALT_COUNT`](DFUMemIO::ALT_COUNT)
+/// memories, and DFU Functional descriptor. Control requests are answered from
+/// `Handler` callbacks using [`DfuState`].
+///
+/// embassy-usb accepts `SET_INTERFACE` before the handler is notified, an alternate
+/// setting change outside of `dfuIDLE` state is ignored by the DFU state.
+pub struct DfuHandler<'d, M: DFUMemIO> {
+    state: &'d DfuState<M>,
+    if_num: u8,
+    interface_strings: [Option<StringIndex>; MAX_ALT_COUNT],
+    vendor_string: Option<StringIndex>,
+    /// String descriptor returned by `get_string()`
+    string: [u8; STRING_LEN],
+}
+
+impl<'d, M: DFUMemIO> DfuHandler<'d, M> {
+    /// Creates a new `DfuHandler` for `state`, interface number is 0 until
+    /// [`register()`](DfuHandler::register) is called.
+    pub fn new(state: &'d DfuState<M>) -> Self {
+        Self {
+            state,
+            if_num: 0,
+            interface_strings: [None; MAX_ALT_COUNT],
+            vendor_string: None,
+            string: [0; STRING_LEN],
+        }
+    }
+
+    /// Adds DFU function and its descriptors to `builder`, and registers the handler.
+    pub fn register<D: Driver<'d>>(&'d mut self, builder: &mut Builder<'d, D>) {
+        let mut function = builder.function(
+            USB_CLASS_APPLICATION_SPECIFIC,
+            USB_SUBCLASS_DFU,
+            USB_PROTOCOL_DFU_MODE,
+        );
+        let mut interface = function.interface();
+        self.if_num = interface.interface_number().0;
+        let options = self.state.core.borrow().options;
+
+        for alt in 0..M::ALT_COUNT {
+            let string = interface.string();
+            self.interface_strings[alt as usize] = Some(string);
+            let mut alt_setting = interface.alt_setting(
+                USB_CLASS_APPLICATION_SPECIFIC,
+                USB_SUBCLASS_DFU,
+                USB_PROTOCOL_DFU_MODE,
+                Some(string),
+            );
+            if alt == M::ALT_COUNT - 1 {
+                alt_setting.descriptor(
+                    DESC_DESCTYPE_DFU,
+                    &functional_descriptor(
+                        options.will_detach,
+                        options.manifestation_tolerant,
+                        options.has_upload,
+                        options.has_download,
+                        options.detach_timeout,
+                        options.transfer_size,
+                        M::DFU_VERSION,
+                    ),
+                );
+            }
+        }
+
+        if M::HAS_VENDOR_ERROR_STRING {
+            let string = interface.string();
+            self.vendor_string = Some(string);
+            self.state.core.borrow_mut().vendor_string = Some(string.0);
+        }
+
+        drop(function);
+        builder.handler(self);
+    }
+
+    /// Class request for this interface
+    fn is_dfu_request(&self, req: &Request) -> bool {
+        req.request_type == RequestType::Class
+            && req.recipient == Recipient::Interface
+            && req.index == self.if_num as u16
+    }
+}
+
+/// Convert a DFU class request
+fn dfu_request(req: &Request) -> control::Request {
+    control::Request {
+        direction: match req.direction {
+            Direction::In => UsbDirection::In,
+            Direction::Out => UsbDirection::Out,
+        },
+        request_type: control::RequestType::Class,
+        recipient: control::Recipient::Interface,
+        request: req.request,
+        value: req.value,
+        index: req.index,
+        length: req.length,
+    }
+}
+
+struct InResponder<'a, 'r> {
+    buf: &'a mut [u8],
+    response: &'r mut Option<InResponse<'a>>,
+}
+
+impl InXfer for InResponder<'_, '_> {
+    fn accept_with(self, data: &[u8]) {
+        if data.len() > self.buf.len() {
+            *self.response = Some(InResponse::Rejected);
+            return;
+        }
+        self.buf[..data.len()].copy_from_slice(data);
+        *self.response = Some(InResponse::Accepted(&self.buf[..data.len()]));
+    }
+
+    fn accept(self, f: impl FnOnce(&mut [u8]) -> Option<usize>) {
+        *self.response = Some(match f(self.buf) {
+            Some(len) => InResponse::Accepted(&self.buf[..len]),
+            None => InResponse::Rejected,
+        });
+    }
+
+    fn reject(self) {
+        *self.response = Some(InResponse::Rejected);
+    }
+}
+
+struct OutResponder<'a, 'r> {
+    data: &'a [u8],
+    response: &'r mut Option<OutResponse>,
+}
+
+impl OutXfer for OutResponder<'_, '_> {
+    fn data(&self) -> &[u8] {
+        self.data
+    }
+
+    fn accept(self) {
+        *self.response = Some(OutResponse::Accepted);
+    }
+
+    fn reject(self) {
+        *self.response = Some(OutResponse::Rejected);
+    }
+}
+
+impl<M: DFUMemIO> Handler for DfuHandler<'_, M> {
+    fn reset(&mut self) {
+        self.state.core.borrow_mut().reset();
+        self.state.wake();
+    }
+
+    fn configured(&mut self, configured: bool) {
+        if configured {
+            self.state.core.borrow_mut().mem.on_configured();
+        }
+    }
+
+    fn set_alternate_setting(&mut self, iface: InterfaceNumber, alternate_setting: u8) {
+        if iface.0 == self.if_num {
+            self.state
+                .core
+                .borrow_mut()
+                .set_alt_setting(alternate_setting);
+        }
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if !self.is_dfu_request(&req) {
+            return None;
+        }
+
+        let mut response = None;
+        self.state.core.borrow_mut().control_out(
+            dfu_request(&req),
+            OutResponder {
+                data,
+                response: &mut response,
+            },
+        );
+        self.state.wake();
+        response
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_dfu_request(&req) {
+            return None;
+        }
+
+        let mut response = None;
+        self.state.core.borrow_mut().control_in(
+            dfu_request(&req),
+            InResponder {
+                buf,
+                response: &mut response,
+            },
+        );
+        self.state.wake();
+        response
+    }
+
+    fn get_string(&mut self, index: StringIndex, lang_id: u16) -> Option<&str> {
+        let core = self.state.core.borrow();
+        let string = if let Some(alt) = self
+            .interface_strings
+            .iter()
+            .position(|&s| s == Some(index))
+        {
+            core.get_string(alt as u8, lang_id)?
+        } else if Some(index) == self.vendor_string {
+            core.get_vendor_string(lang_id)?
+        } else {
+            return None;
+        };
+
+        // the memory can't stay borrowed after return, return a copy
+        let mut len = min(string.len(), STRING_LEN);
+        while !string.is_char_boundary(len) {
+            len -= 1;
+        }
+        self.string[..len].copy_from_slice(&string.as_bytes()[..len]);
+        drop(core);
+
+        core::str::from_utf8(&self.string[..len]).ok()
+    }
+}
@@ -0,0 +1,484 @@
+use crate::class::{
+    DFUManifestationError, DFUMemError, DFUMemIO, DFUState, DFUStatusCode, DfuProgress,
+    EraseProgress, ManifestationProgress, ResetContext, SegmentLimits, UsbResetPolicy,
+    VendorCommandOutcome,
+};
+use crate::suffix::DfuSuffix;
+use core::cmp::min;
+use core::ops::Range;
+
+/// Maximum size of a raw download block.
+const HEX_BLOCK_SIZE: usize = 256;
+
+/// Maximum size of a decoded record: byte count, address, type, data, and checksum.
+const HEX_RECORD_SIZE: usize = 1 + 2 + 1 + 255 + 1;
+
+const RECORD_DATA: u8 = 0x00;
+const RECORD_EOF: u8 = 0x01;
+const RECORD_EXT_SEGMENT_ADDRESS: u8 = 0x02;
+const RECORD_START_SEGMENT_ADDRESS: u8 = 0x03;
+const RECORD_EXT_LINEAR_ADDRESS: u8 = 0x04;
+const RECORD_START_LINEAR_ADDRESS: u8 = 0x05;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum HexState {
+    /// Waiting for a start code `:`
+    Idle,
+    /// Decoding record digits, high nibble is expected
+    High,
+    /// Decoding record digits, low nibble is expected
+    Low(u8),
+    /// EOF record was decoded
+    Eof,
+}
+
+/// [`DFUMemIO`] wrapper that decodes Intel HEX images downloaded by a host.
+///
+/// Download blocks are treated as a stream of Intel HEX text records,
+/// a record may span block boundaries. Data records are written at
+/// addresses specified by the records, together with the Extended Segment Address (`02`)
+/// and Extended Linear Address (`04`) records. Addresses that come
+/// from block numbers and the Address Pointer are ignored.
+///
+/// Each data record is passed to the wrapped memory's
+/// [`store_write_buffer()`](DFUMemIO::store_write_buffer) and [`program()`](DFUMemIO::program)
+/// in chunks up to its [`TRANSFER_SIZE`](DFUMemIO::TRANSFER_SIZE) bytes.
+///
+/// The End Of File (`01`) record marks the end of the image, manifestation
+/// fails with `errNOTDONE` if it was not received. Start address records (`03`, `05`)
+/// are ignored. Malformed records, unknown record types, and checksum errors
+/// are reported to the host as `errFILE`, and decoder state is reset.
+///
+/// Uploads, erase, and manifestation are forwarded to the wrapped memory.
+/// A single download block contains less data than a wrapped memory's block,
+/// however, it may be programmed with several [`program()`](DFUMemIO::program) calls,
+/// [`PROGRAM_TIME_MS`](DFUMemIO::PROGRAM_TIME_MS) should account for this.
+///
+/// Write protection of the wrapped memory, see [`is_write_protected()`](DFUMemIO::is_write_protected),
+/// is checked by `HexMem` for every decoded chunk and erased page, data records that
+/// write to a protected range fail with `errWRITE`. Blank check of the wrapped memory,
+/// see [`is_blank()`](DFUMemIO::is_blank), is done for every decoded chunk too, data records
+/// that write to memory that is not erased fail with `errCHECK_ERASED`. Memory layout permissions,
+/// see [`CHECK_PERMISSIONS`](DFUMemIO::CHECK_PERMISSIONS), are not checked by `HexMem`.
+///
+/// Download blocks must not be larger than `256` bytes.
+pub struct HexMem<M: DFUMemIO> {
+    mem: M,
+    block: [u8; HEX_BLOCK_SIZE],
+    record: [u8; HEX_RECORD_SIZE],
+    record_len: usize,
+    state: HexState,
+    base: u32,
+}
+
+impl<M: DFUMemIO> HexMem<M> {
+    /// Creates a new `HexMem` wrapping `mem`.
+    pub fn new(mem: M) -> Self {
+        Self {
+            mem,
+            block: [0; HEX_BLOCK_SIZE],
+            record: [0; HEX_RECORD_SIZE],
+            record_len: 0,
+            state: HexState::Idle,
+            base: 0,
+        }
+    }
+
+    /// Discard a partially decoded image and start from scratch.
+    ///
+    /// Called automatically on USB reset, after manifestation, and
+    /// when a decoding error occurs.
+    pub fn reset(&mut self) {
+        self.record_len = 0;
+        self.state = HexState::Idle;
+        self.base = 0;
+    }
+
+    /// Returns `true` if End Of File record was received.
+    pub fn is_complete(&self) -> bool {
+        self.state == HexState::Eof
+    }
+
+    /// Returns a reference to the wrapped memory.
+    pub fn inner(&self) -> &M {
+        &self.mem
+    }
+
+    /// Returns a mutable reference to the wrapped memory.
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.mem
+    }
+
+    /// Consumes `HexMem` and returns the wrapped memory.
+    pub fn into_inner(self) -> M {
+        self.mem
+    }
+
+    fn decode(&mut self, length: usize) -> Result<(), DFUMemError> {
+        for i in 0..length {
+            let c = self.block[i];
+
+            match self.state {
+                HexState::Idle | HexState::Eof => match c {
+                    b':' => {
+                        if self.state == HexState::Eof {
+                            // next image
+                            self.base = 0;
+                        }
+                        self.record_len = 0;
+                        self.state = HexState::High;
+                    }
+                    b'\r' | b'\n' | b' ' | b'\t' => {}
+                    _ => return Err(DFUMemError::File),
+                },
+                HexState::High => {
+                    self.state = HexState::Low(hex_digit(c)? << 4);
+                }
+                HexState::Low(high) => {
+                    if self.record_len >= HEX_RECORD_SIZE {
+                        return Err(DFUMemError::File);
+                    }
+                    self.record[self.record_len] = high | hex_digit(c)?;
+                    self.record_len += 1;
+                    self.state = HexState::High;
+
+                    // byte count, address, type, data, checksum
+                    if self.record_len == self.record[0] as usize + 5 {
+                        self.state = self.process_record()?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn process_record(&mut self) -> Result<HexState, DFUMemError> {
+        let record = &self.record[..self.record_len];
+
+        if record.iter().fold(0u8, |a, b| a.wrapping_add(*b)) != 0 {
+            return Err(DFUMemError::File);
+        }
+
+        let count = record[0] as usize;
+        let offset = u16::from_be_bytes([record[1], record[2]]);
+        let data = 4..4 + count;
+
+        match record[3] {
+            RECORD_DATA => {
+                let address = self
+                    .base
+                    .checked_add(offset as u32)
+                    .ok_or(DFUMemError::Address)?;
+                self.program_data(address, data)?;
+            }
+            RECORD_EOF if count == 0 => return Ok(HexState::Eof),
+            RECORD_EXT_SEGMENT_ADDRESS if count == 2 => {
+                self.base = (u16::from_be_bytes([record[4], record[5]]) as u32) << 4;
+            }
+            RECORD_EXT_LINEAR_ADDRESS if count == 2 => {
+                self.base = (u16::from_be_bytes([record[4], record[5]]) as u32) << 16;
+            }
+            RECORD_START_SEGMENT_ADDRESS | RECORD_START_LINEAR_ADDRESS if count == 4 => {}
+            _ => return Err(DFUMemError::File),
+        }
+
+        Ok(HexState::Idle)
+    }
+
+    fn program_data(&mut self, address: u32, data: Range<usize>) -> Result<(), DFUMemError> {
+        let mut pos = data.start;
+        while pos < data.end {
+            let len = min(data.end - pos, M::TRANSFER_SIZE as usize);
+            let chunk_address = address
+                .checked_add((pos - data.start) as u32)
+                .ok_or(DFUMemError::Address)?;
+            if self.mem.is_write_protected(chunk_address, len) {
+                return Err(DFUMemError::Write);
+            }
+            if !self.mem.is_blank(chunk_address, len)? {
+                return Err(DFUMemError::CheckErased);
+            }
+
+            self.mem
+                .store_write_buffer(&self.record[pos..pos + len])
+                .map_err(|_| DFUMemError::Unknown)?;
+            self.mem.program(chunk_address, len)?;
+            pos += len;
+        }
+        Ok(())
+    }
+}
+
+fn hex_digit(c: u8) -> Result<u8, DFUMemError> {
+    match c {
+        b'0'..=b'9' => Ok(c - b'0'),
+        b'a'..=b'f' => Ok(c - b'a' + 10),
+        b'A'..=b'F' => Ok(c - b'A' + 10),
+        _ => Err(DFUMemError::File),
+    }
+}
+
+impl<M: DFUMemIO> DFUMemIO for HexMem<M> {
+    const INITIAL_ADDRESS_POINTER: u32 = M::INITIAL_ADDRESS_POINTER;
+    const MEM_INFO_STRING: &'static str = M::MEM_INFO_STRING;
+    const ALT_COUNT: u8 = M::ALT_COUNT;
+    const HAS_DOWNLOAD: bool = M::HAS_DOWNLOAD;
+    const HAS_UPLOAD: bool = M::HAS_UPLOAD;
+    const MANIFESTATION_TOLERANT: bool = M::MANIFESTATION_TOLERANT;
+    const WILL_DETACH: bool = M::WILL_DETACH;
+    const HAS_READ_UNPROTECT: bool = M::HAS_READ_UNPROTECT;
+    const HAS_CRC_COMMAND: bool = M::HAS_CRC_COMMAND;
+    const HAS_DEVICE_INFO: bool = M::HAS_DEVICE_INFO;
+    const PROGRAM_TIME_MS: u32 = M::PROGRAM_TIME_MS;
+    const ERASE_TIME_MS: u32 = M::ERASE_TIME_MS;
+    const FULL_ERASE_TIME_MS: u32 = M::FULL_ERASE_TIME_MS;
+    const READ_UNPROTECT_TIME_MS: u32 = M::READ_UNPROTECT_TIME_MS;
+    const MANIFESTATION_TIME_MS: u32 = M::MANIFESTATION_TIME_MS;
+    const DETACH_TIMEOUT: u16 = M::DETACH_TIMEOUT;
+    const TRANSFER_SIZE: u16 = M::TRANSFER_SIZE;
+    const DFU_VERSION: u16 = M::DFU_VERSION;
+    const FIRST_DATA_BLOCK: u16 = M::FIRST_DATA_BLOCK;
+    const REDACTED_RANGES: &'static [Range<u32>] = M::REDACTED_RANGES;
+    const REDACTED_FILL: u8 = M::REDACTED_FILL;
+    const BLOCK_CRC: bool = M::BLOCK_CRC;
+    const VERIFY_AFTER_PROGRAM: bool = M::VERIFY_AFTER_PROGRAM;
+    const IMAGE_HEADER_SIZE: usize = M::IMAGE_HEADER_SIZE;
+    const PROGRAM_IMAGE_HEADER: bool = M::PROGRAM_IMAGE_HEADER;
+    const ERROR_AUTOCLEAR_MS: u32 = M::ERROR_AUTOCLEAR_MS;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = M::LAYOUT_SEGMENTS;
+    const SEGMENT_LIMITS: &'static [SegmentLimits] = M::SEGMENT_LIMITS;
+    const OPERATION_BUDGET_FACTOR: u32 = M::OPERATION_BUDGET_FACTOR;
+    const MANIFESTATION_CONSTANT_TIME: bool = M::MANIFESTATION_CONSTANT_TIME;
+    const MAX_FAILED_MANIFESTATIONS: u8 = M::MAX_FAILED_MANIFESTATIONS;
+    const IMAGE_MAGIC: Option<(usize, &'static [u8])> = M::IMAGE_MAGIC;
+    const CHECK_SUFFIX: bool = M::CHECK_SUFFIX;
+    const ALLOW_UPLOAD_DURING_DNLOAD_IDLE: bool = M::ALLOW_UPLOAD_DURING_DNLOAD_IDLE;
+    const ADVANCE_ON_GETSTATE: bool = M::ADVANCE_ON_GETSTATE;
+    const ON_USB_RESET: UsbResetPolicy = M::ON_USB_RESET;
+    const HAS_VENDOR_ERROR_STRING: bool = M::HAS_VENDOR_ERROR_STRING;
+    const MEMIO_IN_USB_INTERRUPT: bool = M::MEMIO_IN_USB_INTERRUPT;
+    const READ_CHUNK_SIZE: usize = M::READ_CHUNK_SIZE;
+    // block addresses are not memory addresses, see the struct documentation
+    const CHECK_PERMISSIONS: bool = false;
+    const WINUSB_VENDOR_CODE: u8 = M::WINUSB_VENDOR_CODE;
+    const WINUSB_GUID: &'static str = M::WINUSB_GUID;
+    const WINUSB_COMPOSITE: bool = M::WINUSB_COMPOSITE;
+    const WEBUSB_VENDOR_CODE: u8 = M::WEBUSB_VENDOR_CODE;
+    const WEBUSB_LANDING_PAGE: Option<&'static str> = M::WEBUSB_LANDING_PAGE;
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        if src.len() > HEX_BLOCK_SIZE {
+            return Err(());
+        }
+        self.block[..src.len()].copy_from_slice(src);
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        self.mem.read_block(address, dest)
+    }
+
+    fn read_chunk(
+        &mut self,
+        address: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DFUMemError> {
+        self.mem.read_chunk(address, offset, buf)
+    }
+
+    fn is_end_of_region(&self, address: u32) -> bool {
+        self.mem.is_end_of_region(address)
+    }
+
+    fn upload_end_address(&self) -> Option<u32> {
+        self.mem.upload_end_address()
+    }
+
+    fn program(&mut self, _address: u32, length: usize) -> Result<(), DFUMemError> {
+        let r = self.decode(length);
+        if r.is_err() {
+            self.reset();
+        }
+        r
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        self.store_write_buffer(data)
+            .map_err(|_| DFUMemError::Unknown)?;
+        self.program(address, data.len())
+    }
+
+    fn program_time_ms(&self, length: usize) -> u32 {
+        self.mem.program_time_ms(length)
+    }
+
+    fn erase_time_ms(&self, address: u32) -> u32 {
+        self.mem.erase_time_ms(address)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        let page = self.mem.page_size_at(address).unwrap_or(1);
+        if self.mem.is_write_protected(address, page as usize) {
+            return Err(DFUMemError::Erase);
+        }
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        self.mem.erase_all_next()
+    }
+
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        self.mem.read_unprotect()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        if !self.is_complete() {
+            self.reset();
+            return Err(DFUManifestationError::NotDone);
+        }
+        self.reset();
+        self.mem.manifestation()
+    }
+
+    fn manifestation_start(&mut self) -> Result<(), DFUManifestationError> {
+        if !self.is_complete() {
+            self.reset();
+            return Err(DFUManifestationError::NotDone);
+        }
+        self.reset();
+        self.mem.manifestation_start()
+    }
+
+    fn manifestation_poll(&mut self) -> ManifestationProgress {
+        self.mem.manifestation_poll()
+    }
+
+    fn manifestation_allowed(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation_allowed()
+    }
+
+    fn validate_suffix(&mut self, suffix: Option<DfuSuffix>) -> Result<(), DFUManifestationError> {
+        self.mem.validate_suffix(suffix)
+    }
+
+    fn usb_reset(&mut self) {
+        self.reset();
+        self.mem.usb_reset()
+    }
+
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        self.reset();
+        self.mem.on_usb_reset(ctx)
+    }
+
+    fn on_manifest_reset(&mut self) {
+        self.mem.on_manifest_reset()
+    }
+
+    fn locate_image(&mut self, header: &[u8]) -> Result<u32, DFUMemError> {
+        self.mem.locate_image(header)
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        self.mem.now_ms()
+    }
+
+    fn profile_ticks(&mut self) -> u32 {
+        self.mem.profile_ticks()
+    }
+
+    fn on_abort(&mut self) {
+        self.mem.on_abort()
+    }
+
+    fn on_configured(&mut self) {
+        self.mem.on_configured()
+    }
+
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        self.mem.on_detach_request(timeout_ms)
+    }
+
+    fn on_detach_expired(&mut self) {
+        self.mem.on_detach_expired()
+    }
+
+    fn on_progress(&mut self, progress: DfuProgress) {
+        self.mem.on_progress(progress)
+    }
+
+    fn on_state_change(&mut self, old: DFUState, new: DFUState, status: DFUStatusCode) {
+        self.mem.on_state_change(old, new, status)
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        self.mem.operation_busy()
+    }
+
+    fn operation_result(&mut self) -> Result<(), DFUMemError> {
+        self.mem.operation_result()
+    }
+
+    fn on_error(&mut self) {
+        self.mem.on_error()
+    }
+
+    fn persist_lockout(&mut self, failures: Option<u8>) -> u8 {
+        self.mem.persist_lockout(failures)
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.mem.mem_info_string()
+    }
+
+    fn initial_address_pointer(&self) -> u32 {
+        self.mem.initial_address_pointer()
+    }
+
+    fn mem_info_string_for(&self, alt: u8) -> &str {
+        self.mem.mem_info_string_for(alt)
+    }
+
+    fn select_alt(&mut self, alt: u8) {
+        self.mem.select_alt(alt)
+    }
+
+    fn vendor_error_string(&self) -> Option<&str> {
+        self.mem.vendor_error_string()
+    }
+
+    fn vendor_commands(&self) -> &[u8] {
+        self.mem.vendor_commands()
+    }
+
+    fn vendor_dnload_command(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+    ) -> Result<VendorCommandOutcome, DFUMemError> {
+        self.mem.vendor_dnload_command(cmd, payload)
+    }
+
+    fn vendor_command_execute(&mut self, cmd: u8) -> Result<(), DFUMemError> {
+        self.mem.vendor_command_execute(cmd)
+    }
+
+    fn device_info(&mut self, buf: &mut [u8]) -> usize {
+        self.mem.device_info(buf)
+    }
+}
@@ -27,18 +27,20 @@
 //! * Write (host to device) - download command
 //! * Erase
 //! * Erase All
+//! * Read Unprotect - erase everything and remove read protection,
+//!   if enabled with [`HAS_READ_UNPROTECT`](DFUMemIO::HAS_READ_UNPROTECT).
+//! * CRC-32 of a memory range - vendor-specific command,
+//!   if enabled with [`HAS_CRC_COMMAND`](DFUMemIO::HAS_CRC_COMMAND).
 //!
-//! ### Not supported operations
-//!
-//! * Read Unprotect - erase everything and remove read protection.
+//! Several memories may be exposed as interface alternate settings,
+//! see [`ALT_COUNT`](DFUMemIO::ALT_COUNT).
 //!
 //! ### Limitations
 //!
 //! * Maximum USB transfer size is limited to what `usb-device` supports
-//! for control enpoint transfers, which is `128` bytes by default.
-//!
-//! * iString field in `DFU_GETSTATUS` is always `0`. Vendor-specific string
-//! error descriptions are not supported.
+//!   for control enpoint transfers, which is `128` bytes by default.
+//!   Requests with a longer data stage are stalled by `usb-device` before
+//!   `DFUClass` can see them.
 //!
 //! ## DFU utilities
 //!
@@ -102,7 +104,6 @@
 //! // either change how DFUClass behaves, or define host's expectations.
 //!
 //! struct MyMem {
-//!     buffer: [u8; 64],
 //!     flash_memory: [u8; 1024],
 //! }
 //!
@@ -132,17 +133,12 @@
 //!         self.erase(0)
 //!     }
 //!
-//!     fn store_write_buffer(&mut self, src:&[u8]) -> Result<(), ()>{
-//!         self.buffer[..src.len()].copy_from_slice(src);
-//!         Ok(())
-//!     }
-//!
-//!     fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError>{
+//!     fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
 //!         // TODO: check address value
 //!         let offset = address as usize;
 //!
-//!         // Write buffer to a memory
-//!         self.flash_memory[offset..offset+length].copy_from_slice(&self.buffer[..length]);
+//!         // Write data to a memory
+//!         self.flash_memory[offset..offset+data.len()].copy_from_slice(data);
 //!
 //!         // TODO: verify that memory is programmed correctly
 //!         Ok(())
@@ -155,7 +151,6 @@
 //! }
 //!
 //! let mut my_mem = MyMem {
-//!     buffer: [0u8; 64],
 //!     flash_memory: [0u8; 1024],
 //! };
 //!
@@ -163,8 +158,9 @@
 //! // let usb_bus_alloc = UsbBus::new(peripheral);
 //! // let usb_dev = UsbDeviceBuilder::new().build();
 //!
-//! // Create DFUClass
-//! let mut dfu = DFUClass::new(&usb_bus_alloc, my_mem);
+//! // Create DFUClass, download data is collected in a BufferedMem buffer
+//! // and passed to MyMem::program_block()
+//! let mut dfu = DFUClass::new(&usb_bus_alloc, BufferedMem::<_, 64>::new(my_mem));
 //!
 //! // usb_dev.poll() must be called periodically, usually from USB interrupt handlers.
 //! // When USB input/output is done, handlers in MyMem may be called.
@@ -175,9 +171,150 @@
 //!
 //! See [usbd-dfu-example](https://github.com/vitalyvb/usbd-dfu-example) for a functioning example.
 //!
+//! [`run_dfu()`] creates `DFUClass` and `UsbDevice` and runs the poll loop,
+//! leaving boot decision and the loop body to a bootloader, see its documentation
+//! for an example.
+//!
+
+#[cfg(feature = "test-util")]
+extern crate std;
+
+#[macro_use]
+mod fmt;
 
 /// DFU protocol module
 pub mod class;
 
+/// DFU run-time mode class for the application firmware
+pub mod runtime;
+
+/// Memory wrapper that retries failed operations
+pub mod retry;
+
+/// Memory wrapper that writes to two memories
+pub mod mirror;
+
+/// Memory wrapper that decodes Intel HEX images
+pub mod hex;
+
+/// Memory wrapper that owns a download buffer
+pub mod buffered;
+
+/// Memory wrapper that combines two memories
+pub mod multi;
+
+/// Memory wrapper for two firmware slots
+pub mod dual_slot;
+
+/// CRC-32 calculation
+pub mod crc;
+
+/// DfuSe command blocks
+pub mod dfuse;
+
+/// DFU file suffix
+pub mod suffix;
+
+/// Memory layout string builder and checks
+pub mod meminfo;
+
+/// Bootloader main loop
+pub mod bootloader;
+
+/// STM32L4 dual-bank flash memory
+#[cfg(feature = "stm32l4")]
+pub mod stm32l4;
+
+/// STM32H7 flash memory
+#[cfg(feature = "stm32h7")]
+pub mod stm32h7;
+
+/// Memory implementation for `embedded-storage` NorFlash drivers
+#[cfg(feature = "nor-flash")]
+pub mod nor_flash;
+
+/// Memory implementation for `embedded-storage-async` NorFlash drivers
+#[cfg(feature = "nor-flash-async")]
+pub mod nor_flash_async;
+
+/// DFU mode handler for `embassy-usb`
+#[cfg(feature = "embassy")]
+pub mod embassy;
+/// Time spent in USB callbacks
+#[cfg(feature = "profiling")]
+pub mod profile;
+
+/// Microsoft OS 2.0 descriptors for WinUSB
+#[cfg(feature = "winusb")]
+pub mod winusb;
+
+/// WebUSB descriptors
+#[cfg(feature = "webusb")]
+pub mod webusb;
+
+/// Mock memory and host requests for tests with `usbd-class-tester`
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+#[doc(inline)]
+pub use crate::class::{
+    DFUClass, DFUManifestationError, DFUMemError, DFUMemIO, DFUState, DFUStatusCode, DfuOptions,
+    DfuPhase, DfuProgress, EraseProgress, InitialState, ManifestationProgress, PollActivity,
+    ResetContext, SegmentLimits, UsbResetPolicy, VendorCommandOutcome,
+};
+
+#[doc(inline)]
+pub use crate::runtime::{DFURuntime, DFURuntimeClass};
+
+#[doc(inline)]
+pub use crate::retry::RetryMem;
+
+#[doc(inline)]
+pub use crate::mirror::MirrorMem;
+
+#[doc(inline)]
+pub use crate::hex::HexMem;
+
+#[doc(inline)]
+pub use crate::buffered::BufferedMem;
+
+#[doc(inline)]
+pub use crate::multi::MultiRegion;
+
+#[doc(inline)]
+pub use crate::dual_slot::{DualSlot, Slot};
+
+#[doc(inline)]
+pub use crate::dfuse::{DfuseCommand, DfuseCommandError};
+
+#[doc(inline)]
+pub use crate::suffix::DfuSuffix;
+
+#[doc(inline)]
+pub use crate::meminfo::{MemInfoString, MemInfoStringError, Perms};
+
+#[doc(inline)]
+pub use crate::bootloader::{run_dfu, BootDecision, BootloaderConfig};
+
+#[cfg(feature = "stm32l4")]
+#[doc(inline)]
+pub use crate::stm32l4::Stm32l4DualBank;
+
+#[cfg(feature = "stm32h7")]
+#[doc(inline)]
+pub use crate::stm32h7::Stm32h7Flash;
+
+#[cfg(feature = "nor-flash")]
+#[doc(inline)]
+pub use crate::nor_flash::NorFlashMemIO;
+
+#[cfg(feature = "nor-flash-async")]
+#[doc(inline)]
+pub use crate::nor_flash_async::{AsyncNorFlashMemIO, NorFlashQueue};
+
+#[cfg(feature = "embassy")]
+#[doc(inline)]
+pub use crate::embassy::{DfuHandler, DfuState};
+#[cfg(feature = "profiling")]
 #[doc(inline)]
-pub use crate::class::{DFUClass, DFUManifestationError, DFUMemError, DFUMemIO};
+pub use crate::profile::{CallStats, DfuProfile};
@@ -0,0 +1,610 @@
+use core::fmt::{self, Write};
+use core::ops::Range;
+
+/// Memory area permissions, the last character of a
+/// [`MEM_INFO_STRING`](crate::DFUMemIO::MEM_INFO_STRING) area.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Perms {
+    /// Readable (`a`)
+    R,
+    /// Erasable (`b`)
+    E,
+    /// Readable and erasable (`c`)
+    RE,
+    /// Writable (`d`)
+    W,
+    /// Readable and writable (`e`)
+    RW,
+    /// Erasable and writable (`f`)
+    EW,
+    /// Readable, erasable, and writable (`g`)
+    RWE,
+}
+
+impl Perms {
+    /// Returns permissions of DfuSe permission letter `a` to `g`.
+    pub fn from_letter(letter: u8) -> Option<Perms> {
+        match letter {
+            b'a' => Some(Perms::R),
+            b'b' => Some(Perms::E),
+            b'c' => Some(Perms::RE),
+            b'd' => Some(Perms::W),
+            b'e' => Some(Perms::RW),
+            b'f' => Some(Perms::EW),
+            b'g' => Some(Perms::RWE),
+            _ => None,
+        }
+    }
+
+    /// Returns `true` if the area may be read.
+    pub fn readable(self) -> bool {
+        matches!(self, Perms::R | Perms::RE | Perms::RW | Perms::RWE)
+    }
+
+    /// Returns `true` if the area may be erased.
+    pub fn erasable(self) -> bool {
+        matches!(self, Perms::E | Perms::RE | Perms::EW | Perms::RWE)
+    }
+
+    /// Returns `true` if the area may be written.
+    pub fn writable(self) -> bool {
+        matches!(self, Perms::W | Perms::RW | Perms::EW | Perms::RWE)
+    }
+
+    /// Returns DfuSe permission letter.
+    pub fn letter(self) -> char {
+        match self {
+            Perms::R => 'a',
+            Perms::E => 'b',
+            Perms::RE => 'c',
+            Perms::W => 'd',
+            Perms::RW => 'e',
+            Perms::EW => 'f',
+            Perms::RWE => 'g',
+        }
+    }
+}
+
+/// Errors that may happen when building a [`MemInfoString`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MemInfoStringError {
+    /// Buffer is too small, the string is left unchanged
+    BufferTooSmall,
+    /// [`region()`](MemInfoString::region) is called more than once, or an area or
+    /// a segment is added before a region
+    Order,
+    /// Memory layout string is malformed, see [`segments()`] and [`areas()`]
+    Syntax,
+}
+
+/// Memory layout string built at runtime, without allocations.
+///
+/// Produces the same syntax as [`MEM_INFO_STRING`](crate::DFUMemIO::MEM_INFO_STRING),
+/// page sizes use `M` or `K` suffix when possible, or a space for bytes. The string
+/// is stored in a buffer of `N` bytes and can be kept in a [`DFUMemIO`](crate::DFUMemIO)
+/// implementation and returned from [`mem_info_string()`](crate::DFUMemIO::mem_info_string):
+///
+/// ```
+/// use usbd_dfu::meminfo::{MemInfoString, MemInfoStringError, Perms};
+///
+/// fn layout(flash_kb: u32) -> Result<MemInfoString<64>, MemInfoStringError> {
+///     let mut s = MemInfoString::new();
+///     s.region("Flash", 0x0800_0000)?
+///         .area(16, 1024, Perms::R)?
+///         .area(flash_kb - 16, 1024, Perms::RWE)?;
+///     Ok(s)
+/// }
+///
+/// assert_eq!(layout(64).unwrap().as_str(), "@Flash/0x08000000/16*1Ka,48*1Kg");
+/// ```
+#[derive(Clone)]
+pub struct MemInfoString<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+    has_area: bool,
+}
+
+impl<const N: usize> Default for MemInfoString<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MemInfoString<N> {
+    /// Creates an empty string.
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; N],
+            len: 0,
+            has_area: false,
+        }
+    }
+
+    /// Starts a memory region named `name` at `address`.
+    ///
+    /// Only one region can be described, additional address ranges of the same region
+    /// are added with [`segment()`](Self::segment).
+    pub fn region(&mut self, name: &str, address: u32) -> Result<&mut Self, MemInfoStringError> {
+        if self.len != 0 {
+            return Err(MemInfoStringError::Order);
+        }
+        self.append(format_args!("@{}/{:#010X}/", name, address))?;
+        self.has_area = false;
+        Ok(self)
+    }
+
+    /// Starts another address range of the region at `address`.
+    pub fn segment(&mut self, address: u32) -> Result<&mut Self, MemInfoStringError> {
+        if !self.has_area {
+            return Err(MemInfoStringError::Order);
+        }
+        self.append(format_args!("/{:#010X}/", address))?;
+        self.has_area = false;
+        Ok(self)
+    }
+
+    /// Adds `pages` pages of `page_size` bytes with permissions `perms`.
+    pub fn area(
+        &mut self,
+        pages: u32,
+        page_size: u32,
+        perms: Perms,
+    ) -> Result<&mut Self, MemInfoStringError> {
+        if self.len == 0 {
+            return Err(MemInfoStringError::Order);
+        }
+
+        let (size, unit) = if page_size != 0 && page_size.is_multiple_of(1024 * 1024) {
+            (page_size / (1024 * 1024), 'M')
+        } else if page_size != 0 && page_size.is_multiple_of(1024) {
+            (page_size / 1024, 'K')
+        } else {
+            (page_size, ' ')
+        };
+        let sep = if self.has_area { "," } else { "" };
+
+        self.append(format_args!(
+            "{}{}*{}{}{}",
+            sep,
+            pages,
+            size,
+            unit,
+            perms.letter()
+        ))?;
+        self.has_area = true;
+        Ok(self)
+    }
+
+    /// Appends address ranges of another memory layout string `layout`.
+    ///
+    /// If the string is empty, `layout` is copied with its region name,
+    /// otherwise the region name of `layout` is dropped and its address ranges
+    /// are added as segments of the region:
+    ///
+    /// ```
+    /// use usbd_dfu::meminfo::MemInfoString;
+    ///
+    /// let mut s = MemInfoString::<64>::new();
+    /// s.extend("@Flash/0x08000000/4*1Kg")
+    ///     .unwrap()
+    ///     .extend("@EEPROM/0x08080000/1*256 e")
+    ///     .unwrap();
+    /// assert_eq!(s.as_str(), "@Flash/0x08000000/4*1Kg/0x08080000/1*256 e");
+    /// ```
+    pub fn extend(&mut self, layout: &str) -> Result<&mut Self, MemInfoStringError> {
+        let layout = if self.len == 0 {
+            layout
+        } else {
+            match layout.find('/') {
+                Some(pos) => &layout[pos..],
+                None => "",
+            }
+        };
+        self.append(format_args!("{}", layout))?;
+        self.has_area = true;
+        Ok(self)
+    }
+
+    /// Returns the string.
+    pub fn as_str(&self) -> &str {
+        // only complete `str`s are appended
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+
+    fn append(&mut self, args: fmt::Arguments) -> Result<(), MemInfoStringError> {
+        let mut w = Writer {
+            buf: &mut self.buf,
+            len: self.len,
+        };
+        w.write_fmt(args)
+            .map_err(|_| MemInfoStringError::BufferTooSmall)?;
+        self.len = w.len;
+        Ok(())
+    }
+}
+
+/// Writes to a buffer, `len` is updated only by the `MemInfoString` on success
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for Writer<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let end = self.len + s.len();
+        if end > self.buf.len() {
+            return Err(fmt::Error);
+        }
+        self.buf[self.len..end].copy_from_slice(s.as_bytes());
+        self.len = end;
+        Ok(())
+    }
+}
+
+/// `0xADDRESS/areas` segments of a memory layout string, in all regions.
+#[derive(Clone)]
+struct Parts<'a> {
+    /// `@`-separated regions, `None` after an error
+    regions: Option<core::str::Split<'a, char>>,
+    /// `/`-separated parts of the current region, after the region name
+    parts: Option<core::str::Split<'a, char>>,
+    /// Layout doesn't start with a region
+    malformed: bool,
+}
+
+impl<'a> Parts<'a> {
+    fn new(layout: &'a str) -> Self {
+        let mut regions = layout.split('@');
+        let malformed = regions.next().is_some_and(|s| !s.is_empty());
+        Self {
+            regions: Some(regions),
+            parts: None,
+            malformed,
+        }
+    }
+
+    /// Returns the next segment start address and its areas,
+    /// nothing is returned after an error
+    fn next_segment(&mut self) -> Option<Result<(u32, &'a str), MemInfoStringError>> {
+        match self.parse() {
+            Ok(segment) => segment.map(Ok),
+            Err(e) => Some(Err(self.fail(e))),
+        }
+    }
+
+    /// Stops the iteration after an error
+    fn fail(&mut self, e: MemInfoStringError) -> MemInfoStringError {
+        self.regions = None;
+        e
+    }
+
+    fn parse(&mut self) -> Result<Option<(u32, &'a str)>, MemInfoStringError> {
+        let regions = match self.regions.as_mut() {
+            Some(regions) => regions,
+            None => return Ok(None),
+        };
+        if self.malformed {
+            return Err(MemInfoStringError::Syntax);
+        }
+
+        loop {
+            if let Some(parts) = self.parts.as_mut() {
+                if let Some(address) = parts.next() {
+                    let address = segment_address(address).ok_or(MemInfoStringError::Syntax)?;
+                    let areas = parts.next().ok_or(MemInfoStringError::Syntax)?;
+                    return Ok(Some((address, areas)));
+                }
+            }
+
+            let mut parts = match regions.next() {
+                Some(region) => region.split('/'),
+                None => return Ok(None),
+            };
+            // region name
+            parts.next();
+            if parts.clone().next().is_none() {
+                // region without segments
+                return Err(MemInfoStringError::Syntax);
+            }
+            self.parts = Some(parts);
+        }
+    }
+}
+
+/// Address ranges of a memory layout string, see [`segments()`].
+#[derive(Clone)]
+pub struct Segments<'a> {
+    parts: Parts<'a>,
+}
+
+/// Returns address ranges described by a memory layout string, one per segment.
+///
+/// Accepts the syntax of [`MEM_INFO_STRING`](crate::DFUMemIO::MEM_INFO_STRING),
+/// segments of all `@name` regions are returned. A malformed segment is returned
+/// as [`MemInfoStringError::Syntax`], and the iteration stops.
+///
+/// ```
+/// use usbd_dfu::meminfo::segments;
+///
+/// let mut s = segments("@Flash/0x08000000/16*1Ka,48*1Kg/0x08100000/1*1Mc");
+/// assert_eq!(s.next(), Some(Ok(0x0800_0000..0x0801_0000)));
+/// assert_eq!(s.next(), Some(Ok(0x0810_0000..0x0820_0000)));
+/// assert_eq!(s.next(), None);
+/// ```
+pub fn segments(layout: &str) -> Segments<'_> {
+    Segments {
+        parts: Parts::new(layout),
+    }
+}
+
+impl Iterator for Segments<'_> {
+    type Item = Result<Range<u32>, MemInfoStringError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (address, areas) = match self.parts.next_segment()? {
+            Ok(segment) => segment,
+            Err(e) => return Some(Err(e)),
+        };
+
+        let mut size: u32 = 0;
+        for area in areas.split(',').filter(|a| !a.trim().is_empty()) {
+            match parse_area(area).and_then(|(area_size, _)| size.checked_add(area_size)) {
+                Some(s) => size = s,
+                None => return Some(Err(self.parts.fail(MemInfoStringError::Syntax))),
+            }
+        }
+
+        match address.checked_add(size) {
+            Some(end) => Some(Ok(address..end)),
+            None => Some(Err(self.parts.fail(MemInfoStringError::Syntax))),
+        }
+    }
+}
+
+/// Address ranges and permissions of a memory layout string, see [`areas()`].
+#[derive(Clone)]
+pub struct Areas<'a> {
+    parts: Parts<'a>,
+    areas: Option<core::str::Split<'a, char>>,
+    address: u32,
+}
+
+/// Returns address ranges and permissions described by a memory layout string,
+/// one per `N*SIZE` area.
+///
+/// Accepts the syntax of [`MEM_INFO_STRING`](crate::DFUMemIO::MEM_INFO_STRING),
+/// areas of all `@name` regions are returned. A malformed area is returned
+/// as [`MemInfoStringError::Syntax`], and the iteration stops.
+///
+/// ```
+/// use usbd_dfu::meminfo::{areas, Perms};
+///
+/// let mut s = areas("@Flash/0x08000000/16*1Ka,48*1Kg/0x08100000/1*1Mc");
+/// assert_eq!(s.next(), Some(Ok((0x0800_0000..0x0800_4000, Perms::R))));
+/// assert_eq!(s.next(), Some(Ok((0x0800_4000..0x0801_0000, Perms::RWE))));
+/// assert_eq!(s.next(), Some(Ok((0x0810_0000..0x0820_0000, Perms::RE))));
+/// assert_eq!(s.next(), None);
+/// ```
+pub fn areas(layout: &str) -> Areas<'_> {
+    Areas {
+        parts: Parts::new(layout),
+        areas: None,
+        address: 0,
+    }
+}
+
+impl Iterator for Areas<'_> {
+    type Item = Result<(Range<u32>, Perms), MemInfoStringError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let area = self
+                .areas
+                .as_mut()
+                .and_then(|areas| areas.find(|a| !a.trim().is_empty()));
+            if let Some(area) = area {
+                let start = self.address;
+                let parsed = parse_area(area)
+                    .and_then(|(size, perms)| Some((start.checked_add(size)?, perms)));
+                return match parsed {
+                    Some((end, perms)) => {
+                        self.address = end;
+                        Some(Ok((start..end, perms)))
+                    }
+                    None => {
+                        self.areas = None;
+                        Some(Err(self.parts.fail(MemInfoStringError::Syntax)))
+                    }
+                };
+            }
+
+            // next segment
+            match self.parts.next_segment()? {
+                Ok((address, areas)) => {
+                    self.address = address;
+                    self.areas = Some(areas.split(','));
+                }
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+/// Parses a `0xADDRESS` segment start
+fn segment_address(address: &str) -> Option<u32> {
+    let address = address.trim();
+    let address = address
+        .strip_prefix("0x")
+        .or_else(|| address.strip_prefix("0X"))?;
+    u32::from_str_radix(address, 16).ok()
+}
+
+/// Parses a `N*SIZE` area, returns its size in bytes and permissions
+fn parse_area(area: &str) -> Option<(u32, Perms)> {
+    let (pages, page) = area.split_once('*')?;
+    let pages: u32 = pages.trim().parse().ok()?;
+
+    // page size, a unit, and a permission letter
+    let page = page.as_bytes();
+    let (perms, page) = page.split_last()?;
+    let perms = Perms::from_letter(*perms)?;
+    let (page, unit) = match page.split_last()? {
+        (b'K', page) => (page, 1024),
+        (b'M', page) => (page, 1024 * 1024),
+        (b'G', page) => (page, 1024 * 1024 * 1024),
+        (b' ', page) => (page, 1),
+        _ => (page, 1),
+    };
+    let page: u32 = core::str::from_utf8(page).ok()?.parse().ok()?;
+
+    Some((pages.checked_mul(page)?.checked_mul(unit)?, perms))
+}
+
+/// Returns `true` if `layout` is a valid memory layout string, see
+/// [`MEM_INFO_STRING`](crate::DFUMemIO::MEM_INFO_STRING).
+///
+/// A layout is one or more `@name` regions, each with one or more `/0xADDRESS/areas`
+/// segments. Areas are comma-separated `N*SIZE` page counts and sizes, followed by
+/// an optional `K`, `M`, `G`, or space unit, and a permission letter from `a` to `g`.
+/// Can be used in constants, see [`dfu_assert_mem_info!`](crate::dfu_assert_mem_info).
+///
+/// ```
+/// use usbd_dfu::meminfo::validate_mem_info;
+///
+/// assert!(validate_mem_info("@Flash/0x08000000/16*1Ka,48*1Kg/0x08100000/1*1Mc"));
+/// assert!(!validate_mem_info("@Flash/0x08000000/16*1Kh"));
+/// ```
+pub const fn validate_mem_info(layout: &str) -> bool {
+    let s = layout.as_bytes();
+    let mut i = 0;
+
+    while i < s.len() {
+        // region name, up to the first segment
+        if s[i] != b'@' {
+            return false;
+        }
+        i += 1;
+        while i < s.len() && s[i] != b'/' && s[i] != b'@' {
+            i += 1;
+        }
+
+        let mut segments = 0;
+        while i < s.len() && s[i] == b'/' {
+            // address
+            i = skip_spaces(s, i + 1);
+            if i + 1 >= s.len() || s[i] != b'0' || (s[i + 1] != b'x' && s[i + 1] != b'X') {
+                return false;
+            }
+            i += 2;
+            let start = i;
+            while i < s.len() && s[i].is_ascii_hexdigit() {
+                i += 1;
+            }
+            if i == start || i - start > 8 {
+                return false;
+            }
+            i = skip_spaces(s, i);
+            if i >= s.len() || s[i] != b'/' {
+                return false;
+            }
+            i += 1;
+
+            // areas
+            loop {
+                i = skip_spaces(s, i);
+                let n = digits(s, i);
+                if n == 0 {
+                    return false;
+                }
+                i += n;
+                if i >= s.len() || s[i] != b'*' {
+                    return false;
+                }
+                i += 1;
+                let n = digits(s, i);
+                if n == 0 {
+                    return false;
+                }
+                i += n;
+                if i < s.len() && matches!(s[i], b'K' | b'M' | b'G' | b' ') {
+                    i += 1;
+                }
+                if i >= s.len() || !matches!(s[i], b'a'..=b'g') {
+                    return false;
+                }
+                i += 1;
+
+                if i >= s.len() || s[i] != b',' {
+                    break;
+                }
+                i += 1;
+                if i >= s.len() || s[i] == b'/' || s[i] == b'@' {
+                    // trailing comma
+                    break;
+                }
+            }
+            segments += 1;
+        }
+
+        if segments == 0 {
+            return false;
+        }
+    }
+
+    !s.is_empty()
+}
+
+const fn skip_spaces(s: &[u8], mut i: usize) -> usize {
+    while i < s.len() && s[i] == b' ' {
+        i += 1;
+    }
+    i
+}
+
+/// Number of decimal digits at `i`
+const fn digits(s: &[u8], i: usize) -> usize {
+    let mut n = 0;
+    while i + n < s.len() && s[i + n].is_ascii_digit() {
+        n += 1;
+    }
+    n
+}
+
+/// Checks a memory layout string at compile time, see [`validate_mem_info()`].
+///
+/// ```
+/// # use usbd_dfu::*;
+/// struct MyMem {}
+///
+/// impl DFUMemIO for MyMem {
+///     const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/16*1Ka,48*1Kg";
+///     const INITIAL_ADDRESS_POINTER: u32 = 0x0800_0000;
+///     const PROGRAM_TIME_MS: u32 = 8;
+///     const ERASE_TIME_MS: u32 = 50;
+///     const FULL_ERASE_TIME_MS: u32 = 50;
+/// #   fn read(&mut self, _: u32, _: usize) -> Result<&[u8], DFUMemError> { todo!() }
+/// #   fn erase(&mut self, _: u32) -> Result<(), DFUMemError> { todo!() }
+/// #   fn erase_all(&mut self) -> Result<(), DFUMemError> { todo!() }
+/// #   fn store_write_buffer(&mut self, _: &[u8]) -> Result<(), ()> { todo!() }
+/// #   fn program(&mut self, _: u32, _: usize) -> Result<(), DFUMemError> { todo!() }
+/// #   fn manifestation(&mut self) -> Result<(), DFUManifestationError> { todo!() }
+///     // ...
+/// }
+///
+/// usbd_dfu::dfu_assert_mem_info!(MyMem::MEM_INFO_STRING);
+/// ```
+///
+/// Size unit is not valid:
+///
+/// ```compile_fail
+/// usbd_dfu::dfu_assert_mem_info!("@Flash/0x08000000/16*1ka");
+/// ```
+#[macro_export]
+macro_rules! dfu_assert_mem_info {
+    ($layout:expr) => {
+        const _: () = assert!(
+            $crate::meminfo::validate_mem_info($layout),
+            "invalid memory layout string"
+        );
+    };
+}
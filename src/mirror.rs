@@ -0,0 +1,364 @@
+use crate::class::{
+    DFUManifestationError, DFUMemError, DFUMemIO, DFUState, DFUStatusCode, DfuProgress,
+    EraseProgress, ManifestationProgress, ResetContext, SegmentLimits, UsbResetPolicy,
+    VendorCommandOutcome,
+};
+use crate::suffix::DfuSuffix;
+use core::ops::Range;
+
+/// [`DFUMemIO`] wrapper that writes every block to two memories.
+///
+/// `MirrorMem` keeps two copies of the firmware updated during a single DFU
+/// session: every [`erase()`](DFUMemIO::erase), [`erase_all()`](DFUMemIO::erase_all),
+/// [`store_write_buffer()`](DFUMemIO::store_write_buffer), [`program()`](DFUMemIO::program),
+/// and [`program_block()`](DFUMemIO::program_block) call is forwarded to the primary memory `A`
+/// and then to the secondary memory `B`.
+/// An operation fails if it fails for either copy, the secondary memory is not
+/// touched if the operation already failed for the primary one.
+/// [`erase_all_next()`](DFUMemIO::erase_all_next) erases the primary memory step by step,
+/// and then the secondary one, a mass erase interrupted by USB reset starts over.
+///
+/// Addresses passed to the secondary memory are shifted by `secondary_offset`,
+/// this allows both copies to be managed by the same kind of memory driver.
+///
+/// Reads are served by the primary memory only, [`manifestation()`](DFUMemIO::manifestation)
+/// and [`usb_reset()`](DFUMemIO::usb_reset) are called once, for the primary memory.
+///
+/// Memory layout is advertised by the primary memory, so [`MEM_INFO_STRING`](DFUMemIO::MEM_INFO_STRING)
+/// describes the size of one copy. Program and erase times are the sum of both memories' times,
+/// transfer size is the smaller of two. [`VERIFY_AFTER_PROGRAM`](DFUMemIO::VERIFY_AFTER_PROGRAM)
+/// is set if either memory sets it, wrap each memory in [`BufferedMem`](crate::BufferedMem)
+/// to verify both copies.
+pub struct MirrorMem<A: DFUMemIO, B: DFUMemIO> {
+    primary: A,
+    secondary: B,
+    secondary_offset: u32,
+    /// Mass erase of the primary memory is done, see `erase_all_next()`
+    erasing_secondary: bool,
+}
+
+impl<A: DFUMemIO, B: DFUMemIO> MirrorMem<A, B> {
+    /// Creates a new `MirrorMem`, both memories receive identical addresses.
+    pub fn new(primary: A, secondary: B) -> Self {
+        Self::with_offset(primary, secondary, 0)
+    }
+
+    /// Creates a new `MirrorMem`, addresses for the `secondary` memory
+    /// are shifted by `secondary_offset` (wrapping).
+    pub fn with_offset(primary: A, secondary: B, secondary_offset: u32) -> Self {
+        Self {
+            primary,
+            secondary,
+            secondary_offset,
+            erasing_secondary: false,
+        }
+    }
+
+    /// Returns a reference to the primary memory.
+    pub fn primary(&self) -> &A {
+        &self.primary
+    }
+
+    /// Returns a reference to the secondary memory.
+    pub fn secondary(&self) -> &B {
+        &self.secondary
+    }
+
+    /// Consumes `MirrorMem` and returns both memories.
+    pub fn into_inner(self) -> (A, B) {
+        (self.primary, self.secondary)
+    }
+
+    fn secondary_address(&self, address: u32) -> u32 {
+        address.wrapping_add(self.secondary_offset)
+    }
+}
+
+impl<A: DFUMemIO, B: DFUMemIO> DFUMemIO for MirrorMem<A, B> {
+    const INITIAL_ADDRESS_POINTER: u32 = A::INITIAL_ADDRESS_POINTER;
+    const MEM_INFO_STRING: &'static str = A::MEM_INFO_STRING;
+    const ALT_COUNT: u8 = A::ALT_COUNT;
+    const HAS_DOWNLOAD: bool = A::HAS_DOWNLOAD && B::HAS_DOWNLOAD;
+    const HAS_UPLOAD: bool = A::HAS_UPLOAD;
+    const MANIFESTATION_TOLERANT: bool = A::MANIFESTATION_TOLERANT;
+    const WILL_DETACH: bool = A::WILL_DETACH;
+    const HAS_READ_UNPROTECT: bool = A::HAS_READ_UNPROTECT;
+    const HAS_CRC_COMMAND: bool = A::HAS_CRC_COMMAND;
+    const HAS_DEVICE_INFO: bool = A::HAS_DEVICE_INFO;
+    const PROGRAM_TIME_MS: u32 = A::PROGRAM_TIME_MS + B::PROGRAM_TIME_MS;
+    const ERASE_TIME_MS: u32 = A::ERASE_TIME_MS + B::ERASE_TIME_MS;
+    const FULL_ERASE_TIME_MS: u32 = A::FULL_ERASE_TIME_MS + B::FULL_ERASE_TIME_MS;
+    const READ_UNPROTECT_TIME_MS: u32 = A::READ_UNPROTECT_TIME_MS;
+    const MANIFESTATION_TIME_MS: u32 = A::MANIFESTATION_TIME_MS;
+    const DETACH_TIMEOUT: u16 = A::DETACH_TIMEOUT;
+    const TRANSFER_SIZE: u16 = if A::TRANSFER_SIZE < B::TRANSFER_SIZE {
+        A::TRANSFER_SIZE
+    } else {
+        B::TRANSFER_SIZE
+    };
+    const DFU_VERSION: u16 = A::DFU_VERSION;
+    const FIRST_DATA_BLOCK: u16 = A::FIRST_DATA_BLOCK;
+    const REDACTED_RANGES: &'static [Range<u32>] = A::REDACTED_RANGES;
+    const REDACTED_FILL: u8 = A::REDACTED_FILL;
+    const BLOCK_CRC: bool = A::BLOCK_CRC;
+    const VERIFY_AFTER_PROGRAM: bool = A::VERIFY_AFTER_PROGRAM || B::VERIFY_AFTER_PROGRAM;
+    const IMAGE_HEADER_SIZE: usize = A::IMAGE_HEADER_SIZE;
+    const PROGRAM_IMAGE_HEADER: bool = A::PROGRAM_IMAGE_HEADER;
+    const ERROR_AUTOCLEAR_MS: u32 = A::ERROR_AUTOCLEAR_MS;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = A::LAYOUT_SEGMENTS;
+    const SEGMENT_LIMITS: &'static [SegmentLimits] = A::SEGMENT_LIMITS;
+    const OPERATION_BUDGET_FACTOR: u32 = A::OPERATION_BUDGET_FACTOR;
+    const MANIFESTATION_CONSTANT_TIME: bool = A::MANIFESTATION_CONSTANT_TIME;
+    const MAX_FAILED_MANIFESTATIONS: u8 = A::MAX_FAILED_MANIFESTATIONS;
+    const IMAGE_MAGIC: Option<(usize, &'static [u8])> = A::IMAGE_MAGIC;
+    const CHECK_SUFFIX: bool = A::CHECK_SUFFIX;
+    const ALLOW_UPLOAD_DURING_DNLOAD_IDLE: bool = A::ALLOW_UPLOAD_DURING_DNLOAD_IDLE;
+    const ADVANCE_ON_GETSTATE: bool = A::ADVANCE_ON_GETSTATE;
+    const ON_USB_RESET: UsbResetPolicy = A::ON_USB_RESET;
+    const HAS_VENDOR_ERROR_STRING: bool = A::HAS_VENDOR_ERROR_STRING;
+    const MEMIO_IN_USB_INTERRUPT: bool = A::MEMIO_IN_USB_INTERRUPT;
+    const READ_CHUNK_SIZE: usize = A::READ_CHUNK_SIZE;
+    const CHECK_PERMISSIONS: bool = A::CHECK_PERMISSIONS;
+    const WINUSB_VENDOR_CODE: u8 = A::WINUSB_VENDOR_CODE;
+    const WINUSB_GUID: &'static str = A::WINUSB_GUID;
+    const WINUSB_COMPOSITE: bool = A::WINUSB_COMPOSITE;
+    const WEBUSB_VENDOR_CODE: u8 = A::WEBUSB_VENDOR_CODE;
+    const WEBUSB_LANDING_PAGE: Option<&'static str> = A::WEBUSB_LANDING_PAGE;
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.primary.store_write_buffer(src)?;
+        self.secondary.store_write_buffer(src)
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.primary.read(address, length)
+    }
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        self.primary.read_block(address, dest)
+    }
+
+    fn read_chunk(
+        &mut self,
+        address: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DFUMemError> {
+        self.primary.read_chunk(address, offset, buf)
+    }
+
+    fn is_end_of_region(&self, address: u32) -> bool {
+        self.primary.is_end_of_region(address)
+    }
+
+    fn upload_end_address(&self) -> Option<u32> {
+        self.primary.upload_end_address()
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.primary.program(address, length)?;
+        let address = self.secondary_address(address);
+        self.secondary.program(address, length)
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        self.primary.program_block(address, data)?;
+        let address = self.secondary_address(address);
+        self.secondary.program_block(address, data)
+    }
+
+    fn program_time_ms(&self, length: usize) -> u32 {
+        self.primary
+            .program_time_ms(length)
+            .saturating_add(self.secondary.program_time_ms(length))
+    }
+
+    fn erase_time_ms(&self, address: u32) -> u32 {
+        let secondary = self.secondary_address(address);
+        self.primary
+            .erase_time_ms(address)
+            .saturating_add(self.secondary.erase_time_ms(secondary))
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.primary.page_size_at(address)
+    }
+
+    fn is_write_protected(&self, address: u32, length: usize) -> bool {
+        let secondary = self.secondary_address(address);
+        self.primary.is_write_protected(address, length)
+            || self.secondary.is_write_protected(secondary, length)
+    }
+
+    fn is_blank(&mut self, address: u32, length: usize) -> Result<bool, DFUMemError> {
+        let secondary = self.secondary_address(address);
+        Ok(
+            self.primary.is_blank(address, length)?
+                && self.secondary.is_blank(secondary, length)?,
+        )
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.primary.erase(address)?;
+        let address = self.secondary_address(address);
+        self.secondary.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.erasing_secondary = false;
+        self.primary.erase_all()?;
+        self.secondary.erase_all()
+    }
+
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        if !self.erasing_secondary {
+            return match self.primary.erase_all_next()? {
+                EraseProgress::Busy(remaining) => Ok(EraseProgress::Busy(
+                    remaining.saturating_add(B::FULL_ERASE_TIME_MS),
+                )),
+                EraseProgress::Done => {
+                    self.erasing_secondary = true;
+                    Ok(EraseProgress::Busy(B::FULL_ERASE_TIME_MS))
+                }
+            };
+        }
+        let progress = self.secondary.erase_all_next();
+        if !matches!(progress, Ok(EraseProgress::Busy(_))) {
+            self.erasing_secondary = false;
+        }
+        progress
+    }
+
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        self.primary.read_unprotect()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.primary.manifestation()
+    }
+
+    fn manifestation_start(&mut self) -> Result<(), DFUManifestationError> {
+        self.primary.manifestation_start()
+    }
+
+    fn manifestation_poll(&mut self) -> ManifestationProgress {
+        self.primary.manifestation_poll()
+    }
+
+    fn manifestation_allowed(&mut self) -> Result<(), DFUManifestationError> {
+        self.primary.manifestation_allowed()
+    }
+
+    fn validate_suffix(&mut self, suffix: Option<DfuSuffix>) -> Result<(), DFUManifestationError> {
+        self.primary.validate_suffix(suffix)
+    }
+
+    fn usb_reset(&mut self) {
+        self.primary.usb_reset()
+    }
+
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        self.erasing_secondary = false;
+        self.primary.on_usb_reset(ctx)
+    }
+
+    fn on_manifest_reset(&mut self) {
+        self.primary.on_manifest_reset()
+    }
+
+    fn locate_image(&mut self, header: &[u8]) -> Result<u32, DFUMemError> {
+        self.primary.locate_image(header)
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        self.primary.now_ms()
+    }
+
+    fn profile_ticks(&mut self) -> u32 {
+        self.primary.profile_ticks()
+    }
+
+    fn on_abort(&mut self) {
+        self.primary.on_abort()
+    }
+
+    fn on_configured(&mut self) {
+        self.primary.on_configured()
+    }
+
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        self.primary.on_detach_request(timeout_ms)
+    }
+
+    fn on_detach_expired(&mut self) {
+        self.primary.on_detach_expired()
+    }
+
+    fn on_progress(&mut self, progress: DfuProgress) {
+        self.primary.on_progress(progress)
+    }
+
+    fn on_state_change(&mut self, old: DFUState, new: DFUState, status: DFUStatusCode) {
+        self.primary.on_state_change(old, new, status)
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        self.primary.operation_busy() || self.secondary.operation_busy()
+    }
+
+    fn operation_result(&mut self) -> Result<(), DFUMemError> {
+        let primary = self.primary.operation_result();
+        let secondary = self.secondary.operation_result();
+        primary.and(secondary)
+    }
+
+    fn on_error(&mut self) {
+        self.primary.on_error();
+        self.secondary.on_error()
+    }
+
+    fn persist_lockout(&mut self, failures: Option<u8>) -> u8 {
+        self.primary.persist_lockout(failures)
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.primary.mem_info_string()
+    }
+
+    fn initial_address_pointer(&self) -> u32 {
+        self.primary.initial_address_pointer()
+    }
+
+    fn mem_info_string_for(&self, alt: u8) -> &str {
+        self.primary.mem_info_string_for(alt)
+    }
+
+    fn select_alt(&mut self, alt: u8) {
+        self.primary.select_alt(alt);
+        self.secondary.select_alt(alt)
+    }
+
+    fn vendor_error_string(&self) -> Option<&str> {
+        self.primary.vendor_error_string()
+    }
+
+    fn vendor_commands(&self) -> &[u8] {
+        self.primary.vendor_commands()
+    }
+
+    fn vendor_dnload_command(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+    ) -> Result<VendorCommandOutcome, DFUMemError> {
+        self.primary.vendor_dnload_command(cmd, payload)
+    }
+
+    fn vendor_command_execute(&mut self, cmd: u8) -> Result<(), DFUMemError> {
+        self.primary.vendor_command_execute(cmd)
+    }
+
+    fn device_info(&mut self, buf: &mut [u8]) -> usize {
+        self.primary.device_info(buf)
+    }
+}
@@ -0,0 +1,504 @@
+use crate::class::{
+    DFUManifestationError, DFUMemError, DFUMemIO, DFUState, DFUStatusCode, DfuProgress,
+    EraseProgress, ManifestationProgress, ResetContext, SegmentLimits, UsbResetPolicy,
+    VendorCommandOutcome,
+};
+use crate::meminfo::{segments, MemInfoString, MemInfoStringError};
+use crate::suffix::DfuSuffix;
+use core::ops::Range;
+
+const fn max(a: u32, b: u32) -> u32 {
+    if a > b {
+        a
+    } else {
+        b
+    }
+}
+
+/// Wrapped memory that serves an address
+enum Region {
+    A,
+    B,
+}
+
+/// [`DFUMemIO`] wrapper that combines two memories with separate address ranges.
+///
+/// Read, erase, and program functions are routed to memory `A` or `B` depending
+/// on the address: address ranges of each memory are parsed from its
+/// [`mem_info_string()`](DFUMemIO::mem_info_string), see [`segments()`](crate::meminfo::segments).
+/// Addresses that are not in either memory fail with [`DFUMemError::Address`].
+/// An operation is routed by the block start address, the memory checks that the whole
+/// block fits in its range.
+///
+/// Memory layout string is the layout of `A` followed by address ranges of `B`,
+/// it is built in a buffer of `N` bytes when `MultiRegion` is created.
+///
+/// Program and erase times are the larger of two, full erase and manifestation
+/// times are the sum of both memories' times, transfer size is the smaller of two.
+/// [`VERIFY_AFTER_PROGRAM`](DFUMemIO::VERIFY_AFTER_PROGRAM) is set if either memory sets it.
+/// [`erase_all()`](DFUMemIO::erase_all) and [`manifestation()`](DFUMemIO::manifestation)
+/// are called for `A`, and then for `B`. Manifestation of `B` is started with
+/// [`manifestation_start()`](DFUMemIO::manifestation_start) when
+/// [`manifestation_poll()`](DFUMemIO::manifestation_poll) of `A` reports it's done,
+/// it's not started if `A` fails. [`erase_all_next()`](DFUMemIO::erase_all_next) erases `A`
+/// step by step, and then `B`, a mass erase interrupted by USB reset starts over.
+/// [`store_write_buffer()`](DFUMemIO::store_write_buffer)
+/// stores data to both memories, the address is not known yet.
+/// Most other constants and functions are forwarded to `A`.
+///
+/// A DFU file suffix is checked if either memory sets [`CHECK_SUFFIX`](DFUMemIO::CHECK_SUFFIX),
+/// [`validate_suffix()`](DFUMemIO::validate_suffix) is called for `A` and then for `B`,
+/// only for memories that set it.
+///
+/// Only one of the memories may have [`REDACTED_RANGES`](DFUMemIO::REDACTED_RANGES)
+/// and [`LAYOUT_SEGMENTS`](DFUMemIO::LAYOUT_SEGMENTS), alternate settings are not
+/// supported, this is checked at compile time.
+///
+/// [`upload_end_address()`](DFUMemIO::upload_end_address) is not forwarded, an upload
+/// may read both memories, and an end address of one memory doesn't apply to the other.
+pub struct MultiRegion<A: DFUMemIO, B: DFUMemIO, const N: usize> {
+    a: A,
+    b: B,
+    layout: MemInfoString<N>,
+    /// Memory that is manifesting, see `manifestation_poll()`
+    manifesting: Option<Region>,
+    /// Mass erase of `A` is done, see `erase_all_next()`
+    erasing_b: bool,
+}
+
+impl<A: DFUMemIO, B: DFUMemIO, const N: usize> MultiRegion<A, B, N> {
+    const CONFIG_OK: () = {
+        assert!(
+            A::REDACTED_RANGES.is_empty() || B::REDACTED_RANGES.is_empty(),
+            "MultiRegion memories can't both have REDACTED_RANGES"
+        );
+        assert!(
+            A::LAYOUT_SEGMENTS.is_empty() || B::LAYOUT_SEGMENTS.is_empty(),
+            "MultiRegion memories can't both have LAYOUT_SEGMENTS"
+        );
+        assert!(
+            A::ALT_COUNT == 1 && B::ALT_COUNT == 1,
+            "MultiRegion memories can't have alternate settings"
+        );
+    };
+
+    /// Creates a new `MultiRegion` combining `a` and `b`.
+    ///
+    /// Fails if the combined memory layout string doesn't fit in `N` bytes,
+    /// or with [`MemInfoStringError::Syntax`] if a memory layout string is malformed.
+    pub fn new(a: A, b: B) -> Result<Self, MemInfoStringError> {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::CONFIG_OK;
+
+        for segment in segments(a.mem_info_string()).chain(segments(b.mem_info_string())) {
+            segment?;
+        }
+
+        let mut layout = MemInfoString::new();
+        layout
+            .extend(a.mem_info_string())?
+            .extend(b.mem_info_string())?;
+
+        Ok(Self {
+            a,
+            b,
+            layout,
+            manifesting: None,
+            erasing_b: false,
+        })
+    }
+
+    /// Returns a reference to the first memory.
+    pub fn a(&self) -> &A {
+        &self.a
+    }
+
+    /// Returns a reference to the second memory.
+    pub fn b(&self) -> &B {
+        &self.b
+    }
+
+    /// Consumes `MultiRegion` and returns both memories.
+    pub fn into_inner(self) -> (A, B) {
+        (self.a, self.b)
+    }
+
+    fn region(&self, address: u32) -> Result<Region, DFUMemError> {
+        let contains = |r: Result<Range<u32>, _>| r.is_ok_and(|r| r.contains(&address));
+        if segments(self.a.mem_info_string()).any(contains) {
+            Ok(Region::A)
+        } else if segments(self.b.mem_info_string()).any(contains) {
+            Ok(Region::B)
+        } else {
+            Err(DFUMemError::Address)
+        }
+    }
+}
+
+impl<A: DFUMemIO, B: DFUMemIO, const N: usize> DFUMemIO for MultiRegion<A, B, N> {
+    const INITIAL_ADDRESS_POINTER: u32 = A::INITIAL_ADDRESS_POINTER;
+    const MEM_INFO_STRING: &'static str = A::MEM_INFO_STRING;
+    const HAS_DOWNLOAD: bool = A::HAS_DOWNLOAD || B::HAS_DOWNLOAD;
+    const HAS_UPLOAD: bool = A::HAS_UPLOAD || B::HAS_UPLOAD;
+    const MANIFESTATION_TOLERANT: bool = A::MANIFESTATION_TOLERANT && B::MANIFESTATION_TOLERANT;
+    const WILL_DETACH: bool = A::WILL_DETACH;
+    const HAS_READ_UNPROTECT: bool = A::HAS_READ_UNPROTECT;
+    const HAS_CRC_COMMAND: bool = A::HAS_CRC_COMMAND;
+    const HAS_DEVICE_INFO: bool = A::HAS_DEVICE_INFO;
+    const PROGRAM_TIME_MS: u32 = max(A::PROGRAM_TIME_MS, B::PROGRAM_TIME_MS);
+    const ERASE_TIME_MS: u32 = max(A::ERASE_TIME_MS, B::ERASE_TIME_MS);
+    const FULL_ERASE_TIME_MS: u32 = A::FULL_ERASE_TIME_MS + B::FULL_ERASE_TIME_MS;
+    const READ_UNPROTECT_TIME_MS: u32 = A::READ_UNPROTECT_TIME_MS;
+    const MANIFESTATION_TIME_MS: u32 = A::MANIFESTATION_TIME_MS + B::MANIFESTATION_TIME_MS;
+    const DETACH_TIMEOUT: u16 = A::DETACH_TIMEOUT;
+    const TRANSFER_SIZE: u16 = if A::TRANSFER_SIZE < B::TRANSFER_SIZE {
+        A::TRANSFER_SIZE
+    } else {
+        B::TRANSFER_SIZE
+    };
+    const DFU_VERSION: u16 = A::DFU_VERSION;
+    const FIRST_DATA_BLOCK: u16 = A::FIRST_DATA_BLOCK;
+    const REDACTED_RANGES: &'static [Range<u32>] = if A::REDACTED_RANGES.is_empty() {
+        B::REDACTED_RANGES
+    } else {
+        A::REDACTED_RANGES
+    };
+    const REDACTED_FILL: u8 = if A::REDACTED_RANGES.is_empty() {
+        B::REDACTED_FILL
+    } else {
+        A::REDACTED_FILL
+    };
+    const BLOCK_CRC: bool = A::BLOCK_CRC;
+    const VERIFY_AFTER_PROGRAM: bool = A::VERIFY_AFTER_PROGRAM || B::VERIFY_AFTER_PROGRAM;
+    const IMAGE_HEADER_SIZE: usize = A::IMAGE_HEADER_SIZE;
+    const PROGRAM_IMAGE_HEADER: bool = A::PROGRAM_IMAGE_HEADER;
+    const ERROR_AUTOCLEAR_MS: u32 = A::ERROR_AUTOCLEAR_MS;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = if A::LAYOUT_SEGMENTS.is_empty() {
+        B::LAYOUT_SEGMENTS
+    } else {
+        A::LAYOUT_SEGMENTS
+    };
+    const SEGMENT_LIMITS: &'static [SegmentLimits] = if A::LAYOUT_SEGMENTS.is_empty() {
+        B::SEGMENT_LIMITS
+    } else {
+        A::SEGMENT_LIMITS
+    };
+    const OPERATION_BUDGET_FACTOR: u32 = A::OPERATION_BUDGET_FACTOR;
+    const MANIFESTATION_CONSTANT_TIME: bool = A::MANIFESTATION_CONSTANT_TIME;
+    const MAX_FAILED_MANIFESTATIONS: u8 = A::MAX_FAILED_MANIFESTATIONS;
+    const IMAGE_MAGIC: Option<(usize, &'static [u8])> = A::IMAGE_MAGIC;
+    const CHECK_SUFFIX: bool = A::CHECK_SUFFIX || B::CHECK_SUFFIX;
+    const ALLOW_UPLOAD_DURING_DNLOAD_IDLE: bool = A::ALLOW_UPLOAD_DURING_DNLOAD_IDLE;
+    const ADVANCE_ON_GETSTATE: bool = A::ADVANCE_ON_GETSTATE;
+    const ON_USB_RESET: UsbResetPolicy = A::ON_USB_RESET;
+    const HAS_VENDOR_ERROR_STRING: bool = A::HAS_VENDOR_ERROR_STRING || B::HAS_VENDOR_ERROR_STRING;
+    const MEMIO_IN_USB_INTERRUPT: bool = A::MEMIO_IN_USB_INTERRUPT && B::MEMIO_IN_USB_INTERRUPT;
+    const READ_CHUNK_SIZE: usize = if A::READ_CHUNK_SIZE == 0 {
+        B::READ_CHUNK_SIZE
+    } else if B::READ_CHUNK_SIZE == 0 || A::READ_CHUNK_SIZE < B::READ_CHUNK_SIZE {
+        A::READ_CHUNK_SIZE
+    } else {
+        B::READ_CHUNK_SIZE
+    };
+    const CHECK_PERMISSIONS: bool = A::CHECK_PERMISSIONS && B::CHECK_PERMISSIONS;
+    const WINUSB_VENDOR_CODE: u8 = A::WINUSB_VENDOR_CODE;
+    const WINUSB_GUID: &'static str = A::WINUSB_GUID;
+    const WINUSB_COMPOSITE: bool = A::WINUSB_COMPOSITE;
+    const WEBUSB_VENDOR_CODE: u8 = A::WEBUSB_VENDOR_CODE;
+    const WEBUSB_LANDING_PAGE: Option<&'static str> = A::WEBUSB_LANDING_PAGE;
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.a.store_write_buffer(src)?;
+        self.b.store_write_buffer(src)
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        match self.region(address)? {
+            Region::A => self.a.read(address, length),
+            Region::B => self.b.read(address, length),
+        }
+    }
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        match self.region(address)? {
+            Region::A => self.a.read_block(address, dest),
+            Region::B => self.b.read_block(address, dest),
+        }
+    }
+
+    fn read_chunk(
+        &mut self,
+        address: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DFUMemError> {
+        match self.region(address)? {
+            Region::A => self.a.read_chunk(address, offset, buf),
+            Region::B => self.b.read_chunk(address, offset, buf),
+        }
+    }
+
+    fn is_end_of_region(&self, address: u32) -> bool {
+        match self.region(address) {
+            Ok(Region::A) => self.a.is_end_of_region(address),
+            Ok(Region::B) => self.b.is_end_of_region(address),
+            Err(_) => true,
+        }
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        match self.region(address)? {
+            Region::A => self.a.program(address, length),
+            Region::B => self.b.program(address, length),
+        }
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        match self.region(address)? {
+            Region::A => self.a.program_block(address, data),
+            Region::B => self.b.program_block(address, data),
+        }
+    }
+
+    fn program_time_ms(&self, length: usize) -> u32 {
+        max(
+            self.a.program_time_ms(length),
+            self.b.program_time_ms(length),
+        )
+    }
+
+    fn erase_time_ms(&self, address: u32) -> u32 {
+        match self.region(address) {
+            Ok(Region::A) => self.a.erase_time_ms(address),
+            Ok(Region::B) => self.b.erase_time_ms(address),
+            Err(_) => Self::ERASE_TIME_MS,
+        }
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        match self.region(address) {
+            Ok(Region::A) => self.a.page_size_at(address),
+            Ok(Region::B) => self.b.page_size_at(address),
+            Err(_) => None,
+        }
+    }
+
+    fn is_write_protected(&self, address: u32, length: usize) -> bool {
+        match self.region(address) {
+            Ok(Region::A) => self.a.is_write_protected(address, length),
+            Ok(Region::B) => self.b.is_write_protected(address, length),
+            Err(_) => false,
+        }
+    }
+
+    fn is_blank(&mut self, address: u32, length: usize) -> Result<bool, DFUMemError> {
+        match self.region(address) {
+            Ok(Region::A) => self.a.is_blank(address, length),
+            Ok(Region::B) => self.b.is_blank(address, length),
+            Err(_) => Ok(true),
+        }
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        match self.region(address)? {
+            Region::A => self.a.erase(address),
+            Region::B => self.b.erase(address),
+        }
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.erasing_b = false;
+        self.a.erase_all()?;
+        self.b.erase_all()
+    }
+
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        if !self.erasing_b {
+            return match self.a.erase_all_next()? {
+                EraseProgress::Busy(remaining) => Ok(EraseProgress::Busy(
+                    remaining.saturating_add(B::FULL_ERASE_TIME_MS),
+                )),
+                EraseProgress::Done => {
+                    self.erasing_b = true;
+                    Ok(EraseProgress::Busy(B::FULL_ERASE_TIME_MS))
+                }
+            };
+        }
+        let progress = self.b.erase_all_next();
+        if !matches!(progress, Ok(EraseProgress::Busy(_))) {
+            self.erasing_b = false;
+        }
+        progress
+    }
+
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        self.a.read_unprotect()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.a.manifestation()?;
+        self.b.manifestation()
+    }
+
+    fn manifestation_start(&mut self) -> Result<(), DFUManifestationError> {
+        self.manifesting = None;
+        self.a.manifestation_start()?;
+        self.manifesting = Some(Region::A);
+        Ok(())
+    }
+
+    fn manifestation_poll(&mut self) -> ManifestationProgress {
+        match self.manifesting {
+            Some(Region::A) => match self.a.manifestation_poll() {
+                ManifestationProgress::Busy(remaining) => {
+                    ManifestationProgress::Busy(remaining.saturating_add(B::MANIFESTATION_TIME_MS))
+                }
+                ManifestationProgress::Done => {
+                    self.manifesting = None;
+                    if let Err(e) = self.b.manifestation_start() {
+                        return ManifestationProgress::Err(e);
+                    }
+                    self.manifesting = Some(Region::B);
+                    self.manifestation_poll()
+                }
+                ManifestationProgress::Err(e) => {
+                    self.manifesting = None;
+                    ManifestationProgress::Err(e)
+                }
+            },
+            Some(Region::B) => {
+                let progress = self.b.manifestation_poll();
+                if !matches!(progress, ManifestationProgress::Busy(_)) {
+                    self.manifesting = None;
+                }
+                progress
+            }
+            None => ManifestationProgress::Done,
+        }
+    }
+
+    fn manifestation_allowed(&mut self) -> Result<(), DFUManifestationError> {
+        self.a.manifestation_allowed()?;
+        self.b.manifestation_allowed()
+    }
+
+    fn validate_suffix(&mut self, suffix: Option<DfuSuffix>) -> Result<(), DFUManifestationError> {
+        if A::CHECK_SUFFIX {
+            self.a.validate_suffix(suffix)?;
+        }
+        if B::CHECK_SUFFIX {
+            self.b.validate_suffix(suffix)?;
+        }
+        Ok(())
+    }
+
+    fn usb_reset(&mut self) {
+        self.b.usb_reset();
+        // may not return
+        self.a.usb_reset()
+    }
+
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        self.erasing_b = false;
+        self.b.on_usb_reset(ctx);
+        // may not return
+        self.a.on_usb_reset(ctx)
+    }
+
+    fn on_manifest_reset(&mut self) {
+        self.b.on_manifest_reset();
+        // may not return
+        self.a.on_manifest_reset()
+    }
+
+    fn locate_image(&mut self, header: &[u8]) -> Result<u32, DFUMemError> {
+        self.a.locate_image(header)
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        self.a.now_ms()
+    }
+
+    fn profile_ticks(&mut self) -> u32 {
+        self.a.profile_ticks()
+    }
+
+    fn on_abort(&mut self) {
+        self.a.on_abort();
+        self.b.on_abort()
+    }
+
+    fn on_configured(&mut self) {
+        self.a.on_configured();
+        self.b.on_configured()
+    }
+
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        self.a.on_detach_request(timeout_ms)
+    }
+
+    fn on_detach_expired(&mut self) {
+        self.a.on_detach_expired()
+    }
+
+    fn on_progress(&mut self, progress: DfuProgress) {
+        self.a.on_progress(progress)
+    }
+
+    fn on_state_change(&mut self, old: DFUState, new: DFUState, status: DFUStatusCode) {
+        self.a.on_state_change(old, new, status)
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        self.a.operation_busy() || self.b.operation_busy()
+    }
+
+    fn operation_result(&mut self) -> Result<(), DFUMemError> {
+        let a = self.a.operation_result();
+        let b = self.b.operation_result();
+        a.and(b)
+    }
+
+    fn on_error(&mut self) {
+        self.a.on_error();
+        self.b.on_error()
+    }
+
+    fn persist_lockout(&mut self, failures: Option<u8>) -> u8 {
+        self.a.persist_lockout(failures)
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.layout.as_str()
+    }
+
+    fn initial_address_pointer(&self) -> u32 {
+        self.a.initial_address_pointer()
+    }
+
+    fn vendor_error_string(&self) -> Option<&str> {
+        self.a
+            .vendor_error_string()
+            .or(self.b.vendor_error_string())
+    }
+
+    fn vendor_commands(&self) -> &[u8] {
+        self.a.vendor_commands()
+    }
+
+    fn vendor_dnload_command(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+    ) -> Result<VendorCommandOutcome, DFUMemError> {
+        self.a.vendor_dnload_command(cmd, payload)
+    }
+
+    fn vendor_command_execute(&mut self, cmd: u8) -> Result<(), DFUMemError> {
+        self.a.vendor_command_execute(cmd)
+    }
+
+    fn device_info(&mut self, buf: &mut [u8]) -> usize {
+        self.a.device_info(buf)
+    }
+}
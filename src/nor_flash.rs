@@ -0,0 +1,182 @@
+use crate::class::{DFUManifestationError, DFUMemError, DFUMemIO};
+use crate::meminfo::{MemInfoString, Perms};
+use embedded_storage::nor_flash::{NorFlash, NorFlashError, NorFlashErrorKind};
+
+const BUFFER_SIZE: usize = 128;
+
+/// [`DFUMemIO`] implementation for a flash driver that implements
+/// `embedded_storage::nor_flash::NorFlash`.
+///
+/// DfuSe address `BASE + offset` is flash `offset`, addresses outside of
+/// `BASE..BASE + capacity()` are rejected with [`DFUMemError::Address`].
+///
+/// * Uploads read with `ReadNorFlash::read()`.
+/// * Erase requests erase a `T::ERASE_SIZE` page that contains the address,
+///   full erase erases all pages.
+/// * Downloads are written with `NorFlash::write()`. If block length is not a multiple
+///   of `T::WRITE_SIZE`, the last write is padded with `0xff`.
+///
+/// Memory layout string is built from `BASE`, `capacity()`, and `T::ERASE_SIZE`, all
+/// pages are readable, erasable, and writable, for example, `@Flash/0x08000000/64*2Kg`.
+/// [`manifestation()`](DFUMemIO::manifestation) does nothing.
+///
+/// Flash errors are reported as:
+///
+/// * `OutOfBounds` - [`DFUMemError::Address`],
+/// * `NotAligned` - [`DFUMemError::Write`],
+/// * other errors - [`DFUMemError::Erase`] or [`DFUMemError::Prog`], depending on the
+///   operation, or [`DFUMemError::Unknown`] when reading.
+///
+/// `T::WRITE_SIZE` must not be larger than 128 bytes, this is checked at compile time.
+pub struct NorFlashMemIO<T: NorFlash, const BASE: u32> {
+    flash: T,
+    layout: MemInfoString<48>,
+    buffer: [u8; BUFFER_SIZE],
+}
+
+impl<T: NorFlash, const BASE: u32> NorFlashMemIO<T, BASE> {
+    const WRITE_SIZE_OK: () = assert!(
+        T::WRITE_SIZE > 0 && T::WRITE_SIZE <= BUFFER_SIZE,
+        "NorFlash::WRITE_SIZE is larger than NorFlashMemIO buffer"
+    );
+
+    /// Creates a new `NorFlashMemIO` with `flash` mapped at `BASE`.
+    pub fn new(flash: T) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::WRITE_SIZE_OK;
+
+        let pages = flash.capacity() / T::ERASE_SIZE;
+        let mut layout = MemInfoString::new();
+        // fits: 18 bytes of region and two u32 numbers
+        let _ = layout
+            .region("Flash", BASE)
+            .and_then(|s| s.area(pages as u32, T::ERASE_SIZE as u32, Perms::RWE));
+
+        Self {
+            flash,
+            layout,
+            buffer: [0xff; BUFFER_SIZE],
+        }
+    }
+
+    /// Returns a reference to the flash driver.
+    pub fn flash(&self) -> &T {
+        &self.flash
+    }
+
+    /// Returns a mutable reference to the flash driver.
+    pub fn flash_mut(&mut self) -> &mut T {
+        &mut self.flash
+    }
+
+    /// Consumes `NorFlashMemIO` and returns the flash driver.
+    pub fn into_inner(self) -> T {
+        self.flash
+    }
+
+    /// Translate `address` and `length` to a flash offset.
+    pub fn offset(&self, address: u32, length: usize) -> Result<u32, DFUMemError> {
+        let offset = address.checked_sub(BASE).ok_or(DFUMemError::Address)?;
+        let end = (offset as usize)
+            .checked_add(length)
+            .ok_or(DFUMemError::Address)?;
+        if end > self.flash.capacity() {
+            return Err(DFUMemError::Address);
+        }
+        Ok(offset)
+    }
+
+    /// Write the first `length` bytes of the buffer, padded to `T::WRITE_SIZE`.
+    fn write_buffer(&mut self, offset: u32, length: usize) -> Result<(), DFUMemError> {
+        let padded = length.next_multiple_of(T::WRITE_SIZE);
+        if padded > BUFFER_SIZE {
+            return Err(DFUMemError::Address);
+        }
+        self.buffer[length..padded].fill(0xff);
+        self.flash
+            .write(offset, &self.buffer[..padded])
+            .map_err(|e| mem_error(e, DFUMemError::Prog))
+    }
+}
+
+/// Convert a flash error, `other` is returned for implementation-specific errors.
+pub(crate) fn mem_error<E: NorFlashError>(e: E, other: DFUMemError) -> DFUMemError {
+    match e.kind() {
+        NorFlashErrorKind::OutOfBounds => DFUMemError::Address,
+        NorFlashErrorKind::NotAligned => DFUMemError::Write,
+        _ => other,
+    }
+}
+
+impl<T: NorFlash, const BASE: u32> DFUMemIO for NorFlashMemIO<T, BASE> {
+    const INITIAL_ADDRESS_POINTER: u32 = BASE;
+    // not used, the layout is returned by `mem_info_string()`
+    const MEM_INFO_STRING: &'static str = "@Flash";
+    const PROGRAM_TIME_MS: u32 = 5;
+    const ERASE_TIME_MS: u32 = 50;
+    const FULL_ERASE_TIME_MS: u32 = 1000;
+    const TRANSFER_SIZE: u16 = BUFFER_SIZE as u16;
+
+    fn mem_info_string(&self) -> &str {
+        self.layout.as_str()
+    }
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        let offset = self.offset(address, dest.len())?;
+        self.flash
+            .read(offset, dest)
+            .map_err(|e| mem_error(e, DFUMemError::Unknown))?;
+        Ok(dest.len())
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        if src.len() > BUFFER_SIZE {
+            return Err(());
+        }
+        self.buffer[..src.len()].copy_from_slice(src);
+        Ok(())
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        let offset = self.offset(address, length)?;
+        self.write_buffer(offset, length)
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        let offset = self.offset(address, data.len())?;
+
+        let aligned = data.len() - data.len() % T::WRITE_SIZE;
+        if aligned > 0 {
+            self.flash
+                .write(offset, &data[..aligned])
+                .map_err(|e| mem_error(e, DFUMemError::Prog))?;
+        }
+
+        let tail = &data[aligned..];
+        if tail.is_empty() {
+            return Ok(());
+        }
+        self.buffer[..tail.len()].copy_from_slice(tail);
+        self.write_buffer(offset + aligned as u32, tail.len())
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        let offset = self.offset(address, 1)?;
+        let from = offset - offset % T::ERASE_SIZE as u32;
+        self.flash
+            .erase(from, from + T::ERASE_SIZE as u32)
+            .map_err(|e| mem_error(e, DFUMemError::Erase))
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        let capacity = self.flash.capacity();
+        let to = capacity - capacity % T::ERASE_SIZE;
+        self.flash
+            .erase(0, to as u32)
+            .map_err(|e| mem_error(e, DFUMemError::Erase))
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,246 @@
+use crate::class::{DFUManifestationError, DFUMemError, DFUMemIO};
+use crate::meminfo::{MemInfoString, Perms};
+use crate::nor_flash::mem_error;
+use core::cell::{Cell, RefCell};
+use core::future::poll_fn;
+use core::marker::PhantomData;
+use core::task::{Poll, Waker};
+use embedded_storage_async::nor_flash::NorFlash;
+
+const BUFFER_SIZE: usize = 128;
+
+/// Flash operation queued by [`AsyncNorFlashMemIO`], offsets are flash offsets.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operation {
+    /// Erase flash from `from` to `to`
+    Erase {
+        /// Start offset, aligned to `ERASE_SIZE`
+        from: u32,
+        /// End offset, aligned to `ERASE_SIZE`
+        to: u32,
+    },
+    /// Write `length` bytes of the queue buffer at `offset`
+    Write {
+        /// Flash offset, not checked for `WRITE_SIZE` alignment
+        offset: u32,
+        /// Data length, padded to `WRITE_SIZE`
+        length: usize,
+    },
+}
+
+/// Operation queue shared by [`AsyncNorFlashMemIO`] and a task that runs
+/// [`run()`](NorFlashQueue::run).
+///
+/// The queue holds a single operation and a download buffer. It is not `Sync`,
+/// `usb_dev.poll()` and the executor must run in the same thread.
+pub struct NorFlashQueue {
+    op: Cell<Option<Operation>>,
+    result: Cell<Option<Result<(), DFUMemError>>>,
+    waker: Cell<Option<Waker>>,
+    buffer: RefCell<[u8; BUFFER_SIZE]>,
+}
+
+impl Default for NorFlashQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NorFlashQueue {
+    /// Creates an empty queue.
+    pub const fn new() -> Self {
+        Self {
+            op: Cell::new(None),
+            result: Cell::new(None),
+            waker: Cell::new(None),
+            buffer: RefCell::new([0xff; BUFFER_SIZE]),
+        }
+    }
+
+    /// Returns the queued or running operation.
+    pub fn pending(&self) -> Option<Operation> {
+        self.op.get()
+    }
+
+    /// Executes queued operations with `flash`, never completes.
+    ///
+    /// An operation stays queued until `flash` completes it, [`DFUClass`](crate::DFUClass)
+    /// reports `dfuDNBUSY` state meanwhile.
+    pub async fn run<T: NorFlash>(&self, flash: &mut T) -> ! {
+        loop {
+            let op = poll_fn(|cx| match self.op.get() {
+                Some(op) => Poll::Ready(op),
+                None => {
+                    self.waker.set(Some(cx.waker().clone()));
+                    Poll::Pending
+                }
+            })
+            .await;
+
+            let r = match op {
+                Operation::Erase { from, to } => flash
+                    .erase(from, to)
+                    .await
+                    .map_err(|e| mem_error(e, DFUMemError::Erase)),
+                Operation::Write { offset, length } => {
+                    // buffer can't stay borrowed while the flash is busy
+                    let data = *self.buffer.borrow();
+                    flash
+                        .write(offset, &data[..length])
+                        .await
+                        .map_err(|e| mem_error(e, DFUMemError::Prog))
+                }
+            };
+
+            self.result.set(Some(r));
+            self.op.set(None);
+        }
+    }
+
+    fn queue(&self, op: Operation) {
+        self.result.set(None);
+        self.op.set(Some(op));
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// [`DFUMemIO`] implementation for a flash driver that implements
+/// `embedded_storage_async::nor_flash::NorFlash`.
+///
+/// Program and erase requests are queued to a [`NorFlashQueue`] and return immediately,
+/// the operation is executed by [`NorFlashQueue::run()`] future which the application
+/// runs in its executor. Device stays in `dfuDNBUSY` state until the operation completes,
+/// see [`operation_busy()`](DFUMemIO::operation_busy), flash errors are reported by the
+/// following `DFU_GETSTATUS` request.
+///
+/// Addresses, layout string, and error conversion are the same as in
+/// [`NorFlashMemIO`](crate::nor_flash::NorFlashMemIO). Uploads are not supported,
+/// [`HAS_UPLOAD`](DFUMemIO::HAS_UPLOAD) is `false`, because the flash can't be read
+/// from a control request.
+///
+/// `T::WRITE_SIZE` must not be larger than 128 bytes, this is checked at compile time.
+pub struct AsyncNorFlashMemIO<'a, T: NorFlash, const BASE: u32> {
+    queue: &'a NorFlashQueue,
+    capacity: usize,
+    layout: MemInfoString<48>,
+    flash: PhantomData<T>,
+}
+
+impl<'a, T: NorFlash, const BASE: u32> AsyncNorFlashMemIO<'a, T, BASE> {
+    const WRITE_SIZE_OK: () = assert!(
+        T::WRITE_SIZE > 0 && T::WRITE_SIZE <= BUFFER_SIZE,
+        "NorFlash::WRITE_SIZE is larger than AsyncNorFlashMemIO buffer"
+    );
+
+    /// Creates a new `AsyncNorFlashMemIO` for `flash` mapped at `BASE`.
+    ///
+    /// `flash` is used only to get its capacity, it should be passed to
+    /// [`NorFlashQueue::run()`] later.
+    pub fn new(queue: &'a NorFlashQueue, flash: &T) -> Self {
+        #[allow(clippy::let_unit_value)]
+        let _ = Self::WRITE_SIZE_OK;
+
+        let capacity = flash.capacity();
+        let pages = capacity / T::ERASE_SIZE;
+        let mut layout = MemInfoString::new();
+        // fits: 18 bytes of region and two u32 numbers
+        let _ = layout
+            .region("Flash", BASE)
+            .and_then(|s| s.area(pages as u32, T::ERASE_SIZE as u32, Perms::RWE));
+
+        Self {
+            queue,
+            capacity,
+            layout,
+            flash: PhantomData,
+        }
+    }
+
+    /// Returns the operation queue.
+    pub fn queue(&self) -> &'a NorFlashQueue {
+        self.queue
+    }
+
+    /// Translate `address` and `length` to a flash offset.
+    pub fn offset(&self, address: u32, length: usize) -> Result<u32, DFUMemError> {
+        let offset = address.checked_sub(BASE).ok_or(DFUMemError::Address)?;
+        let end = (offset as usize)
+            .checked_add(length)
+            .ok_or(DFUMemError::Address)?;
+        if end > self.capacity {
+            return Err(DFUMemError::Address);
+        }
+        Ok(offset)
+    }
+}
+
+impl<T: NorFlash, const BASE: u32> DFUMemIO for AsyncNorFlashMemIO<'_, T, BASE> {
+    const INITIAL_ADDRESS_POINTER: u32 = BASE;
+    // not used, the layout is returned by `mem_info_string()`
+    const MEM_INFO_STRING: &'static str = "@Flash";
+    const HAS_UPLOAD: bool = false;
+    const PROGRAM_TIME_MS: u32 = 5;
+    const ERASE_TIME_MS: u32 = 50;
+    const FULL_ERASE_TIME_MS: u32 = 1000;
+    const TRANSFER_SIZE: u16 = BUFFER_SIZE as u16;
+
+    fn mem_info_string(&self) -> &str {
+        self.layout.as_str()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        if src.len() > BUFFER_SIZE {
+            return Err(());
+        }
+        self.queue.buffer.borrow_mut()[..src.len()].copy_from_slice(src);
+        Ok(())
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        let offset = self.offset(address, length)?;
+        let padded = length.next_multiple_of(T::WRITE_SIZE);
+        if padded > BUFFER_SIZE {
+            return Err(DFUMemError::Address);
+        }
+        self.queue.buffer.borrow_mut()[length..padded].fill(0xff);
+
+        self.queue.queue(Operation::Write {
+            offset,
+            length: padded,
+        });
+        Ok(())
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        let offset = self.offset(address, 1)?;
+        let from = offset - offset % T::ERASE_SIZE as u32;
+        self.queue.queue(Operation::Erase {
+            from,
+            to: from + T::ERASE_SIZE as u32,
+        });
+        Ok(())
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        let to = self.capacity - self.capacity % T::ERASE_SIZE;
+        self.queue.queue(Operation::Erase {
+            from: 0,
+            to: to as u32,
+        });
+        Ok(())
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        Ok(())
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        self.queue.op.get().is_some()
+    }
+
+    fn operation_result(&mut self) -> Result<(), DFUMemError> {
+        self.queue.result.take().unwrap_or(Ok(()))
+    }
+}
@@ -0,0 +1,37 @@
+/// Durations of calls of a single [`DFUClass`](crate::DFUClass) function.
+///
+/// Durations are in [`profile_ticks()`](crate::DFUMemIO::profile_ticks) units.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallStats {
+    /// Number of calls
+    pub count: u32,
+    /// Longest call
+    pub max: u32,
+    /// Sum of all calls, saturates at `u32::MAX`
+    pub total: u32,
+}
+
+impl CallStats {
+    pub(crate) fn record(&mut self, ticks: u32) {
+        self.count = self.count.saturating_add(1);
+        self.max = self.max.max(ticks);
+        self.total = self.total.saturating_add(ticks);
+    }
+}
+
+/// Time spent in [`DFUClass`](crate::DFUClass) functions called by `usb-device`,
+/// see [`DFUClass::profile()`](crate::DFUClass::profile).
+///
+/// `control_in` and `control_out` include all control requests passed to the class,
+/// not only DFU requests. Memory functions are called from `poll` and from
+/// control handlers, so these numbers show how long USB interrupt handler may be
+/// blocked by [`DFUMemIO`](crate::DFUMemIO) implementation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DfuProfile {
+    /// `UsbClass::control_in()` calls
+    pub control_in: CallStats,
+    /// `UsbClass::control_out()` calls
+    pub control_out: CallStats,
+    /// `UsbClass::poll()` calls
+    pub poll: CallStats,
+}
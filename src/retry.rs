@@ -0,0 +1,345 @@
+use crate::class::{
+    DFUManifestationError, DFUMemError, DFUMemIO, DFUState, DFUStatusCode, DfuProgress,
+    EraseProgress, ManifestationProgress, ResetContext, SegmentLimits, UsbResetPolicy,
+    VendorCommandOutcome,
+};
+use crate::suffix::DfuSuffix;
+use core::ops::Range;
+
+/// [`DFUMemIO`] wrapper that retries failed program and erase operations.
+///
+/// Some memories (for example, external flash chips) occasionally report
+/// a transient failure that succeeds when the operation is repeated.
+/// `RetryMem` repeats [`program()`](DFUMemIO::program) and [`erase()`](DFUMemIO::erase)
+/// up to `N` more times before the error is passed to [`DFUClass`](crate::DFUClass)
+/// and reported to the host.
+///
+/// [`DFUMemError::Address`] and [`DFUMemError::Target`] errors are never retried,
+/// repeating an operation with an invalid address will not make it valid.
+///
+/// All constants are forwarded from the wrapped implementation. Note that
+/// retries happen within the same `usb_dev.poll([])` call, a host only
+/// waits for [`PROGRAM_TIME_MS`](DFUMemIO::PROGRAM_TIME_MS) or
+/// [`ERASE_TIME_MS`](DFUMemIO::ERASE_TIME_MS) milliseconds.
+pub struct RetryMem<M: DFUMemIO, const N: u8> {
+    mem: M,
+    backoff: Option<fn(&mut M, u8)>,
+    retries: u32,
+}
+
+impl<M: DFUMemIO, const N: u8> RetryMem<M, N> {
+    /// Creates a new `RetryMem` wrapping `mem`.
+    pub fn new(mem: M) -> Self {
+        Self {
+            mem,
+            backoff: None,
+            retries: 0,
+        }
+    }
+
+    /// Creates a new `RetryMem` wrapping `mem` with a back-off function.
+    ///
+    /// `backoff` is called before every retry with a reference to the
+    /// wrapped memory and the number of the retry attempt, starting from `1`.
+    /// It may, for example, wait for some time or reset a flash controller.
+    pub fn with_backoff(mem: M, backoff: fn(&mut M, u8)) -> Self {
+        Self {
+            mem,
+            backoff: Some(backoff),
+            retries: 0,
+        }
+    }
+
+    /// Total number of retries performed since creation or
+    /// the last [`reset_retries()`](RetryMem::reset_retries) call.
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Reset retry counter.
+    pub fn reset_retries(&mut self) {
+        self.retries = 0;
+    }
+
+    /// Returns a reference to the wrapped memory.
+    pub fn inner(&self) -> &M {
+        &self.mem
+    }
+
+    /// Returns a mutable reference to the wrapped memory.
+    pub fn inner_mut(&mut self) -> &mut M {
+        &mut self.mem
+    }
+
+    /// Consumes `RetryMem` and returns the wrapped memory.
+    pub fn into_inner(self) -> M {
+        self.mem
+    }
+
+    fn retry(
+        &mut self,
+        mut op: impl FnMut(&mut M) -> Result<(), DFUMemError>,
+    ) -> Result<(), DFUMemError> {
+        let mut attempt = 0;
+        loop {
+            match op(&mut self.mem) {
+                Err(DFUMemError::Address) => return Err(DFUMemError::Address),
+                Err(DFUMemError::Target) => return Err(DFUMemError::Target),
+                Err(e) if attempt >= N => return Err(e),
+                Err(_) => {
+                    attempt += 1;
+                    self.retries = self.retries.wrapping_add(1);
+                    if let Some(backoff) = self.backoff {
+                        backoff(&mut self.mem, attempt);
+                    }
+                }
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+}
+
+impl<M: DFUMemIO, const N: u8> DFUMemIO for RetryMem<M, N> {
+    const INITIAL_ADDRESS_POINTER: u32 = M::INITIAL_ADDRESS_POINTER;
+    const MEM_INFO_STRING: &'static str = M::MEM_INFO_STRING;
+    const ALT_COUNT: u8 = M::ALT_COUNT;
+    const HAS_DOWNLOAD: bool = M::HAS_DOWNLOAD;
+    const HAS_UPLOAD: bool = M::HAS_UPLOAD;
+    const MANIFESTATION_TOLERANT: bool = M::MANIFESTATION_TOLERANT;
+    const WILL_DETACH: bool = M::WILL_DETACH;
+    const HAS_READ_UNPROTECT: bool = M::HAS_READ_UNPROTECT;
+    const HAS_CRC_COMMAND: bool = M::HAS_CRC_COMMAND;
+    const HAS_DEVICE_INFO: bool = M::HAS_DEVICE_INFO;
+    const PROGRAM_TIME_MS: u32 = M::PROGRAM_TIME_MS;
+    const ERASE_TIME_MS: u32 = M::ERASE_TIME_MS;
+    const FULL_ERASE_TIME_MS: u32 = M::FULL_ERASE_TIME_MS;
+    const READ_UNPROTECT_TIME_MS: u32 = M::READ_UNPROTECT_TIME_MS;
+    const MANIFESTATION_TIME_MS: u32 = M::MANIFESTATION_TIME_MS;
+    const DETACH_TIMEOUT: u16 = M::DETACH_TIMEOUT;
+    const TRANSFER_SIZE: u16 = M::TRANSFER_SIZE;
+    const DFU_VERSION: u16 = M::DFU_VERSION;
+    const FIRST_DATA_BLOCK: u16 = M::FIRST_DATA_BLOCK;
+    const REDACTED_RANGES: &'static [Range<u32>] = M::REDACTED_RANGES;
+    const REDACTED_FILL: u8 = M::REDACTED_FILL;
+    const BLOCK_CRC: bool = M::BLOCK_CRC;
+    const VERIFY_AFTER_PROGRAM: bool = M::VERIFY_AFTER_PROGRAM;
+    const IMAGE_HEADER_SIZE: usize = M::IMAGE_HEADER_SIZE;
+    const PROGRAM_IMAGE_HEADER: bool = M::PROGRAM_IMAGE_HEADER;
+    const ERROR_AUTOCLEAR_MS: u32 = M::ERROR_AUTOCLEAR_MS;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = M::LAYOUT_SEGMENTS;
+    const SEGMENT_LIMITS: &'static [SegmentLimits] = M::SEGMENT_LIMITS;
+    const OPERATION_BUDGET_FACTOR: u32 = M::OPERATION_BUDGET_FACTOR;
+    const MANIFESTATION_CONSTANT_TIME: bool = M::MANIFESTATION_CONSTANT_TIME;
+    const MAX_FAILED_MANIFESTATIONS: u8 = M::MAX_FAILED_MANIFESTATIONS;
+    const IMAGE_MAGIC: Option<(usize, &'static [u8])> = M::IMAGE_MAGIC;
+    const CHECK_SUFFIX: bool = M::CHECK_SUFFIX;
+    const ALLOW_UPLOAD_DURING_DNLOAD_IDLE: bool = M::ALLOW_UPLOAD_DURING_DNLOAD_IDLE;
+    const ADVANCE_ON_GETSTATE: bool = M::ADVANCE_ON_GETSTATE;
+    const ON_USB_RESET: UsbResetPolicy = M::ON_USB_RESET;
+    const HAS_VENDOR_ERROR_STRING: bool = M::HAS_VENDOR_ERROR_STRING;
+    const MEMIO_IN_USB_INTERRUPT: bool = M::MEMIO_IN_USB_INTERRUPT;
+    const READ_CHUNK_SIZE: usize = M::READ_CHUNK_SIZE;
+    const CHECK_PERMISSIONS: bool = M::CHECK_PERMISSIONS;
+    const WINUSB_VENDOR_CODE: u8 = M::WINUSB_VENDOR_CODE;
+    const WINUSB_GUID: &'static str = M::WINUSB_GUID;
+    const WINUSB_COMPOSITE: bool = M::WINUSB_COMPOSITE;
+    const WEBUSB_VENDOR_CODE: u8 = M::WEBUSB_VENDOR_CODE;
+    const WEBUSB_LANDING_PAGE: Option<&'static str> = M::WEBUSB_LANDING_PAGE;
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        self.mem.read_block(address, dest)
+    }
+
+    fn read_chunk(
+        &mut self,
+        address: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DFUMemError> {
+        self.mem.read_chunk(address, offset, buf)
+    }
+
+    fn is_end_of_region(&self, address: u32) -> bool {
+        self.mem.is_end_of_region(address)
+    }
+
+    fn upload_end_address(&self) -> Option<u32> {
+        self.mem.upload_end_address()
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.retry(|mem| mem.program(address, length))
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        self.retry(|mem| mem.program_block(address, data))
+    }
+
+    fn program_time_ms(&self, length: usize) -> u32 {
+        self.mem.program_time_ms(length)
+    }
+
+    fn erase_time_ms(&self, address: u32) -> u32 {
+        self.mem.erase_time_ms(address)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn is_write_protected(&self, address: u32, length: usize) -> bool {
+        self.mem.is_write_protected(address, length)
+    }
+
+    fn is_blank(&mut self, address: u32, length: usize) -> Result<bool, DFUMemError> {
+        self.mem.is_blank(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.retry(|mem| mem.erase(address))
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        self.mem.erase_all_next()
+    }
+
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        self.mem.read_unprotect()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn manifestation_start(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation_start()
+    }
+
+    fn manifestation_poll(&mut self) -> ManifestationProgress {
+        self.mem.manifestation_poll()
+    }
+
+    fn manifestation_allowed(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation_allowed()
+    }
+
+    fn validate_suffix(&mut self, suffix: Option<DfuSuffix>) -> Result<(), DFUManifestationError> {
+        self.mem.validate_suffix(suffix)
+    }
+
+    fn usb_reset(&mut self) {
+        self.mem.usb_reset()
+    }
+
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        self.mem.on_usb_reset(ctx)
+    }
+
+    fn on_manifest_reset(&mut self) {
+        self.mem.on_manifest_reset()
+    }
+
+    fn locate_image(&mut self, header: &[u8]) -> Result<u32, DFUMemError> {
+        self.mem.locate_image(header)
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        self.mem.now_ms()
+    }
+
+    fn profile_ticks(&mut self) -> u32 {
+        self.mem.profile_ticks()
+    }
+
+    fn on_abort(&mut self) {
+        self.mem.on_abort()
+    }
+
+    fn on_configured(&mut self) {
+        self.mem.on_configured()
+    }
+
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        self.mem.on_detach_request(timeout_ms)
+    }
+
+    fn on_detach_expired(&mut self) {
+        self.mem.on_detach_expired()
+    }
+
+    fn on_progress(&mut self, progress: DfuProgress) {
+        self.mem.on_progress(progress)
+    }
+
+    fn on_state_change(&mut self, old: DFUState, new: DFUState, status: DFUStatusCode) {
+        self.mem.on_state_change(old, new, status)
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        self.mem.operation_busy()
+    }
+
+    fn operation_result(&mut self) -> Result<(), DFUMemError> {
+        self.mem.operation_result()
+    }
+
+    fn on_error(&mut self) {
+        self.mem.on_error()
+    }
+
+    fn persist_lockout(&mut self, failures: Option<u8>) -> u8 {
+        self.mem.persist_lockout(failures)
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.mem.mem_info_string()
+    }
+
+    fn initial_address_pointer(&self) -> u32 {
+        self.mem.initial_address_pointer()
+    }
+
+    fn mem_info_string_for(&self, alt: u8) -> &str {
+        self.mem.mem_info_string_for(alt)
+    }
+
+    fn select_alt(&mut self, alt: u8) {
+        self.mem.select_alt(alt)
+    }
+
+    fn vendor_error_string(&self) -> Option<&str> {
+        self.mem.vendor_error_string()
+    }
+
+    fn vendor_commands(&self) -> &[u8] {
+        self.mem.vendor_commands()
+    }
+
+    fn vendor_dnload_command(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+    ) -> Result<VendorCommandOutcome, DFUMemError> {
+        self.mem.vendor_dnload_command(cmd, payload)
+    }
+
+    fn vendor_command_execute(&mut self, cmd: u8) -> Result<(), DFUMemError> {
+        self.mem.vendor_command_execute(cmd)
+    }
+
+    fn device_info(&mut self, buf: &mut [u8]) -> usize {
+        self.mem.device_info(buf)
+    }
+}
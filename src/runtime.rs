@@ -0,0 +1,252 @@
+use crate::class::{
+    functional_descriptor, DFUState, DFUStatusCode, DESC_DESCTYPE_DFU, DFU_DETACH, DFU_GETSTATE,
+    DFU_GETSTATUS, DFU_VERSION_DFUSE, USB_CLASS_APPLICATION_SPECIFIC, USB_PROTOCOL_RUN_TIME,
+    USB_SUBCLASS_DFU,
+};
+use core::cmp::min;
+use core::marker::PhantomData;
+use usb_device::{class_prelude::*, control::Request};
+
+/// Trait that describes DFU features of a device for the application firmware,
+/// used by [`DFURuntimeClass`].
+///
+/// Constants have the same meaning as [`DFUMemIO`](crate::DFUMemIO) ones, and should have
+/// the same values as used by a bootloader: DFU Functional descriptor reported in
+/// run-time mode must match the DFU mode descriptor.
+pub trait DFURuntime {
+    /// If set, DFU descriptor will have *bitCanDnload* bit set. Default is `true`.
+    ///
+    /// See [`DFUMemIO::HAS_DOWNLOAD`](crate::DFUMemIO::HAS_DOWNLOAD).
+    const HAS_DOWNLOAD: bool = true;
+
+    /// If set, DFU descriptor will have *bitCanUpload* bit set. Default is `true`.
+    ///
+    /// See [`DFUMemIO::HAS_UPLOAD`](crate::DFUMemIO::HAS_UPLOAD).
+    const HAS_UPLOAD: bool = true;
+
+    /// If set, DFU descriptor will have *bitManifestationTolerant* bit set. Default is `true`.
+    ///
+    /// See [`DFUMemIO::MANIFESTATION_TOLERANT`](crate::DFUMemIO::MANIFESTATION_TOLERANT).
+    const MANIFESTATION_TOLERANT: bool = true;
+
+    /// If set, DFU descriptor will have *bitWillDetach* bit set. Default is `true`.
+    ///
+    /// Should be set if [`detach()`](DFURuntime::detach) resets the device into
+    /// DFU mode, so a host does not need to reset USB bus.
+    const WILL_DETACH: bool = true;
+
+    /// wDetachTimeOut field in DFU descriptor. Default value: `250` ms.
+    ///
+    /// Time in milliseconds that device will wait after receipt of `DFU_DETACH` request
+    /// if USB reset request is not received before reverting to a normal operation.
+    /// A shorter `wTimeout` of the request is used if a host sends one.
+    const DETACH_TIMEOUT: u16 = 250;
+
+    /// wTransferSize field in DFU descriptor. Default value: `128` bytes.
+    ///
+    /// See [`DFUMemIO::TRANSFER_SIZE`](crate::DFUMemIO::TRANSFER_SIZE).
+    const TRANSFER_SIZE: u16 = 128;
+
+    /// bcdDFUVersion field in DFU descriptor. Default is
+    /// [`DFU_VERSION_DFUSE`].
+    ///
+    /// See [`DFUMemIO::DFU_VERSION`](crate::DFUMemIO::DFU_VERSION).
+    const DFU_VERSION: u16 = DFU_VERSION_DFUSE;
+
+    /// Called when a host sends `DFU_DETACH` request, after the request is accepted
+    /// and the device is in `appDETACH` state.
+    ///
+    /// Usually the implementation sets a flag for a bootloader to start DFU mode,
+    /// and, if [`WILL_DETACH`](DFURuntime::WILL_DETACH) is set, resets the device,
+    /// in this case this function should not return.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn detach(&mut self);
+
+    /// Called when USB is reset in `appDETACH` state, a host expects the device
+    /// to enter DFU mode.
+    ///
+    /// The implementation should reset the device into a bootloader and this function
+    /// should not return. If it returns, the device stays in `appIDLE` state.
+    /// Default implementation does nothing.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn usb_reset(&mut self) {}
+
+    /// Returns the current time in milliseconds.
+    ///
+    /// The value should increase monotonically and may wrap around. Used to return
+    /// to `appIDLE` state after detach timeout. Default implementation returns `0`,
+    /// the device stays in `appDETACH` state until USB reset.
+    ///
+    /// This function is called from `usb_dev.poll([])` (USB interrupt context).
+    ///
+    fn now_ms(&mut self) -> u32 {
+        0
+    }
+}
+
+/// DFU run-time mode USB class implementation for usb-device library.
+///
+/// Adds DFU interface to the application firmware, so a host can switch the device
+/// into DFU mode with `DFU_DETACH` request, for example, with `dfu-util -e`.
+/// The interface reports `appIDLE` state, and `appDETACH` after `DFU_DETACH` request
+/// until the timeout expires or USB is reset.
+pub struct DFURuntimeClass<B: UsbBus, R: DFURuntime> {
+    if_num: InterfaceNumber,
+    state: DFUState,
+    /// Timeout and receipt time of `DFU_DETACH` request in `appDETACH` state
+    detach: Option<(u16, u32)>,
+    _bus: PhantomData<B>,
+    runtime: R,
+}
+
+impl<B: UsbBus, R: DFURuntime> UsbClass<B> for DFURuntimeClass<B, R> {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(
+            self.if_num,
+            USB_CLASS_APPLICATION_SPECIFIC,
+            USB_SUBCLASS_DFU,
+            USB_PROTOCOL_RUN_TIME,
+        )?;
+
+        writer.write(
+            DESC_DESCTYPE_DFU,
+            &functional_descriptor(
+                R::WILL_DETACH,
+                R::MANIFESTATION_TOLERANT,
+                R::HAS_UPLOAD,
+                R::HAS_DOWNLOAD,
+                R::DETACH_TIMEOUT,
+                R::TRANSFER_SIZE,
+                R::DFU_VERSION,
+            ),
+        )
+    }
+
+    fn reset(&mut self) {
+        if self.state == DFUState::AppDetach {
+            // may not return
+            self.runtime.usb_reset();
+        }
+        self.state = DFUState::AppIdle;
+        self.detach = None;
+    }
+
+    fn poll(&mut self) {
+        self.check_detach_timeout();
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<B>) {
+        let req = *xfer.request();
+
+        if !self.is_dfu_request(&req) {
+            return;
+        }
+
+        self.check_detach_timeout();
+
+        match req.request {
+            DFU_GETSTATUS if req.length >= 6 => {
+                let v = [
+                    // bStatus
+                    DFUStatusCode::OK as u8,
+                    // bwPollTimeout
+                    0,
+                    0,
+                    0,
+                    // bState
+                    self.state as u8,
+                    // iString
+                    0,
+                ];
+                xfer.accept_with(&v).ok();
+            }
+            DFU_GETSTATE if req.length > 0 => {
+                xfer.accept_with(&[self.state as u8]).ok();
+            }
+            _ => {
+                self.stall();
+                xfer.reject().ok();
+            }
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let req = *xfer.request();
+
+        if !self.is_dfu_request(&req) {
+            return;
+        }
+
+        self.check_detach_timeout();
+
+        match req.request {
+            DFU_DETACH if self.state == DFUState::AppIdle => {
+                let timeout = min(req.value, R::DETACH_TIMEOUT);
+                self.state = DFUState::AppDetach;
+                self.detach = Some((timeout, self.runtime.now_ms()));
+                xfer.accept().ok();
+                // may not return
+                self.runtime.detach();
+            }
+            _ => {
+                self.stall();
+                xfer.reject().ok();
+            }
+        }
+    }
+}
+
+impl<B: UsbBus, R: DFURuntime> DFURuntimeClass<B, R> {
+    /// Creates a new DFURuntimeClass with the provided UsbBus and DFURuntime.
+    pub fn new(alloc: &UsbBusAllocator<B>, runtime: R) -> Self {
+        Self {
+            if_num: alloc.interface(),
+            state: DFUState::AppIdle,
+            detach: None,
+            _bus: PhantomData,
+            runtime,
+        }
+    }
+
+    /// This function will consume self and return the owned runtime
+    /// argument that was moved in the call to new()
+    pub fn release(self) -> R {
+        self.runtime
+    }
+
+    /// Returns `true` in `appDETACH` state: a host has sent `DFU_DETACH` request, and
+    /// neither USB reset happened nor the timeout has expired yet.
+    ///
+    /// The timeout is checked from `usb_dev.poll([])`.
+    pub fn detach_pending(&self) -> bool {
+        self.state == DFUState::AppDetach
+    }
+
+    fn is_dfu_request(&self, req: &Request) -> bool {
+        req.request_type == control::RequestType::Class
+            && req.recipient == control::Recipient::Interface
+            && req.index == u8::from(self.if_num) as u16
+    }
+
+    /// Unexpected request, `appDETACH` state is left
+    fn stall(&mut self) {
+        self.state = DFUState::AppIdle;
+        self.detach = None;
+    }
+
+    fn check_detach_timeout(&mut self) {
+        if let Some((timeout, since)) = self.detach {
+            if self.runtime.now_ms().wrapping_sub(since) >= timeout as u32 {
+                self.state = DFUState::AppIdle;
+                self.detach = None;
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+use crate::class::{DFUManifestationError, DFUMemError, DFUMemIO};
+use core::ops::Range;
+
+/// Start address of flash bank 1.
+pub const BANK1_BASE: u32 = 0x0800_0000;
+
+/// Start address of flash bank 2.
+pub const BANK2_BASE: u32 = 0x0810_0000;
+
+/// Size of a flash sector, the smallest erasable region.
+pub const SECTOR_SIZE: u32 = 128 * 1024;
+
+/// Flash is programmed in 256-bit flash words.
+pub const FLASH_WORD_SIZE: usize = 32;
+
+const BUFFER_SIZE: usize = 128;
+
+/// Flash bank
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bank {
+    /// Bank 1
+    Bank1,
+    /// Bank 2
+    Bank2,
+}
+
+/// Access to STM32H7 flash interface registers.
+///
+/// Implementation performs register writes and waits for `QW` and `BSY` flags
+/// where necessary, [`Stm32h7Flash`] decides what to program and erase.
+pub trait H7Flash {
+    /// Size of a single bank in bytes, a multiple of [`SECTOR_SIZE`].
+    const BANK_SIZE: u32;
+
+    /// `true` if the device has bank 2.
+    const DUAL_BANK: bool;
+
+    /// Memory layout, see [`DFUMemIO::MEM_INFO_STRING`].
+    const MEM_INFO_STRING: &'static str;
+
+    /// Unlock `FLASH_CR1` and `FLASH_CR2` (`FLASH_KEYR1`, `FLASH_KEYR2` sequence).
+    fn unlock(&mut self);
+
+    /// Lock `FLASH_CR1` and `FLASH_CR2`.
+    fn lock(&mut self);
+
+    /// Erase a sector, `sector` is a sector number within a `bank` (`SER`, `SNB`).
+    fn erase_sector(&mut self, bank: Bank, sector: u8) -> Result<(), DFUMemError>;
+
+    /// Erase a whole bank (`BER`).
+    fn erase_bank(&mut self, bank: Bank) -> Result<(), DFUMemError>;
+
+    /// Program a flash word at `address` (`PG`), `address` is 32 byte aligned.
+    fn program_flash_word(
+        &mut self,
+        address: u32,
+        data: &[u8; FLASH_WORD_SIZE],
+    ) -> Result<(), DFUMemError>;
+
+    /// Read memory at `address`.
+    fn read(&mut self, address: u32, length: usize) -> &[u8];
+}
+
+/// [`DFUMemIO`] implementation for STM32H7 flash.
+///
+/// H7 flash is programmed in aligned 32-byte flash words with ECC, a flash word
+/// can be programmed only once after erase. `Stm32h7Flash` collects bytes of
+/// download blocks into flash words and programs a word when its last byte is
+/// received, so blocks do not have to be aligned. Missing bytes of a word are
+/// filled with `0xff`, the last incomplete word is programmed at manifestation.
+/// Programming a word that is not erased fails with `errCHECK_ERASED`.
+///
+/// Erase requests erase a whole 128 KB sector that contains the address,
+/// [`ERASE_TIME_MS`](DFUMemIO::ERASE_TIME_MS) is a sector erase time. Full erase erases
+/// all banks.
+///
+/// Sequential transfers skip a gap between banks with [`LAYOUT_SEGMENTS`](DFUMemIO::LAYOUT_SEGMENTS).
+pub struct Stm32h7Flash<F: H7Flash> {
+    flash: F,
+    buffer: [u8; BUFFER_SIZE],
+    word: [u8; FLASH_WORD_SIZE],
+    word_address: Option<u32>,
+}
+
+impl<F: H7Flash> Stm32h7Flash<F> {
+    /// Creates a new `Stm32h7Flash`.
+    pub fn new(flash: F) -> Self {
+        Self {
+            flash,
+            buffer: [0xff; BUFFER_SIZE],
+            word: [0xff; FLASH_WORD_SIZE],
+            word_address: None,
+        }
+    }
+
+    /// Returns a reference to the flash interface.
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Consumes `Stm32h7Flash` and returns the flash interface.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Returns the address of a partially received flash word, if there is one.
+    pub fn pending_word(&self) -> Option<u32> {
+        self.word_address
+    }
+
+    /// Resolve `address` to a bank and a sector number within the bank.
+    pub fn sector(address: u32) -> Result<(Bank, u8), DFUMemError> {
+        let (bank, offset) = if address >= BANK1_BASE && address - BANK1_BASE < F::BANK_SIZE {
+            (Bank::Bank1, address - BANK1_BASE)
+        } else if F::DUAL_BANK && address >= BANK2_BASE && address - BANK2_BASE < F::BANK_SIZE {
+            (Bank::Bank2, address - BANK2_BASE)
+        } else {
+            return Err(DFUMemError::Address);
+        };
+        Ok((bank, (offset / SECTOR_SIZE) as u8))
+    }
+
+    fn check_range(address: u32, length: usize) -> Result<(), DFUMemError> {
+        let end = address
+            .checked_add(length as u32)
+            .ok_or(DFUMemError::Address)?;
+        if Self::LAYOUT_SEGMENTS
+            .iter()
+            .any(|r| address >= r.start && end <= r.end)
+        {
+            Ok(())
+        } else {
+            Err(DFUMemError::Address)
+        }
+    }
+
+    fn discard_word(&mut self) {
+        self.word_address = None;
+        self.word = [0xff; FLASH_WORD_SIZE];
+    }
+
+    fn flush_word(&mut self) -> Result<(), DFUMemError> {
+        let address = match self.word_address.take() {
+            Some(address) => address,
+            None => return Ok(()),
+        };
+
+        let word = self.word;
+        self.word = [0xff; FLASH_WORD_SIZE];
+
+        // ECC does not allow to program a flash word twice
+        if self
+            .flash
+            .read(address, FLASH_WORD_SIZE)
+            .iter()
+            .any(|b| *b != 0xff)
+        {
+            return Err(DFUMemError::CheckErased);
+        }
+
+        self.flash.program_flash_word(address, &word)
+    }
+
+    fn program_bytes(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        for i in 0..length {
+            let byte_address = address + i as u32;
+            let word_address = byte_address & !(FLASH_WORD_SIZE as u32 - 1);
+            let offset = (byte_address - word_address) as usize;
+
+            if self.word_address != Some(word_address) {
+                self.flush_word()?;
+                self.word_address = Some(word_address);
+            }
+
+            self.word[offset] = self.buffer[i];
+            if offset == FLASH_WORD_SIZE - 1 {
+                self.flush_word()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<F: H7Flash> DFUMemIO for Stm32h7Flash<F> {
+    const INITIAL_ADDRESS_POINTER: u32 = BANK1_BASE;
+    const MEM_INFO_STRING: &'static str = F::MEM_INFO_STRING;
+    const PROGRAM_TIME_MS: u32 = 2;
+    const ERASE_TIME_MS: u32 = 2000;
+    const FULL_ERASE_TIME_MS: u32 = 8000;
+    const MANIFESTATION_TIME_MS: u32 = 2;
+    const TRANSFER_SIZE: u16 = BUFFER_SIZE as u16;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = if F::DUAL_BANK {
+        &[
+            BANK1_BASE..BANK1_BASE + F::BANK_SIZE,
+            BANK2_BASE..BANK2_BASE + F::BANK_SIZE,
+        ]
+    } else {
+        #[allow(clippy::single_range_in_vec_init)]
+        &[BANK1_BASE..BANK1_BASE + F::BANK_SIZE]
+    };
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        if src.len() > BUFFER_SIZE {
+            return Err(());
+        }
+        self.buffer[..src.len()].copy_from_slice(src);
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        Self::check_range(address, length)?;
+        Ok(self.flash.read(address, length))
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        if length > BUFFER_SIZE {
+            return Err(DFUMemError::Address);
+        }
+        Self::check_range(address, length)?;
+
+        self.flash.unlock();
+        let r = self.program_bytes(address, length);
+        self.flash.lock();
+        if r.is_err() {
+            self.discard_word();
+        }
+        r
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        let (bank, sector) = Self::sector(address)?;
+
+        self.flash.unlock();
+        let r = self.flash.erase_sector(bank, sector);
+        self.flash.lock();
+        r
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.flash.unlock();
+        let mut r = self.flash.erase_bank(Bank::Bank1);
+        if r.is_ok() && F::DUAL_BANK {
+            r = self.flash.erase_bank(Bank::Bank2);
+        }
+        self.flash.lock();
+        r
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        // program the tail of the image
+        self.flash.unlock();
+        let r = self.flush_word();
+        self.flash.lock();
+        r.map_err(|_| DFUManifestationError::Unknown)
+    }
+
+    fn usb_reset(&mut self) {
+        // download was interrupted
+        self.discard_word();
+    }
+}
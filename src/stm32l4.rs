@@ -0,0 +1,235 @@
+use crate::class::{DFUManifestationError, DFUMemError, DFUMemIO};
+use core::cmp::min;
+
+/// Start address of the main flash memory.
+pub const FLASH_BASE: u32 = 0x0800_0000;
+
+/// Size of a flash page, the smallest erasable region.
+pub const PAGE_SIZE: u32 = 2048;
+
+/// Flash is programmed in 64-bit double words.
+pub const DOUBLE_WORD_SIZE: usize = 8;
+
+const BUFFER_SIZE: usize = 128;
+
+/// Flash bank
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Bank {
+    /// Bank 1
+    Bank1,
+    /// Bank 2
+    Bank2,
+}
+
+impl Bank {
+    /// Returns the other bank.
+    pub fn other(self) -> Bank {
+        match self {
+            Bank::Bank1 => Bank::Bank2,
+            Bank::Bank2 => Bank::Bank1,
+        }
+    }
+}
+
+/// Access to STM32L4 flash interface registers.
+///
+/// Implementation performs register writes and waits for `BSY` flag
+/// where necessary, [`Stm32l4DualBank`] decides what to program and erase,
+/// and in what order option bytes are modified.
+pub trait L4Flash {
+    /// Size of one bank in bytes.
+    const BANK_SIZE: u32;
+
+    /// Memory layout of a single bank at [`FLASH_BASE`],
+    /// see [`DFUMemIO::MEM_INFO_STRING`].
+    const MEM_INFO_STRING: &'static str;
+
+    /// Bank mapped at [`FLASH_BASE`], the one the device has booted from
+    /// (`SYSCFG_MEMRMP.FB_MODE`).
+    fn active_bank(&mut self) -> Bank;
+
+    /// Unlock `FLASH_CR` (`FLASH_KEYR` sequence).
+    fn unlock(&mut self);
+
+    /// Lock `FLASH_CR`.
+    fn lock(&mut self);
+
+    /// Erase a page, `page` is a page number within a `bank` (`PER`, `BKER`, `PNB`).
+    fn erase_page(&mut self, bank: Bank, page: u16) -> Result<(), DFUMemError>;
+
+    /// Erase a whole bank (`MER1` or `MER2`).
+    fn erase_bank(&mut self, bank: Bank) -> Result<(), DFUMemError>;
+
+    /// Program a double word at `address` (`PG`), `address` is 8 byte aligned.
+    fn program_double_word(&mut self, address: u32, data: u64) -> Result<(), DFUMemError>;
+
+    /// Read memory at `address`.
+    fn read(&mut self, address: u32, length: usize) -> &[u8];
+
+    /// Unlock option bytes (`FLASH_OPTKEYR` sequence).
+    fn option_unlock(&mut self);
+
+    /// Set `BFB2` bit value in `FLASH_OPTR`.
+    fn set_bfb2(&mut self, bfb2: bool);
+
+    /// Start option bytes modification (`OPTSTRT`) and wait for completion.
+    fn option_start(&mut self) -> Result<(), DFUManifestationError>;
+
+    /// Reload option bytes (`OBL_LAUNCH`), this resets the device and should not return.
+    fn option_launch(&mut self);
+}
+
+/// [`DFUMemIO`] implementation for STM32L4 dual-bank flash.
+///
+/// A host downloads an image at [`FLASH_BASE`] addresses, as if it
+/// replaces the running firmware. `Stm32l4DualBank` programs the image
+/// to the inactive bank instead: logical address `FLASH_BASE + offset` is
+/// translated to `FLASH_BASE + BANK_SIZE + offset`, where the inactive bank
+/// is mapped. Erase requests erase corresponding pages of the inactive bank,
+/// full erase erases the whole inactive bank. Uploads read the inactive bank too,
+/// so a host can verify the image.
+///
+/// [`manifestation()`](DFUMemIO::manifestation) sets `BFB2` option bit so the device boots
+/// from the bank with the new image, and reloads option bytes, which resets the device.
+/// The running firmware is never modified, if the update is interrupted,
+/// the device still boots from the old bank.
+///
+/// Device must be configured in dual-bank mode.
+pub struct Stm32l4DualBank<F: L4Flash> {
+    flash: F,
+    active: Bank,
+    buffer: [u8; BUFFER_SIZE],
+}
+
+impl<F: L4Flash> Stm32l4DualBank<F> {
+    /// Creates a new `Stm32l4DualBank`, the active bank is read from `flash`.
+    pub fn new(mut flash: F) -> Self {
+        let active = flash.active_bank();
+        Self {
+            flash,
+            active,
+            buffer: [0xff; BUFFER_SIZE],
+        }
+    }
+
+    /// Bank the device has booted from.
+    pub fn active_bank(&self) -> Bank {
+        self.active
+    }
+
+    /// Bank that receives a new image.
+    pub fn inactive_bank(&self) -> Bank {
+        self.active.other()
+    }
+
+    /// Returns a reference to the flash interface.
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// Consumes `Stm32l4DualBank` and returns the flash interface.
+    pub fn into_inner(self) -> F {
+        self.flash
+    }
+
+    /// Translate a logical `address` and `length` to an address in the inactive bank.
+    pub fn inactive_address(address: u32, length: usize) -> Result<u32, DFUMemError> {
+        let end = address
+            .checked_add(length as u32)
+            .ok_or(DFUMemError::Address)?;
+        if address < FLASH_BASE || end > FLASH_BASE + F::BANK_SIZE {
+            return Err(DFUMemError::Address);
+        }
+        Ok(address + F::BANK_SIZE)
+    }
+
+    /// Translate a logical `address` to a page number in the inactive bank.
+    pub fn inactive_page(&self, address: u32) -> Result<(Bank, u16), DFUMemError> {
+        Self::inactive_address(address, 1)?;
+        let page = (address - FLASH_BASE) / PAGE_SIZE;
+        Ok((self.inactive_bank(), page as u16))
+    }
+
+    fn program_words(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        let mut pos = 0;
+        while pos < length {
+            let len = min(length - pos, DOUBLE_WORD_SIZE);
+            let mut word = [0xff; DOUBLE_WORD_SIZE];
+            word[..len].copy_from_slice(&self.buffer[pos..pos + len]);
+            self.flash
+                .program_double_word(address + pos as u32, u64::from_le_bytes(word))?;
+            pos += len;
+        }
+        Ok(())
+    }
+}
+
+impl<F: L4Flash> DFUMemIO for Stm32l4DualBank<F> {
+    const INITIAL_ADDRESS_POINTER: u32 = FLASH_BASE;
+    const MEM_INFO_STRING: &'static str = F::MEM_INFO_STRING;
+    const MANIFESTATION_TOLERANT: bool = false;
+    const PROGRAM_TIME_MS: u32 = 2;
+    const ERASE_TIME_MS: u32 = 25;
+    const FULL_ERASE_TIME_MS: u32 = 25;
+    const MANIFESTATION_TIME_MS: u32 = 50;
+    const TRANSFER_SIZE: u16 = BUFFER_SIZE as u16;
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        if src.len() > BUFFER_SIZE {
+            return Err(());
+        }
+        self.buffer[..src.len()].copy_from_slice(src);
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        let address = Self::inactive_address(address, length)?;
+        Ok(self.flash.read(address, length))
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        if !(address as usize).is_multiple_of(DOUBLE_WORD_SIZE) || length > BUFFER_SIZE {
+            return Err(DFUMemError::Address);
+        }
+        let address = Self::inactive_address(address, length)?;
+
+        self.flash.unlock();
+        let r = self.program_words(address, length);
+        self.flash.lock();
+        r
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        let (bank, page) = self.inactive_page(address)?;
+
+        self.flash.unlock();
+        let r = self.flash.erase_page(bank, page);
+        self.flash.lock();
+        r
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        let bank = self.inactive_bank();
+
+        self.flash.unlock();
+        let r = self.flash.erase_bank(bank);
+        self.flash.lock();
+        r
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        // boot from bank 2 if it has the new image
+        let bfb2 = self.inactive_bank() == Bank::Bank2;
+
+        self.flash.unlock();
+        self.flash.option_unlock();
+        self.flash.set_bfb2(bfb2);
+        if let Err(e) = self.flash.option_start() {
+            self.flash.lock();
+            return Err(e);
+        }
+        // may not return
+        self.flash.option_launch();
+        Ok(())
+    }
+}
@@ -0,0 +1,110 @@
+use crate::crc::crc32_update;
+
+/// DFU file suffix, the last 16 bytes of a file prepared by `dfu-suffix`,
+/// see [`CHECK_SUFFIX`](crate::DFUMemIO::CHECK_SUFFIX).
+///
+/// ```
+/// use usbd_dfu::DfuSuffix;
+///
+/// let suffix = [
+///     0x00, 0x02, 0x11, 0xdf, 0x83, 0x04, 0x1a, 0x01, b'U', b'F', b'D', 16, 0, 0, 0, 0,
+/// ];
+/// let s = DfuSuffix::decode(&suffix, 0).expect("suffix");
+/// assert_eq!((s.id_vendor, s.id_product, s.bcd_device), (0x0483, 0xdf11, 0x0200));
+/// assert!(DfuSuffix::decode(&[0; 16], 0).is_none());
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DfuSuffix {
+    /// Firmware release number, `0xffff` if any
+    pub bcd_device: u16,
+    /// USB Product ID, `0xffff` if any
+    pub id_product: u16,
+    /// USB Vendor ID, `0xffff` if any
+    pub id_vendor: u16,
+    /// DFU specification version, `0x0100` or `0x011A`
+    pub bcd_dfu: u16,
+    /// `dwCRC` value of the suffix
+    pub crc: u32,
+    /// CRC of the received file, without `dwCRC`
+    pub file_crc: u32,
+}
+
+impl DfuSuffix {
+    /// Suffix length
+    pub const LEN: usize = 16;
+
+    /// Decodes `bytes`, `None` if they don't end with a DFU suffix signature.
+    /// `file_crc` is CRC of the file, see [`crc_matches()`](DfuSuffix::crc_matches).
+    pub fn decode(bytes: &[u8], file_crc: u32) -> Option<Self> {
+        let b = bytes.get(bytes.len().checked_sub(Self::LEN)?..)?;
+        if &b[8..11] != b"UFD" || (b[11] as usize) < Self::LEN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+        Some(Self {
+            bcd_device: u16_at(0),
+            id_product: u16_at(2),
+            id_vendor: u16_at(4),
+            bcd_dfu: u16_at(6),
+            crc: u32::from_le_bytes([b[12], b[13], b[14], b[15]]),
+            file_crc,
+        })
+    }
+
+    /// Returns `true` if `dwCRC` matches the received file.
+    ///
+    /// The CRC is CRC-32 without the final inversion, as calculated by `dfu-suffix`.
+    pub fn crc_matches(&self) -> bool {
+        self.crc == self.file_crc
+    }
+
+    /// Returns `true` if `id_vendor`, `id_product`, and `bcd_device` match a device,
+    /// `0xffff` in the suffix matches any value.
+    pub fn matches_device(&self, vid: u16, pid: u16, bcd_device: u16) -> bool {
+        let m = |s: u16, d: u16| s == 0xffff || s == d;
+        m(self.id_vendor, vid) && m(self.id_product, pid) && m(self.bcd_device, bcd_device)
+    }
+}
+
+/// The last [`DfuSuffix::LEN`] bytes of a download session, and CRC of the bytes before
+#[derive(Clone, Copy)]
+pub(crate) struct SuffixTail {
+    tail: [u8; DfuSuffix::LEN],
+    len: usize,
+    crc: u32,
+}
+
+impl SuffixTail {
+    pub(crate) const fn new() -> Self {
+        Self {
+            tail: [0; DfuSuffix::LEN],
+            len: 0,
+            crc: 0xffff_ffff,
+        }
+    }
+
+    /// Appends the next data of a file
+    pub(crate) fn push(&mut self, data: &[u8]) {
+        let keep = data.len().min(DfuSuffix::LEN);
+        // bytes of the tail that are not the last ones anymore
+        let evict = (self.len + keep).saturating_sub(DfuSuffix::LEN);
+        self.crc = crc32_update(self.crc, &self.tail[..evict]);
+        self.tail.copy_within(evict..self.len, 0);
+        self.len -= evict;
+
+        let (head, last) = data.split_at(data.len() - keep);
+        self.crc = crc32_update(self.crc, head);
+        self.tail[self.len..self.len + keep].copy_from_slice(last);
+        self.len += keep;
+    }
+
+    /// Suffix of the file, if there is one
+    pub(crate) fn suffix(&self) -> Option<DfuSuffix> {
+        if self.len < DfuSuffix::LEN {
+            return None;
+        }
+        // dwCRC is not included
+        let file_crc = crc32_update(self.crc, &self.tail[..DfuSuffix::LEN - 4]);
+        DfuSuffix::decode(&self.tail, file_crc)
+    }
+}
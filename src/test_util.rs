@@ -0,0 +1,358 @@
+use core::cmp::min;
+use std::vec;
+use std::vec::Vec;
+
+use usb_device::class::UsbClass;
+use usbd_class_tester::prelude::*;
+
+use crate::class::{DFUManifestationError, DFUMemError, DFUMemIO, DFUState, DFUStatusCode};
+use crate::meminfo::{MemInfoString, Perms};
+
+/// Memory function called by [`MockMem`], see [`MockMem::calls`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MockCall {
+    /// `read()` with address and length
+    Read(u32, usize),
+    /// `erase()` with address
+    Erase(u32),
+    /// `erase_all()`
+    EraseAll,
+    /// `program()` with address and length
+    Program(u32, usize),
+    /// `manifestation()`
+    Manifestation,
+}
+
+/// Failures of [`MockMem`] functions, see [`MockMem::faults`].
+///
+/// Address faults fail every call that covers the address, until the fault is removed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MockFaults {
+    /// `read()` of a range that contains the address fails with the error
+    pub read: Option<(u32, DFUMemError)>,
+    /// `erase()` of a page that contains the address fails with the error
+    pub erase: Option<(u32, DFUMemError)>,
+    /// `program()` of a range that contains the address fails with the error
+    pub program: Option<(u32, DFUMemError)>,
+    /// `erase_all()` fails with the error
+    pub erase_all: Option<DFUMemError>,
+    /// `manifestation()` fails with the error
+    pub manifestation: Option<DFUManifestationError>,
+}
+
+/// Flash-like memory for tests of a bootloader with [`DFUClass`](crate::DFUClass).
+///
+/// Memory of `pages` pages of `page_size` bytes each starts at `base` address, it's
+/// erased (`0xff`) initially. Programming only clears bits like flash does,
+/// a block programmed without an erase fails with [`DFUMemError::Verify`].
+/// Memory layout string and Address Pointer are provided at runtime, timing
+/// constants are `10`, `20`, and `30` ms, other [`DFUMemIO`] constants are defaults.
+/// A test memory with other constants or functions may wrap `MockMem` and forward
+/// functions it doesn't change to it.
+///
+/// ```
+/// use usbd_dfu::test_util::{MockCall, MockMem};
+/// use usbd_dfu::DFUMemError;
+///
+/// let mut mem = MockMem::new(0x0800_0000, 4, 1024);
+/// assert_eq!(mem.layout(), "@Flash/0x08000000/4*1Kg");
+/// mem.faults.program = Some((0x0800_0c00, DFUMemError::Prog));
+/// assert_eq!(mem.calls, []);
+/// ```
+pub struct MockMem {
+    /// Memory functions called by the class, in order
+    pub calls: Vec<MockCall>,
+    /// Failures to inject
+    pub faults: MockFaults,
+    base: u32,
+    page_size: u32,
+    layout: MemInfoString<64>,
+    memory: Vec<u8>,
+    buffer: Vec<u8>,
+}
+
+impl MockMem {
+    /// Creates erased memory of `pages` pages of `page_size` bytes at `base` address.
+    pub fn new(base: u32, pages: u32, page_size: u32) -> Self {
+        assert!(pages > 0 && page_size > 0, "memory must not be empty");
+        let mut layout = MemInfoString::new();
+        layout
+            .region("Flash", base)
+            .and_then(|l| l.area(pages, page_size, Perms::RWE))
+            .expect("memory layout string");
+        Self {
+            calls: Vec::new(),
+            faults: MockFaults::default(),
+            base,
+            page_size,
+            layout,
+            memory: vec![0xff; (pages * page_size) as usize],
+            buffer: vec![0; Self::TRANSFER_SIZE as usize],
+        }
+    }
+
+    /// Memory layout string, returned by [`mem_info_string()`](DFUMemIO::mem_info_string).
+    pub fn layout(&self) -> &str {
+        self.layout.as_str()
+    }
+
+    /// Memory contents.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// Memory contents, for example, to put an image in it before upload.
+    pub fn memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory
+    }
+
+    /// Offset of `length` bytes at `address`, `None` if they are outside of memory
+    fn offset(&self, address: u32, length: usize) -> Option<usize> {
+        let offset = address.checked_sub(self.base)? as usize;
+        if offset.checked_add(length)? <= self.memory.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Error of `fault` if it covers `length` bytes at `address`
+    fn fault(
+        fault: Option<(u32, DFUMemError)>,
+        address: u32,
+        length: usize,
+    ) -> Option<DFUMemError> {
+        match fault {
+            Some((at, e)) if at >= address && ((at - address) as usize) < length.max(1) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl DFUMemIO for MockMem {
+    const INITIAL_ADDRESS_POINTER: u32 = 0;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x00000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.calls.push(MockCall::Read(address, length));
+        if let Some(e) = Self::fault(self.faults.read, address, length) {
+            return Err(e);
+        }
+        let offset = self.offset(address, 0).ok_or(DFUMemError::Address)?;
+        let end = min(offset + length, self.memory.len());
+        Ok(&self.memory[offset..end])
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.calls.push(MockCall::Erase(address));
+        let offset = self.offset(address, 1).ok_or(DFUMemError::Address)?;
+        let page_size = self.page_size as usize;
+        let page = offset - offset % page_size;
+        let start = self.base + page as u32;
+        if let Some(e) = Self::fault(self.faults.erase, start, page_size) {
+            return Err(e);
+        }
+        self.memory[page..page + page_size].fill(0xff);
+        Ok(())
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.calls.push(MockCall::EraseAll);
+        if let Some(e) = self.faults.erase_all {
+            return Err(e);
+        }
+        self.memory.fill(0xff);
+        Ok(())
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.buffer
+            .get_mut(..src.len())
+            .ok_or(())?
+            .copy_from_slice(src);
+        Ok(())
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.calls.push(MockCall::Program(address, length));
+        if let Some(e) = Self::fault(self.faults.program, address, length) {
+            return Err(e);
+        }
+        let offset = self.offset(address, length).ok_or(DFUMemError::Address)?;
+        let mem = &mut self.memory[offset..offset + length];
+        let data = &self.buffer[..length];
+        for (m, d) in mem.iter_mut().zip(data) {
+            // flash write sets bits to 0 only
+            *m &= d;
+        }
+        if mem != data {
+            return Err(DFUMemError::Verify);
+        }
+        Ok(())
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.calls.push(MockCall::Manifestation);
+        match self.faults.manifestation {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.offset(address, 1).map(|_| self.page_size)
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.layout.as_str()
+    }
+
+    fn initial_address_pointer(&self) -> u32 {
+        self.base
+    }
+}
+
+/// DFU requests of a host for an emulated `usbd-class-tester` device.
+///
+/// Requests are sent to interface `0`, replies are returned as received.
+/// A request stalled in the setup stage fails with [`AnyUsbError::EP0Stalled`],
+/// in the data stage with [`AnyUsbError::EPStalled`].
+pub trait DeviceExt<C> {
+    /// Class request from the device to the host.
+    fn read(
+        &mut self,
+        cls: &mut C,
+        req: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> AnyResult<Vec<u8>>;
+
+    /// Class request from the host to the device.
+    fn write(
+        &mut self,
+        cls: &mut C,
+        req: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+        data: &[u8],
+    ) -> AnyResult<Vec<u8>>;
+
+    /// `DFU_DETACH` request with `wTimeout` value `timeout`.
+    fn detach(&mut self, cls: &mut C, timeout: u16) -> AnyResult<Vec<u8>>;
+
+    /// `DFU_DNLOAD` request, block `block_num` with `data`.
+    fn download(&mut self, cls: &mut C, block_num: u16, data: &[u8]) -> AnyResult<Vec<u8>>;
+
+    /// `DFU_UPLOAD` request, block `block_num` of up to `length` bytes.
+    fn upload(&mut self, cls: &mut C, block_num: u16, length: usize) -> AnyResult<Vec<u8>>;
+
+    /// `DFU_GETSTATUS` request, compare the reply with [`status()`].
+    fn get_status(&mut self, cls: &mut C) -> AnyResult<Vec<u8>>;
+
+    /// `DFU_CLRSTATUS` request.
+    fn clear_status(&mut self, cls: &mut C) -> AnyResult<Vec<u8>>;
+
+    /// `DFU_GETSTATE` request.
+    fn get_state(&mut self, cls: &mut C) -> AnyResult<Vec<u8>>;
+
+    /// `DFU_ABORT` request.
+    fn abort(&mut self, cls: &mut C) -> AnyResult<Vec<u8>>;
+
+    /// USB reset. Emulated bus can't signal it, the class is reset the way
+    /// `usb-device` does, then the device is enumerated again.
+    fn bus_reset(&mut self, cls: &mut C) -> AnyResult<()>;
+}
+
+impl<'a, C, M> DeviceExt<C> for Device<'a, C, M>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    M: UsbDeviceCtx<C<'a> = C>,
+{
+    fn read(
+        &mut self,
+        cls: &mut C,
+        req: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+    ) -> AnyResult<Vec<u8>> {
+        self.control_read(
+            cls,
+            CtrRequestType::to_host().class().interface(),
+            req,
+            value,
+            index,
+            length,
+        )
+    }
+
+    fn write(
+        &mut self,
+        cls: &mut C,
+        req: u8,
+        value: u16,
+        index: u16,
+        length: u16,
+        data: &[u8],
+    ) -> AnyResult<Vec<u8>> {
+        self.control_write(
+            cls,
+            CtrRequestType::to_device().class().interface(),
+            req,
+            value,
+            index,
+            length,
+            data,
+        )
+    }
+
+    fn detach(&mut self, cls: &mut C, timeout: u16) -> AnyResult<Vec<u8>> {
+        self.write(cls, 0x0, timeout, 0, 0, &[])
+    }
+
+    fn download(&mut self, cls: &mut C, block_num: u16, data: &[u8]) -> AnyResult<Vec<u8>> {
+        if data.len() > u16::MAX as usize {
+            return Err(AnyUsbError::DataConversion);
+        }
+        self.write(cls, 0x1, block_num, 0, data.len() as u16, data)
+    }
+
+    fn upload(&mut self, cls: &mut C, block_num: u16, length: usize) -> AnyResult<Vec<u8>> {
+        if length > u16::MAX as usize {
+            return Err(AnyUsbError::DataConversion);
+        }
+        self.read(cls, 0x2, block_num, 0, length as u16)
+    }
+
+    fn get_status(&mut self, cls: &mut C) -> AnyResult<Vec<u8>> {
+        self.read(cls, 0x3, 0, 0, 6)
+    }
+
+    fn clear_status(&mut self, cls: &mut C) -> AnyResult<Vec<u8>> {
+        self.write(cls, 0x4, 0, 0, 0, &[])
+    }
+
+    fn get_state(&mut self, cls: &mut C) -> AnyResult<Vec<u8>> {
+        self.read(cls, 0x5, 0, 0, 1)
+    }
+
+    fn abort(&mut self, cls: &mut C) -> AnyResult<Vec<u8>> {
+        self.write(cls, 0x6, 0, 0, 0, &[])
+    }
+
+    fn bus_reset(&mut self, cls: &mut C) -> AnyResult<()> {
+        cls.reset();
+        self.setup(cls)
+    }
+}
+
+/// Expected `DFU_GETSTATUS` reply with `status`, `poll_timeout` in milliseconds, and `state`.
+pub fn status(status: DFUStatusCode, poll_timeout: u32, state: DFUState) -> [u8; 6] {
+    let t = poll_timeout.to_le_bytes();
+    [status.into(), t[0], t[1], t[2], state.into(), 0]
+}
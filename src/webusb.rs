@@ -0,0 +1,77 @@
+/// `wIndex` of `GET_URL` vendor request
+pub const WEBUSB_GET_URL: u16 = 2;
+
+/// String index of the landing page URL, `iLandingPage`
+pub const LANDING_PAGE_INDEX: u16 = 1;
+
+/// `WEBUSB_URL` descriptor type
+const WEBUSB_URL: u8 = 3;
+
+/// WebUSB platform capability UUID 3408B638-09A9-47A0-8BFD-A0768815B665
+const WEBUSB_PLATFORM_UUID: [u8; 16] = [
+    0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47, 0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65,
+];
+
+/// WebUSB specification version 1.0
+const WEBUSB_VERSION: u16 = 0x0100;
+
+/// URL prefixes of `bScheme` values `0` and `1`, other URLs are sent with `bScheme` `255`
+const SCHEMES: [&str; 2] = ["http://", "https://"];
+
+/// Longest URL that fits in a descriptor after its 3-byte header
+const MAX_URL_LEN: usize = 255 - 3;
+
+/// `bScheme` and the rest of `url` after the scheme prefix
+const fn split_scheme(url: &str) -> (u8, &[u8]) {
+    let b = url.as_bytes();
+    let mut scheme = 0;
+    while scheme < SCHEMES.len() {
+        let prefix = SCHEMES[scheme].as_bytes();
+        if b.len() >= prefix.len() {
+            let mut i = 0;
+            while i < prefix.len() && b[i] == prefix[i] {
+                i += 1;
+            }
+            if i == prefix.len() {
+                return (scheme as u8, b.split_at(i).1);
+            }
+        }
+        scheme += 1;
+    }
+    (255, b)
+}
+
+/// Returns `true` if landing page `url` is not empty and fits in a URL descriptor
+pub(crate) const fn is_valid_url(url: &str) -> bool {
+    let (_, rest) = split_scheme(url);
+    !rest.is_empty() && rest.len() <= MAX_URL_LEN
+}
+
+/// Data of WebUSB platform capability descriptor, after `bDevCapabilityType`
+pub(crate) fn platform_capability(vendor_code: u8, has_landing_page: bool) -> [u8; 21] {
+    let mut data = [0; 21];
+    // data[0] is bReserved
+    data[1..17].copy_from_slice(&WEBUSB_PLATFORM_UUID);
+    data[17..19].copy_from_slice(&WEBUSB_VERSION.to_le_bytes());
+    data[19] = vendor_code;
+    if has_landing_page {
+        data[20] = LANDING_PAGE_INDEX as u8;
+    }
+    data
+}
+
+/// Writes URL descriptor of `url` to `buf`, returns its length, or `None` if `buf`
+/// is too short.
+pub(crate) fn write_url_descriptor(buf: &mut [u8], url: &str) -> Option<usize> {
+    let (scheme, rest) = split_scheme(url);
+    let len = 3 + rest.len();
+    if buf.len() < len || len > 255 {
+        return None;
+    }
+
+    buf[0] = len as u8;
+    buf[1] = WEBUSB_URL;
+    buf[2] = scheme;
+    buf[3..len].copy_from_slice(rest);
+    Some(len)
+}
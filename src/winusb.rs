@@ -0,0 +1,154 @@
+/// `wIndex` of the vendor request that reads MS OS 2.0 descriptor set
+pub const MS_OS_20_DESCRIPTOR_INDEX: u16 = 7;
+
+/// MS OS 2.0 platform capability UUID D8DD60DF-4589-4CC7-9CD2-659D9E648A9F
+const MS_OS_20_PLATFORM_UUID: [u8; 16] = [
+    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e, 0x64, 0x8a, 0x9f,
+];
+
+/// Windows 8.1, the first version that supports MS OS 2.0 descriptors
+const WINDOWS_VERSION: u32 = 0x0603_0000;
+
+const MS_OS_20_SET_HEADER_DESCRIPTOR: u16 = 0x00;
+const MS_OS_20_SUBSET_HEADER_CONFIGURATION: u16 = 0x01;
+const MS_OS_20_SUBSET_HEADER_FUNCTION: u16 = 0x02;
+const MS_OS_20_FEATURE_COMPATIBLE_ID: u16 = 0x03;
+const MS_OS_20_FEATURE_REG_PROPERTY: u16 = 0x04;
+
+/// `REG_MULTI_SZ` registry value type
+const REG_MULTI_SZ: u16 = 7;
+
+const SET_HEADER_LEN: usize = 10;
+const SUBSET_HEADER_LEN: usize = 8;
+const COMPATIBLE_ID_LEN: usize = 20;
+
+const PROPERTY_NAME: &str = "DeviceInterfaceGUIDs";
+/// Length of a GUID in `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}` format
+pub(crate) const GUID_LEN: usize = 38;
+/// UTF-16 property name with a terminating null
+const PROPERTY_NAME_LEN: usize = (PROPERTY_NAME.len() + 1) * 2;
+/// UTF-16 list of a single GUID, terminated by two nulls
+const PROPERTY_DATA_LEN: usize = (GUID_LEN + 2) * 2;
+const REG_PROPERTY_LEN: usize = 10 + PROPERTY_NAME_LEN + PROPERTY_DATA_LEN;
+
+/// Length of MS OS 2.0 descriptor set. Configuration and function subset headers
+/// are added for `composite` devices.
+pub const fn descriptor_set_len(composite: bool) -> usize {
+    let features = COMPATIBLE_ID_LEN + REG_PROPERTY_LEN;
+    if composite {
+        SET_HEADER_LEN + 2 * SUBSET_HEADER_LEN + features
+    } else {
+        SET_HEADER_LEN + features
+    }
+}
+
+/// Returns `true` if `guid` is in `{xxxxxxxx-xxxx-xxxx-xxxx-xxxxxxxxxxxx}` format
+pub(crate) const fn is_valid_guid(guid: &str) -> bool {
+    let b = guid.as_bytes();
+    if b.len() != GUID_LEN || b[0] != b'{' || b[GUID_LEN - 1] != b'}' {
+        return false;
+    }
+    let mut i = 1;
+    while i < GUID_LEN - 1 {
+        let ok = match i {
+            9 | 14 | 19 | 24 => b[i] == b'-',
+            _ => b[i].is_ascii_hexdigit(),
+        };
+        if !ok {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Data of MS OS 2.0 platform capability descriptor, after `bDevCapabilityType`
+pub(crate) fn platform_capability(composite: bool, vendor_code: u8) -> [u8; 25] {
+    let mut data = [0; 25];
+    // data[0] is bReserved
+    data[1..17].copy_from_slice(&MS_OS_20_PLATFORM_UUID);
+    data[17..21].copy_from_slice(&WINDOWS_VERSION.to_le_bytes());
+    data[21..23].copy_from_slice(&(descriptor_set_len(composite) as u16).to_le_bytes());
+    data[23] = vendor_code;
+    // data[24] is bAltEnumCode, alternate enumeration is not supported
+    data
+}
+
+/// Sequential writer of little-endian descriptor fields
+struct Writer<'b> {
+    buf: &'b mut [u8],
+    pos: usize,
+}
+
+impl Writer<'_> {
+    fn bytes(&mut self, data: &[u8]) {
+        self.buf[self.pos..self.pos + data.len()].copy_from_slice(data);
+        self.pos += data.len();
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// ASCII string as UTF-16, followed by `nulls` null characters
+    fn utf16(&mut self, s: &str, nulls: usize) {
+        for &c in s.as_bytes() {
+            self.bytes(&[c, 0]);
+        }
+        for _ in 0..nulls {
+            self.bytes(&[0, 0]);
+        }
+    }
+}
+
+/// Writes MS OS 2.0 descriptor set to `buf`, returns its length, or `None` if `buf`
+/// is too short.
+///
+/// `first_interface` is the DFU interface number of a composite device, the features
+/// are wrapped into configuration and function subsets.
+pub(crate) fn write_descriptor_set(
+    buf: &mut [u8],
+    guid: &str,
+    first_interface: Option<u8>,
+) -> Option<usize> {
+    let total = descriptor_set_len(first_interface.is_some());
+    if buf.len() < total {
+        return None;
+    }
+
+    let mut w = Writer { buf, pos: 0 };
+
+    w.u16(SET_HEADER_LEN as u16);
+    w.u16(MS_OS_20_SET_HEADER_DESCRIPTOR);
+    w.bytes(&WINDOWS_VERSION.to_le_bytes());
+    w.u16(total as u16);
+
+    if let Some(interface) = first_interface {
+        w.u16(SUBSET_HEADER_LEN as u16);
+        w.u16(MS_OS_20_SUBSET_HEADER_CONFIGURATION);
+        // bConfigurationValue is the configuration index, bReserved
+        w.bytes(&[0, 0]);
+        w.u16((total - SET_HEADER_LEN) as u16);
+
+        w.u16(SUBSET_HEADER_LEN as u16);
+        w.u16(MS_OS_20_SUBSET_HEADER_FUNCTION);
+        // bFirstInterface, bReserved
+        w.bytes(&[interface, 0]);
+        w.u16((total - SET_HEADER_LEN - SUBSET_HEADER_LEN) as u16);
+    }
+
+    w.u16(COMPATIBLE_ID_LEN as u16);
+    w.u16(MS_OS_20_FEATURE_COMPATIBLE_ID);
+    // CompatibleID, SubCompatibleID
+    w.bytes(b"WINUSB\0\0\0\0\0\0\0\0\0\0");
+
+    w.u16(REG_PROPERTY_LEN as u16);
+    w.u16(MS_OS_20_FEATURE_REG_PROPERTY);
+    w.u16(REG_MULTI_SZ);
+    w.u16(PROPERTY_NAME_LEN as u16);
+    w.utf16(PROPERTY_NAME, 1);
+    w.u16(PROPERTY_DATA_LEN as u16);
+    w.utf16(guid, 2);
+
+    Some(w.pos)
+}
@@ -0,0 +1,230 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const LAYOUTS: [&str; 3] = [
+    "@Flash/0x08000000/2*32 g",
+    "@Option Bytes/0x1FFF7800/1*64 e",
+    "@SPI Flash/0x90000000/2*32 g",
+];
+
+/// Three memories selected by an alternate setting.
+pub struct AltMem {
+    mems: [MockMem; 3],
+    alt: usize,
+    selected: Vec<u8>,
+}
+
+impl DFUMemIO for AltMem {
+    const INITIAL_ADDRESS_POINTER: u32 = 0;
+    const MEM_INFO_STRING: &'static str = LAYOUTS[0];
+    const ALT_COUNT: u8 = 3;
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mems[self.alt].read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mems[self.alt].erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mems[self.alt].erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mems[self.alt].store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mems[self.alt].program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mems[self.alt].manifestation()
+    }
+
+    fn mem_info_string_for(&self, alt: u8) -> &str {
+        LAYOUTS[alt as usize]
+    }
+
+    fn select_alt(&mut self, alt: u8) {
+        self.alt = alt as usize;
+        self.selected.push(alt);
+    }
+}
+
+struct MkAlt {}
+
+impl UsbDeviceCtx for MkAlt {
+    type C<'c> = DFUClass<EmulatedUsbBus, AltMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, AltMem>> {
+        let mut mems = [(); 3].map(|_| MockMem::new(0, 2, 32));
+        // alt 1 is erased
+        mems[0].memory_mut().fill(0x10);
+        mems[2].memory_mut().fill(0x30);
+        let mem = AltMem {
+            mems,
+            alt: 0,
+            selected: Vec::new(),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_alt_descriptors() {
+    MkAlt {}
+        .with_usb(|mut dfu, mut dev| {
+            let desc = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 255)
+                .expect("desc");
+            assert_eq!(desc.len(), 9 + 3 * 9 + 9);
+
+            // a single interface
+            assert_eq!(desc[4], 1);
+
+            // interface descriptors, one per alternate setting
+            for alt in 0..3 {
+                let d = &desc[9 + alt * 9..18 + alt * 9];
+                assert_eq!(d[..4], [9, 4, 0, alt as u8]);
+                assert_eq!(d[5..8], [0xfe, 0x01, 0x02]);
+                assert_eq!(d[8], 4 + alt as u8);
+
+                let istr = dev.device_get_string(&mut dfu, d[8], 0x409).expect("str");
+                assert_eq!(istr, LAYOUTS[alt]);
+            }
+
+            // functional descriptor
+            assert_eq!(desc[36..38], [9, 0x21]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_alt_select() {
+    MkAlt {}
+        .with_usb(|mut dfu, mut dev| {
+            let alt = dev.interface_get_interface(&mut dfu).expect("alt");
+            assert_eq!(alt, 0);
+
+            dev.interface_set_interface(&mut dfu, 0, 2).expect("set");
+            let alt = dev.interface_get_interface(&mut dfu).expect("alt");
+            assert_eq!(alt, 2);
+            assert_eq!(dfu.alt_setting(), 2);
+
+            /* Upload block 2 (offset 0) from alt 2 */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [0x30; 32]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            dev.interface_set_interface(&mut dfu, 0, 1).expect("set");
+
+            /* Download block 2 (offset 0) to alt 1 */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            // memory can't change during a download
+            let e = dev
+                .interface_set_interface(&mut dfu, 0, 2)
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+            assert_eq!(dfu.alt_setting(), 1);
+
+            // setting the same alternate setting is accepted
+            dev.interface_set_interface(&mut dfu, 0, 1).expect("set");
+
+            let mem = dfu.release();
+            assert_eq!(mem.selected, [2, 1]);
+            assert_eq!(mem.mems[0].memory()[..32], [0x10; 32]);
+            assert_eq!(mem.mems[1].memory()[..32], [0x55; 32]);
+            assert_eq!(mem.mems[2].memory()[..32], [0x30; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_alt_invalid() {
+    MkAlt {}
+        .with_usb(|mut dfu, mut dev| {
+            let e = dev
+                .interface_set_interface(&mut dfu, 0, 3)
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            let alt = dev.interface_get_interface(&mut dfu).expect("alt");
+            assert_eq!(alt, 0);
+
+            let mem = dfu.release();
+            assert_eq!(mem.selected, [] as [u8; 0]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_alt_reset() {
+    MkAlt {}
+        .with_usb(|mut dfu, mut dev| {
+            dev.interface_set_interface(&mut dfu, 0, 1).expect("set");
+
+            dev.bus_reset(&mut dfu).expect("reset");
+            assert_eq!(dfu.alt_setting(), 0);
+
+            let mem = dfu.release();
+            assert_eq!(mem.selected, [1, 0]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_alt_strings_lang_id() {
+    MkAlt {}
+        .with_usb(|mut dfu, mut dev| {
+            for (alt, layout) in LAYOUTS.iter().enumerate() {
+                let index = 4 + alt as u8;
+
+                // EN_US
+                let istr = dev.device_get_string(&mut dfu, index, 0x409).expect("str");
+                assert_eq!(istr, *layout);
+
+                // lang_id = 0
+                let istr = dev.device_get_string(&mut dfu, index, 0).expect("str");
+                assert_eq!(istr, *layout);
+
+                // unsupported lang_id
+                dev.device_get_string(&mut dfu, index, 1)
+                    .expect_err("stall");
+            }
+
+            // no string after the last alternate setting
+            dev.device_get_string(&mut dfu, 7, 0x409)
+                .expect_err("stall");
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,161 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::Cell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const CLOCKMEM_BASE: u32 = 0x0800_0000;
+
+thread_local! {
+    /// Fake clock, in milliseconds
+    static CLOCK: Cell<u32> = const { Cell::new(1000) };
+    /// Number of on_abort() calls
+    static ABORTS: Cell<u32> = const { Cell::new(0) };
+}
+
+fn advance_clock(ms: u32) {
+    CLOCK.with(|c| c.set(c.get() + ms));
+}
+
+fn aborts() -> u32 {
+    ABORTS.with(|a| a.get())
+}
+
+/// Memory with a fake clock that clears dfuERROR state after 100 ms.
+pub struct ClockMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for ClockMem {
+    const INITIAL_ADDRESS_POINTER: u32 = CLOCKMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const ERROR_AUTOCLEAR_MS: u32 = 100;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        CLOCK.with(|c| c.get())
+    }
+
+    fn on_abort(&mut self) {
+        ABORTS.with(|a| a.set(a.get() + 1));
+    }
+}
+
+struct MkClock {}
+
+impl UsbDeviceCtx for MkClock {
+    type C<'c> = DFUClass<EmulatedUsbBus, ClockMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, ClockMem>> {
+        let mem = ClockMem {
+            mem: MockMem::new(CLOCKMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_error_autoclear() {
+    MkClock {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), unknown command */
+            let e = dev.download(&mut dfu, 0, &[0x99]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Download block 2, rejected in dfuERROR state */
+            advance_clock(50);
+            let e = dev.download(&mut dfu, 2, &[0x55; 32]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+            assert_eq!(aborts(), 0);
+
+            /* Download block 2 again after the timeout, no Clear Status */
+            advance_clock(100);
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+            assert_eq!(aborts(), 1);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..32], [0x55; 32]);
+            assert_eq!(aborts(), 1);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_error_autoclear_hold() {
+    MkClock {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), unknown command */
+            let e = dev.download(&mut dfu, 0, &[0x99]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            // host keeps reading the status, 300 ms in total
+            for _ in 0..5 {
+                advance_clock(60);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(
+                    vec,
+                    status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+                );
+            }
+            assert_eq!(aborts(), 0);
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status, nothing to clear after a long time */
+            advance_clock(1000);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+            assert_eq!(aborts(), 0);
+        })
+        .expect("with_usb");
+}
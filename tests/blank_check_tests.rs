@@ -0,0 +1,172 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const BCMEM_BASE: u32 = 0x0800_0000;
+const PAGE_SIZE: u32 = 256;
+
+/// RAM-backed memory that is not erased initially
+pub struct BcMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for BcMem {
+    const INITIAL_ADDRESS_POINTER: u32 = BCMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/4*256 g";
+    const PROGRAM_TIME_MS: u32 = 5;
+    const ERASE_TIME_MS: u32 = 10;
+    const FULL_ERASE_TIME_MS: u32 = 20;
+    const TRANSFER_SIZE: u16 = 64;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn is_blank(&mut self, address: u32, length: usize) -> Result<bool, DFUMemError> {
+        let offset = (address - BCMEM_BASE) as usize;
+        let range = self
+            .mem
+            .memory()
+            .get(offset..offset + length)
+            .ok_or(DFUMemError::Address)?;
+        Ok(range.iter().all(|&b| b == 0xff))
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkBc {}
+
+impl UsbDeviceCtx for MkBc {
+    type C<'c> = DFUClass<EmulatedUsbBus, BcMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, BcMem>> {
+        let mut mem = BcMem {
+            mem: MockMem::new(BCMEM_BASE, 4, PAGE_SIZE),
+        };
+        mem.mem.memory_mut().fill(0);
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_blank_check_not_erased() {
+    MkBc {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0), page is not erased */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrCheckErased, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, []);
+            assert_eq!(mem.mem.memory()[..64], [0; 64]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_blank_check_erased() {
+    MkBc {}
+        .with_usb(|mut dfu, mut dev| {
+            let b = (BCMEM_BASE + PAGE_SIZE).to_le_bytes();
+
+            /* Erase Page */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 6 (offset 256), page is erased */
+            let vec = dev.download(&mut dfu, 6, &[0x55; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 6 (offset 256) again, it's programmed already */
+            let vec = dev.download(&mut dfu, 6, &[0xaa; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrCheckErased, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Erase(BCMEM_BASE + PAGE_SIZE),
+                    MockCall::Program(BCMEM_BASE + PAGE_SIZE, 64)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[0x100..0x140], [0x55; 64]);
+        })
+        .expect("with_usb");
+}
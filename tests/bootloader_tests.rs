@@ -0,0 +1,167 @@
+#![allow(unused_variables)]
+
+use core::ops::ControlFlow;
+
+use usb_device::bus::{PollResult, UsbBus, UsbBusAllocator};
+use usb_device::endpoint::{EndpointAddress, EndpointType};
+use usb_device::{UsbDirection, UsbError};
+use usbd_dfu::bootloader::*;
+use usbd_dfu::class::*;
+
+/// USB bus without a host.
+pub struct NullBus {
+    next_ep: u8,
+}
+
+impl UsbBus for NullBus {
+    fn alloc_ep(
+        &mut self,
+        ep_dir: UsbDirection,
+        ep_addr: Option<EndpointAddress>,
+        ep_type: EndpointType,
+        max_packet_size: u16,
+        interval: u8,
+    ) -> usb_device::Result<EndpointAddress> {
+        Ok(ep_addr.unwrap_or_else(|| {
+            self.next_ep += 1;
+            EndpointAddress::from_parts(self.next_ep as usize, ep_dir)
+        }))
+    }
+
+    fn enable(&mut self) {}
+
+    fn reset(&self) {}
+
+    fn set_device_address(&self, addr: u8) {}
+
+    fn write(&self, ep_addr: EndpointAddress, buf: &[u8]) -> usb_device::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn read(&self, ep_addr: EndpointAddress, buf: &mut [u8]) -> usb_device::Result<usize> {
+        Err(UsbError::WouldBlock)
+    }
+
+    fn set_stalled(&self, ep_addr: EndpointAddress, stalled: bool) {}
+
+    fn is_stalled(&self, ep_addr: EndpointAddress) -> bool {
+        false
+    }
+
+    fn suspend(&self) {}
+
+    fn resume(&self) {}
+
+    fn poll(&self) -> PollResult {
+        PollResult::None
+    }
+}
+
+/// Memory that is never accessed.
+pub struct NullMem {
+    id: u32,
+}
+
+impl DFUMemIO for NullMem {
+    const INITIAL_ADDRESS_POINTER: u32 = 0x0800_0000;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        unreachable!()
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        unreachable!()
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        unreachable!()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        unreachable!()
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        unreachable!()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        unreachable!()
+    }
+}
+
+const CONFIG: BootloaderConfig = BootloaderConfig {
+    manufacturer: "usbd-dfu",
+    serial_number: "0001",
+    ..BootloaderConfig::new(0x1209, 0x0001)
+};
+
+#[test]
+fn test_bootloader_application() {
+    let alloc = UsbBusAllocator::new(NullBus { next_ep: 0 });
+
+    let mut calls = 0;
+    let mem = run_dfu(
+        &alloc,
+        NullMem { id: 1 },
+        &CONFIG,
+        BootDecision::Application,
+        |usb_dev, dfu| {
+            calls += 1;
+            ControlFlow::Break(())
+        },
+    )
+    .expect("run_dfu");
+
+    assert_eq!(mem.id, 1);
+    assert_eq!(calls, 0);
+}
+
+#[test]
+fn test_bootloader_loop() {
+    let alloc = UsbBusAllocator::new(NullBus { next_ep: 0 });
+
+    let mut calls = 0;
+    let mem = run_dfu(
+        &alloc,
+        NullMem { id: 2 },
+        &CONFIG,
+        BootDecision::DfuUnexpectedReset,
+        |usb_dev, dfu| {
+            assert_eq!(dfu.get_address_pointer(), 0x0800_0000);
+            assert!(!dfu.download_in_progress());
+            calls += 1;
+            if calls == 3 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        },
+    )
+    .expect("run_dfu");
+
+    assert_eq!(mem.id, 2);
+    assert_eq!(calls, 3);
+}
+
+#[test]
+fn test_bootloader_config_error() {
+    let alloc = UsbBusAllocator::new(NullBus { next_ep: 0 });
+
+    let config = BootloaderConfig {
+        max_packet_size_0: 12,
+        ..CONFIG
+    };
+    let r = run_dfu(
+        &alloc,
+        NullMem { id: 3 },
+        &config,
+        BootDecision::Dfu,
+        |usb_dev, dfu| ControlFlow::Break(()),
+    );
+    assert!(matches!(r, Err(BuilderError::InvalidPacketSize)));
+}
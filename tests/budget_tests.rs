@@ -0,0 +1,208 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::Cell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const SLOWMEM_BASE: u32 = 0x0800_0000;
+
+thread_local! {
+    /// Fake clock, in milliseconds
+    static CLOCK: Cell<u32> = const { Cell::new(1000) };
+}
+
+fn advance_clock(ms: u32) {
+    CLOCK.with(|c| c.set(c.get() + ms));
+}
+
+/// Memory with operations that complete in the background.
+pub struct SlowMem {
+    mem: MockMem,
+    /// Time when the running operation completes, `u32::MAX` never completes
+    done_at: Option<u32>,
+    /// Duration of the next operation
+    duration: u32,
+    errors: u32,
+}
+
+impl DFUMemIO for SlowMem {
+    const INITIAL_ADDRESS_POINTER: u32 = SLOWMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const OPERATION_BUDGET_FACTOR: u32 = 3;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)?;
+        self.done_at = Some(self.now_ms().saturating_add(self.duration));
+        Ok(())
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()?;
+        self.done_at = Some(self.now_ms().saturating_add(self.duration));
+        Ok(())
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)?;
+        self.done_at = Some(self.now_ms().saturating_add(self.duration));
+        Ok(())
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        CLOCK.with(|c| c.get())
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        let now = self.now_ms();
+        match self.done_at {
+            Some(t) if now < t => true,
+            _ => {
+                self.done_at = None;
+                false
+            }
+        }
+    }
+
+    fn on_error(&mut self) {
+        self.errors += 1;
+        self.done_at = None;
+    }
+}
+
+struct MkSlow<const DURATION: u32> {}
+
+impl<const DURATION: u32> UsbDeviceCtx for MkSlow<DURATION> {
+    type C<'c> = DFUClass<EmulatedUsbBus, SlowMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, SlowMem>> {
+        let mem = SlowMem {
+            mem: MockMem::new(SLOWMEM_BASE, 1, 1024),
+            done_at: None,
+            duration: DURATION,
+            errors: 0,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_budget_program_completes() {
+    MkSlow::<25> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x11; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            assert!(dfu.is_busy());
+            assert_eq!(dfu.take_poll_activity(), PollActivity::ExecutedCommand);
+
+            /* Get Status, still running */
+            advance_clock(10);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+            assert_eq!(dfu.take_poll_activity(), PollActivity::CommandPending);
+
+            /* Get Status, longer than expected, but within the budget */
+            advance_clock(20);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            assert!(!dfu.is_busy());
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..32], [0x11; 32]);
+            assert_eq!(mem.errors, 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_budget_program_timeout() {
+    MkSlow::<{ u32::MAX }> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x11; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status, budget is 30 ms */
+            advance_clock(30);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status, the operation is abandoned */
+            advance_clock(1);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.errors, 1);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_budget_erase_timeout() {
+    MkSlow::<{ u32::MAX }> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), erase page */
+            let b = SLOWMEM_BASE.to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+            /* Get Status, budget is 60 ms */
+            advance_clock(61);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrErase, 0, DFUState::DfuError));
+
+            let mem = dfu.release();
+            assert_eq!(mem.errors, 1);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,160 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::buffered::BufferedMem;
+use usbd_dfu::class::*;
+use usbd_dfu::retry::RetryMem;
+
+const BUFMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory without a buffer, programmed with `program_block()`.
+pub struct BufMem {
+    mem: MockMem,
+    /// Number of `program_block()` calls to fail
+    failures: u8,
+}
+
+impl BufMem {
+    fn new(failures: u8) -> Self {
+        Self {
+            mem: MockMem::new(BUFMEM_BASE, 1, 1024),
+            failures,
+        }
+    }
+}
+
+impl DFUMemIO for BufMem {
+    const INITIAL_ADDRESS_POINTER: u32 = BUFMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        if self.failures > 0 {
+            self.failures -= 1;
+            return Err(DFUMemError::Prog);
+        }
+        self.mem.store_write_buffer(data).expect("buffer");
+        self.mem.program(address, data.len())
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkBuffered {}
+
+impl UsbDeviceCtx for MkBuffered {
+    type C<'c> = DFUClass<EmulatedUsbBus, BufferedMem<BufMem, 32>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, BufferedMem<BufMem, 32>>> {
+        Ok(DFUClass::new(alloc, BufferedMem::new(BufMem::new(0))))
+    }
+}
+
+struct MkBufferedRetry {}
+
+impl UsbDeviceCtx for MkBufferedRetry {
+    type C<'c> = DFUClass<EmulatedUsbBus, RetryMem<BufferedMem<BufMem, 64>, 1>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RetryMem<BufferedMem<BufMem, 64>, 1>>> {
+        let mem = RetryMem::new(BufferedMem::new(BufMem::new(1)));
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_buffered_download() {
+    MkBuffered {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1), short block */
+            let vec = dev.download(&mut dfu, 3, &[0xaa; 5]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release().into_inner();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(BUFMEM_BASE, 32),
+                    MockCall::Program(BUFMEM_BASE + 32, 5)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..32], [0x55; 32]);
+            assert_eq!(mem.mem.memory()[32..37], [0xaa; 5]);
+            assert_eq!(mem.mem.memory()[37..64], [0xff; 27]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_buffered_retry() {
+    MkBufferedRetry {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0), the first attempt fails */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.retries(), 1);
+            let mem = mem.into_inner().into_inner();
+            assert_eq!(mem.mem.calls, [MockCall::Program(BUFMEM_BASE, 32)]);
+            assert_eq!(mem.mem.memory()[..32], [0x55; 32]);
+        })
+        .expect("with_usb");
+}
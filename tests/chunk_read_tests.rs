@@ -0,0 +1,161 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const CHUNKMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that is read in chunks only, the last block is short.
+pub struct ChunkMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for ChunkMem {
+    const INITIAL_ADDRESS_POINTER: u32 = CHUNKMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*100 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const READ_CHUNK_SIZE: usize = 8;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        panic!("read() is not used");
+    }
+
+    fn read_chunk(
+        &mut self,
+        address: u32,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize, DFUMemError> {
+        let data = self.mem.read(address + offset as u32, buf.len())?;
+        buf[..data.len()].copy_from_slice(data);
+        Ok(data.len())
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkChunk {
+    /// `read_chunk()` fails at this address
+    fail_at: Option<u32>,
+}
+
+impl UsbDeviceCtx for MkChunk {
+    type C<'c> = DFUClass<EmulatedUsbBus, ChunkMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, ChunkMem>> {
+        let mut mem = ChunkMem {
+            mem: MockMem::new(CHUNKMEM_BASE, 1, 100),
+        };
+        for (i, b) in mem.mem.memory_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        mem.mem.faults.read = self.fail_at.map(|a| (a, DFUMemError::Address));
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_chunk_read() {
+    MkChunk { fail_at: None }
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 3 (offset 1) */
+            let vec = dev.upload(&mut dfu, 3, 32).expect("vec");
+            assert_eq!(vec, (32..64).collect::<Vec<u8>>());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+
+            let mem = dfu.release();
+            let a = CHUNKMEM_BASE + 32;
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Read(a, 8),
+                    MockCall::Read(a + 8, 8),
+                    MockCall::Read(a + 16, 8),
+                    MockCall::Read(a + 24, 8)
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_chunk_read_short() {
+    MkChunk { fail_at: None }
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 5 (offset 3), the last 4 bytes of memory */
+            let vec = dev.upload(&mut dfu, 5, 32).expect("vec");
+            assert_eq!(vec, [96, 97, 98, 99]);
+
+            /* Get Status, short frame ends the upload */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::Read(CHUNKMEM_BASE + 96, 8)]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_chunk_read_err() {
+    MkChunk {
+        fail_at: Some(CHUNKMEM_BASE + 16),
+    }
+    .with_usb(|mut dfu, mut dev| {
+        /* Upload block 2 (offset 0), the third chunk fails */
+        let e = dev.upload(&mut dfu, 2, 32).expect_err("stall");
+        assert_eq!(e, AnyUsbError::EP0Stalled);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(
+            vec,
+            status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+        );
+
+        let mem = dfu.release();
+        let a = CHUNKMEM_BASE;
+        assert_eq!(
+            mem.mem.calls,
+            [
+                MockCall::Read(a, 8),
+                MockCall::Read(a + 8, 8),
+                MockCall::Read(a + 16, 8)
+            ]
+        );
+    })
+    .expect("with_usb");
+}
@@ -0,0 +1,145 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const CMDMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory with `U` as `HAS_READ_UNPROTECT` value, advertises `vendor` commands.
+pub struct CmdMem<const U: bool> {
+    mem: MockMem,
+    vendor: &'static [u8],
+}
+
+impl<const U: bool> DFUMemIO for CmdMem<U> {
+    const INITIAL_ADDRESS_POINTER: u32 = CMDMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const HAS_READ_UNPROTECT: bool = U;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        Ok(())
+    }
+
+    fn vendor_commands(&self) -> &[u8] {
+        self.vendor
+    }
+}
+
+struct MkCmd<const U: bool> {
+    vendor: &'static [u8],
+}
+
+impl<const U: bool> UsbDeviceCtx for MkCmd<U> {
+    type C<'c> = DFUClass<EmulatedUsbBus, CmdMem<U>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, CmdMem<U>>> {
+        let mem = CmdMem {
+            mem: MockMem::new(CMDMEM_BASE, 1, 1024),
+            vendor: self.vendor,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Upload Get Commands, then check the state
+fn get_commands<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, length: usize) -> Vec<u8> {
+    /* Upload block 0 */
+    let vec = dev.upload(dfu, 0, length).expect("vec");
+
+    /* Get Status */
+    let st = dev.get_status(dfu).expect("vec");
+    assert_eq!(st, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+    vec
+}
+
+#[test]
+fn test_commands_default() {
+    MkCmd::<false> { vendor: &[] }
+        .with_usb(|mut dfu, mut dev| {
+            let vec = get_commands(&mut dfu, &mut dev, 32);
+            assert_eq!(vec, [0x00, 0x21, 0x41]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_commands_vendor() {
+    MkCmd::<false> {
+        vendor: &[0xe1, 0xe2],
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = get_commands(&mut dfu, &mut dev, 32);
+        assert_eq!(vec, [0x00, 0x21, 0x41, 0xe1, 0xe2]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_commands_unprotect_vendor() {
+    MkCmd::<true> {
+        vendor: &[0xe1, 0xe2],
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = get_commands(&mut dfu, &mut dev, 32);
+        assert_eq!(vec, [0x00, 0x21, 0x41, 0x92, 0xe1, 0xe2]);
+
+        /* Upload block 0, exact length */
+        let vec = get_commands(&mut dfu, &mut dev, 6);
+        assert_eq!(vec, [0x00, 0x21, 0x41, 0x92, 0xe1, 0xe2]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_commands_vendor_short() {
+    MkCmd::<false> {
+        vendor: &[0xe1, 0xe2],
+    }
+    .with_usb(|mut dfu, mut dev| {
+        /* Upload block 0, shorter than the list */
+        let vec = dev.upload(&mut dfu, 0, 4).expect("vec");
+        assert_eq!(vec, [0x00, 0x21, 0x41, 0xe1]);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+    })
+    .expect("with_usb");
+}
@@ -0,0 +1,261 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::{InterfaceNumber, StringIndex, UsbBusAllocator};
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::descriptor::DescriptorWriter;
+use usb_device::LangID;
+use usbd_dfu::class::*;
+
+const COMPMEM_BASE: u32 = 0x0800_0000;
+
+/// DFU interface number in the composite device
+const DFU_IF: u16 = 1;
+
+/// Mock memory.
+pub struct CompMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for CompMem {
+    const INITIAL_ADDRESS_POINTER: u32 = COMPMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+/// Composite device: a vendor-specific stub class as interface 0,
+/// and DFU as interface 1.
+///
+/// Emulated device polls a single class, so requests are passed to
+/// `DFUClass` only, the stub doesn't handle any, and `DFUClass` itself
+/// must ignore requests for other interfaces.
+pub struct Composite {
+    stub_if: InterfaceNumber,
+    dfu: DFUClass<EmulatedUsbBus, CompMem>,
+}
+
+impl UsbClass<EmulatedUsbBus> for Composite {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        writer.interface(self.stub_if, 0xff, 0, 0)?;
+        self.dfu.get_configuration_descriptors(writer)
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        self.dfu.get_string(index, lang_id)
+    }
+
+    fn reset(&mut self) {
+        self.dfu.reset()
+    }
+
+    fn poll(&mut self) {
+        self.dfu.poll()
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<EmulatedUsbBus>) {
+        self.dfu.control_in(xfer)
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<EmulatedUsbBus>) {
+        self.dfu.control_out(xfer)
+    }
+}
+
+struct MkComposite {}
+
+impl UsbDeviceCtx for MkComposite {
+    type C<'c> = Composite;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<Composite> {
+        let mem = CompMem {
+            mem: MockMem::new(COMPMEM_BASE, 1, 1024),
+        };
+        let stub_if = alloc.interface();
+        Ok(Composite {
+            stub_if,
+            dfu: DFUClass::new(alloc, mem),
+        })
+    }
+}
+
+#[test]
+fn test_composite_descriptors() {
+    MkComposite {}
+        .with_usb(|mut cls, mut dev| {
+            let desc = dev
+                .device_get_descriptor(&mut cls, 2, 0, 0, 255)
+                .expect("desc");
+
+            // configuration, stub interface, DFU interface
+            assert_eq!(desc[9..13], [9, 4, 0, 0]);
+            assert_eq!(desc[18..22], [9, 4, DFU_IF as u8, 0]);
+            assert_eq!(desc[23..26], [0xfe, 0x01, 0x02]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_composite_wrong_index() {
+    MkComposite {}
+        .with_usb(|mut cls, mut dev| {
+            /* Download block 2 for interface 0, not handled */
+            let e = dev
+                .write(&mut cls, 0x1, 2, 0, 32, &[0x55; 32])
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status for interface 0, not handled */
+            let e = dev.read(&mut cls, 0x3, 0, 0, 6).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Detach for interface 0, not handled */
+            let e = dev
+                .write(&mut cls, 0x0, 1000, 0, 0, &[])
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, DFU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            assert!(!cls.dfu.download_in_progress());
+            let mem = cls.dfu.release();
+            assert_eq!(mem.mem.calls, []);
+            assert_eq!(mem.mem.memory()[..32], [0xff; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_composite_stub_requests() {
+    MkComposite {}
+        .with_usb(|mut cls, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev
+                .write(&mut cls, 0x1, 2, DFU_IF, 32, &[0x55; 32])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, DFU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, DFU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            // stub interface requests that look like DFU requests
+            /* Abort for interface 0 */
+            let e = dev.write(&mut cls, 0x6, 0, 0, 0, &[]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Download len 0 for interface 0, would start manifestation */
+            let e = dev.write(&mut cls, 0x1, 3, 0, 0, &[]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Download block 0 (command) for interface 0, erase all */
+            let e = dev
+                .write(&mut cls, 0x1, 0, 0, 1, &[0x41])
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get State for interface 0 */
+            let e = dev.read(&mut cls, 0x5, 0, 0, 1).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get State, download session is intact */
+            let vec = dev.read(&mut cls, 0x5, 0, DFU_IF, 1).expect("vec");
+            assert_eq!(vec, [DFUState::DfuDnloadIdle as u8]);
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, DFU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            assert!(cls.dfu.download_in_progress());
+            let mem = cls.dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::Program(COMPMEM_BASE, 32)]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_composite_correct_index() {
+    MkComposite {}
+        .with_usb(|mut cls, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev
+                .write(&mut cls, 0x1, 2, DFU_IF, 32, &[0x55; 32])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, DFU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, DFU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1) len 0, trigger manifestation */
+            let vec = dev.write(&mut cls, 0x1, 3, DFU_IF, 0, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, DFU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, DFU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.read(&mut cls, 0x2, 2, DFU_IF, 32).expect("vec");
+            assert_eq!(vec, [0x55; 32]);
+
+            let mem = cls.dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(COMPMEM_BASE, 32),
+                    MockCall::Manifestation,
+                    MockCall::Read(COMPMEM_BASE, 32)
+                ]
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,157 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::{Cell, RefCell};
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const CTMEM_BASE: u32 = 0x0800_0000;
+
+thread_local! {
+    /// Fake clock, in milliseconds
+    static CLOCK: Cell<u32> = const { Cell::new(1000) };
+    /// Status replies received by a host
+    static REPLIES: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn advance_clock(ms: u32) {
+    CLOCK.with(|c| c.set(c.get() + ms));
+}
+
+fn set_clock(ms: u32) {
+    CLOCK.with(|c| c.set(ms));
+}
+
+/// Memory that verifies an image at manifestation.
+pub struct CtMem {
+    mem: MockMem,
+    verify: fn() -> Result<(), DFUManifestationError>,
+}
+
+impl DFUMemIO for CtMem {
+    const INITIAL_ADDRESS_POINTER: u32 = CTMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const MANIFESTATION_TIME_MS: u32 = 100;
+    const TRANSFER_SIZE: u16 = 32;
+    const MANIFESTATION_CONSTANT_TIME: bool = true;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        let r = (self.verify)();
+        if r.is_ok() {
+            // a successful check takes longer
+            advance_clock(30);
+        }
+        r
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        CLOCK.with(|c| c.get())
+    }
+}
+
+struct MkCt {
+    verify: fn() -> Result<(), DFUManifestationError>,
+}
+
+impl UsbDeviceCtx for MkCt {
+    type C<'c> = DFUClass<EmulatedUsbBus, CtMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, CtMem>> {
+        let mem = CtMem {
+            mem: MockMem::new(CTMEM_BASE, 1, 1024),
+            verify: self.verify,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Download an image, and return status replies during manifestation
+fn manifestation_replies(verify: fn() -> Result<(), DFUManifestationError>) -> Vec<Vec<u8>> {
+    REPLIES.with(|r| r.borrow_mut().clear());
+
+    MkCt { verify }
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1) len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            // host asks at the same times, regardless of how long the check takes
+            let start = CLOCK.with(|c| c.get());
+            for i in 0..4 {
+                set_clock(start + i * 40);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                REPLIES.with(|r| r.borrow_mut().push(vec));
+            }
+        })
+        .expect("with_usb");
+
+    REPLIES.with(|r| r.borrow().clone())
+}
+
+#[test]
+fn test_manifestation_constant_time() {
+    let pass = manifestation_replies(|| Ok(()));
+    let bad_key = manifestation_replies(|| Err(DFUManifestationError::Target));
+    let bad_format = manifestation_replies(|| Err(DFUManifestationError::File));
+
+    // result is not visible until MANIFESTATION_TIME_MS elapses
+    for replies in [&pass, &bad_key, &bad_format] {
+        assert_eq!(
+            replies[..3],
+            [status(DFUStatusCode::OK, 100, DFUState::DfuManifest); 3]
+        );
+    }
+
+    assert_eq!(pass[3], status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+    assert_eq!(
+        bad_key[3],
+        status(DFUStatusCode::ErrFile, 0, DFUState::DfuError)
+    );
+    assert_eq!(bad_key, bad_format);
+}
@@ -0,0 +1,269 @@
+#![allow(unused_variables)]
+
+use std::ops::Range;
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::crc::crc32;
+use usbd_dfu::dfuse::DfuseCommand;
+
+const CRCMEM_BASE: u32 = 0x0200_0000;
+const REDACTED: Range<u32> = CRCMEM_BASE + 0x800..CRCMEM_BASE + 0x810;
+
+/// Memory with a pattern, the last page is not readable
+pub struct CrcMem<const CRC: bool> {
+    mem: MockMem,
+}
+
+impl<const CRC: bool> CrcMem<CRC> {
+    fn new() -> Self {
+        let mut mem = MockMem::new(CRCMEM_BASE, 4, 1024);
+        for (i, b) in mem.memory_mut().iter_mut().enumerate() {
+            *b = (i ^ (i >> 8)) as u8;
+        }
+        Self { mem }
+    }
+}
+
+impl<const CRC: bool> DFUMemIO for CrcMem<CRC> {
+    const INITIAL_ADDRESS_POINTER: u32 = CRCMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x02000000/3*1Kg,1*1Kb";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 64;
+    const REDACTED_RANGES: &'static [Range<u32>] = &[REDACTED];
+    const HAS_CRC_COMMAND: bool = CRC;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkCrc<const CRC: bool> {}
+
+impl<const CRC: bool> UsbDeviceCtx for MkCrc<CRC> {
+    type C<'c> = DFUClass<EmulatedUsbBus, CrcMem<CRC>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, CrcMem<CRC>>> {
+        Ok(DFUClass::new(alloc, CrcMem::new()))
+    }
+}
+
+/// Host-side CRC-32 of a memory range, redacted bytes are `0xff`
+fn host_crc(range: Range<u32>) -> u32 {
+    let mem = CrcMem::<true>::new();
+    let data: Vec<u8> = range
+        .map(|a| {
+            if REDACTED.contains(&a) {
+                0xff
+            } else {
+                mem.mem.memory()[(a - CRCMEM_BASE) as usize]
+            }
+        })
+        .collect();
+    crc32(&data)
+}
+
+/// Send CRC command, wait until it's done, return the number of dfuDNBUSY replies
+/// and the final status
+fn crc_command<C>(
+    dfu: &mut C,
+    dev: &mut impl DeviceExt<C>,
+    address: u32,
+    length: u32,
+) -> (usize, Vec<u8>) {
+    let mut buf = [0u8; DfuseCommand::MAX_LEN];
+    let len = DfuseCommand::Crc { address, length }.encode(&mut buf);
+
+    /* Download block 0 (command) */
+    let vec = dev.download(dfu, 0, &buf[..len]).expect("vec");
+    assert_eq!(vec, []);
+
+    let mut busy = 0;
+    loop {
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        if vec != status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy) {
+            return (busy, vec);
+        }
+        busy += 1;
+    }
+}
+
+#[test]
+fn test_crc_command() {
+    MkCrc::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 0 (get commands) */
+            let vec = dev.upload(&mut dfu, 0, 32).expect("vec");
+            assert_eq!(vec, [0x00, 0x21, 0x41, 0xe4]);
+
+            let range = CRCMEM_BASE + 3..CRCMEM_BASE + 0xc00;
+            let (busy, vec) = crc_command(&mut dfu, &mut dev, range.start, range.len() as u32);
+            assert!(busy > 1, "computed in a single poll");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2, the digest */
+            let vec = dev.upload(&mut dfu, 2, 64).expect("vec");
+            assert_eq!(vec, host_crc(range).to_le_bytes());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 2 (offset 0), memory again */
+            let vec = dev.upload(&mut dfu, 2, 64).expect("vec");
+            assert_eq!(vec, CrcMem::<true>::new().mem.memory()[..64]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_crc_command_small() {
+    MkCrc::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            for range in [
+                CRCMEM_BASE + 0x7f0..CRCMEM_BASE + 0x820,
+                CRCMEM_BASE + 0x10..CRCMEM_BASE + 0x11,
+                CRCMEM_BASE..CRCMEM_BASE,
+            ] {
+                let (busy, vec) = crc_command(&mut dfu, &mut dev, range.start, range.len() as u32);
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+                /* Abort */
+                let vec = dev.abort(&mut dfu).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Upload block 2, the digest */
+                let vec = dev.upload(&mut dfu, 2, 64).expect("vec");
+                assert_eq!(vec, host_crc(range).to_le_bytes());
+            }
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_crc_command_not_readable() {
+    MkCrc::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            for (address, length) in [
+                (CRCMEM_BASE + 0xbff, 2),
+                (CRCMEM_BASE + 0x1000, 1),
+                (0xffff_fff0, 0x20),
+            ] {
+                let (busy, vec) = crc_command(&mut dfu, &mut dev, address, length);
+                assert_eq!(busy, 1);
+                assert_eq!(
+                    vec,
+                    status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+                );
+
+                /* Clear Status */
+                let vec = dev.clear_status(&mut dfu).expect("vec");
+                assert_eq!(vec, []);
+            }
+
+            /* Upload block 2 (offset 0), there is no digest */
+            let vec = dev.upload(&mut dfu, 2, 64).expect("vec");
+            assert_eq!(vec, CrcMem::<true>::new().mem.memory()[..64]);
+
+            /* Only the range outside of memory and the upload are read */
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Read(CRCMEM_BASE + 0x1000, 1),
+                    MockCall::Read(CRCMEM_BASE, 64)
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_crc_command_stale() {
+    MkCrc::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            let (busy, vec) = crc_command(&mut dfu, &mut dev, CRCMEM_BASE, 16);
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Set Address Pointer, the digest is dropped */
+            let b = (CRCMEM_BASE + 0x40).to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0x40) */
+            let vec = dev.upload(&mut dfu, 2, 64).expect("vec");
+            assert_eq!(vec, CrcMem::<true>::new().mem.memory()[0x40..0x80]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_crc_command_disabled() {
+    MkCrc::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 0 (get commands) */
+            let vec = dev.upload(&mut dfu, 0, 32).expect("vec");
+            assert_eq!(vec, [0x00, 0x21, 0x41]);
+
+            let mut buf = [0u8; DfuseCommand::MAX_LEN];
+            let len = DfuseCommand::Crc {
+                address: CRCMEM_BASE,
+                length: 16,
+            }
+            .encode(&mut buf);
+
+            /* Download block 0 (command), not supported */
+            let e = dev.download(&mut dfu, 0, &buf[..len]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,195 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::crc::{crc32, crc32_update};
+
+const CRCMEMSIZE: usize = 1024;
+const CRCMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that expects a CRC-32 trailer in every download block.
+pub struct CrcMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for CrcMem {
+    const INITIAL_ADDRESS_POINTER: u32 = CRCMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 64;
+    const BLOCK_CRC: bool = true;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkCrc {}
+
+impl UsbDeviceCtx for MkCrc {
+    type C<'c> = DFUClass<EmulatedUsbBus, CrcMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, CrcMem>> {
+        let mem = CrcMem {
+            mem: MockMem::new(CRCMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Host side: append CRC-32 trailer to a block
+fn with_crc(payload: &[u8]) -> Vec<u8> {
+    let mut v = payload.to_vec();
+    v.extend_from_slice(&crc32(payload).to_le_bytes());
+    v
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+    let crc = crc32_update(0xffff_ffff, b"1234");
+    let crc = crc32_update(crc, b"56789");
+    assert_eq!(crc ^ 0xffff_ffff, 0xcbf4_3926);
+}
+
+#[test]
+fn test_download_crc() {
+    MkCrc {}
+        .with_usb(|mut dfu, mut dev| {
+            let image: Vec<u8> = (0..150).map(|i| i as u8).collect();
+
+            // 60 bytes of payload per block
+            for (block, payload) in image.chunks(60).enumerate() {
+                /* Download block */
+                let vec = dev
+                    .download(&mut dfu, 2 + block as u16, &with_crc(payload))
+                    .expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(CRCMEM_BASE, 60),
+                    MockCall::Program(CRCMEM_BASE + 60, 60),
+                    MockCall::Program(CRCMEM_BASE + 120, 30)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..150], image);
+            assert_eq!(mem.mem.memory()[150..], [0xff; CRCMEMSIZE - 150]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_download_crc_corrupted() {
+    MkCrc {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev
+                .download(&mut dfu, 2, &with_crc(&[0x11; 60]))
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1), a bit flipped in transit */
+            let mut block = with_crc(&[0x22; 60]);
+            block[10] ^= 0x04;
+            let vec = dev.download(&mut dfu, 3, &block);
+            assert_eq!(vec, Err(AnyUsbError::EPStalled));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 3 (offset 1) again */
+            let vec = dev
+                .download(&mut dfu, 3, &with_crc(&[0x22; 60]))
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 4 (offset 2), trailer only */
+            let vec = dev.download(&mut dfu, 4, &with_crc(&[]));
+            assert_eq!(vec, Err(AnyUsbError::EPStalled));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(CRCMEM_BASE, 60),
+                    MockCall::Program(CRCMEM_BASE + 60, 60)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..60], [0x11; 60]);
+            assert_eq!(mem.mem.memory()[60..120], [0x22; 60]);
+        })
+        .expect("with_usb");
+}
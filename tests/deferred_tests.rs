@@ -0,0 +1,240 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const DEFMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that is accessed from the main loop only.
+pub struct DefMem {
+    mem: MockMem,
+    resets: Vec<ResetContext>,
+}
+
+impl DFUMemIO for DefMem {
+    const INITIAL_ADDRESS_POINTER: u32 = DEFMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const MANIFESTATION_TIME_MS: u32 = 40;
+    const TRANSFER_SIZE: u16 = 32;
+    const MEMIO_IN_USB_INTERRUPT: bool = false;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        self.resets.push(ctx);
+    }
+}
+
+struct MkDef {}
+
+impl UsbDeviceCtx for MkDef {
+    type C<'c> = DFUClass<EmulatedUsbBus, DefMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, DefMem>> {
+        let mem = DefMem {
+            mem: MockMem::new(DEFMEM_BASE, 1, 1024),
+            resets: Vec::new(),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_deferred_download() {
+    MkDef {}
+        .with_usb(|mut dfu, mut dev| {
+            assert!(!dfu.update_pending());
+
+            /* Download block 0 (command), erase page 0x08000000 */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, 0x00, 0x00, 0x00, 0x08])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+            assert!(dfu.update_pending());
+
+            // not executed from poll
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+            assert!(dfu.update_pending());
+
+            dfu.update();
+            assert!(!dfu.update_pending());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            dfu.update();
+            // nothing left to do
+            dfu.update();
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1) len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 40, DFUState::DfuManifest));
+            assert!(dfu.update_pending());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 40, DFUState::DfuManifest));
+
+            dfu.update();
+            assert!(!dfu.update_pending());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Erase(DEFMEM_BASE),
+                    MockCall::Program(DEFMEM_BASE, 32),
+                    MockCall::Manifestation
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..32], [0x55; 32]);
+            assert_eq!(mem.mem.memory()[32..64], [0xff; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_deferred_poll_activity() {
+    MkDef {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), mass erase */
+            let vec = dev.download(&mut dfu, 0, &[0x41]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 30, DFUState::DfuDnBusy));
+
+            // poll didn't call memory functions
+            assert_eq!(dfu.take_poll_activity(), PollActivity::CommandPending);
+            assert!(dfu.is_busy());
+
+            dfu.update();
+            assert!(!dfu.is_busy());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::EraseAll]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_deferred_manifestation_on_reset() {
+    MkDef {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            dfu.update();
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1) len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            // host resets the bus before manifestation has started
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            // manifestation is not executed from USB reset handler
+            assert!(dfu.update_pending());
+            let vec = dev.get_state(&mut dfu).expect("vec");
+            assert_eq!(vec, [DFUState::DfuManifestSync as u8]);
+
+            dfu.update();
+            assert!(!dfu.update_pending());
+            let vec = dev.get_state(&mut dfu).expect("vec");
+            assert_eq!(vec, [DFUState::DfuIdle as u8]);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [MockCall::Program(DEFMEM_BASE, 32), MockCall::Manifestation]
+            );
+            assert_eq!(
+                mem.resets,
+                [ResetContext {
+                    state: DFUState::DfuIdle,
+                    manifested: true,
+                    downloaded: true,
+                }]
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,139 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const LOGMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that fails to program the block at offset 512 and to manifest.
+pub struct LogMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for LogMem {
+    const INITIAL_ADDRESS_POINTER: u32 = LOGMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkLog {}
+
+impl UsbDeviceCtx for MkLog {
+    type C<'c> = DFUClass<EmulatedUsbBus, LogMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, LogMem>> {
+        let mut mem = LogMem {
+            mem: MockMem::new(LOGMEM_BASE, 1, 1024),
+        };
+        mem.mem.faults.program = Some((LOGMEM_BASE + 512, DFUMemError::Prog));
+        mem.mem.faults.manifestation = Some(DFUManifestationError::Firmware);
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+fn is_format<T: defmt::Format>() {}
+
+#[test]
+fn test_defmt_format() {
+    is_format::<DFUMemError>();
+    is_format::<DFUManifestationError>();
+}
+
+#[test]
+fn test_defmt_logged_paths() {
+    // logging must not change the protocol
+    MkLog {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 18 (offset 512), program fails */
+            let vec = dev.download(&mut dfu, 18, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 2 (offset 0), then leave DFU, manifestation fails */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download 0 length */
+            let vec = dev.download(&mut dfu, 0, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrFirmware, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,321 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::Cell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usbd_dfu::class::*;
+
+const DETMEM_BASE: u32 = 0x0800_0000;
+
+thread_local! {
+    /// Fake clock, in milliseconds
+    static CLOCK: Cell<u32> = const { Cell::new(1000) };
+    /// Timeout of the last on_detach_request() call
+    static DETACHED: Cell<Option<u16>> = const { Cell::new(None) };
+    /// Number of on_detach_expired() calls
+    static EXPIRED: Cell<u32> = const { Cell::new(0) };
+}
+
+fn advance_clock(ms: u32) {
+    CLOCK.with(|c| c.set(c.get() + ms));
+}
+
+fn detached() -> Option<u16> {
+    DETACHED.with(|d| d.take())
+}
+
+fn expired() -> u32 {
+    EXPIRED.with(|e| e.take())
+}
+
+/// Memory with a fake clock that records detach requests,
+/// `D` is `WILL_DETACH` value.
+pub struct DetMem<const D: bool = true> {
+    mem: MockMem,
+}
+
+impl<const D: bool> DFUMemIO for DetMem<D> {
+    const INITIAL_ADDRESS_POINTER: u32 = DETMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const WILL_DETACH: bool = D;
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        CLOCK.with(|c| c.get())
+    }
+
+    fn on_detach_request(&mut self, timeout_ms: u16) {
+        DETACHED.with(|d| d.set(Some(timeout_ms)));
+    }
+
+    fn on_detach_expired(&mut self) {
+        EXPIRED.with(|e| e.set(e.get() + 1));
+    }
+}
+
+struct MkDet {}
+
+impl UsbDeviceCtx for MkDet {
+    type C<'c> = DFUClass<EmulatedUsbBus, DetMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, DetMem>> {
+        let mem = DetMem {
+            mem: MockMem::new(DETMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_detach_idle() {
+    MkDet {}
+        .with_usb(|mut dfu, mut dev| {
+            assert!(!dfu.detach_pending());
+
+            /* Detach */
+            let vec = dev.detach(&mut dfu, 500).expect("vec");
+            assert_eq!(vec, []);
+            assert_eq!(detached(), Some(500));
+            assert!(dfu.detach_pending());
+
+            /* Get Status, state is not changed */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            advance_clock(499);
+            dfu.poll();
+            assert!(dfu.detach_pending());
+
+            advance_clock(1);
+            dfu.poll();
+            assert!(!dfu.detach_pending());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_detach_dnload_idle() {
+    MkDet {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Detach */
+            let vec = dev.detach(&mut dfu, 100).expect("vec");
+            assert_eq!(vec, []);
+            assert_eq!(detached(), Some(100));
+            assert!(dfu.detach_pending());
+
+            /* Get Status, the session continues */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            assert!(dfu.download_in_progress());
+
+            /* Download block 3 (offset 1) */
+            let vec = dev.download(&mut dfu, 3, &[0xaa; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            // USB reset cancels the request
+            dev.bus_reset(&mut dfu).expect("reset");
+            assert!(!dfu.detach_pending());
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..32], [0x55; 32]);
+            assert_eq!(mem.mem.memory()[32..64], [0xaa; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_detach_error_state() {
+    MkDet {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), unknown command */
+            let e = dev.download(&mut dfu, 0, &[0x99]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Detach */
+            let vec = dev.detach(&mut dfu, 0).expect("vec");
+            assert_eq!(vec, []);
+            assert_eq!(detached(), Some(0));
+
+            /* Get Status, the error is not changed */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+
+            // zero timeout expires immediately
+            assert!(!dfu.detach_pending());
+        })
+        .expect("with_usb");
+}
+
+struct MkNoDet {}
+
+impl UsbDeviceCtx for MkNoDet {
+    type C<'c> = DFUClass<EmulatedUsbBus, DetMem<false>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, DetMem<false>>> {
+        let mem = DetMem {
+            mem: MockMem::new(DETMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_will_detach_descriptor() {
+    MkDet {}
+        .with_usb(|mut dfu, mut dev| {
+            let vec = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 130)
+                .expect("vec");
+            assert_eq!(vec[18..21], [9, 0x21, 0b1111]);
+        })
+        .expect("with_usb");
+
+    MkNoDet {}
+        .with_usb(|mut dfu, mut dev| {
+            let vec = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 130)
+                .expect("vec");
+            // bitWillDetach is cleared
+            assert_eq!(vec[18..21], [9, 0x21, 0b0111]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_no_will_detach() {
+    MkNoDet {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Detach */
+            let vec = dev.detach(&mut dfu, 500).expect("vec");
+            assert_eq!(vec, []);
+            assert!(dfu.detach_pending());
+
+            // the device waits for a bus reset, the application is not asked to detach
+            assert_eq!(detached(), None);
+
+            /* Get Status, state is not changed */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            dev.bus_reset(&mut dfu).expect("reset");
+            assert!(!dfu.detach_pending());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_detach_tick_expired() {
+    MkDet {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Detach */
+            let vec = dev.detach(&mut dfu, 500).expect("vec");
+            assert_eq!(vec, []);
+            assert!(dfu.detach_pending());
+
+            dfu.tick(499);
+            assert!(dfu.detach_pending());
+            assert_eq!(expired(), 0);
+
+            dfu.tick(1);
+            assert!(!dfu.detach_pending());
+            assert_eq!(expired(), 1);
+
+            // nothing is armed
+            dfu.tick(1000);
+            assert_eq!(expired(), 0);
+
+            /* Get Status, state is not changed */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_detach_tick_reset() {
+    MkDet {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Detach */
+            let vec = dev.detach(&mut dfu, 500).expect("vec");
+            assert_eq!(vec, []);
+
+            dfu.tick(200);
+            assert!(dfu.detach_pending());
+
+            // reset cancels the request
+            dev.bus_reset(&mut dfu).expect("reset");
+            assert!(!dfu.detach_pending());
+
+            dfu.tick(1000);
+            assert_eq!(expired(), 0);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,127 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const INFOMEM_BASE: u32 = 0x0800_0000;
+
+/// Chip UID, bootloader version, and active bank
+const DEVICE_INFO: [u8; 16] = [
+    0x11, 0x22, 0x33, 0x44, 0x55, 0x66, 0x77, 0x88, 0x99, 0xaa, 0xbb, 0xcc, 1, 2, 0, 1,
+];
+
+/// Memory with `I` as `HAS_DEVICE_INFO` value.
+pub struct InfoMem<const I: bool> {
+    mem: MockMem,
+}
+
+impl<const I: bool> DFUMemIO for InfoMem<I> {
+    const INITIAL_ADDRESS_POINTER: u32 = INFOMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const HAS_DEVICE_INFO: bool = I;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn device_info(&mut self, buf: &mut [u8]) -> usize {
+        let len = buf.len().min(DEVICE_INFO.len());
+        buf[..len].copy_from_slice(&DEVICE_INFO[..len]);
+        len
+    }
+}
+
+struct MkInfo<const I: bool> {}
+
+impl<const I: bool> UsbDeviceCtx for MkInfo<I> {
+    type C<'c> = DFUClass<EmulatedUsbBus, InfoMem<I>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, InfoMem<I>>> {
+        let mem = InfoMem {
+            mem: MockMem::new(INFOMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_device_info() {
+    MkInfo::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 1, device information */
+            let vec = dev.upload(&mut dfu, 1, 64).expect("vec");
+            assert_eq!(vec, DEVICE_INFO);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 1, limited by wLength */
+            let vec = dev.upload(&mut dfu, 1, 12).expect("vec");
+            assert_eq!(vec, DEVICE_INFO[..12]);
+
+            /* Upload block 0, Get Commands is not changed */
+            let vec = dev.upload(&mut dfu, 0, 64).expect("vec");
+            assert_eq!(vec, [0x00, 0x21, 0x41]);
+
+            /* Upload block 0, 3 bytes */
+            let vec = dev.upload(&mut dfu, 0, 3).expect("vec");
+            assert_eq!(vec, [0x00, 0x21, 0x41]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_device_info_disabled() {
+    MkInfo::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 0, 3 bytes */
+            let vec = dev.upload(&mut dfu, 0, 3).expect("vec");
+            assert_eq!(vec, [0x00, 0x21, 0x41]);
+
+            /* Upload block 1 */
+            let e = dev.upload(&mut dfu, 1, 64).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
@@ -64,11 +64,11 @@ impl UsbDeviceCtx for MkDFU {
     type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
     const EP0_SIZE: u8 = 32;
 
-    fn create_class<'a>(
+    fn create_class(
         &mut self,
-        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
-        Ok(DFUClass::new(&alloc, TestMem::new()))
+        Ok(DFUClass::new(alloc, TestMem::new()))
     }
 }
 
@@ -80,7 +80,7 @@ fn test_manifestation() {
 
             /* Get Status */
             vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(&vec[..], &status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(&vec[..], &status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 3 (offset 1) len 0, trigger manifestation */
             vec = dev.download(&mut dfu, 3, &[]).expect("vec");
@@ -88,19 +88,22 @@ fn test_manifestation() {
 
             /* Get State */
             vec = dev.get_state(&mut dfu).expect("vec");
-            assert_eq!(&vec[..], &[DFU_MANIFEST_SYNC]);
+            assert_eq!(&vec[..], &[DFUState::DfuManifestSync as u8]);
 
             /* Get Status */
             vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(&vec[..], &status(STATUS_OK, 0x123, DFU_MANIFEST));
+            assert_eq!(
+                &vec[..],
+                &status(DFUStatusCode::OK, 0x123, DFUState::DfuManifest)
+            );
 
             /* Get State */
             vec = dev.get_state(&mut dfu).expect("vec");
-            assert_eq!(&vec[..], &[DFU_MANIFEST_SYNC]);
+            assert_eq!(&vec[..], &[DFUState::DfuManifestSync as u8]);
 
             /* Get Status */
             vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(&vec[..], &status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(&vec[..], &status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
         })
         .expect("with_usb");
 }
@@ -115,7 +118,10 @@ fn test_err_por() {
 
             /* Get Status */
             vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(&vec[..], &status(STATUS_ERR_POR, 0, DFU_ERROR));
+            assert_eq!(
+                &vec[..],
+                &status(DFUStatusCode::ErrPOR, 0, DFUState::DfuError)
+            );
 
             /* Clear Status */
             vec = dev.clear_status(&mut dfu).expect("vec");
@@ -123,7 +129,7 @@ fn test_err_por() {
 
             /* Get Status */
             vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(&vec[..], &status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(&vec[..], &status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
         })
         .expect("with_usb");
 }
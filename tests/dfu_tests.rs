@@ -18,15 +18,16 @@ pub struct TestMem {
     overrides: TestMemOverride,
 }
 
+type ReadFn = fn(&mut TestMem, address: u32, length: usize) -> Result<&[u8], DFUMemError>;
+type EraseFn = fn(&mut TestMem, address: u32) -> Result<(), DFUMemError>;
+type ProgramFn = fn(&mut TestMem, address: u32, length: usize) -> Result<(), DFUMemError>;
+type ManifestationFn = fn(&mut TestMem) -> Result<(), DFUManifestationError>;
+
 struct TestMemOverride {
-    read: Option<
-        fn(&mut TestMem, address: u32, length: usize) -> core::result::Result<&[u8], DFUMemError>,
-    >,
-    erase: Option<fn(&mut TestMem, address: u32) -> Result<(), DFUMemError>>,
-    program: Option<
-        fn(&mut TestMem, address: u32, length: usize) -> core::result::Result<(), DFUMemError>,
-    >,
-    manifestation: Option<fn(&mut TestMem) -> Result<(), DFUManifestationError>>,
+    read: Option<ReadFn>,
+    erase: Option<EraseFn>,
+    program: Option<ProgramFn>,
+    manifestation: Option<ManifestationFn>,
 }
 
 impl TestMem {
@@ -95,7 +96,8 @@ impl TestMem {
 const TESTMEM_BASE: u32 = 0x0200_0000;
 
 impl DFUMemIO for TestMem {
-    const INITIAL_ADDRESS_POINTER: u32 = TESTMEM_BASE;
+    // the first 16K are read-only
+    const INITIAL_ADDRESS_POINTER: u32 = TESTMEM_BASE + 0x4000;
     const MANIFESTATION_TOLERANT: bool = false;
     const PROGRAM_TIME_MS: u32 = 50;
     const ERASE_TIME_MS: u32 = 0x1ff;
@@ -199,11 +201,11 @@ impl UsbDeviceCtx for MkDFU {
     type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
     const EP0_SIZE: u8 = 32;
 
-    fn create_class<'a>(
+    fn create_class(
         &mut self,
-        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
-        Ok(DFUClass::new(&alloc, TestMem::new(None)))
+        Ok(DFUClass::new(alloc, TestMem::new(None)))
     }
 }
 
@@ -213,7 +215,7 @@ fn test_simple_get_status() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
         })
         .expect("with_usb");
 }
@@ -289,7 +291,7 @@ fn test_set_address_pointer() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 0 (command), address pointer = new_addr */
             let b = new_addr.to_le_bytes();
@@ -301,12 +303,12 @@ fn test_set_address_pointer() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DN_BUSY));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
             assert_eq!(dfu.get_address_pointer(), new_addr);
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
         })
         .expect("with_usb");
 }
@@ -317,27 +319,27 @@ fn test_upload() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Upload block 2 (offset 0) */
             let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
             assert_eq!(vec.len(), 128);
-            assert_eq!(vec[0..10], [0, 0, 1, 0, 2, 0, 3, 0, 4, 0]);
-            assert_eq!(vec[120..128], [60, 0, 61, 0, 62, 0, 63, 0]);
+            assert_eq!(vec[0..10], [0, 32, 1, 32, 2, 32, 3, 32, 4, 32]);
+            assert_eq!(vec[120..128], [60, 32, 61, 32, 62, 32, 63, 32]);
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_UPLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
 
             /* Upload block 7 (offset 5*128) */
             let vec = dev.upload(&mut dfu, 7, 128).expect("vec");
             assert_eq!(vec.len(), 128);
-            assert_eq!(vec[0..10], [64, 1, 65, 1, 66, 1, 67, 1, 68, 1]);
-            assert_eq!(vec[120..128], [124, 1, 125, 1, 126, 1, 127, 1]);
+            assert_eq!(vec[0..10], [64, 33, 65, 33, 66, 33, 67, 33, 68, 33]);
+            assert_eq!(vec[120..128], [124, 33, 125, 33, 126, 33, 127, 33]);
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_UPLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
 
             /* Abort */
             let vec = dev.abort(&mut dfu).expect("vec");
@@ -345,7 +347,169 @@ fn test_upload() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+}
+
+struct MkDFUBase {}
+
+impl UsbDeviceCtx for MkDFUBase {
+    type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
+        Ok(DFUClass::new_with_address(
+            alloc,
+            TestMem::new(None),
+            TESTMEM_BASE + 1024,
+        ))
+    }
+}
+
+#[test]
+fn test_initial_address() {
+    MkDFUBase {}
+        .with_usb(|mut dfu, mut dev| {
+            assert_eq!(dfu.get_address_pointer(), TESTMEM_BASE + 1024);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 2 (offset 0 from 1024) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec.len(), 128);
+            assert_eq!(vec[0..10], [0, 2, 1, 2, 2, 2, 3, 2, 4, 2]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            assert_eq!(dfu.get_address_pointer(), TESTMEM_BASE + 1024);
+        })
+        .expect("with_usb");
+}
+
+struct MkDFUState {
+    initial: Option<InitialState>,
+}
+
+impl UsbDeviceCtx for MkDFUState {
+    type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
+        let initial = self.initial.take().expect("initial");
+        Ok(DFUClass::new_with_state(alloc, TestMem::new(None), initial))
+    }
+}
+
+#[test]
+fn test_initial_state_error() {
+    let initial = Some(InitialState::Error(DFUMemError::Verify));
+    MkDFUState { initial }
+        .with_usb(|mut dfu, mut dev| {
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrVerify, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_initial_state_reset() {
+    let initial = Some(InitialState::UnexpectedReset);
+    MkDFUState { initial }
+        .with_usb(|mut dfu, mut dev| {
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrPOR, 0, DFUState::DfuError));
+        })
+        .expect("with_usb");
+
+    let initial = Some(InitialState::FirmwareCorrupted);
+    MkDFUState { initial }
+        .with_usb(|mut dfu, mut dev| {
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrFirmware, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_initial_state_idle_at() {
+    let initial = Some(InitialState::IdleAt(TESTMEM_BASE + 1024));
+    MkDFUState { initial }
+        .with_usb(|mut dfu, mut dev| {
+            assert_eq!(dfu.get_address_pointer(), TESTMEM_BASE + 1024);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 2 (offset 0 from 1024) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec[0..10], [0, 2, 1, 2, 2, 2, 3, 2, 4, 2]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_upload_pointer_change() {
+    MkDFU {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec[0..10], [0, 32, 1, 32, 2, 32, 3, 32, 4, 32]);
+
+            // pointer changes in the middle of the upload session
+            dfu.set_address_pointer(TestMem::INITIAL_ADDRESS_POINTER + 1024);
+
+            /* Upload block 3 (offset 128), still from the initial base */
+            let vec = dev.upload(&mut dfu, 3, 128).expect("vec");
+            assert_eq!(vec[0..10], [64, 32, 65, 32, 66, 32, 67, 32, 68, 32]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0), the next session uses the new base */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec[0..10], [0, 34, 1, 34, 2, 34, 3, 34, 4, 34]);
+
+            // short frame ends the session
+            dfu.set_address_pointer(TestMem::INITIAL_ADDRESS_POINTER);
+
+            /* Upload block 3 (offset 128), short frame */
+            let vec = dev.upload(&mut dfu, 3, 16).expect("vec");
+            assert_eq!(vec[0..10], [64, 34, 65, 34, 66, 34, 67, 34, 68, 34]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec[0..10], [0, 32, 1, 32, 2, 32, 3, 32, 4, 32]);
         })
         .expect("with_usb");
 }
@@ -362,7 +526,7 @@ fn test_erase() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 0 (command), erase = blkaddr */
             let b = blkaddr.to_le_bytes();
@@ -373,11 +537,18 @@ fn test_erase() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, TestMem::ERASE_TIME_MS, DFU_DN_BUSY));
+            assert_eq!(
+                vec,
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::ERASE_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
+            );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Abort */
             let vec = dev.abort(&mut dfu).expect("vec");
@@ -386,8 +557,8 @@ fn test_erase() {
             /* Upload block 9 (offset 7) - not erased */
             let vec = dev.upload(&mut dfu, 9, 128).expect("vec");
             assert_eq!(vec.len(), 128);
-            assert_eq!(vec[0..10], [192, 1, 193, 1, 194, 1, 195, 1, 196, 1]);
-            assert_eq!(vec[120..128], [252, 1, 253, 1, 254, 1, 255, 1]);
+            assert_eq!(vec[0..10], [192, 33, 193, 33, 194, 33, 195, 33, 196, 33]);
+            assert_eq!(vec[120..128], [252, 33, 253, 33, 254, 33, 255, 33]);
 
             /* Upload block 10 (offset 8) - erased */
             let vec = dev.upload(&mut dfu, 10, 128).expect("vec");
@@ -404,8 +575,8 @@ fn test_erase() {
             /* Upload block 18 (offset 16) - not erased */
             let vec = dev.upload(&mut dfu, 18, 128).expect("vec");
             assert_eq!(vec.len(), 128);
-            assert_eq!(vec[0..10], [0, 4, 1, 4, 2, 4, 3, 4, 4, 4]);
-            assert_eq!(vec[120..128], [60, 4, 61, 4, 62, 4, 63, 4]);
+            assert_eq!(vec[0..10], [0, 36, 1, 36, 2, 36, 3, 36, 4, 36]);
+            assert_eq!(vec[120..128], [60, 36, 61, 36, 62, 36, 63, 36]);
         })
         .expect("with_usb");
 }
@@ -420,7 +591,7 @@ fn test_erase_all() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 0 (command), erase = full */
             let vec = dev.download(&mut dfu, 0, &[0x41]).expect("vec");
@@ -430,12 +601,16 @@ fn test_erase_all() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::FULL_ERASE_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::FULL_ERASE_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Abort */
             let vec = dev.abort(&mut dfu).expect("vec");
@@ -446,7 +621,7 @@ fn test_erase_all() {
                 /* Upload block - erased */
                 let vec = dev.upload(&mut dfu, blk as u16, 128).expect("vec");
 
-                if vec.len() == 0 {
+                if vec.is_empty() {
                     break;
                 }
 
@@ -455,7 +630,7 @@ fn test_erase_all() {
                 blk += 1;
                 assert!(blk < 0xffff);
             }
-            assert_eq!(blk - 2, TESTMEMSIZE / 128);
+            assert_eq!(blk - 2, (TESTMEMSIZE - 0x4000) / 128);
         })
         .expect("with_usb");
 }
@@ -466,16 +641,16 @@ fn test_upload_last() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Upload block 2 (offset 0) */
             let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
             assert_eq!(vec.len(), 128);
-            assert_eq!(vec[0..10], [0, 0, 1, 0, 2, 0, 3, 0, 4, 0]);
-            assert_eq!(vec[120..128], [60, 0, 61, 0, 62, 0, 63, 0]);
+            assert_eq!(vec[0..10], [0, 32, 1, 32, 2, 32, 3, 32, 4, 32]);
+            assert_eq!(vec[120..128], [60, 32, 61, 32, 62, 32, 63, 32]);
 
-            /* Upload block 513 (offset 511*128) - Last block */
-            let vec = dev.upload(&mut dfu, 513, 128).expect("vec");
+            /* Upload block 385 (offset 383*128) - Last block */
+            let vec = dev.upload(&mut dfu, 385, 128).expect("vec");
             assert_eq!(vec.len(), 128);
             assert_eq!(
                 vec[0..10],
@@ -485,15 +660,106 @@ fn test_upload_last() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_UPLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
 
-            /* Upload block 514 (offset 512*128), short read */
-            let vec = dev.upload(&mut dfu, 514, 128).expect("vec");
+            /* Upload block 386 (offset 384*128), short read */
+            let vec = dev.upload(&mut dfu, 386, 128).expect("vec");
             assert_eq!(vec.len(), 0);
 
             /* Get Status, dfuIdle after short frame */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_program_read_only() {
+    MkDFU {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), erase = the first read-only page */
+            let b = TESTMEM_BASE.to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::ERASE_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
+            );
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrErase, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            // the last read-only block
+            dfu.set_address_pointer(TestMem::INITIAL_ADDRESS_POINTER - 128);
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0; 128]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
+            );
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrWrite, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            dfu.set_address_pointer(TESTMEM_BASE);
+
+            /* Upload block 2 (offset 0), not erased */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec[0..10], [0, 0, 1, 0, 2, 0, 3, 0, 4, 0]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 129 (offset 127*128), not programmed */
+            let vec = dev.upload(&mut dfu, 129, 128).expect("vec");
+            assert_eq!(vec[0..10], [192, 31, 193, 31, 194, 31, 195, 31, 196, 31]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_upload_short_length() {
+    MkDFU {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 5 (offset 3*128), 64 bytes of a 128 byte block */
+            let vec = dev.upload(&mut dfu, 5, 64).expect("vec");
+            assert_eq!(vec.len(), 64);
+            // not offset 3*64
+            assert_eq!(vec[0..8], [192, 32, 193, 32, 194, 32, 195, 32]);
+            assert_eq!(vec[56..64], [220, 32, 221, 32, 222, 32, 223, 32]);
+
+            /* Get Status, dfuIdle after short frame */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
         })
         .expect("with_usb");
 }
@@ -506,7 +772,7 @@ fn test_upload_err_bad_address() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 0 (command), address pointer = invalid_addr */
             let b = invalid_addr.to_le_bytes();
@@ -518,12 +784,12 @@ fn test_upload_err_bad_address() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DN_BUSY));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
             assert_eq!(dfu.get_address_pointer(), invalid_addr);
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Abort */
             let vec = dev.abort(&mut dfu).expect("vec");
@@ -535,7 +801,10 @@ fn test_upload_err_bad_address() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_ADDRESS, 0, DFU_ERROR));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
         })
         .expect("with_usb");
 }
@@ -548,7 +817,7 @@ fn test_download_to_upload_err() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 0 (command), address pointer */
             let b = xaddr.to_le_bytes();
@@ -559,11 +828,11 @@ fn test_download_to_upload_err() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DN_BUSY));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Can't call Upload from dfuDnloadIdle, expect stall */
 
@@ -573,7 +842,10 @@ fn test_download_to_upload_err() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_STALLED_PKT, 0, DFU_ERROR));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
         })
         .expect("with_usb");
 }
@@ -584,7 +856,7 @@ fn test_download_program0_with_tail() {
     .with_usb(|mut dfu, mut dev| {
         /* Get Status */
         let vec = dev.get_status(&mut dfu).expect("vec");
-        assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
         /* Download block 2 (offset 0) */
         let vec = dev.download(&mut dfu, 2, &[0; 128]).expect("vec");
@@ -592,18 +864,18 @@ fn test_download_program0_with_tail() {
 
         /* Get State */
         let vec = dev.get_state(&mut dfu).expect("vec");
-        assert_eq!(vec, [DFU_DNLOAD_SYNC]);
+        assert_eq!(vec, [DFUState::DfuDnloadSync as u8]);
 
         /* Get Status */
         let vec = dev.get_status(&mut dfu).expect("vec");
         assert_eq!(
             vec,
-            status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+            status(DFUStatusCode::OK, TestMem::PROGRAM_TIME_MS, DFUState::DfuDnBusy)
         );
 
         /* Get Status */
         let vec = dev.get_status(&mut dfu).expect("vec");
-        assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
         /* Download block 3 (offset 1), with a wLength of 64 bytes, emulate short write */
         let vec = dev.download(&mut dfu, 3, &[0; 64]).expect("vec");
@@ -611,18 +883,18 @@ fn test_download_program0_with_tail() {
 
         /* Get State */
         let vec = dev.get_state(&mut dfu).expect("vec");
-        assert_eq!(vec, [DFU_DNLOAD_SYNC]);
+        assert_eq!(vec, [DFUState::DfuDnloadSync as u8]);
 
         /* Get Status */
         let vec = dev.get_status(&mut dfu).expect("vec");
         assert_eq!(
             vec,
-            status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+            status(DFUStatusCode::OK, TestMem::PROGRAM_TIME_MS, DFUState::DfuDnBusy)
         );
 
         /* Get Status */
         let vec = dev.get_status(&mut dfu).expect("vec");
-        assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
         /* Abort */
         let vec = dev.abort(&mut dfu).expect("vec");
@@ -637,14 +909,14 @@ fn test_download_program0_with_tail() {
         let vec = dev.upload(&mut dfu, 3, 128).expect("vec");
         assert_eq!(vec.len(), 128);
         assert_eq!(vec[0..64], [0; 64]);
-        assert_eq!(vec[64..72], [96, 0, 97, 0, 98, 0, 99, 0]);
-        assert_eq!(vec[120..128], [124, 0, 125, 0, 126, 0, 127, 0]);
+        assert_eq!(vec[64..72], [96, 32, 97, 32, 98, 32, 99, 32]);
+        assert_eq!(vec[120..128], [124, 32, 125, 32, 126, 32, 127, 32]);
 
         /* Upload block 4 (offset 2) - intact, short read of 64 bytes */
         let vec = dev.upload(&mut dfu, 4, 64).expect("vec");
         assert_eq!(vec.len(), 64);
-        assert_eq!(vec[0..10], [128, 0, 129, 0, 130, 0, 131, 0, 132, 0]);
-        assert_eq!(vec[56..64], [156, 0, 157, 0, 158, 0, 159, 0]);
+        assert_eq!(vec[0..10], [128, 32, 129, 32, 130, 32, 131, 32, 132, 32]);
+        assert_eq!(vec[56..64], [156, 32, 157, 32, 158, 32, 159, 32]);
     })
     .expect("with_usb");
 }
@@ -655,7 +927,7 @@ fn test_download_program_err_verify_and_to_idle() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 2 (offset 0) */
             let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
@@ -665,12 +937,16 @@ fn test_download_program_err_verify_and_to_idle() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_VERIFY, 0, DFU_ERROR));
+            assert_eq!(vec, status(DFUStatusCode::ErrVerify, 0, DFUState::DfuError));
 
             /* Clear Status */
             let vec = dev.clear_status(&mut dfu).expect("vec");
@@ -678,7 +954,7 @@ fn test_download_program_err_verify_and_to_idle() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
         })
         .expect("with_usb");
 }
@@ -691,7 +967,7 @@ fn test_erase_and_program() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 0 (command), erase = blkaddr */
             let b = blkaddr.to_le_bytes();
@@ -702,11 +978,18 @@ fn test_erase_and_program() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, TestMem::ERASE_TIME_MS, DFU_DN_BUSY));
+            assert_eq!(
+                vec,
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::ERASE_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
+            );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Download block 2 (offset 0) */
             let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
@@ -716,12 +999,16 @@ fn test_erase_and_program() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Abort */
             let vec = dev.abort(&mut dfu).expect("vec");
@@ -747,7 +1034,7 @@ fn test_manifestation() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 2 (offset 0) */
             let vec = dev.download(&mut dfu, 2, &[0; 128]).expect("vec");
@@ -757,12 +1044,16 @@ fn test_manifestation() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Download block 3 (offset 1) len 0, trigger manifestation */
             let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
@@ -770,7 +1061,7 @@ fn test_manifestation() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 1, DFU_MANIFEST));
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
 
             unreachable!("device must reset");
         })
@@ -784,9 +1075,9 @@ impl UsbDeviceCtx for MkDFUMTret {
     type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
     const EP0_SIZE: u8 = 32;
 
-    fn create_class<'a>(
+    fn create_class(
         &mut self,
-        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
         fn manifestation(tm: &mut TestMem) -> Result<(), DFUManifestationError> {
             Ok(())
@@ -797,7 +1088,7 @@ impl UsbDeviceCtx for MkDFUMTret {
             program: None,
             manifestation: Some(manifestation),
         };
-        Ok(DFUClass::new(&alloc, TestMem::new(Some(overrides))))
+        Ok(DFUClass::new(alloc, TestMem::new(Some(overrides))))
     }
 }
 
@@ -807,7 +1098,7 @@ fn test_manifestation_no_reset() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 2 (offset 0) */
             let vec = dev.download(&mut dfu, 2, &[0x0; 128]).expect("vec");
@@ -817,12 +1108,16 @@ fn test_manifestation_no_reset() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Download block 3 (offset 1) len 0, trigger manifestation */
             let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
@@ -830,11 +1125,14 @@ fn test_manifestation_no_reset() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 1, DFU_MANIFEST));
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_MANIFEST_WAIT_RESET));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::OK, 0, DFUState::DfuManifestWaitReset)
+            );
 
             /* Abort */
             let e = dev.abort(&mut dfu).expect_err("stall");
@@ -842,7 +1140,10 @@ fn test_manifestation_no_reset() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_MANIFEST_WAIT_RESET));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::OK, 0, DFUState::DfuManifestWaitReset)
+            );
         })
         .expect("with_usb");
 }
@@ -854,9 +1155,9 @@ impl UsbDeviceCtx for MkDFUMTerr {
     type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
     const EP0_SIZE: u8 = 32;
 
-    fn create_class<'a>(
+    fn create_class(
         &mut self,
-        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
         fn manifestation(tm: &mut TestMem) -> Result<(), DFUManifestationError> {
             Err(DFUManifestationError::NotDone)
@@ -867,7 +1168,7 @@ impl UsbDeviceCtx for MkDFUMTerr {
             program: None,
             manifestation: Some(manifestation),
         };
-        Ok(DFUClass::new(&alloc, TestMem::new(Some(overrides))))
+        Ok(DFUClass::new(alloc, TestMem::new(Some(overrides))))
     }
 }
 
@@ -877,7 +1178,7 @@ fn test_manifestation_err_not_done() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 2 (offset 0) */
             let vec = dev.download(&mut dfu, 2, &[0; 128]).expect("vec");
@@ -887,12 +1188,16 @@ fn test_manifestation_err_not_done() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Download block 3 (offset 1) len 0, trigger manifestation */
             let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
@@ -900,11 +1205,14 @@ fn test_manifestation_err_not_done() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 1, DFU_MANIFEST));
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_NOTDONE, 0, DFU_ERROR));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrNotdone, 0, DFUState::DfuError)
+            );
         })
         .expect("with_usb");
 }
@@ -915,9 +1223,9 @@ impl UsbDeviceCtx for MkDFUEraseErr {
     type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
     const EP0_SIZE: u8 = 32;
 
-    fn create_class<'a>(
+    fn create_class(
         &mut self,
-        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
         fn erase(tm: &mut TestMem, address: u32) -> core::result::Result<(), DFUMemError> {
             Err(DFUMemError::CheckErased)
@@ -929,7 +1237,7 @@ impl UsbDeviceCtx for MkDFUEraseErr {
             program: None,
             manifestation: None,
         };
-        Ok(DFUClass::new(&alloc, TestMem::new(Some(overrides))))
+        Ok(DFUClass::new(alloc, TestMem::new(Some(overrides))))
     }
 }
 
@@ -941,7 +1249,7 @@ fn test_erase_err_verfail() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 0 (command), erase = blkaddr */
             let b = blkaddr.to_le_bytes();
@@ -952,11 +1260,21 @@ fn test_erase_err_verfail() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, TestMem::ERASE_TIME_MS, DFU_DN_BUSY));
+            assert_eq!(
+                vec,
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::ERASE_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
+            );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_CHECK_ERASED, 0, DFU_ERROR));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrCheckErased, 0, DFUState::DfuError)
+            );
         })
         .expect("with_usb");
 }
@@ -967,9 +1285,9 @@ impl UsbDeviceCtx for MkDFUProgErr {
     type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
     const EP0_SIZE: u8 = 32;
 
-    fn create_class<'a>(
+    fn create_class(
         &mut self,
-        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
         fn program(tm: &mut TestMem, address: u32, length: usize) -> Result<(), DFUMemError> {
             if address > TestMem::INITIAL_ADDRESS_POINTER {
@@ -985,7 +1303,7 @@ impl UsbDeviceCtx for MkDFUProgErr {
             program: Some(program),
             manifestation: None,
         };
-        Ok(DFUClass::new(&alloc, TestMem::new(Some(overrides))))
+        Ok(DFUClass::new(alloc, TestMem::new(Some(overrides))))
     }
 }
 
@@ -995,7 +1313,7 @@ fn test_program_err_prog_write() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 2 (offset 0) */
             let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
@@ -1005,12 +1323,16 @@ fn test_program_err_prog_write() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_PROG, 0, DFU_ERROR));
+            assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
 
             /* Clear Status */
             let vec = dev.clear_status(&mut dfu).expect("vec");
@@ -1024,12 +1346,16 @@ fn test_program_err_prog_write() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_WRITE, 0, DFU_ERROR));
+            assert_eq!(vec, status(DFUStatusCode::ErrWrite, 0, DFUState::DfuError));
         })
         .expect("with_usb");
 }
@@ -1040,9 +1366,9 @@ impl UsbDeviceCtx for MkDFUReadErr {
     type C<'c> = DFUClass<EmulatedUsbBus, TestMem>;
     const EP0_SIZE: u8 = 32;
 
-    fn create_class<'a>(
+    fn create_class(
         &mut self,
-        alloc: &'a UsbBusAllocator<EmulatedUsbBus>,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
     ) -> AnyResult<DFUClass<EmulatedUsbBus, TestMem>> {
         fn read(
             tm: &mut TestMem,
@@ -1062,7 +1388,7 @@ impl UsbDeviceCtx for MkDFUReadErr {
             program: None,
             manifestation: None,
         };
-        Ok(DFUClass::new(&alloc, TestMem::new(Some(overrides))))
+        Ok(DFUClass::new(alloc, TestMem::new(Some(overrides))))
     }
 }
 
@@ -1072,7 +1398,7 @@ fn test_read_err_addr_vend() {
         .with_usb(|mut dfu, mut dev| {
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Upload block 2 (offset 0) */
             let e = dev.upload(&mut dfu, 2, 128).expect_err("stall");
@@ -1080,7 +1406,10 @@ fn test_read_err_addr_vend() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_ADDRESS, 0, DFU_ERROR));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
 
             /* Clear Status */
             let vec = dev.clear_status(&mut dfu).expect("vec");
@@ -1092,12 +1421,13 @@ fn test_read_err_addr_vend() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_VENDOR, 0, DFU_ERROR));
+            assert_eq!(vec, status(DFUStatusCode::ErrVendor, 0, DFUState::DfuError));
         })
         .expect("with_usb");
 }
 
 #[test]
+#[allow(clippy::assertions_on_constants)]
 fn test_download_program_short() {
     MkDFU {}
         .with_usb(|mut dfu, mut dev| {
@@ -1107,7 +1437,7 @@ fn test_download_program_short() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Download block 0 (command), erase = blkaddr */
             let mut b = blkaddr.to_le_bytes();
@@ -1118,11 +1448,18 @@ fn test_download_program_short() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, TestMem::ERASE_TIME_MS, DFU_DN_BUSY));
+            assert_eq!(
+                vec,
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::ERASE_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
+            );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Download block 2 (offset 0), full block of 0x55 */
             let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
@@ -1132,12 +1469,16 @@ fn test_download_program_short() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             blkaddr = TestMem::INITIAL_ADDRESS_POINTER + 128;
 
@@ -1150,11 +1491,11 @@ fn test_download_program_short() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DN_BUSY));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Download block 2 (offset 0), new address, short block of 0xaa */
             let vec = dev.download(&mut dfu, 2, &[0xaa; 16]).expect("vec");
@@ -1164,12 +1505,16 @@ fn test_download_program_short() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             blkaddr = TestMem::INITIAL_ADDRESS_POINTER;
 
@@ -1182,11 +1527,11 @@ fn test_download_program_short() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DN_BUSY));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Abort */
             let vec = dev.abort(&mut dfu).expect("vec");
@@ -1207,6 +1552,86 @@ fn test_download_program_short() {
         .expect("with_usb");
 }
 
+#[test]
+fn test_download_mixed_short_blocks() {
+    MkDFU {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), erase page at the base */
+            let b = TestMem::INITIAL_ADDRESS_POINTER.to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::ERASE_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
+            );
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            // block address stride is TRANSFER_SIZE, whatever the length of a block is
+            let blocks: [(u16, u8, usize); 4] =
+                [(2, 0x11, 16), (3, 0x22, 128), (5, 0x33, 32), (6, 0x44, 128)];
+            for (block, fill, len) in blocks {
+                let vec = dev
+                    .download(&mut dfu, block, &[fill; 128][..len])
+                    .expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(
+                    vec,
+                    status(
+                        DFUStatusCode::OK,
+                        TestMem::PROGRAM_TIME_MS,
+                        DFUState::DfuDnBusy
+                    )
+                );
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0), short block and erased tail */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec[..16], [0x11; 16]);
+            assert_eq!(vec[16..], [0xff; 112]);
+
+            /* Upload block 3 (offset 128) */
+            let vec = dev.upload(&mut dfu, 3, 128).expect("vec");
+            assert_eq!(vec, [0x22; 128]);
+
+            /* Upload block 4 (offset 256), not written */
+            let vec = dev.upload(&mut dfu, 4, 128).expect("vec");
+            assert_eq!(vec, [0xff; 128]);
+
+            /* Upload block 5 (offset 384), short block and erased tail */
+            let vec = dev.upload(&mut dfu, 5, 128).expect("vec");
+            assert_eq!(vec[..32], [0x33; 32]);
+            assert_eq!(vec[32..], [0xff; 96]);
+
+            /* Upload block 6 (offset 512) */
+            let vec = dev.upload(&mut dfu, 6, 128).expect("vec");
+            assert_eq!(vec, [0x44; 128]);
+        })
+        .expect("with_usb");
+}
+
 #[test]
 fn test_status_err_small_buffer() {
     MkDFU {}
@@ -1230,12 +1655,54 @@ fn test_state_err_small_buffer() {
 }
 
 #[test]
-fn test_commands_err_small_buffer() {
+fn test_read_unprotect_err_not_supported() {
+    MkDFU {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), Read Unprotect */
+            let e = dev.download(&mut dfu, 0, &[0x92]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_commands_small_buffer() {
     MkDFU {}
         .with_usb(|mut dfu, mut dev| {
             /* Upload block 0 (get commands), 2 byte buffer */
-            let e = dev.upload(&mut dfu, 0, 2).expect_err("stall");
-            assert_eq!(e, AnyUsbError::EP0Stalled);
+            let vec = dev.upload(&mut dfu, 0, 2).expect("vec");
+            assert_eq!(vec, [0x00, 0x21]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 0 (get commands), 1 byte buffer */
+            let vec = dev.upload(&mut dfu, 0, 1).expect("vec");
+            assert_eq!(vec, [0x00]);
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec.len(), 128);
+
+            /* Upload block 0 (get commands) in dfuUPLOAD-IDLE, 1 byte buffer */
+            let vec = dev.upload(&mut dfu, 0, 1).expect("vec");
+            assert_eq!(vec, [0x00]);
+
+            /* Get Status, the state is unchanged */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+
+            /* Upload block 3 (offset 1), session continues */
+            let vec = dev.upload(&mut dfu, 3, 128).expect("vec");
+            assert_eq!(vec.len(), 128);
         })
         .expect("with_usb");
 }
@@ -1255,12 +1722,12 @@ fn test_err_addr_overflow() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DN_BUSY));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
             assert_eq!(dfu.get_address_pointer(), invalid_addr);
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_DNLOAD_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
 
             /* Download block 3 (offset 1), real start address 0x1_0000_0070 */
             let vec = dev.download(&mut dfu, 3, &[0x55; 128]).expect("vec");
@@ -1270,12 +1737,19 @@ fn test_err_addr_overflow() {
             let vec = dev.get_status(&mut dfu).expect("vec");
             assert_eq!(
                 vec,
-                status(STATUS_OK, TestMem::PROGRAM_TIME_MS, DFU_DN_BUSY)
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
             );
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_ADDRESS, 0, DFU_ERROR));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
 
             /* Clear Status */
             let vec = dev.clear_status(&mut dfu).expect("vec");
@@ -1283,7 +1757,7 @@ fn test_err_addr_overflow() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_OK, 0, DFU_IDLE));
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
 
             /* Upload block 3 (offset 1) - real start address 0x1_0000_0070 */
             let e = dev.upload(&mut dfu, 3, 128).expect_err("stall");
@@ -1291,7 +1765,80 @@ fn test_err_addr_overflow() {
 
             /* Get Status */
             let vec = dev.get_status(&mut dfu).expect("vec");
-            assert_eq!(vec, status(STATUS_ERR_ADDRESS, 0, DFU_ERROR));
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            let end_addr: u32 = 0xffff_ff80;
+
+            /* Download block 0 (command), address pointer = end_addr */
+            let b = end_addr.to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0), start fits, end 0x1_0000_0000 does not */
+            let e = dev.upload(&mut dfu, 2, 128).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0), shorter block ends at 0xffff_ffc0 */
+            let vec = dev.upload(&mut dfu, 2, 64).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Download block 2 (offset 0), start fits, end does not */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(
+                    DFUStatusCode::OK,
+                    TestMem::PROGRAM_TIME_MS,
+                    DFUState::DfuDnBusy
+                )
+            );
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
         })
         .expect("with_usb");
 }
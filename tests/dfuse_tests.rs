@@ -0,0 +1,106 @@
+use usbd_dfu::dfuse::{DfuseCommand, DfuseCommandError};
+
+#[test]
+fn test_dfuse_roundtrip() {
+    let commands = [
+        DfuseCommand::SetAddressPointer(0x0800_4000),
+        DfuseCommand::SetAddressPointer(0xffff_ffff),
+        DfuseCommand::ErasePage(0x1234_5678),
+        DfuseCommand::ErasePage(0),
+        DfuseCommand::MassErase,
+        DfuseCommand::ReadUnprotect,
+        DfuseCommand::Echo(true),
+        DfuseCommand::Echo(false),
+        DfuseCommand::Crc {
+            address: 0x0800_0000,
+            length: 0x1_0000,
+        },
+    ];
+
+    for cmd in commands {
+        let mut buf = [0u8; DfuseCommand::MAX_LEN];
+        let len = cmd.encode(&mut buf);
+        assert_eq!(DfuseCommand::decode(&buf[..len]), Ok(cmd));
+    }
+
+    let mut buf = [0u8; DfuseCommand::MAX_LEN];
+    let len = DfuseCommand::ErasePage(0x1234_5678).encode(&mut buf);
+    assert_eq!(buf[..len], [0x41, 0x78, 0x56, 0x34, 0x12]);
+    let len = DfuseCommand::MassErase.encode(&mut buf);
+    assert_eq!(buf[..len], [0x41]);
+    let len = DfuseCommand::Crc {
+        address: 0x0800_0000,
+        length: 0x2400,
+    }
+    .encode(&mut buf);
+    assert_eq!(buf[..len], [0xe4, 0, 0, 0, 0x08, 0, 0x24, 0, 0]);
+}
+
+#[test]
+fn test_dfuse_decode_err() {
+    assert_eq!(DfuseCommand::decode(&[]), Err(DfuseCommandError::Empty));
+    assert_eq!(
+        DfuseCommand::decode(&[0x00]),
+        Err(DfuseCommandError::UnknownCommand(0x00))
+    );
+    assert_eq!(
+        DfuseCommand::decode(&[0x99, 1, 2, 3, 4]),
+        Err(DfuseCommandError::UnknownCommand(0x99))
+    );
+    assert_eq!(
+        DfuseCommand::decode(&[0x21]),
+        Err(DfuseCommandError::Length)
+    );
+    assert_eq!(
+        DfuseCommand::decode(&[0x21, 1, 2, 3]),
+        Err(DfuseCommandError::Length)
+    );
+    assert_eq!(
+        DfuseCommand::decode(&[0x41, 1, 2, 3, 4, 5]),
+        Err(DfuseCommandError::Length)
+    );
+    assert_eq!(
+        DfuseCommand::decode(&[0x92, 0]),
+        Err(DfuseCommandError::Length)
+    );
+    assert_eq!(
+        DfuseCommand::decode(&[0xe0, 2]),
+        Err(DfuseCommandError::Argument)
+    );
+    assert_eq!(
+        DfuseCommand::decode(&[0xe0]),
+        Err(DfuseCommandError::Length)
+    );
+    assert_eq!(
+        DfuseCommand::decode(&[0xe4, 1, 2, 3, 4]),
+        Err(DfuseCommandError::Length)
+    );
+}
+
+/// Decode pseudo-random blocks, successfully decoded ones must encode to the same bytes
+#[test]
+fn test_dfuse_decode_fuzz() {
+    let mut seed: u32 = 0x1234_5678;
+    let mut next = || {
+        // xorshift32
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        seed
+    };
+
+    let codes = [0x00, 0x21, 0x41, 0x92, 0xe0, 0xe4];
+    for _ in 0..100_000 {
+        let len = (next() % 11) as usize;
+        let mut data: Vec<u8> = (0..len).map(|_| next() as u8).collect();
+        if len > 0 && next() % 2 == 0 {
+            data[0] = codes[(next() % 6) as usize];
+        }
+
+        if let Ok(cmd) = DfuseCommand::decode(&data) {
+            let mut buf = [0u8; DfuseCommand::MAX_LEN];
+            let n = cmd.encode(&mut buf);
+            assert_eq!(buf[..n], data);
+        }
+    }
+}
@@ -0,0 +1,164 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const DRYMEMSIZE: usize = 1024;
+const DRYMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory with a golden image that records calls.
+pub struct DryMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for DryMem {
+    const INITIAL_ADDRESS_POINTER: u32 = DRYMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkDry {}
+
+impl UsbDeviceCtx for MkDry {
+    type C<'c> = DFUClass<EmulatedUsbBus, DryMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, DryMem>> {
+        let mut mem = DryMem {
+            mem: MockMem::new(DRYMEM_BASE, 1, DRYMEMSIZE as u32),
+        };
+        for (i, b) in mem.mem.memory_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Erase all, download `image` in 32-byte blocks, and trigger manifestation
+fn download_session(
+    dfu: &mut DFUClass<EmulatedUsbBus, DryMem>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, DryMem>, MkDry>,
+    image: &[u8],
+) {
+    /* Download block 0 (command), erase all */
+    let vec = dev.download(dfu, 0, &[0x41]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 30, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+    for (block, data) in image.chunks(32).enumerate() {
+        /* Download block */
+        let vec = dev.download(dfu, 2 + block as u16, data).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+    }
+    assert_eq!(dfu.downloaded_bytes(), image.len() as u32);
+
+    /* Download len 0, trigger manifestation */
+    let n = image.len().div_ceil(32) as u16;
+    let vec = dev.download(dfu, 2 + n, &[]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+}
+
+#[test]
+fn test_dry_run() {
+    MkDry {}
+        .with_usb(|mut dfu, mut dev| {
+            let golden: Vec<u8> = (0..DRYMEMSIZE).map(|i| i as u8).collect();
+            assert!(!dfu.is_dry_run());
+
+            dfu.set_dry_run(true);
+            assert!(dfu.is_dry_run());
+            download_session(&mut dfu, &mut dev, &[0x55; 80]);
+
+            let mut uploaded = Vec::new();
+            for block in 0..4 {
+                /* Upload block */
+                let vec = dev.upload(&mut dfu, 2 + block, 32).expect("vec");
+                uploaded.extend_from_slice(&vec);
+            }
+            assert_eq!(uploaded, golden[..128]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            dfu.set_dry_run(false);
+            download_session(&mut dfu, &mut dev, &[0x55; 80]);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Read(DRYMEM_BASE, 32),
+                    MockCall::Read(DRYMEM_BASE + 32, 32),
+                    MockCall::Read(DRYMEM_BASE + 64, 32),
+                    MockCall::Read(DRYMEM_BASE + 96, 32),
+                    MockCall::EraseAll,
+                    MockCall::Program(DRYMEM_BASE, 32),
+                    MockCall::Program(DRYMEM_BASE + 32, 32),
+                    MockCall::Program(DRYMEM_BASE + 64, 16),
+                    MockCall::Manifestation
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..80], [0x55; 80]);
+            assert_eq!(mem.mem.memory()[80..], [0xff; DRYMEMSIZE - 80]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,454 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::buffered::BufferedMem;
+use usbd_dfu::class::*;
+use usbd_dfu::dual_slot::{DualSlot, Slot};
+
+const SLOT_SIZE: u32 = 1024;
+const PAGE_SIZE: u32 = 512;
+const SLOT_A: u32 = 0x0800_0000;
+const SLOT_B: u32 = SLOT_A + SLOT_SIZE;
+
+/// Memory with two slots, the active slot is changed by `commit_swap` only after
+/// the device is reset.
+pub struct RamSlots {
+    mem: MockMem,
+    active: Slot,
+    swapped_to: Option<Slot>,
+}
+
+impl RamSlots {
+    fn new(active: Slot) -> Self {
+        let mut mem = MockMem::new(SLOT_A, 2 * SLOT_SIZE / PAGE_SIZE, PAGE_SIZE);
+        mem.memory_mut()[..SLOT_SIZE as usize].fill(0xaa);
+        mem.memory_mut()[SLOT_SIZE as usize..].fill(0xbb);
+        Self {
+            mem,
+            active,
+            swapped_to: None,
+        }
+    }
+}
+
+impl DFUMemIO for RamSlots {
+    const INITIAL_ADDRESS_POINTER: u32 = SLOT_A;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/2*512 g";
+    const PROGRAM_TIME_MS: u32 = 5;
+    const ERASE_TIME_MS: u32 = 10;
+    const FULL_ERASE_TIME_MS: u32 = 20;
+    const TRANSFER_SIZE: u16 = 64;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+fn active_slot(mem: &RamSlots) -> Slot {
+    mem.active
+}
+
+fn commit_swap(mem: &mut RamSlots, slot: Slot) -> Result<(), DFUManifestationError> {
+    if !mem.mem.calls.contains(&MockCall::Manifestation) {
+        return Err(DFUManifestationError::Unknown);
+    }
+    mem.swapped_to = Some(slot);
+    Ok(())
+}
+
+fn dual_slot(active: Slot) -> DualSlot<RamSlots> {
+    DualSlot::new(
+        RamSlots::new(active),
+        SLOT_A,
+        SLOT_B,
+        SLOT_SIZE,
+        active_slot,
+        commit_swap,
+    )
+}
+
+fn slot_a(mem: &DualSlot<RamSlots>) -> &[u8] {
+    &mem.inner().mem.memory()[..SLOT_SIZE as usize]
+}
+
+fn slot_b(mem: &DualSlot<RamSlots>) -> &[u8] {
+    &mem.inner().mem.memory()[SLOT_SIZE as usize..]
+}
+
+#[test]
+fn test_dual_slot_program() {
+    let mut mem = dual_slot(Slot::A);
+    assert_eq!(mem.active_slot(), Slot::A);
+    assert_eq!(mem.inactive_slot(), Slot::B);
+
+    // written to the inactive slot
+    mem.store_write_buffer(&[0x11; 64]).expect("store");
+    mem.program(SLOT_A + 64, 64).expect("program");
+    mem.store_write_buffer(&[0x22; 32]).expect("store");
+    mem.program(SLOT_A + 128, 32).expect("program");
+    assert_eq!(slot_a(&mem), [0xaa; SLOT_SIZE as usize]);
+    assert_eq!(slot_b(&mem)[..64], [0xbb; 64]);
+    assert_eq!(slot_b(&mem)[64..128], [0x11; 64]);
+    assert_eq!(slot_b(&mem)[128..160], [0x22; 32]);
+
+    // read from the active slot
+    let data = mem.read(SLOT_A + 64, 64).expect("read");
+    assert_eq!(data, [0xaa; 64]);
+
+    let mut buf = [0u8; 16];
+    let len = mem.read_block(SLOT_A + 128, &mut buf).expect("read");
+    assert_eq!(buf[..len], [0xaa; 16][..len]);
+
+    // the other way around
+    let mut mem = dual_slot(Slot::B);
+    mem.store_write_buffer(&[0x22; 64]).expect("store");
+    mem.program(SLOT_A, 64).expect("program");
+    assert_eq!(slot_a(&mem)[..64], [0x22; 64]);
+    assert_eq!(slot_b(&mem), [0xbb; SLOT_SIZE as usize]);
+
+    let data = mem.read(SLOT_A, 64).expect("read");
+    assert_eq!(data, [0xbb; 64]);
+}
+
+#[test]
+fn test_dual_slot_boundary() {
+    let mut mem = dual_slot(Slot::A);
+    mem.store_write_buffer(&[0x11; 64]).expect("store");
+
+    // the last block of the slot
+    mem.program(SLOT_A + SLOT_SIZE - 64, 64).expect("program");
+    assert_eq!(slot_b(&mem)[SLOT_SIZE as usize - 64..], [0x11; 64]);
+
+    // crosses the slot end
+    let e = mem.program(SLOT_A + SLOT_SIZE - 32, 64).expect_err("err");
+    assert_eq!(e as u8, DFUMemError::Address as u8);
+
+    // outside of the slot
+    let e = mem.program(SLOT_B, 64).expect_err("err");
+    assert_eq!(e as u8, DFUMemError::Address as u8);
+
+    let e = mem.program(SLOT_A - 64, 64).expect_err("err");
+    assert_eq!(e as u8, DFUMemError::Address as u8);
+
+    let e = mem.erase(SLOT_B).expect_err("err");
+    assert_eq!(e as u8, DFUMemError::Address as u8);
+
+    let e = mem.read(SLOT_A + SLOT_SIZE - 32, 64).expect_err("err");
+    assert_eq!(e as u8, DFUMemError::Address as u8);
+
+    assert_eq!(mem.page_size_at(SLOT_A), Some(PAGE_SIZE));
+    assert_eq!(mem.page_size_at(SLOT_B), None);
+
+    // nothing else is changed
+    assert_eq!(slot_a(&mem), [0xaa; SLOT_SIZE as usize]);
+    assert_eq!(
+        slot_b(&mem)[..SLOT_SIZE as usize - 64],
+        [0xbb; SLOT_SIZE as usize - 64]
+    );
+}
+
+#[test]
+fn test_dual_slot_erase() {
+    let mut mem = dual_slot(Slot::A);
+
+    mem.erase(SLOT_A + PAGE_SIZE).expect("erase");
+    assert_eq!(slot_a(&mem), [0xaa; SLOT_SIZE as usize]);
+    assert_eq!(
+        slot_b(&mem)[..PAGE_SIZE as usize],
+        [0xbb; PAGE_SIZE as usize]
+    );
+    assert_eq!(
+        slot_b(&mem)[PAGE_SIZE as usize..],
+        [0xff; PAGE_SIZE as usize]
+    );
+
+    // only the inactive slot is erased
+    let mut mem = dual_slot(Slot::B);
+    mem.erase_all().expect("erase");
+    assert_eq!(slot_a(&mem), [0xff; SLOT_SIZE as usize]);
+    assert_eq!(slot_b(&mem), [0xbb; SLOT_SIZE as usize]);
+}
+
+#[test]
+fn test_dual_slot_erase_all_next() {
+    let mut mem = dual_slot(Slot::A);
+
+    // one page per call
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Busy(10)));
+    assert_eq!(
+        slot_b(&mem)[..PAGE_SIZE as usize],
+        [0xff; PAGE_SIZE as usize]
+    );
+    assert_eq!(
+        slot_b(&mem)[PAGE_SIZE as usize..],
+        [0xbb; PAGE_SIZE as usize]
+    );
+
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Done));
+    assert_eq!(slot_a(&mem), [0xaa; SLOT_SIZE as usize]);
+    assert_eq!(slot_b(&mem), [0xff; SLOT_SIZE as usize]);
+
+    // the next mass erase starts over
+    mem.inner_mut().mem.memory_mut()[SLOT_SIZE as usize..].fill(0xbb);
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Busy(10)));
+    assert_eq!(
+        slot_b(&mem)[PAGE_SIZE as usize..],
+        [0xbb; PAGE_SIZE as usize]
+    );
+}
+
+#[test]
+fn test_dual_slot_manifestation() {
+    let mut mem = dual_slot(Slot::A);
+    mem.manifestation().expect("manifestation");
+    assert!(mem.inner().mem.calls.contains(&MockCall::Manifestation));
+    assert_eq!(mem.inner().swapped_to, Some(Slot::B));
+
+    let mut mem = dual_slot(Slot::B);
+    mem.manifestation().expect("manifestation");
+    assert_eq!(mem.inner().swapped_to, Some(Slot::A));
+}
+
+/// Mock memory with both slots that is verified after programming, the byte at
+/// `lost` is silently lost when it's programmed
+pub struct VerifySlots {
+    mem: MockMem,
+    lost: Option<u32>,
+}
+
+impl DFUMemIO for VerifySlots {
+    const INITIAL_ADDRESS_POINTER: u32 = SLOT_A;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/2*512 g";
+    const PROGRAM_TIME_MS: u32 = 5;
+    const ERASE_TIME_MS: u32 = 10;
+    const FULL_ERASE_TIME_MS: u32 = 20;
+    const TRANSFER_SIZE: u16 = 64;
+    const VERIFY_AFTER_PROGRAM: bool = true;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        self.mem.store_write_buffer(data).expect("buffer");
+        self.mem.program(address, data.len())?;
+        if let Some(lost) = self.lost {
+            if (address..address + data.len() as u32).contains(&lost) {
+                self.mem.memory_mut()[(lost - SLOT_A) as usize] = 0;
+            }
+        }
+        Ok(())
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+/// Slot `A` is active, the inactive slot is verified by `BufferedMem`
+fn verified_slots(lost: Option<u32>) -> DualSlot<BufferedMem<VerifySlots, 64>> {
+    let mem = VerifySlots {
+        mem: MockMem::new(SLOT_A, 4, PAGE_SIZE),
+        lost,
+    };
+    DualSlot::new(
+        BufferedMem::new(mem),
+        SLOT_A,
+        SLOT_B,
+        SLOT_SIZE,
+        |_| Slot::A,
+        |_, _| Ok(()),
+    )
+}
+
+#[test]
+fn test_dual_slot_verify() {
+    // the written slot is read back, not the running image
+    let mut mem = verified_slots(None);
+    mem.store_write_buffer(&[0x55; 16]).expect("buffer");
+    assert_eq!(mem.program(SLOT_A, 16), Ok(()));
+    let memory = mem.inner().inner().mem.memory();
+    assert_eq!(memory[..16], [0xff; 16]);
+    assert_eq!(memory[SLOT_SIZE as usize..][..16], [0x55; 16]);
+
+    // a lost write to the inactive slot is detected
+    let mut mem = verified_slots(Some(SLOT_B + 4));
+    mem.store_write_buffer(&[0x55; 16]).expect("buffer");
+    assert_eq!(mem.program(SLOT_A, 16), Err(DFUMemError::Verify));
+}
+
+struct MkDualSlot {}
+
+impl UsbDeviceCtx for MkDualSlot {
+    type C<'c> = DFUClass<EmulatedUsbBus, DualSlot<RamSlots>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, DualSlot<RamSlots>>> {
+        Ok(DFUClass::new(alloc, dual_slot(Slot::A)))
+    }
+}
+
+#[test]
+fn test_dual_slot_download() {
+    MkDualSlot {}
+        .with_usb(|mut dfu, mut dev| {
+            let b = SLOT_A.to_le_bytes();
+
+            /* Download block 0 (command), erase = slot start */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0), served by the active slot */
+            let vec = dev.upload(&mut dfu, 2, 64).expect("vec");
+            assert_eq!(vec, [0xaa; 64]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 0 (command), set address = the last 32 bytes */
+            let b = (SLOT_A + SLOT_SIZE - 32).to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 2 (offset 0), crosses the slot end */
+            let vec = dev.download(&mut dfu, 2, &[0x66; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 0 (command), set address = slot start */
+            let b = SLOT_A.to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1) len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.inner().swapped_to, Some(Slot::B));
+            assert_eq!(slot_a(&mem), [0xaa; SLOT_SIZE as usize]);
+            assert_eq!(slot_b(&mem)[..64], [0x55; 64]);
+            assert_eq!(
+                slot_b(&mem)[64..PAGE_SIZE as usize],
+                [0xff; PAGE_SIZE as usize - 64]
+            );
+            assert_eq!(
+                slot_b(&mem)[PAGE_SIZE as usize..],
+                [0xbb; PAGE_SIZE as usize]
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,176 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::dfuse::DfuseCommand;
+
+const ECHOMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that must not be written during echo self-test.
+pub struct EchoMem {
+    mem: MockMem,
+    /// Number of `store_write_buffer()` calls
+    stores: u32,
+}
+
+impl DFUMemIO for EchoMem {
+    const INITIAL_ADDRESS_POINTER: u32 = ECHOMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 128;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.stores += 1;
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkEcho {}
+
+impl UsbDeviceCtx for MkEcho {
+    type C<'c> = DFUClass<EmulatedUsbBus, EchoMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, EchoMem>> {
+        let mem = EchoMem {
+            mem: MockMem::new(ECHOMEM_BASE, 1, 1024),
+            stores: 0,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Download block 0 with a command
+fn command(
+    dfu: &mut DFUClass<EmulatedUsbBus, EchoMem>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, EchoMem>, MkEcho>,
+    cmd: DfuseCommand,
+) {
+    let mut buf = [0u8; DfuseCommand::MAX_LEN];
+    let len = cmd.encode(&mut buf);
+
+    /* Download block 0 (command) */
+    let vec = dev.download(dfu, 0, &buf[..len]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+    /* Abort */
+    let vec = dev.abort(dfu).expect("vec");
+    assert_eq!(vec, []);
+}
+
+#[test]
+fn test_echo() {
+    MkEcho {}
+        .with_usb(|mut dfu, mut dev| {
+            command(&mut dfu, &mut dev, DfuseCommand::Echo(true));
+
+            let mut seed: u32 = 0x2545_f491;
+            for size in [1, 31, 32, 33, 100, 127, 128] {
+                let block: Vec<u8> = (0..size)
+                    .map(|_| {
+                        // xorshift32
+                        seed ^= seed << 13;
+                        seed ^= seed >> 17;
+                        seed ^= seed << 5;
+                        seed as u8
+                    })
+                    .collect();
+
+                /* Download block 2 */
+                let vec = dev.download(&mut dfu, 2, &block).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+                /* Abort */
+                let vec = dev.abort(&mut dfu).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Upload block 2 */
+                let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+                assert_eq!(vec, block);
+
+                /* Abort */
+                let vec = dev.abort(&mut dfu).expect("vec");
+                assert_eq!(vec, []);
+            }
+
+            command(&mut dfu, &mut dev, DfuseCommand::Echo(false));
+
+            /* Upload block 2, memory contents */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec, [0xff; 128]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.stores, 0);
+            assert!(mem
+                .mem
+                .calls
+                .iter()
+                .all(|c| matches!(c, MockCall::Read(..))));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_echo_reset() {
+    MkEcho {}
+        .with_usb(|mut dfu, mut dev| {
+            command(&mut dfu, &mut dev, DfuseCommand::Echo(true));
+
+            /* USB reset and enumeration leaves echo mode */
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Download block 2 */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 16]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..16], [0x55; 16]);
+        })
+        .expect("with_usb");
+}
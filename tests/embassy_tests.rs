@@ -0,0 +1,467 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::{pin, Pin};
+use std::task::{Context, Waker};
+
+use usbd_class_tester::prelude::*;
+
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::Direction;
+use embassy_usb::Handler;
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::embassy::*;
+
+const RAMMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that is accessed from the main loop or an async task.
+pub struct RamMem {
+    mem: MockMem,
+}
+
+impl RamMem {
+    fn new() -> Self {
+        Self {
+            mem: MockMem::new(RAMMEM_BASE, 1, 1024),
+        }
+    }
+}
+
+impl DFUMemIO for RamMem {
+    const INITIAL_ADDRESS_POINTER: u32 = RAMMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const MANIFESTATION_TIME_MS: u32 = 40;
+    const TRANSFER_SIZE: u16 = 32;
+    const MEMIO_IN_USB_INTERRUPT: bool = false;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+/// Request results, `None` is a stalled request
+type Results = Vec<Option<Vec<u8>>>;
+
+/// Host request, or a call to execute pending operations
+#[derive(Clone, Copy)]
+enum Step {
+    Download(u16, &'static [u8]),
+    Upload(u16, u16),
+    GetStatus,
+    ClearStatus,
+    Abort,
+    Update,
+}
+
+const DATA: [u8; 32] = [0x5a; 32];
+
+/// Request script
+const SCRIPT: &[Step] = &[
+    Step::GetStatus,
+    /* Set Address Pointer */
+    Step::Download(0, &[0x21, 0x00, 0x01, 0x00, 0x08]),
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    /* Erase page */
+    Step::Download(0, &[0x41, 0x00, 0x00, 0x00, 0x08]),
+    Step::GetStatus,
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    /* Download block 2, program at the Address Pointer */
+    Step::Download(2, &DATA),
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    /* Upload is not allowed during download */
+    Step::Upload(2, 32),
+    Step::GetStatus,
+    Step::ClearStatus,
+    Step::GetStatus,
+    /* Set Address Pointer, upload block 2 */
+    Step::Download(0, &[0x21, 0x00, 0x01, 0x00, 0x08]),
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    Step::Abort,
+    Step::Upload(2, 32),
+    Step::GetStatus,
+    Step::Abort,
+    /* Download block 2 at the end of memory, fails */
+    Step::Download(0, &[0x21, 0xf0, 0x03, 0x00, 0x08]),
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    Step::Download(2, &DATA),
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    Step::ClearStatus,
+    /* Download block 2 again, then leave DFU */
+    Step::Download(0, &[0x21, 0x00, 0x02, 0x00, 0x08]),
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    Step::Download(2, &DATA),
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    Step::Download(0, &[]),
+    Step::GetStatus,
+    Step::Update,
+    Step::GetStatus,
+    Step::GetStatus,
+];
+
+struct MkRam {}
+
+impl UsbDeviceCtx for MkRam {
+    type C<'c> = DFUClass<EmulatedUsbBus, RamMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RamMem>> {
+        Ok(DFUClass::new(alloc, RamMem::new()))
+    }
+}
+
+/// Run the script with `usb-device` front-end
+fn run_usb_device() -> (Results, RamMem) {
+    MkRam {}
+        .with_usb(|mut dfu, mut dev| {
+            let mut results = Vec::new();
+            for step in SCRIPT {
+                let r = match *step {
+                    Step::Download(block, data) => dev.download(&mut dfu, block, data),
+                    Step::Upload(block, length) => dev.upload(&mut dfu, block, length as usize),
+                    Step::GetStatus => dev.get_status(&mut dfu),
+                    Step::ClearStatus => dev.clear_status(&mut dfu),
+                    Step::Abort => dev.abort(&mut dfu),
+                    Step::Update => {
+                        dfu.update();
+                        continue;
+                    }
+                };
+                results.push(r.ok());
+            }
+            RESULTS.with(|r| r.replace((results, Some(dfu.release()))));
+        })
+        .expect("with_usb");
+
+    let (results, mem) = RESULTS.with(|r| r.take());
+    (results, mem.expect("mem"))
+}
+
+thread_local! {
+    /// Results of `with_usb()` closure
+    static RESULTS: RefCell<(Results, Option<RamMem>)> =
+        const { RefCell::new((Vec::new(), None)) };
+}
+
+fn request(direction: Direction, request: u8, value: u16, length: u16) -> Request {
+    Request {
+        direction,
+        request_type: RequestType::Class,
+        recipient: Recipient::Interface,
+        request,
+        value,
+        index: 0,
+        length,
+    }
+}
+
+fn control_out(
+    handler: &mut impl Handler,
+    request: u8,
+    value: u16,
+    data: &[u8],
+) -> Option<Vec<u8>> {
+    let req = request_out(request, value, data.len() as u16);
+    match handler.control_out(req, data) {
+        Some(OutResponse::Accepted) => Some(Vec::new()),
+        Some(OutResponse::Rejected) => None,
+        None => panic!("not handled"),
+    }
+}
+
+fn request_out(request: u8, value: u16, length: u16) -> Request {
+    self::request(Direction::Out, request, value, length)
+}
+
+fn control_in(handler: &mut impl Handler, request: u8, value: u16, length: u16) -> Option<Vec<u8>> {
+    let req = self::request(Direction::In, request, value, length);
+    let mut buf = [0; 128];
+    match handler.control_in(req, &mut buf) {
+        // embassy-usb truncates the response to the request length
+        Some(InResponse::Accepted(data)) => Some(data[..data.len().min(length as usize)].to_vec()),
+        Some(InResponse::Rejected) => None,
+        None => panic!("not handled"),
+    }
+}
+
+/// Poll `fut` once, it never completes
+fn step(fut: Pin<&mut impl Future<Output = ()>>) {
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(fut.poll(&mut cx).is_pending());
+}
+
+/// Run the script with `embassy-usb` front-end
+fn run_embassy() -> (Results, RamMem) {
+    let state = DfuState::new(RamMem::new());
+    let mut handler = DfuHandler::new(&state);
+    let mut results = Vec::new();
+
+    {
+        let mut run = pin!(async {
+            state.run().await;
+        });
+        step(run.as_mut());
+
+        for s in SCRIPT {
+            let r = match *s {
+                Step::Download(block, data) => control_out(&mut handler, 1, block, data),
+                Step::Upload(block, length) => control_in(&mut handler, 2, block, length),
+                Step::GetStatus => control_in(&mut handler, 3, 0, 6),
+                Step::ClearStatus => control_out(&mut handler, 4, 0, &[]),
+                Step::Abort => control_out(&mut handler, 6, 0, &[]),
+                Step::Update => {
+                    step(run.as_mut());
+                    continue;
+                }
+            };
+            results.push(r);
+        }
+    }
+
+    (results, state.into_inner())
+}
+
+#[test]
+fn test_embassy_same_sequence() {
+    let (usb_device, usb_device_mem) = run_usb_device();
+    let (embassy, embassy_mem) = run_embassy();
+
+    assert_eq!(usb_device.len(), embassy.len());
+    for (i, (a, b)) in usb_device.iter().zip(&embassy).enumerate() {
+        assert_eq!(a, b, "request {}", i);
+    }
+
+    assert_eq!(usb_device_mem.mem.calls, embassy_mem.mem.calls);
+    assert_eq!(usb_device_mem.mem.memory(), embassy_mem.mem.memory());
+
+    // the script went through download, error, and manifestation
+    assert_eq!(
+        embassy_mem.mem.calls,
+        [
+            MockCall::Erase(RAMMEM_BASE),
+            MockCall::Program(RAMMEM_BASE + 0x100, 32),
+            MockCall::Read(RAMMEM_BASE + 0x100, 32),
+            MockCall::Program(RAMMEM_BASE + 0x3f0, 32),
+            MockCall::Program(RAMMEM_BASE + 0x200, 32),
+            MockCall::Manifestation
+        ]
+    );
+    assert!(embassy.contains(&Some(
+        status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError).to_vec()
+    )));
+    assert_eq!(
+        embassy.last(),
+        Some(&Some(
+            status(DFUStatusCode::OK, 0, DFUState::DfuIdle).to_vec()
+        ))
+    );
+}
+
+#[test]
+fn test_embassy_run_wakes() {
+    let state = DfuState::new(RamMem::new());
+    let mut handler = DfuHandler::new(&state);
+
+    let mut run = pin!(async {
+        state.run().await;
+    });
+    step(run.as_mut());
+
+    /* Download block 2 (offset 0) */
+    let r = control_out(&mut handler, 1, 2, &DATA);
+    assert_eq!(r, Some(vec![]));
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy).to_vec())
+    );
+    assert!(state.is_busy());
+
+    step(run.as_mut());
+    assert!(!state.is_busy());
+    assert_eq!(state.take_poll_activity(), PollActivity::ExecutedCommand);
+    state.with_mem(|m| assert_eq!(m.mem.calls, [MockCall::Program(RAMMEM_BASE, 32)]));
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle).to_vec())
+    );
+
+    /* Requests to other interfaces are not handled */
+    let mut req = request_out(6, 0, 0);
+    req.index = 1;
+    assert_eq!(handler.control_out(req, &[]), None);
+}
+
+#[test]
+fn test_embassy_status_while_busy() {
+    let state = DfuState::new(RamMem::new());
+    let mut handler = DfuHandler::new(&state);
+
+    let mut run = pin!(async {
+        state.run().await;
+    });
+    step(run.as_mut());
+
+    /* Download block 2 (offset 0) */
+    let r = control_out(&mut handler, 1, 2, &DATA);
+    assert_eq!(r, Some(vec![]));
+
+    // host polls before run() executes the command, twice
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy).to_vec())
+    );
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy).to_vec())
+    );
+    assert!(state.is_busy());
+
+    step(run.as_mut());
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle).to_vec())
+    );
+
+    /* Download 0 length */
+    let r = control_out(&mut handler, 1, 0, &[]);
+    assert_eq!(r, Some(vec![]));
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::OK, 40, DFUState::DfuManifest).to_vec())
+    );
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::OK, 40, DFUState::DfuManifest).to_vec())
+    );
+
+    step(run.as_mut());
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::OK, 0, DFUState::DfuIdle).to_vec())
+    );
+
+    state.with_mem(|m| {
+        assert_eq!(
+            m.mem.calls,
+            [MockCall::Program(RAMMEM_BASE, 32), MockCall::Manifestation]
+        );
+        assert_eq!(m.mem.memory()[..32], DATA);
+    });
+}
+
+#[test]
+fn test_embassy_download_length_mismatch() {
+    let state = DfuState::new(RamMem::new());
+    let mut handler = DfuHandler::new(&state);
+
+    let mut run = pin!(async {
+        state.run().await;
+    });
+    step(run.as_mut());
+
+    /* Set Address Pointer, wLength 5 with 2 bytes of data */
+    let req = request_out(1, 0, 5);
+    assert_eq!(
+        handler.control_out(req, &[0x21, 0x00]),
+        Some(OutResponse::Rejected)
+    );
+    assert!(!state.is_busy());
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError).to_vec())
+    );
+
+    /* Clear Status */
+    let r = control_out(&mut handler, 4, 0, &[]);
+    assert_eq!(r, Some(vec![]));
+
+    /* Download block 2, wLength 32 with 16 bytes of data */
+    let req = request_out(1, 2, 32);
+    assert_eq!(
+        handler.control_out(req, &DATA[..16]),
+        Some(OutResponse::Rejected)
+    );
+    assert!(!state.is_busy());
+
+    /* Get Status */
+    let r = control_in(&mut handler, 3, 0, 6);
+    assert_eq!(
+        r,
+        Some(status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError).to_vec())
+    );
+
+    step(run.as_mut());
+    state.with_mem(|m| assert!(m.mem.calls.is_empty()));
+}
@@ -0,0 +1,137 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const PAGE: usize = 256;
+const PAGES: usize = 5;
+const CHIPMEMSIZE: usize = PAGE * PAGES;
+const CHIPMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that erases one page per `erase_all_next()` call.
+pub struct ChipMem {
+    mem: MockMem,
+    /// Pages erased by the running mass erase
+    erased: usize,
+}
+
+impl DFUMemIO for ChipMem {
+    const INITIAL_ADDRESS_POINTER: u32 = CHIPMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/5*256 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 500;
+    const TRANSFER_SIZE: u16 = 32;
+    const MEMIO_IN_USB_INTERRUPT: bool = false;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        unreachable!()
+    }
+
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        self.mem.erase(CHIPMEM_BASE + (self.erased * PAGE) as u32)?;
+        self.erased += 1;
+        if self.erased == PAGES {
+            self.erased = 0;
+            return Ok(EraseProgress::Done);
+        }
+        Ok(EraseProgress::Busy((PAGES - self.erased) as u32 * 100))
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkChip {}
+
+impl UsbDeviceCtx for MkChip {
+    type C<'c> = DFUClass<EmulatedUsbBus, ChipMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, ChipMem>> {
+        let mut mem = ChipMem {
+            mem: MockMem::new(CHIPMEM_BASE, PAGES as u32, PAGE as u32),
+            erased: 0,
+        };
+        for (i, b) in mem.mem.memory_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_erase_all_incremental() {
+    MkChip {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), mass erase */
+            let vec = dev.download(&mut dfu, 0, &[0x41]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 500, DFUState::DfuDnBusy));
+
+            // one page per update(), remaining time is reported
+            for remaining in [400, 300, 200, 100] {
+                dfu.update();
+                assert!(dfu.update_pending());
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(
+                    vec,
+                    status(DFUStatusCode::OK, remaining, DFUState::DfuDnBusy)
+                );
+            }
+
+            dfu.update();
+            assert!(!dfu.update_pending());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload all blocks */
+            for block in 2..2 + (CHIPMEMSIZE / 32) as u16 {
+                let vec = dev.upload(&mut dfu, block, 32).expect("vec");
+                assert_eq!(vec, [0xff; 32], "block {}", block);
+            }
+
+            let mem = dfu.release();
+            let erases: Vec<_> = (0..PAGES)
+                .map(|n| MockCall::Erase(CHIPMEM_BASE + (n * PAGE) as u32))
+                .collect();
+            assert_eq!(mem.mem.calls[..PAGES], erases);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,190 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const BLKMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory with `F` as `FIRST_DATA_BLOCK` value.
+pub struct BlkMem<const F: u16> {
+    mem: MockMem,
+}
+
+impl<const F: u16> DFUMemIO for BlkMem<F> {
+    const INITIAL_ADDRESS_POINTER: u32 = BLKMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const FIRST_DATA_BLOCK: u16 = F;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkBlk<const F: u16> {}
+
+impl<const F: u16> UsbDeviceCtx for MkBlk<F> {
+    type C<'c> = DFUClass<EmulatedUsbBus, BlkMem<F>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, BlkMem<F>>> {
+        let mem = BlkMem {
+            mem: MockMem::new(BLKMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Download a block, then wait for it to be programmed
+fn download_block<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, block: u16, data: &[u8]) {
+    let vec = dev.download(dfu, block, data).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+}
+
+#[test]
+fn test_first_data_block_1() {
+    MkBlk::<1> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 1 (offset 0) */
+            download_block(&mut dfu, &mut dev, 1, &[0x11; 32]);
+
+            /* Download block 2 (offset 32) */
+            download_block(&mut dfu, &mut dev, 2, &[0x22; 32]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 0, Get Commands is still available */
+            let vec = dev.upload(&mut dfu, 0, 32).expect("vec");
+            assert_eq!(vec, [0x00, 0x21, 0x41]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 1 (offset 0) */
+            let vec = dev.upload(&mut dfu, 1, 32).expect("vec");
+            assert_eq!(vec, [0x11; 32]);
+
+            /* Upload block 2 (offset 32) */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [0x22; 32]);
+
+            /* Upload block 3 (offset 64), not changed */
+            let vec = dev.upload(&mut dfu, 3, 32).expect("vec");
+            assert_eq!(vec, [0xff; 32]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 0 (command), set Address Pointer */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, 0x00, 0x01, 0x00, 0x08])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 1 (offset 0x100) */
+            download_block(&mut dfu, &mut dev, 1, &[0x33; 32]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[0..32], [0x11; 32]);
+            assert_eq!(mem.mem.memory()[32..64], [0x22; 32]);
+            assert_eq!(mem.mem.memory()[0x100..0x120], [0x33; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_first_data_block_0() {
+    MkBlk::<0> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (offset 0), not a command */
+            download_block(&mut dfu, &mut dev, 0, &[0x21, 0x00, 0x01, 0x00, 0x08]);
+
+            /* Download block 1 (offset 32) */
+            download_block(&mut dfu, &mut dev, 1, &[0x22; 32]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 0 (offset 0), not Get Commands */
+            let vec = dev.upload(&mut dfu, 0, 32).expect("vec");
+            assert_eq!(vec[0..6], [0x21, 0x00, 0x01, 0x00, 0x08, 0xff]);
+
+            /* Upload block 1 (offset 32) */
+            let vec = dev.upload(&mut dfu, 1, 32).expect("vec");
+            assert_eq!(vec, [0x22; 32]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[0..5], [0x21, 0x00, 0x01, 0x00, 0x08]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_first_data_block_last() {
+    MkBlk::<1> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0xFFFF, there is no DfuSe block number for it */
+            let vec = dev.download(&mut dfu, 0xFFFF, &[0x11; 32]);
+            assert!(vec.is_err());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
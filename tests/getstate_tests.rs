@@ -0,0 +1,156 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const GSMEM_BASE: u32 = 0x0800_0000;
+
+/// Mock memory, `A` is `ADVANCE_ON_GETSTATE` value.
+pub struct GsMem<const A: bool> {
+    mem: MockMem,
+}
+
+impl<const A: bool> DFUMemIO for GsMem<A> {
+    const INITIAL_ADDRESS_POINTER: u32 = GSMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/4*256g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 64;
+    const ADVANCE_ON_GETSTATE: bool = A;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkGs<const A: bool> {}
+
+impl<const A: bool> UsbDeviceCtx for MkGs<A> {
+    type C<'c> = DFUClass<EmulatedUsbBus, GsMem<A>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, GsMem<A>>> {
+        let mem = GsMem {
+            mem: MockMem::new(GSMEM_BASE, 4, 256),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Get State, expect `state`
+fn expect_state<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, state: DFUState) {
+    let vec = dev.get_state(dfu).expect("vec");
+    assert_eq!(vec, [state as u8]);
+}
+
+/// Download block `block_num`, then poll with Get State until the block is processed
+fn download<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, block_num: u16, data: &[u8]) {
+    let vec = dev.download(dfu, block_num, data).expect("vec");
+    assert_eq!(vec, []);
+
+    expect_state(dfu, dev, DFUState::DfuDnBusy);
+    expect_state(dfu, dev, DFUState::DfuDnloadIdle);
+    expect_state(dfu, dev, DFUState::DfuDnloadIdle);
+}
+
+#[test]
+fn test_getstate_download() {
+    MkGs::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            let b = (GSMEM_BASE + 0x100).to_le_bytes();
+
+            /* Set Address Pointer */
+            download(&mut dfu, &mut dev, 0, &[0x21, b[0], b[1], b[2], b[3]]);
+
+            /* Erase Page */
+            download(&mut dfu, &mut dev, 0, &[0x41, b[0], b[1], b[2], b[3]]);
+
+            /* Download blocks 2 and 3 */
+            download(&mut dfu, &mut dev, 2, &[0x55; 64]);
+            download(&mut dfu, &mut dev, 3, &[0xaa; 64]);
+
+            /* Download len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 4, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            expect_state(&mut dfu, &mut dev, DFUState::DfuManifest);
+            expect_state(&mut dfu, &mut dev, DFUState::DfuIdle);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Erase(GSMEM_BASE + 0x100),
+                    MockCall::Program(GSMEM_BASE + 0x100, 64),
+                    MockCall::Program(GSMEM_BASE + 0x140, 64),
+                    MockCall::Manifestation
+                ]
+            );
+            assert_eq!(mem.mem.memory()[0x100..0x140], [0x55; 64]);
+            assert_eq!(mem.mem.memory()[0x140..0x180], [0xaa; 64]);
+            assert_eq!(mem.mem.memory()[0x180..0x200], [0xff; 128]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_getstate_default() {
+    MkGs::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            // state doesn't change without Get Status
+            expect_state(&mut dfu, &mut dev, DFUState::DfuDnloadSync);
+            expect_state(&mut dfu, &mut dev, DFUState::DfuDnloadSync);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            // the block is programmed, waiting for Get Status
+            expect_state(&mut dfu, &mut dev, DFUState::DfuDnloadSync);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::Program(GSMEM_BASE, 64)]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,228 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::hex::HexMem;
+
+const RAMMEM_BASE: u32 = 0x0800_0000;
+
+/// Mock memory.
+pub struct RamMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for RamMem {
+    const INITIAL_ADDRESS_POINTER: u32 = RAMMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkHex {}
+
+impl UsbDeviceCtx for MkHex {
+    type C<'c> = DFUClass<EmulatedUsbBus, HexMem<RamMem>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, HexMem<RamMem>>> {
+        let mem = RamMem {
+            mem: MockMem::new(RAMMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, HexMem::new(mem)))
+    }
+}
+
+/// Host side: encode Intel HEX record
+fn record(rtype: u8, offset: u16, data: &[u8]) -> String {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(rtype);
+    bytes.extend_from_slice(data);
+    let sum = bytes.iter().fold(0u8, |a, b| a.wrapping_add(*b));
+    bytes.push(sum.wrapping_neg());
+
+    let mut s = String::from(":");
+    for b in bytes {
+        s.push_str(&format!("{:02X}", b));
+    }
+    s.push_str("\r\n");
+    s
+}
+
+/// Download `image` in 32-byte blocks
+fn download_image(
+    dfu: &mut DFUClass<EmulatedUsbBus, HexMem<RamMem>>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, HexMem<RamMem>>, MkHex>,
+    image: &[u8],
+) {
+    for (block, data) in image.chunks(32).enumerate() {
+        /* Download block */
+        let vec = dev.download(dfu, 2 + block as u16, data).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+    }
+}
+
+#[test]
+fn test_hex_download() {
+    MkHex {}
+        .with_usb(|mut dfu, mut dev| {
+            let data1: Vec<u8> = (0..40).collect();
+            let data2: Vec<u8> = (0xa0..0xaa).collect();
+            let data3 = [0xde, 0xad, 0xbe, 0xef];
+
+            let image = [
+                record(0x04, 0, &[0x08, 0x00]),
+                record(0x00, 0x0000, &data1),
+                record(0x00, 0x0200, &data2),
+                record(0x04, 0, &[0x08, 0x00]),
+                record(0x00, 0x0300, &data3),
+                record(0x05, 0, &[0x08, 0x00, 0x00, 0x00]),
+                record(0x01, 0, &[]),
+            ]
+            .concat();
+
+            download_image(&mut dfu, &mut dev, image.as_bytes());
+
+            /* Download len 0, trigger manifestation */
+            let n = image.len().div_ceil(32) as u16;
+            let vec = dev.download(&mut dfu, 2 + n, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release().into_inner();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(RAMMEM_BASE, 32),
+                    MockCall::Program(RAMMEM_BASE + 32, 8),
+                    MockCall::Program(RAMMEM_BASE + 0x200, 10),
+                    MockCall::Program(RAMMEM_BASE + 0x300, 4),
+                    MockCall::Manifestation,
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..40], data1);
+            assert_eq!(mem.mem.memory()[0x200..0x20a], data2);
+            assert_eq!(mem.mem.memory()[0x300..0x304], data3);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_hex_checksum_err() {
+    MkHex {}
+        .with_usb(|mut dfu, mut dev| {
+            let mut image = record(0x00, 0x0000, &[1, 2, 3, 4]);
+            // corrupt checksum
+            image.replace_range(17..19, "00");
+
+            /* Download block 2 */
+            let vec = dev.download(&mut dfu, 2, image.as_bytes()).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 2, not a HEX record */
+            let vec = dev.download(&mut dfu, 2, b"hello").expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+
+            let mem = dfu.release().into_inner();
+            assert_eq!(mem.mem.calls, []);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_hex_no_eof() {
+    MkHex {}
+        .with_usb(|mut dfu, mut dev| {
+            let image = record(0x04, 0, &[0x08, 0x00]) + &record(0x00, 0x0010, &[1, 2, 3, 4]);
+
+            download_image(&mut dfu, &mut dev, image.as_bytes());
+
+            /* Download len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 4, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrNotdone, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release().into_inner();
+            assert_eq!(mem.mem.calls, [MockCall::Program(RAMMEM_BASE + 0x10, 4)]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,159 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::Cell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const BANK_SIZE: usize = 512;
+const BANKMEM_BASE: u32 = 0x0800_0000;
+const BANK2_BASE: u32 = BANKMEM_BASE + BANK_SIZE as u32;
+
+thread_local! {
+    /// Bank the device runs from
+    static ACTIVE_BANK: Cell<u8> = const { Cell::new(1) };
+}
+
+/// Dual-bank memory, Address Pointer starts at the inactive bank.
+pub struct BankMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for BankMem {
+    const INITIAL_ADDRESS_POINTER: u32 = BANKMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/2*512 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn initial_address_pointer(&self) -> u32 {
+        match ACTIVE_BANK.with(|b| b.get()) {
+            1 => BANK2_BASE,
+            _ => BANKMEM_BASE,
+        }
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+fn bank_mem() -> BankMem {
+    let mut mem = MockMem::new(BANKMEM_BASE, 2, BANK_SIZE as u32);
+    mem.memory_mut()[..BANK_SIZE].fill(0x11);
+    mem.memory_mut()[BANK_SIZE..].fill(0x22);
+    BankMem { mem }
+}
+
+struct MkBank {
+    /// Use `new_with_address()` with the value
+    address: Option<u32>,
+}
+
+impl UsbDeviceCtx for MkBank {
+    type C<'c> = DFUClass<EmulatedUsbBus, BankMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, BankMem>> {
+        ACTIVE_BANK.with(|b| b.set(1));
+        match self.address {
+            Some(address) => Ok(DFUClass::new_with_address(alloc, bank_mem(), address)),
+            None => Ok(DFUClass::new(alloc, bank_mem())),
+        }
+    }
+}
+
+/// Send a request that is invalid in dfuIDLE state, then clear the error
+fn error_and_clear<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>) {
+    /* Download block 2, longer than wTransferSize */
+    let vec = dev.download(dfu, 2, &[0; 64]);
+    assert!(vec.is_err());
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(
+        vec,
+        status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+    );
+
+    /* Clear Status */
+    let vec = dev.clear_status(dfu).expect("vec");
+    assert_eq!(vec, []);
+}
+
+#[test]
+fn test_initial_address_runtime() {
+    MkBank { address: None }
+        .with_usb(|mut dfu, mut dev| {
+            assert_eq!(dfu.get_address_pointer(), BANK2_BASE);
+
+            /* Upload block 2, inactive bank */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [0x22; 32]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            // the same bank, Address Pointer is kept
+            dfu.set_address_pointer(BANK2_BASE + 0x40);
+            error_and_clear(&mut dfu, &mut dev);
+            assert_eq!(dfu.get_address_pointer(), BANK2_BASE + 0x40);
+
+            // banks are swapped, Address Pointer follows after the error is cleared
+            ACTIVE_BANK.with(|b| b.set(2));
+            assert_eq!(dfu.get_address_pointer(), BANK2_BASE + 0x40);
+            error_and_clear(&mut dfu, &mut dev);
+            assert_eq!(dfu.get_address_pointer(), BANKMEM_BASE);
+
+            /* Upload block 2 */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [0x11; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_initial_address_fixed() {
+    MkBank {
+        address: Some(BANKMEM_BASE + 0x40),
+    }
+    .with_usb(|mut dfu, mut dev| {
+        assert_eq!(dfu.get_address_pointer(), BANKMEM_BASE + 0x40);
+
+        // application provided address is kept
+        ACTIVE_BANK.with(|b| b.set(2));
+        error_and_clear(&mut dfu, &mut dev);
+        assert_eq!(dfu.get_address_pointer(), BANKMEM_BASE + 0x40);
+    })
+    .expect("with_usb");
+}
@@ -0,0 +1,190 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use core::ops::Range;
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const GAPMEM_BASE: u32 = 0x0800_0000;
+const BANK0: Range<u32> = GAPMEM_BASE..GAPMEM_BASE + 0x100;
+const BANK1: Range<u32> = GAPMEM_BASE + 0x200..GAPMEM_BASE + 0x300;
+
+/// Two flash banks with a reserved area in between.
+pub struct GapMem {
+    mem: MockMem,
+}
+
+fn in_banks(address: u32, length: usize) -> bool {
+    let end = address + length as u32;
+    [BANK0, BANK1]
+        .iter()
+        .any(|r| r.start <= address && end <= r.end)
+}
+
+impl DFUMemIO for GapMem {
+    const INITIAL_ADDRESS_POINTER: u32 = GAPMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*256 g,1*256 a,1*256 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 64;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = &[BANK0, BANK1];
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        if address == BANK1.end {
+            return Ok(&[]);
+        }
+        if !in_banks(address, length) {
+            return Err(DFUMemError::Address);
+        }
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        if !in_banks(address, length) {
+            return Err(DFUMemError::Address);
+        }
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkGap {}
+
+impl UsbDeviceCtx for MkGap {
+    type C<'c> = DFUClass<EmulatedUsbBus, GapMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, GapMem>> {
+        let mem = GapMem {
+            mem: MockMem::new(GAPMEM_BASE, 3, 256),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_layout_download_upload() {
+    MkGap {}
+        .with_usb(|mut dfu, mut dev| {
+            let image: Vec<u8> = (0..512).map(|i| (i * 7) as u8).collect();
+
+            for (block, data) in image.chunks(64).enumerate() {
+                /* Download block */
+                let vec = dev.download(&mut dfu, 2 + block as u16, data).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            let mut uploaded = Vec::new();
+            for block in 0..9 {
+                /* Upload block */
+                let vec = dev.upload(&mut dfu, 2 + block, 64).expect("vec");
+                uploaded.extend_from_slice(&vec);
+            }
+            assert_eq!(uploaded, image);
+
+            /* Get Status, short frame ends the upload */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            let addresses: Vec<u32> = mem
+                .mem
+                .calls
+                .iter()
+                .filter_map(|c| match c {
+                    MockCall::Program(address, _) => Some(*address),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(
+                addresses,
+                [0x000, 0x040, 0x080, 0x0c0, 0x200, 0x240, 0x280, 0x2c0].map(|a| GAPMEM_BASE + a)
+            );
+            assert_eq!(mem.mem.memory()[..0x100], image[..0x100]);
+            assert_eq!(mem.mem.memory()[0x100..0x200], [0xff; 0x100]);
+            assert_eq!(mem.mem.memory()[0x200..0x300], image[0x100..]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_layout_address_pointer() {
+    MkGap {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), set address pointer to the last block of bank 0 */
+            let b = (BANK0.end - 64).to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            for block in 2..4 {
+                /* Download block */
+                let vec = dev
+                    .download(&mut dfu, block, &[block as u8; 64])
+                    .expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(BANK0.end - 64, 64),
+                    MockCall::Program(BANK1.start, 64)
+                ]
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,343 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const LIFEMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that records calls, `usb_reset()` returns after manifestation.
+pub struct LifeMem<const TOLERANT: bool> {
+    mem: MockMem,
+    events: Vec<&'static str>,
+}
+
+impl<const TOLERANT: bool> DFUMemIO for LifeMem<TOLERANT> {
+    const INITIAL_ADDRESS_POINTER: u32 = LIFEMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const MANIFESTATION_TOLERANT: bool = TOLERANT;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.events.push("program");
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.events.push("manifestation");
+        self.mem.manifestation()
+    }
+
+    fn usb_reset(&mut self) {
+        self.events.push("usb_reset");
+    }
+
+    fn on_configured(&mut self) {
+        self.events.push("on_configured");
+    }
+}
+
+struct MkLife<const TOLERANT: bool> {}
+
+impl<const TOLERANT: bool> UsbDeviceCtx for MkLife<TOLERANT> {
+    type C<'c> = DFUClass<EmulatedUsbBus, LifeMem<TOLERANT>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, LifeMem<TOLERANT>>> {
+        let mem = LifeMem {
+            mem: MockMem::new(LIFEMEM_BASE, 1, 1024),
+            events: Vec::new(),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Download `image` in 32-byte blocks and trigger manifestation
+fn download_image<const TOLERANT: bool>(
+    dfu: &mut DFUClass<EmulatedUsbBus, LifeMem<TOLERANT>>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, LifeMem<TOLERANT>>, MkLife<TOLERANT>>,
+    image: &[u8],
+) {
+    for (block, data) in image.chunks(32).enumerate() {
+        /* Download block */
+        let vec = dev.download(dfu, 2 + block as u16, data).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+    }
+
+    /* Download len 0, trigger manifestation */
+    let n = image.len().div_ceil(32) as u16;
+    let vec = dev.download(dfu, 2 + n, &[]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+}
+
+#[test]
+fn test_lifecycle_tolerant() {
+    MkLife::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            let conf = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 255)
+                .expect("vec");
+
+            download_image(&mut dfu, &mut dev, &[0x33; 64]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* USB reset and enumeration */
+            dev.bus_reset(&mut dfu).expect("reset");
+            let vec = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 255)
+                .expect("vec");
+            assert_eq!(vec, conf);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            download_image(&mut dfu, &mut dev, &[0x22; 32]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.events,
+                [
+                    "on_configured",
+                    "program",
+                    "program",
+                    "manifestation",
+                    "usb_reset",
+                    "on_configured",
+                    "program",
+                    "manifestation"
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..32], [0x22; 32]);
+            assert_eq!(mem.mem.memory()[32..64], [0x33; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_lifecycle_into_inner() {
+    MkLife::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            download_image(&mut dfu, &mut dev, &[0x33; 48]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            // the application continues with the programmed memory
+            let mut mem = dfu.into_inner();
+            assert_eq!(mem.mem.memory()[..48], [0x33; 48]);
+            assert_eq!(mem.mem.memory()[48..64], [0xff; 16]);
+
+            let data = mem.read(LIFEMEM_BASE + 16, 32).expect("read");
+            assert_eq!(data, [0x33; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_lifecycle_wait_reset() {
+    MkLife::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            download_image(&mut dfu, &mut dev, &[0x33; 64]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::OK, 0, DFUState::DfuManifestWaitReset)
+            );
+
+            /* USB reset and enumeration, usb_reset() returns */
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            download_image(&mut dfu, &mut dev, &[0x22; 32]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::OK, 0, DFUState::DfuManifestWaitReset)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.events,
+                [
+                    "on_configured",
+                    "program",
+                    "program",
+                    "manifestation",
+                    "usb_reset",
+                    "on_configured",
+                    "program",
+                    "manifestation"
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..32], [0x22; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_lifecycle_reset_in_download() {
+    MkLife::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x11; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* USB reset and enumeration before the block is programmed */
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrUsbr, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status, the block was dropped */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            download_image(&mut dfu, &mut dev, &[0x22; 32]);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.events,
+                [
+                    "on_configured",
+                    "usb_reset",
+                    "on_configured",
+                    "program",
+                    "manifestation"
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..32], [0x22; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_lifecycle_configured() {
+    MkLife::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Get Configuration */
+            let conf = dev.device_get_configuration(&mut dfu).expect("conf");
+            assert_eq!(conf, 1);
+
+            /* Set Configuration 0, device is deconfigured */
+            dev.device_set_configuration(&mut dfu, 0).expect("set");
+
+            /* Set Configuration 1 */
+            dev.device_set_configuration(&mut dfu, 1).expect("set");
+
+            /* USB reset and enumeration */
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.events,
+                [
+                    "on_configured",
+                    "on_configured",
+                    "usb_reset",
+                    "on_configured"
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_lifecycle_reset_before_manifestation() {
+    MkLife::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x11; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1) len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* USB reset and enumeration without the final Get Status */
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.events,
+                [
+                    "on_configured",
+                    "program",
+                    "manifestation",
+                    "usb_reset",
+                    "on_configured"
+                ]
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,250 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const SLOTMEMSIZE: usize = 2048;
+const SLOTMEM_BASE: u32 = 0x0800_0000;
+const HEADER_MAGIC: &[u8; 4] = b"IMG0";
+
+/// Memory with images that carry a 32-byte header with a load address and length.
+pub struct SlotMem<const SKIP_HEADER: bool> {
+    mem: MockMem,
+}
+
+impl<const SKIP_HEADER: bool> SlotMem<SKIP_HEADER> {
+    fn new() -> Self {
+        Self {
+            mem: MockMem::new(SLOTMEM_BASE, 2, 1024),
+        }
+    }
+}
+
+impl<const SKIP_HEADER: bool> DFUMemIO for SlotMem<SKIP_HEADER> {
+    const INITIAL_ADDRESS_POINTER: u32 = SLOTMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/2*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 64;
+    const IMAGE_HEADER_SIZE: usize = 32;
+    const PROGRAM_IMAGE_HEADER: bool = !SKIP_HEADER;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn locate_image(&mut self, header: &[u8]) -> Result<u32, DFUMemError> {
+        assert_eq!(header.len(), 32);
+        if &header[..4] != HEADER_MAGIC {
+            return Err(DFUMemError::Target);
+        }
+        let address = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        let length = u32::from_le_bytes(header[8..12].try_into().unwrap());
+
+        let end = SLOTMEM_BASE + SLOTMEMSIZE as u32;
+        match address.checked_add(length) {
+            Some(e) if address >= SLOTMEM_BASE && e <= end => Ok(address),
+            _ => Err(DFUMemError::Address),
+        }
+    }
+}
+
+struct MkSlot<const SKIP_HEADER: bool> {}
+
+impl<const SKIP_HEADER: bool> UsbDeviceCtx for MkSlot<SKIP_HEADER> {
+    type C<'c> = DFUClass<EmulatedUsbBus, SlotMem<SKIP_HEADER>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, SlotMem<SKIP_HEADER>>> {
+        Ok(DFUClass::new(alloc, SlotMem::new()))
+    }
+}
+
+/// Host side: image with a header
+fn image(magic: &[u8; 4], address: u32, data: &[u8]) -> Vec<u8> {
+    let mut v = magic.to_vec();
+    v.extend_from_slice(&address.to_le_bytes());
+    v.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    v.resize(32, 0);
+    v.extend_from_slice(data);
+    v
+}
+
+#[test]
+fn test_locate_program_header() {
+    MkSlot::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            let data: Vec<u8> = (0..96).collect();
+            let img = image(HEADER_MAGIC, SLOTMEM_BASE + 0x400, &data);
+
+            for (block, chunk) in img.chunks(64).enumerate() {
+                /* Download block */
+                let vec = dev
+                    .download(&mut dfu, 2 + block as u16, chunk)
+                    .expect("vec");
+                assert_eq!(vec, []);
+                assert_eq!(dfu.get_address_pointer(), SLOTMEM_BASE + 0x400);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            /* Download block 0 (command), set address pointer, image location is fixed */
+            let b = SLOTMEM_BASE.to_le_bytes();
+            let e = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(SLOTMEM_BASE + 0x400, 64),
+                    MockCall::Program(SLOTMEM_BASE + 0x440, 64)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[0x400..0x480], img);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_locate_skip_header() {
+    MkSlot::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            let data: Vec<u8> = (0..100).collect();
+            let img = image(HEADER_MAGIC, SLOTMEM_BASE + 0x100, &data);
+
+            for (block, chunk) in img.chunks(64).enumerate() {
+                /* Download block */
+                let vec = dev
+                    .download(&mut dfu, 2 + block as u16, chunk)
+                    .expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            /* Abort, the next download is a new session */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 2, header only */
+            let img = image(HEADER_MAGIC, SLOTMEM_BASE + 0x200, &[]);
+            let vec = dev.download(&mut dfu, 2, &img).expect("vec");
+            assert_eq!(vec, []);
+            assert_eq!(dfu.get_address_pointer(), SLOTMEM_BASE + 0x200);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(SLOTMEM_BASE + 0x100, 32),
+                    MockCall::Program(SLOTMEM_BASE + 0x120, 64),
+                    MockCall::Program(SLOTMEM_BASE + 0x160, 4)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[0x100..0x164], data);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_locate_rejected() {
+    MkSlot::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2, invalid magic */
+            let img = image(b"BAD!", SLOTMEM_BASE, &[0x55; 32]);
+            let e = dev.download(&mut dfu, 2, &img).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrTarget, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 2, image does not fit in memory */
+            let img = image(HEADER_MAGIC, SLOTMEM_BASE + 0x7e0, &[0x55; 64]);
+            let e = dev.download(&mut dfu, 2, &img[..48]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 2, shorter than the header */
+            let img = image(HEADER_MAGIC, SLOTMEM_BASE, &[]);
+            let e = dev.download(&mut dfu, 2, &img[..20]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, []);
+        })
+        .expect("with_usb");
+}
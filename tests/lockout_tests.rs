@@ -0,0 +1,234 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::Cell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const LOCKMEM_BASE: u32 = 0x0800_0000;
+const IMAGE_MAGIC: u8 = 0xaa;
+
+thread_local! {
+    /// Failure counter kept in "backup registers"
+    static STORED: Cell<Option<u8>> = const { Cell::new(None) };
+}
+
+/// Memory that accepts images starting with `IMAGE_MAGIC`.
+pub struct LockMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for LockMem {
+    const INITIAL_ADDRESS_POINTER: u32 = LOCKMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const MAX_FAILED_MANIFESTATIONS: u8 = 2;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        if self.mem.memory()[0] != IMAGE_MAGIC {
+            return Err(DFUManifestationError::File);
+        }
+        self.mem.manifestation()
+    }
+
+    fn persist_lockout(&mut self, failures: Option<u8>) -> u8 {
+        match failures {
+            Some(n) => {
+                STORED.with(|s| s.set(Some(n)));
+                n
+            }
+            None => STORED.with(|s| s.get()).unwrap_or(0),
+        }
+    }
+}
+
+struct MkLock {}
+
+impl UsbDeviceCtx for MkLock {
+    type C<'c> = DFUClass<EmulatedUsbBus, LockMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, LockMem>> {
+        let mem = LockMem {
+            mem: MockMem::new(LOCKMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Download a single block image and manifest it, returns the final status
+fn download_image(
+    dfu: &mut DFUClass<EmulatedUsbBus, LockMem>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, LockMem>, MkLock>,
+    first: u8,
+) -> Vec<u8> {
+    /* Download block 2 (offset 0) */
+    let vec = dev.download(dfu, 2, &[first; 32]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+    /* Download block 3 (offset 1) len 0, trigger manifestation */
+    let vec = dev.download(dfu, 3, &[]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+    /* Get Status */
+    dev.get_status(dfu).expect("vec")
+}
+
+#[test]
+fn test_lockout_counting() {
+    STORED.with(|s| s.set(None));
+
+    MkLock {}
+        .with_usb(|mut dfu, mut dev| {
+            assert_eq!(dfu.failed_manifestations(), 0);
+
+            // IMAGE_MAGIC can be programmed over it without an erase
+            let vec = download_image(&mut dfu, &mut dev, 0xbb);
+            assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+            assert_eq!(dfu.failed_manifestations(), 1);
+            assert!(!dfu.is_locked_out());
+            assert_eq!(STORED.with(|s| s.get()), Some(1));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            // success resets the counter
+            let vec = download_image(&mut dfu, &mut dev, IMAGE_MAGIC);
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+            assert_eq!(dfu.failed_manifestations(), 0);
+            assert_eq!(STORED.with(|s| s.get()), Some(0));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_lockout() {
+    STORED.with(|s| s.set(None));
+
+    MkLock {}
+        .with_usb(|mut dfu, mut dev| {
+            for _ in 0..2 {
+                let vec = download_image(&mut dfu, &mut dev, 0x55);
+                assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+
+                /* Clear Status */
+                let vec = dev.clear_status(&mut dfu).expect("vec");
+                assert_eq!(vec, []);
+            }
+            assert!(dfu.is_locked_out());
+
+            /* Download block 2, refused */
+            let e = dev
+                .download(&mut dfu, 2, &[IMAGE_MAGIC; 32])
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrVendor, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 0 (command), refused too */
+            let e = dev.download(&mut dfu, 0, &[0x41]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrVendor, 0, DFUState::DfuError));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_lockout_reset() {
+    STORED.with(|s| s.set(None));
+
+    MkLock {}
+        .with_usb(|mut dfu, mut dev| {
+            for _ in 0..2 {
+                let vec = download_image(&mut dfu, &mut dev, 0x55);
+                assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+
+                /* Clear Status */
+                let vec = dev.clear_status(&mut dfu).expect("vec");
+                assert_eq!(vec, []);
+            }
+
+            // USB reset does not clear the lockout
+            dev.bus_reset(&mut dfu).expect("reset");
+            assert!(dfu.is_locked_out());
+
+            /* Download block 2, refused */
+            let e = dev
+                .download(&mut dfu, 2, &[IMAGE_MAGIC; 32])
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+        })
+        .expect("with_usb");
+
+    // device reset, the counter is restored
+    MkLock {}
+        .with_usb(|dfu, dev| {
+            assert_eq!(dfu.failed_manifestations(), 2);
+            assert!(dfu.is_locked_out());
+        })
+        .expect("with_usb");
+
+    // the counter is cleared by policy, e.g. after a power cycle
+    STORED.with(|s| s.set(None));
+    MkLock {}
+        .with_usb(|mut dfu, mut dev| {
+            assert!(!dfu.is_locked_out());
+
+            let vec = download_image(&mut dfu, &mut dev, IMAGE_MAGIC);
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+}
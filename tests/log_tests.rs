@@ -0,0 +1,219 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::RefCell;
+use std::sync::Once;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+use log::{Level, Log, Metadata, Record};
+
+const LOGMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that fails to program the block at offset 512 and to manifest.
+pub struct LogMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for LogMem {
+    const INITIAL_ADDRESS_POINTER: u32 = LOGMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkLog {}
+
+impl UsbDeviceCtx for MkLog {
+    type C<'c> = DFUClass<EmulatedUsbBus, LogMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, LogMem>> {
+        let mut mem = LogMem {
+            mem: MockMem::new(LOGMEM_BASE, 1, 1024),
+        };
+        mem.mem.faults.program = Some((LOGMEM_BASE + 512, DFUMemError::Prog));
+        mem.mem.faults.manifestation = Some(DFUManifestationError::Firmware);
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+thread_local! {
+    /// Messages logged by the test thread
+    static MESSAGES: RefCell<Vec<(Level, String)>> = const { RefCell::new(Vec::new()) };
+}
+
+struct TestLogger;
+
+impl Log for TestLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        MESSAGES.with(|m| {
+            m.borrow_mut()
+                .push((record.level(), record.args().to_string()))
+        });
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: TestLogger = TestLogger;
+static INIT: Once = Once::new();
+
+/// Install the logger, and return messages logged by `f`
+fn logged(f: impl FnOnce()) -> Vec<(Level, String)> {
+    INIT.call_once(|| {
+        log::set_logger(&LOGGER).expect("set_logger");
+        log::set_max_level(log::LevelFilter::Trace);
+    });
+    MESSAGES.with(|m| m.borrow_mut().clear());
+    f();
+    MESSAGES.with(|m| m.take())
+}
+
+fn contains(messages: &[(Level, String)], level: Level, text: &str) -> bool {
+    messages.iter().any(|(l, m)| *l == level && m == text)
+}
+
+#[test]
+fn test_log_download() {
+    let messages = logged(|| {
+        MkLog {}
+            .with_usb(|mut dfu, mut dev| {
+                /* Download block 2 (offset 0) */
+                let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            })
+            .expect("with_usb");
+    });
+
+    assert!(contains(
+        &messages,
+        Level::Trace,
+        "dfu: control_out request 1 value 2 length 32"
+    ));
+    assert!(contains(
+        &messages,
+        Level::Trace,
+        "dfu: control_in request 3 value 0 length 6"
+    ));
+    assert!(contains(
+        &messages,
+        Level::Debug,
+        "dfu: DfuIdle -> DfuDnloadSync"
+    ));
+    assert!(contains(
+        &messages,
+        Level::Debug,
+        "dfu: execute WriteMemory { block_num: 0, len: 32 }"
+    ));
+    assert!(contains(
+        &messages,
+        Level::Debug,
+        "dfu: DfuDnloadSync -> DfuDnloadIdle"
+    ));
+    assert!(!messages
+        .iter()
+        .any(|(l, m)| *l == Level::Warn && m.starts_with("dfu:")));
+}
+
+#[test]
+fn test_log_errors() {
+    let messages = logged(|| {
+        MkLog {}
+            .with_usb(|mut dfu, mut dev| {
+                /* Download block 18 (offset 512), program fails */
+                let vec = dev.download(&mut dfu, 18, &[0x55; 32]).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+
+                /* Clear Status */
+                let vec = dev.clear_status(&mut dfu).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Download 0 length, manifestation fails */
+                let vec = dev.download(&mut dfu, 0, &[]).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(
+                    vec,
+                    status(DFUStatusCode::ErrFirmware, 0, DFUState::DfuError)
+                );
+            })
+            .expect("with_usb");
+    });
+
+    assert!(contains(
+        &messages,
+        Level::Warn,
+        "dfu: WriteMemory { block_num: 16, len: 32 } failed: Prog"
+    ));
+    assert!(contains(
+        &messages,
+        Level::Warn,
+        "dfu: DfuDnBusy -> DfuError, status ErrProg"
+    ));
+    assert!(contains(
+        &messages,
+        Level::Warn,
+        "dfu: manifestation failed: Firmware"
+    ));
+}
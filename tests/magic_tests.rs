@@ -0,0 +1,225 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const MAGICMEM_BASE: u32 = 0x0800_0000;
+const MAGIC: &[u8] = b"PROD";
+
+/// Memory that expects `MAGIC` at `OFFSET` of an image.
+pub struct MagicMem<const OFFSET: usize> {
+    mem: MockMem,
+}
+
+impl<const OFFSET: usize> DFUMemIO for MagicMem<OFFSET> {
+    const INITIAL_ADDRESS_POINTER: u32 = MAGICMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const IMAGE_MAGIC: Option<(usize, &'static [u8])> = Some((OFFSET, MAGIC));
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkMagic<const OFFSET: usize> {}
+
+impl<const OFFSET: usize> UsbDeviceCtx for MkMagic<OFFSET> {
+    type C<'c> = DFUClass<EmulatedUsbBus, MagicMem<OFFSET>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, MagicMem<OFFSET>>> {
+        let mem = MagicMem {
+            mem: MockMem::new(MAGICMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// 64 byte image with `magic` at `offset`
+fn image(offset: usize, magic: &[u8]) -> Vec<u8> {
+    let mut image = vec![0x55; 64];
+    image[offset..offset + magic.len()].copy_from_slice(magic);
+    image
+}
+
+/// Download a block, expect it to be programmed
+fn download_ok<const OFFSET: usize>(
+    dfu: &mut DFUClass<EmulatedUsbBus, MagicMem<OFFSET>>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, MagicMem<OFFSET>>, MkMagic<OFFSET>>,
+    block: u16,
+    data: &[u8],
+) {
+    /* Download block */
+    let vec = dev.download(dfu, block, data).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+}
+
+#[test]
+fn test_magic_match() {
+    MkMagic::<4> {}
+        .with_usb(|mut dfu, mut dev| {
+            let image = image(4, MAGIC);
+            download_ok(&mut dfu, &mut dev, 2, &image[..32]);
+            download_ok(&mut dfu, &mut dev, 3, &image[32..]);
+
+            /* Download block 4 (offset 2) len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 4, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..64], image[..]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_magic_mismatch() {
+    MkMagic::<4> {}
+        .with_usb(|mut dfu, mut dev| {
+            let image = image(4, b"TEST");
+
+            /* Download block 2 (offset 0), wrong magic */
+            let e = dev.download(&mut dfu, 2, &image[..32]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrTarget, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            // a new session is checked again
+            let image = crate::image(4, MAGIC);
+            download_ok(&mut dfu, &mut dev, 2, &image[..32]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::Program(MAGICMEM_BASE, 32)]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_magic_second_block() {
+    MkMagic::<36> {}
+        .with_usb(|mut dfu, mut dev| {
+            let image = image(36, b"TEST");
+
+            // magic is not in the first block
+            download_ok(&mut dfu, &mut dev, 2, &image[..32]);
+
+            /* Download block 3 (offset 1), wrong magic */
+            let e = dev.download(&mut dfu, 3, &image[32..]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrTarget, 0, DFUState::DfuError));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::Program(MAGICMEM_BASE, 32)]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_magic_across_blocks() {
+    MkMagic::<30> {}
+        .with_usb(|mut dfu, mut dev| {
+            let image = image(30, MAGIC);
+            download_ok(&mut dfu, &mut dev, 2, &image[..32]);
+            download_ok(&mut dfu, &mut dev, 3, &image[32..]);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Program(MAGICMEM_BASE, 32),
+                    MockCall::Program(MAGICMEM_BASE + 32, 32)
+                ]
+            );
+        })
+        .expect("with_usb");
+
+    MkMagic::<30> {}
+        .with_usb(|mut dfu, mut dev| {
+            let image = image(30, b"PRxx");
+            download_ok(&mut dfu, &mut dev, 2, &image[..32]);
+
+            /* Download block 3 (offset 1), the second half of magic is wrong */
+            let e = dev.download(&mut dfu, 3, &image[32..]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrTarget, 0, DFUState::DfuError));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_magic_short_image() {
+    MkMagic::<36> {}
+        .with_usb(|mut dfu, mut dev| {
+            let image = image(4, MAGIC);
+            download_ok(&mut dfu, &mut dev, 2, &image[..32]);
+
+            /* Download block 3 (offset 1) len 0, image ends before magic */
+            let e = dev.download(&mut dfu, 3, &[]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrTarget, 0, DFUState::DfuError));
+        })
+        .expect("with_usb");
+}
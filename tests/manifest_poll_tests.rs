@@ -0,0 +1,227 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::Cell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const STAGEMEM_BASE: u32 = 0x0800_0000;
+
+/// Manifestation duration
+const COPY_TIME_MS: u32 = 3000;
+
+thread_local! {
+    /// Fake clock, in milliseconds
+    static CLOCK: Cell<u32> = const { Cell::new(1000) };
+}
+
+fn advance_clock(ms: u32) {
+    CLOCK.with(|c| c.set(c.get() + ms));
+}
+
+/// Memory that copies a staged image during manifestation, fails at the end if `FAIL`.
+pub struct StageMem<const FAIL: bool> {
+    mem: MockMem,
+    /// Time when the manifestation completes
+    done_at: Option<u32>,
+    calls: Vec<&'static str>,
+}
+
+impl<const FAIL: bool> DFUMemIO for StageMem<FAIL> {
+    const INITIAL_ADDRESS_POINTER: u32 = STAGEMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const MANIFESTATION_TIME_MS: u32 = 40;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.calls.push("manifestation");
+        Ok(())
+    }
+
+    fn manifestation_start(&mut self) -> Result<(), DFUManifestationError> {
+        self.calls.push("manifestation_start");
+        self.done_at = Some(self.now_ms() + COPY_TIME_MS);
+        Ok(())
+    }
+
+    fn manifestation_poll(&mut self) -> ManifestationProgress {
+        let now = self.now_ms();
+        match self.done_at {
+            Some(t) if now < t => ManifestationProgress::Busy(t - now),
+            _ => {
+                self.calls.push("done");
+                self.done_at = None;
+                if FAIL {
+                    ManifestationProgress::Err(DFUManifestationError::Firmware)
+                } else {
+                    ManifestationProgress::Done
+                }
+            }
+        }
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        CLOCK.with(|c| c.get())
+    }
+
+    fn usb_reset(&mut self) {
+        self.calls.push("usb_reset");
+    }
+}
+
+struct MkStage<const FAIL: bool> {}
+
+impl<const FAIL: bool> UsbDeviceCtx for MkStage<FAIL> {
+    type C<'c> = DFUClass<EmulatedUsbBus, StageMem<FAIL>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, StageMem<FAIL>>> {
+        let mem = StageMem {
+            mem: MockMem::new(STAGEMEM_BASE, 1, 1024),
+            done_at: None,
+            calls: Vec::new(),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Download a block, then a zero-length block to start manifestation
+fn download_and_leave<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>) {
+    /* Download block 2 (offset 0) */
+    let vec = dev.download(dfu, 2, &[0x55; 32]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+    /* Download 0 length */
+    let vec = dev.download(dfu, 0, &[]).expect("vec");
+    assert_eq!(vec, []);
+}
+
+#[test]
+fn test_manifestation_poll() {
+    MkStage::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            download_and_leave(&mut dfu, &mut dev);
+
+            /* Get Status, manifestation starts after this request */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 40, DFUState::DfuManifest));
+            assert!(dfu.is_busy());
+
+            /* Get Status, remaining time is reported */
+            advance_clock(1000);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 2000, DFUState::DfuManifest));
+
+            /* Get Status */
+            advance_clock(1000);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1000, DFUState::DfuManifest));
+            assert!(dfu.is_busy());
+
+            /* Get Status, manifestation is complete */
+            advance_clock(1000);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+            assert!(!dfu.is_busy());
+
+            let mem = dfu.release();
+            assert_eq!(mem.calls, ["manifestation_start", "done"]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_manifestation_poll_error() {
+    MkStage::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            download_and_leave(&mut dfu, &mut dev);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 40, DFUState::DfuManifest));
+
+            /* Get Status */
+            advance_clock(2000);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1000, DFUState::DfuManifest));
+
+            /* Get Status, manifestation failed */
+            advance_clock(1000);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrFirmware, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_manifestation_poll_reset() {
+    MkStage::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            download_and_leave(&mut dfu, &mut dev);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 40, DFUState::DfuManifest));
+
+            // USB reset doesn't wait for manifestation
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Get Status, manifestation continues after reset */
+            advance_clock(1000);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 2000, DFUState::DfuManifest));
+            assert!(dfu.is_busy());
+
+            /* Get Status, manifestation is complete before usb_reset() */
+            advance_clock(2000);
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+            assert!(!dfu.is_busy());
+
+            let mem = dfu.release();
+            assert_eq!(mem.calls, ["manifestation_start", "done", "usb_reset"]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,367 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::meminfo::*;
+
+const RTMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory with a layout known at runtime.
+pub struct RtMem {
+    mem: MockMem,
+    layout: MemInfoString<32>,
+}
+
+impl DFUMemIO for RtMem {
+    const INITIAL_ADDRESS_POINTER: u32 = RTMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.layout.as_str()
+    }
+}
+
+struct MkRt {
+    /// Flash size in KiB, e.g. read from a flash size register
+    flash_kb: u32,
+}
+
+impl UsbDeviceCtx for MkRt {
+    type C<'c> = DFUClass<EmulatedUsbBus, RtMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RtMem>> {
+        let mut layout = MemInfoString::new();
+        layout
+            .region("Flash", RTMEM_BASE)
+            .expect("region")
+            .area(self.flash_kb, 1024, Perms::RWE)
+            .expect("area");
+
+        let mem = RtMem {
+            mem: MockMem::new(RTMEM_BASE, self.flash_kb, 1024),
+            layout,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_meminfo_same_as_const() {
+    let mut s = MemInfoString::<64>::new();
+    s.region("Flash", 0x0200_0000)
+        .unwrap()
+        .area(16, 1024, Perms::R)
+        .unwrap()
+        .area(48, 1024, Perms::RWE)
+        .unwrap();
+    assert_eq!(s.as_str(), "@Flash/0x02000000/16*1Ka,48*1Kg");
+
+    let mut s = MemInfoString::<64>::new();
+    s.region("Flash", 0x0800_0000)
+        .unwrap()
+        .area(1, 256, Perms::RWE)
+        .unwrap()
+        .area(1, 256, Perms::R)
+        .unwrap()
+        .area(1, 256, Perms::RWE)
+        .unwrap();
+    assert_eq!(s.as_str(), "@Flash/0x08000000/1*256 g,1*256 a,1*256 g");
+
+    let mut s = MemInfoString::<64>::new();
+    s.region("Internal Flash", 0x0800_0000)
+        .unwrap()
+        .area(2, 128 * 1024, Perms::RWE)
+        .unwrap()
+        .segment(0x0810_0000)
+        .unwrap()
+        .area(1, 1024 * 1024, Perms::RE)
+        .unwrap();
+    assert_eq!(
+        s.as_str(),
+        "@Internal Flash/0x08000000/2*128Kg/0x08100000/1*1Mc"
+    );
+
+    let letters: Vec<char> = [
+        Perms::R,
+        Perms::E,
+        Perms::RE,
+        Perms::W,
+        Perms::RW,
+        Perms::EW,
+        Perms::RWE,
+    ]
+    .iter()
+    .map(|p| p.letter())
+    .collect();
+    assert_eq!(letters, ['a', 'b', 'c', 'd', 'e', 'f', 'g']);
+}
+
+#[test]
+fn test_meminfo_errors() {
+    let mut s = MemInfoString::<32>::new();
+    assert_eq!(
+        s.area(1, 1024, Perms::RWE).err(),
+        Some(MemInfoStringError::Order)
+    );
+    assert_eq!(s.segment(0).err(), Some(MemInfoStringError::Order));
+
+    s.region("Flash", 0x0800_0000).unwrap();
+    assert_eq!(
+        s.region("Flash", 0x0800_0000).err(),
+        Some(MemInfoStringError::Order)
+    );
+    // segment without areas
+    assert_eq!(s.segment(0).err(), Some(MemInfoStringError::Order));
+
+    // "@Flash/0x08000000/" is 18 bytes
+    s.area(12, 1024, Perms::RWE).unwrap();
+    assert_eq!(s.as_str(), "@Flash/0x08000000/12*1Kg");
+
+    // the string is unchanged if the area doesn't fit
+    assert_eq!(
+        s.area(1120, 1024, Perms::R).err(),
+        Some(MemInfoStringError::BufferTooSmall)
+    );
+    assert_eq!(s.as_str(), "@Flash/0x08000000/12*1Kg");
+
+    s.area(1, 512, Perms::R).unwrap();
+    assert_eq!(s.as_str(), "@Flash/0x08000000/12*1Kg,1*512 a");
+
+    let mut s = MemInfoString::<8>::new();
+    assert_eq!(
+        s.region("Flash", 0).err(),
+        Some(MemInfoStringError::BufferTooSmall)
+    );
+    assert_eq!(s.as_str(), "");
+}
+
+#[test]
+fn test_meminfo_descriptor() {
+    MkRt { flash_kb: 64 }
+        .with_usb(|mut dfu, mut dev| {
+            // get string descriptor (EN_US)
+            let istr = dev.device_get_string(&mut dfu, 4, 0x409).expect("str");
+            assert_eq!(istr, "@Flash/0x08000000/64*1Kg");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_meminfo_descriptor_per_instance() {
+    // the same firmware on devices with different flash sizes
+    MkRt { flash_kb: 512 }
+        .with_usb(|mut dfu, mut dev| {
+            let istr = dev.device_get_string(&mut dfu, 4, 0x409).expect("str");
+            assert_eq!(istr, "@Flash/0x08000000/512*1Kg");
+        })
+        .expect("with_usb");
+
+    MkRt { flash_kb: 1024 }
+        .with_usb(|mut dfu, mut dev| {
+            let istr = dev.device_get_string(&mut dfu, 4, 0x409).expect("str");
+            assert_eq!(istr, "@Flash/0x08000000/1024*1Kg");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_meminfo_segments() {
+    let mut s = segments("@Flash/0x08000000/16*1Ka,48*1Kg");
+    assert_eq!(s.next(), Some(Ok(0x0800_0000..0x0801_0000)));
+    assert_eq!(s.next(), None);
+
+    let s: Vec<_> = segments("@Internal Flash/0x08000000/2*128Kg/0x08100000/1*1Mc").collect();
+    assert_eq!(
+        s,
+        [Ok(0x0800_0000..0x0804_0000), Ok(0x0810_0000..0x0820_0000)]
+    );
+
+    let mut s = segments("@Flash/0x08000000/1*256 g,1*256 a");
+    assert_eq!(s.next(), Some(Ok(0x0800_0000..0x0800_0200)));
+    assert_eq!(s.next(), None);
+
+    // several regions
+    let s: Vec<_> = segments("@A/0x08000000/4*1Ka@B/0x09000000/4*1Kg,").collect();
+    assert_eq!(
+        s,
+        [Ok(0x0800_0000..0x0800_1000), Ok(0x0900_0000..0x0900_1000)]
+    );
+
+    // a malformed segment is an error, and stops the iteration
+    let mut s = segments("@Flash/0x08000000/1*1Kg/08100000/1*1Kg/0x08200000/1*1Kg");
+    assert_eq!(s.next(), Some(Ok(0x0800_0000..0x0800_0400)));
+    assert_eq!(s.next(), Some(Err(MemInfoStringError::Syntax)));
+    assert_eq!(s.next(), None);
+    for layout in [
+        "@Flash/0x08000000/1*1Kx",
+        "@Flash/0x08000000",
+        "@Flash",
+        "@Flash/0xFFFFFC00/2*1Kg",
+        "Flash/0x08000000/1*1Kg",
+    ] {
+        let s: Vec<_> = segments(layout).collect();
+        assert_eq!(s, [Err(MemInfoStringError::Syntax)], "{}", layout);
+    }
+    assert_eq!(segments("").count(), 0);
+}
+
+#[test]
+fn test_meminfo_areas() {
+    let s: Vec<_> = areas("@Flash/0x08000000/2*256 g,1*256 a/0x08100000/1*1Kb").collect();
+    assert_eq!(
+        s,
+        [
+            Ok((0x0800_0000..0x0800_0200, Perms::RWE)),
+            Ok((0x0800_0200..0x0800_0300, Perms::R)),
+            Ok((0x0810_0000..0x0810_0400, Perms::E)),
+        ]
+    );
+
+    // several regions
+    let s: Vec<_> = areas("@A/0x08000000/4*1Ka@B/0x09000000/4*1Kg").collect();
+    assert_eq!(
+        s,
+        [
+            Ok((0x0800_0000..0x0800_1000, Perms::R)),
+            Ok((0x0900_0000..0x0900_1000, Perms::RWE)),
+        ]
+    );
+
+    for (letter, perms) in [
+        (b'a', Perms::R),
+        (b'b', Perms::E),
+        (b'c', Perms::RE),
+        (b'd', Perms::W),
+        (b'e', Perms::RW),
+        (b'f', Perms::EW),
+        (b'g', Perms::RWE),
+    ] {
+        assert_eq!(Perms::from_letter(letter), Some(perms));
+        assert_eq!(perms.letter(), letter as char);
+        let bits = letter - b'a' + 1;
+        assert_eq!(perms.readable(), bits & 1 != 0);
+        assert_eq!(perms.erasable(), bits & 2 != 0);
+        assert_eq!(perms.writable(), bits & 4 != 0);
+    }
+    assert_eq!(Perms::from_letter(b'h'), None);
+
+    // a malformed area is an error, and stops the iteration
+    let mut s = areas("@Flash/0x08000000/1*1Kg,1*1Kx,1*1Kg");
+    assert_eq!(s.next(), Some(Ok((0x0800_0000..0x0800_0400, Perms::RWE))));
+    assert_eq!(s.next(), Some(Err(MemInfoStringError::Syntax)));
+    assert_eq!(s.next(), None);
+    for layout in ["@Flash", "@Flash/0xFFFFFC00/2*1Kg,1*1Kg"] {
+        let s: Vec<_> = areas(layout).filter(|a| a.is_err()).collect();
+        assert_eq!(s, [Err(MemInfoStringError::Syntax)], "{}", layout);
+    }
+}
+
+#[test]
+fn test_meminfo_extend() {
+    let mut s = MemInfoString::<64>::new();
+    s.extend("@Flash/0x08000000/4*1Kg").unwrap();
+    assert_eq!(s.as_str(), "@Flash/0x08000000/4*1Kg");
+
+    s.extend("@EEPROM/0x08080000/1*256 e").unwrap();
+    assert_eq!(s.as_str(), "@Flash/0x08000000/4*1Kg/0x08080000/1*256 e");
+
+    // areas can be added to the last segment
+    s.area(1, 256, Perms::R).unwrap();
+    assert_eq!(
+        s.as_str(),
+        "@Flash/0x08000000/4*1Kg/0x08080000/1*256 e,1*256 a"
+    );
+
+    let mut s = MemInfoString::<16>::new();
+    assert_eq!(
+        s.extend("@Flash/0x08000000/4*1Kg").err(),
+        Some(MemInfoStringError::BufferTooSmall)
+    );
+}
+
+usbd_dfu::dfu_assert_mem_info!(RtMem::MEM_INFO_STRING);
+
+#[test]
+fn test_meminfo_validate() {
+    for layout in [
+        "@Flash/0x08000000/1*1Kg",
+        "@Flash/0x08000000/16*1Ka,48*1Kg",
+        "@Flash/0x08000000/1*100 g",
+        "@QSPI/0x90000000/1*1Mg",
+        "@SDRAM/0xC0000000/1*1Gg",
+        "@Flash/0x08000000/2*256 g,1*256 a,16*16 g",
+        "@Flash/0x08000000/16*1Ka,48*1Kg/0x08100000/1*1Mc",
+        "@Flash/0x08000000/02*128Kg,/0x08100000/02*128Kg",
+        "@Internal Flash  /0x08000000/04*016Kg,01*064Kg,07*128Kg",
+        "@Option Bytes  /0x1FFFF800/01*016 e",
+        "@Flash/0x08000000/1*1Kg@EEPROM/0x08080000/1*2Ke",
+        "@Flash/0x8000000/1*1Kg",
+    ] {
+        assert!(validate_mem_info(layout), "{}", layout);
+    }
+
+    for layout in [
+        "",
+        "@Flash",
+        "Flash/0x08000000/1*1Kg",
+        "@Flash/08000000/1*1Kg",
+        "@Flash/0x/1*1Kg",
+        "@Flash/0x108000000/1*1Kg",
+        "@Flash/0x08000000",
+        "@Flash/0x08000000/",
+        "@Flash/0x08000000/1*1Kh",
+        "@Flash/0x08000000/1*1kg",
+        "@Flash/0x08000000/1*1KB",
+        "@Flash/0x08000000/1*Kg",
+        "@Flash/0x08000000/*1Kg",
+        "@Flash/0x08000000/1x1Kg",
+        "@Flash/0x08000000/1*1Kg,,1*1Kg",
+        "@Flash/0x08000000/1*1Kg;",
+        "@Flash/0x08000000/1*1Kg@",
+    ] {
+        assert!(!validate_mem_info(layout), "{}", layout);
+    }
+
+    // same units as segments()
+    assert_eq!(
+        segments("@SDRAM/0x40000000/1*1Gg").next(),
+        Some(Ok(0x4000_0000..0x8000_0000))
+    );
+}
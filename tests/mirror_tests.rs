@@ -0,0 +1,262 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::mirror::MirrorMem;
+
+const RAMMEMSIZE: usize = 2048;
+const PRIMARY_BASE: u32 = 0x0800_0000;
+const SECONDARY_BASE: u32 = 0x0801_0000;
+
+/// Mock memory at `base`, `erase_all_next()` erases it page by page.
+pub struct RamMem {
+    mem: MockMem,
+    base: u32,
+    /// Offset of the next page erased by `erase_all_next()`
+    erase_offset: usize,
+}
+
+impl RamMem {
+    fn new(base: u32) -> Self {
+        Self {
+            mem: MockMem::new(base, 2, 1024),
+            base,
+            erase_offset: 0,
+        }
+    }
+
+    /// Memory with all bits programmed to 0
+    fn zeroed(base: u32) -> Self {
+        let mut mem = Self::new(base);
+        mem.mem.memory_mut().fill(0);
+        mem
+    }
+}
+
+impl DFUMemIO for RamMem {
+    const INITIAL_ADDRESS_POINTER: u32 = PRIMARY_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/2*1Kg";
+    const PROGRAM_TIME_MS: u32 = 5;
+    const ERASE_TIME_MS: u32 = 10;
+    const FULL_ERASE_TIME_MS: u32 = 20;
+    const TRANSFER_SIZE: u16 = 64;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn erase_all_next(&mut self) -> Result<EraseProgress, DFUMemError> {
+        self.mem.erase(self.base + self.erase_offset as u32)?;
+        self.erase_offset += 1024;
+        if self.erase_offset < RAMMEMSIZE {
+            return Ok(EraseProgress::Busy(10));
+        }
+        self.erase_offset = 0;
+        Ok(EraseProgress::Done)
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+type Mirror = MirrorMem<RamMem, RamMem>;
+
+struct MkMirror {}
+
+impl UsbDeviceCtx for MkMirror {
+    type C<'c> = DFUClass<EmulatedUsbBus, Mirror>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, Mirror>> {
+        let mem = MirrorMem::with_offset(
+            RamMem::new(PRIMARY_BASE),
+            RamMem::new(SECONDARY_BASE),
+            SECONDARY_BASE - PRIMARY_BASE,
+        );
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Secondary copy fails to program the second block
+struct MkMirrorErr {}
+
+impl UsbDeviceCtx for MkMirrorErr {
+    type C<'c> = DFUClass<EmulatedUsbBus, Mirror>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, Mirror>> {
+        let mut secondary = RamMem::new(SECONDARY_BASE);
+        secondary.mem.faults.program = Some((SECONDARY_BASE + 64, DFUMemError::Prog));
+        let mem = MirrorMem::with_offset(
+            RamMem::new(PRIMARY_BASE),
+            secondary,
+            SECONDARY_BASE - PRIMARY_BASE,
+        );
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_mirror_descriptor() {
+    MkMirror {}
+        .with_usb(|mut dfu, mut dev| {
+            let vec = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 130)
+                .expect("vec");
+
+            // dfu descriptor, transfer size is the smaller of two
+            assert_eq!(vec[18..], [9, 0x21, 0b1111, 250, 0, 64, 0, 0x1a, 1]);
+
+            // only one copy is advertised
+            let vec = dev.device_get_string(&mut dfu, 4, 0x409).expect("vec");
+            assert_eq!(vec, RamMem::MEM_INFO_STRING);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_mirror_download() {
+    MkMirror {}
+        .with_usb(|mut dfu, mut dev| {
+            let b = PRIMARY_BASE.to_le_bytes();
+
+            /* Download block 0 (command), erase = base */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            for block in 0..4u8 {
+                let data: Vec<u8> = (0..64).map(|i| block * 64 + i).collect();
+
+                /* Download block */
+                let vec = dev
+                    .download(&mut dfu, 2 + block as u16, &data)
+                    .expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 3 (offset 1), served by the primary copy */
+            let vec = dev.upload(&mut dfu, 3, 64).expect("vec");
+            assert_eq!(vec, (64..128).collect::<Vec<u8>>());
+
+            let (primary, secondary) = dfu.release().into_inner();
+            assert_eq!(primary.mem.calls[0], MockCall::Erase(PRIMARY_BASE));
+            assert_eq!(secondary.mem.calls[0], MockCall::Erase(SECONDARY_BASE));
+            assert_eq!(primary.mem.memory(), secondary.mem.memory());
+            assert_eq!(primary.mem.memory()[..256], (0..=255).collect::<Vec<u8>>());
+            assert_eq!(primary.mem.memory()[256..], [0xff; 1792]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_mirror_secondary_err() {
+    MkMirrorErr {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1), fails in secondary copy */
+            let vec = dev.download(&mut dfu, 3, &[0xaa; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+
+            let (primary, secondary) = dfu.release().into_inner();
+            assert_eq!(primary.mem.memory()[..64], [0x55; 64]);
+            assert_eq!(secondary.mem.memory()[..64], [0x55; 64]);
+            assert_eq!(primary.mem.memory()[64..128], [0xaa; 64]);
+            assert_eq!(secondary.mem.memory()[64..128], [0xff; 64]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_mirror_erase_all_next() {
+    let mut mem = MirrorMem::new(RamMem::zeroed(PRIMARY_BASE), RamMem::zeroed(PRIMARY_BASE));
+
+    // primary memory is erased first, the secondary one is not touched
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Busy(30)));
+    assert_eq!(mem.primary().mem.memory()[..1024], [0xff; 1024]);
+    assert_eq!(mem.primary().mem.memory()[1024..], [0; 1024]);
+    assert_eq!(mem.secondary().mem.memory(), [0; RAMMEMSIZE]);
+
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Busy(20)));
+    assert_eq!(mem.primary().mem.memory(), [0xff; RAMMEMSIZE]);
+    assert_eq!(mem.secondary().mem.memory(), [0; RAMMEMSIZE]);
+
+    // then the secondary one
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Busy(10)));
+    assert_eq!(mem.secondary().mem.memory()[..1024], [0xff; 1024]);
+    assert_eq!(mem.secondary().mem.memory()[1024..], [0; 1024]);
+
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Done));
+    assert_eq!(mem.secondary().mem.memory(), [0xff; RAMMEMSIZE]);
+
+    // the next mass erase starts with the primary memory
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Busy(30)));
+}
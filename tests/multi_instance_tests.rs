@@ -0,0 +1,269 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::{InterfaceNumber, StringIndex, UsbBusAllocator};
+use usb_device::class::{ControlIn, ControlOut, UsbClass};
+use usb_device::descriptor::DescriptorWriter;
+use usb_device::LangID;
+use usbd_dfu::class::*;
+
+const INSTMEM_BASE: u32 = 0x0800_0000;
+
+/// MCU flash interface number
+const MCU_IF: u16 = 0;
+/// Co-processor interface number
+const COPROC_IF: u16 = 1;
+
+/// Mock memory, `N` selects memory layout string.
+pub struct InstMem<const N: u8> {
+    mem: MockMem,
+}
+
+impl<const N: u8> DFUMemIO for InstMem<N> {
+    const INITIAL_ADDRESS_POINTER: u32 = INSTMEM_BASE;
+    const MEM_INFO_STRING: &'static str = if N == 0 {
+        "@MCU Flash/0x08000000/1*1Kg"
+    } else {
+        "@Co-processor/0x08000000/1*1Kg"
+    };
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+/// Composite device with two DFU interfaces.
+///
+/// Emulated device polls a single class. `usb-device` offers a request
+/// to every class until one of them accepts it, here class requests are
+/// passed to the co-processor interface if `wIndex` is its interface number,
+/// and to the MCU interface otherwise, so the MCU interface must ignore
+/// requests for other interfaces itself.
+pub struct TwoDfu {
+    mcu: DFUClass<EmulatedUsbBus, InstMem<0>>,
+    coproc: DFUClass<EmulatedUsbBus, InstMem<1>>,
+}
+
+impl TwoDfu {
+    fn is_coproc(&self, index: u16) -> bool {
+        index == u8::from(self.coproc.interface_number()) as u16
+    }
+}
+
+impl UsbClass<EmulatedUsbBus> for TwoDfu {
+    fn get_configuration_descriptors(
+        &self,
+        writer: &mut DescriptorWriter,
+    ) -> usb_device::Result<()> {
+        self.mcu.get_configuration_descriptors(writer)?;
+        self.coproc.get_configuration_descriptors(writer)
+    }
+
+    fn get_string(&self, index: StringIndex, lang_id: LangID) -> Option<&str> {
+        self.mcu
+            .get_string(index, lang_id)
+            .or_else(|| self.coproc.get_string(index, lang_id))
+    }
+
+    fn get_alt_setting(&mut self, interface: InterfaceNumber) -> Option<u8> {
+        self.mcu
+            .get_alt_setting(interface)
+            .or_else(|| self.coproc.get_alt_setting(interface))
+    }
+
+    fn set_alt_setting(&mut self, interface: InterfaceNumber, alternative: u8) -> bool {
+        self.mcu.set_alt_setting(interface, alternative)
+            || self.coproc.set_alt_setting(interface, alternative)
+    }
+
+    fn reset(&mut self) {
+        self.mcu.reset();
+        self.coproc.reset();
+    }
+
+    fn poll(&mut self) {
+        self.mcu.poll();
+        self.coproc.poll();
+    }
+
+    fn control_in(&mut self, xfer: ControlIn<EmulatedUsbBus>) {
+        if self.is_coproc(xfer.request().index) {
+            self.coproc.control_in(xfer)
+        } else {
+            self.mcu.control_in(xfer)
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<EmulatedUsbBus>) {
+        if self.is_coproc(xfer.request().index) {
+            self.coproc.control_out(xfer)
+        } else {
+            self.mcu.control_out(xfer)
+        }
+    }
+}
+
+struct MkTwoDfu {}
+
+impl UsbDeviceCtx for MkTwoDfu {
+    type C<'c> = TwoDfu;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(&mut self, alloc: &UsbBusAllocator<EmulatedUsbBus>) -> AnyResult<TwoDfu> {
+        let mcu = InstMem {
+            mem: MockMem::new(INSTMEM_BASE, 1, 1024),
+        };
+        let coproc = InstMem {
+            mem: MockMem::new(INSTMEM_BASE, 1, 1024),
+        };
+        Ok(TwoDfu {
+            mcu: DFUClass::new(alloc, mcu),
+            coproc: DFUClass::new(alloc, coproc),
+        })
+    }
+}
+
+#[test]
+fn test_instances_descriptors() {
+    MkTwoDfu {}
+        .with_usb(|mut cls, mut dev| {
+            assert_eq!(u8::from(cls.mcu.interface_number()) as u16, MCU_IF);
+            assert_eq!(u8::from(cls.coproc.interface_number()) as u16, COPROC_IF);
+
+            let desc = dev
+                .device_get_descriptor(&mut cls, 2, 0, 0, 255)
+                .expect("desc");
+
+            // configuration, then interface and DFU functional descriptors
+            // of each instance
+            assert_eq!(desc.len(), 9 + 2 * (9 + 9));
+            assert_eq!(desc[4], 2);
+            assert_eq!(desc[9..17], [9, 4, MCU_IF as u8, 0, 0, 0xfe, 0x01, 0x02]);
+            assert_eq!(desc[18..20], [9, 0x21]);
+            assert_eq!(
+                desc[27..35],
+                [9, 4, COPROC_IF as u8, 0, 0, 0xfe, 0x01, 0x02]
+            );
+            assert_eq!(desc[36..38], [9, 0x21]);
+
+            // each interface has its own string
+            let mcu_str = desc[17];
+            let coproc_str = desc[35];
+            assert_ne!(mcu_str, coproc_str);
+
+            let s = dev
+                .device_get_string(&mut cls, mcu_str, 0x409)
+                .expect("string");
+            assert_eq!(s, "@MCU Flash/0x08000000/1*1Kg");
+
+            let s = dev
+                .device_get_string(&mut cls, coproc_str, 0x409)
+                .expect("string");
+            assert_eq!(s, "@Co-processor/0x08000000/1*1Kg");
+
+            // neither instance answers for other strings
+            let e = dev
+                .device_get_string(&mut cls, coproc_str + 1, 0x409)
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_instances_independent() {
+    MkTwoDfu {}
+        .with_usb(|mut cls, mut dev| {
+            /* Download block 2 (offset 0) to the co-processor */
+            let vec = dev
+                .write(&mut cls, 0x1, 2, COPROC_IF, 32, &[0x55; 32])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, COPROC_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, COPROC_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Get Status of the MCU interface, not disturbed */
+            let vec = dev.read(&mut cls, 0x3, 0, MCU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 2 (offset 0) from the MCU */
+            let vec = dev.read(&mut cls, 0x2, 2, MCU_IF, 32).expect("vec");
+            assert_eq!(vec, [0xff; 32]);
+
+            /* Abort the MCU upload */
+            let vec = dev.write(&mut cls, 0x6, 0, MCU_IF, 0, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get State of the co-processor, download session is intact */
+            let vec = dev.read(&mut cls, 0x5, 0, COPROC_IF, 1).expect("vec");
+            assert_eq!(vec, [DFUState::DfuDnloadIdle as u8]);
+
+            /* Get Status for a missing interface, not handled */
+            let e = dev.read(&mut cls, 0x3, 0, 2, 6).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Download block 3 (offset 1) len 0, trigger co-processor manifestation */
+            let vec = dev.write(&mut cls, 0x1, 3, COPROC_IF, 0, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, COPROC_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.read(&mut cls, 0x3, 0, COPROC_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Get Status of the MCU interface */
+            let vec = dev.read(&mut cls, 0x3, 0, MCU_IF, 6).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            assert!(!cls.mcu.download_in_progress());
+
+            let TwoDfu { mcu, coproc } = cls;
+            let mcu = mcu.release();
+            let coproc = coproc.release();
+            assert_eq!(mcu.mem.calls, [MockCall::Read(INSTMEM_BASE, 32)]);
+            assert_eq!(mcu.mem.memory()[..32], [0xff; 32]);
+            assert_eq!(
+                coproc.mem.calls,
+                [MockCall::Program(INSTMEM_BASE, 32), MockCall::Manifestation]
+            );
+            assert_eq!(coproc.mem.memory()[..32], [0x55; 32]);
+        })
+        .expect("with_usb");
+}
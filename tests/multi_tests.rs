@@ -0,0 +1,386 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::meminfo::MemInfoStringError;
+use usbd_dfu::multi::MultiRegion;
+
+const FLASH_BASE: u32 = 0x0800_0000;
+const EEPROM_BASE: u32 = 0x0800_0080;
+
+/// Memory with a layout and a base address set at runtime.
+pub struct RegionMem {
+    mem: MockMem,
+    layout: &'static str,
+    /// `manifestation_poll()` calls that report `Busy` after `manifestation_start()`
+    manifest_polls: u32,
+    remaining: u32,
+    /// `manifestation_poll()` reported `Done`
+    manifested: bool,
+}
+
+impl RegionMem {
+    fn new(base: u32, pages: u32, layout: &'static str, fill: u8) -> Self {
+        let mut mem = MockMem::new(base, pages, 32);
+        mem.memory_mut().fill(fill);
+        Self {
+            mem,
+            layout,
+            manifest_polls: 0,
+            remaining: 0,
+            manifested: false,
+        }
+    }
+}
+
+impl DFUMemIO for RegionMem {
+    const INITIAL_ADDRESS_POINTER: u32 = FLASH_BASE;
+    const MEM_INFO_STRING: &'static str = "@Region/0x00000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn manifestation_start(&mut self) -> Result<(), DFUManifestationError> {
+        self.remaining = self.manifest_polls;
+        self.mem.manifestation()
+    }
+
+    fn manifestation_poll(&mut self) -> ManifestationProgress {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            return ManifestationProgress::Busy(100);
+        }
+        self.manifested = true;
+        ManifestationProgress::Done
+    }
+
+    fn mem_info_string(&self) -> &str {
+        self.layout
+    }
+}
+
+type Multi = MultiRegion<RegionMem, RegionMem, 64>;
+
+struct MkMulti {}
+
+impl UsbDeviceCtx for MkMulti {
+    type C<'c> = DFUClass<EmulatedUsbBus, Multi>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, Multi>> {
+        let flash = RegionMem::new(FLASH_BASE, 4, "@Flash/0x08000000/4*32 g", 0x11);
+        let eeprom = RegionMem::new(EEPROM_BASE, 2, "@EEPROM/0x08000080/2*32 g", 0x22);
+        Ok(DFUClass::new(alloc, Multi::new(flash, eeprom).unwrap()))
+    }
+}
+
+struct MkMultiPoll {}
+
+impl UsbDeviceCtx for MkMultiPoll {
+    type C<'c> = DFUClass<EmulatedUsbBus, Multi>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, Multi>> {
+        let mut flash = RegionMem::new(FLASH_BASE, 4, "@Flash/0x08000000/4*32 g", 0x11);
+        let mut eeprom = RegionMem::new(EEPROM_BASE, 2, "@EEPROM/0x08000080/2*32 g", 0x22);
+        flash.manifest_polls = 2;
+        eeprom.manifest_polls = 1;
+        Ok(DFUClass::new(alloc, Multi::new(flash, eeprom).unwrap()))
+    }
+}
+
+/// Set Address Pointer and wait for the command to complete
+fn set_address(
+    dfu: &mut DFUClass<EmulatedUsbBus, Multi>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, Multi>, MkMulti>,
+    address: u32,
+) {
+    let a = address.to_le_bytes();
+    let vec = dev
+        .download(dfu, 0, &[0x21, a[0], a[1], a[2], a[3]])
+        .expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+}
+
+#[test]
+fn test_multi_layout() {
+    let flash = RegionMem::new(FLASH_BASE, 4, "@Flash/0x08000000/4*32 g", 0);
+    let eeprom = RegionMem::new(EEPROM_BASE, 2, "@EEPROM/0x08000080/2*32 g", 0);
+
+    // doesn't fit
+    assert!(MultiRegion::<_, _, 32>::new(flash, eeprom).is_err());
+
+    // malformed layout
+    let flash = RegionMem::new(FLASH_BASE, 4, "@Flash/0x08000000/4*32 g", 0);
+    let eeprom = RegionMem::new(EEPROM_BASE, 2, "@EEPROM/0x08000080/2*32x", 0);
+    assert_eq!(
+        MultiRegion::<_, _, 64>::new(flash, eeprom).err(),
+        Some(MemInfoStringError::Syntax)
+    );
+
+    MkMulti {}
+        .with_usb(|mut dfu, mut dev| {
+            let istr = dev.device_get_string(&mut dfu, 4, 0x409).expect("str");
+            assert_eq!(istr, "@Flash/0x08000000/4*32 g/0x08000080/2*32 g");
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_multi_download_boundary() {
+    MkMulti {}
+        .with_usb(|mut dfu, mut dev| {
+            set_address(&mut dfu, &mut dev, EEPROM_BASE - 32);
+
+            /* Download block 2 (offset 0), the last flash block */
+            let vec = dev.download(&mut dfu, 2, &[0x01; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1), the first EEPROM block */
+            let vec = dev.download(&mut dfu, 3, &[0x02; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 0 (command), erase an EEPROM page */
+            let a = (EEPROM_BASE + 32).to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, a[0], a[1], a[2], a[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+            let (flash, eeprom) = dfu.release().into_inner();
+            assert_eq!(flash.mem.calls, [MockCall::Program(EEPROM_BASE - 32, 32)]);
+            assert_eq!(
+                eeprom.mem.calls,
+                [
+                    MockCall::Program(EEPROM_BASE, 32),
+                    MockCall::Erase(EEPROM_BASE + 32)
+                ]
+            );
+            assert_eq!(flash.mem.memory()[96..], [0x01; 32]);
+            assert_eq!(eeprom.mem.memory()[..32], [0x02; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_multi_upload_boundary() {
+    MkMulti {}
+        .with_usb(|mut dfu, mut dev| {
+            set_address(&mut dfu, &mut dev, EEPROM_BASE - 32);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0), the last flash block */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [0x11; 32]);
+
+            /* Upload block 3 (offset 1), the first EEPROM block */
+            let vec = dev.upload(&mut dfu, 3, 32).expect("vec");
+            assert_eq!(vec, [0x22; 32]);
+
+            let (flash, eeprom) = dfu.release().into_inner();
+            assert_eq!(flash.mem.calls, [MockCall::Read(EEPROM_BASE - 32, 32)]);
+            assert_eq!(eeprom.mem.calls, [MockCall::Read(EEPROM_BASE, 32)]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_multi_outside() {
+    MkMulti {}
+        .with_usb(|mut dfu, mut dev| {
+            set_address(&mut dfu, &mut dev, EEPROM_BASE + 64);
+
+            /* Download block 2 (offset 0), past the EEPROM */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0), past the EEPROM */
+            let e = dev.upload(&mut dfu, 2, 32).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            let (flash, eeprom) = dfu.release().into_inner();
+            assert_eq!(flash.mem.calls, []);
+            assert_eq!(eeprom.mem.calls, []);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_multi_erase_all() {
+    MkMulti {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), erase all */
+            let vec = dev.download(&mut dfu, 0, &[0x41]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 60, DFUState::DfuDnBusy));
+
+            /* Get Status, flash is erased, EEPROM is next */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 30, DFUState::DfuDnBusy));
+
+            /* Get Status, both memories are erased */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let (flash, eeprom) = dfu.release().into_inner();
+            assert_eq!(flash.mem.calls, [MockCall::EraseAll]);
+            assert_eq!(eeprom.mem.calls, [MockCall::EraseAll]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_multi_manifestation_poll() {
+    MkMultiPoll {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x01; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download 0 length */
+            let vec = dev.download(&mut dfu, 0, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status, manifestation starts after this request */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 2, DFUState::DfuManifest));
+
+            /* Get Status, A is done, B reports its remaining time */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 100, DFUState::DfuManifest));
+
+            /* Get Status, manifestation is complete */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let (flash, eeprom) = dfu.release().into_inner();
+            assert_eq!(
+                flash.mem.calls,
+                [MockCall::Program(FLASH_BASE, 32), MockCall::Manifestation]
+            );
+            assert_eq!(eeprom.mem.calls, [MockCall::Manifestation]);
+            assert!(flash.manifested);
+            assert!(eeprom.manifested);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_multi_erase_all_next() {
+    let flash = RegionMem::new(FLASH_BASE, 4, "@Flash/0x08000000/4*32 g", 0x11);
+    let eeprom = RegionMem::new(EEPROM_BASE, 2, "@EEPROM/0x08000080/2*32 g", 0x22);
+    let mut mem = Multi::new(flash, eeprom).unwrap();
+
+    // A is erased first, B is not touched
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Busy(30)));
+    assert_eq!(mem.a().mem.calls, [MockCall::EraseAll]);
+    assert_eq!(mem.b().mem.calls, []);
+
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Done));
+    assert_eq!(mem.a().mem.calls, [MockCall::EraseAll]);
+    assert_eq!(mem.b().mem.calls, [MockCall::EraseAll]);
+
+    // the next mass erase starts with A
+    assert_eq!(mem.erase_all_next(), Ok(EraseProgress::Busy(30)));
+    assert_eq!(mem.a().mem.calls, [MockCall::EraseAll, MockCall::EraseAll]);
+}
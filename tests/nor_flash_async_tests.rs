@@ -0,0 +1,244 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::future::{poll_fn, Future};
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll, Waker};
+
+use usbd_class_tester::prelude::*;
+
+use embedded_storage_async::nor_flash::{ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash};
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::nor_flash_async::*;
+
+const FLASH_SIZE: usize = 1024;
+const FLASH_BASE: u32 = 0x0800_0000;
+
+/// Async NOR flash, every operation takes `delay` polls to complete.
+pub struct MockAsyncFlash {
+    memory: Vec<u8>,
+    events: Vec<String>,
+    delay: u32,
+    fail: Option<NorFlashErrorKind>,
+}
+
+impl MockAsyncFlash {
+    fn new() -> Self {
+        Self {
+            memory: vec![0xff; FLASH_SIZE],
+            events: Vec::new(),
+            delay: 0,
+            fail: None,
+        }
+    }
+
+    async fn busy(&mut self) -> Result<(), NorFlashErrorKind> {
+        let mut left = self.delay;
+        poll_fn(|cx| {
+            if left == 0 {
+                return Poll::Ready(());
+            }
+            left -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+        match self.fail {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+}
+
+impl ErrorType for MockAsyncFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MockAsyncFlash {
+    const READ_SIZE: usize = 1;
+
+    async fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), NorFlashErrorKind> {
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.memory[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_SIZE
+    }
+}
+
+impl NorFlash for MockAsyncFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 64;
+
+    async fn erase(&mut self, from: u32, to: u32) -> Result<(), NorFlashErrorKind> {
+        self.events.push(format!("erase {} {}", from, to));
+        self.busy().await?;
+        self.memory[from as usize..to as usize].fill(0xff);
+        Ok(())
+    }
+
+    async fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), NorFlashErrorKind> {
+        self.events
+            .push(format!("write {} {}", offset, bytes.len()));
+        self.busy().await?;
+        let offset = offset as usize;
+        self.memory[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+type AsyncMem = AsyncNorFlashMemIO<'static, MockAsyncFlash, FLASH_BASE>;
+
+thread_local! {
+    /// Queue shared by the class and the test
+    static QUEUE: &'static NorFlashQueue = Box::leak(Box::new(NorFlashQueue::new()));
+}
+
+fn queue() -> &'static NorFlashQueue {
+    QUEUE.with(|q| *q)
+}
+
+struct MkAsync {}
+
+impl UsbDeviceCtx for MkAsync {
+    type C<'c> = DFUClass<EmulatedUsbBus, AsyncMem>;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, AsyncMem>> {
+        let mem = AsyncMem::new(queue(), &MockAsyncFlash::new());
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Poll `fut` once, it never completes
+fn step(fut: Pin<&mut impl Future<Output = ()>>) {
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(fut.poll(&mut cx).is_pending());
+}
+
+#[test]
+fn test_async_layout() {
+    let mem = AsyncMem::new(queue(), &MockAsyncFlash::new());
+    assert_eq!(mem.mem_info_string(), "@Flash/0x08000000/16*64 g");
+    assert!(matches!(
+        mem.offset(FLASH_BASE + FLASH_SIZE as u32, 1),
+        Err(DFUMemError::Address)
+    ));
+}
+
+#[test]
+fn test_async_erase() {
+    MkAsync {}
+        .with_usb(|mut dfu, mut dev| {
+            let mut flash = MockAsyncFlash::new();
+            flash.delay = 2;
+            flash.memory[64..128].fill(0);
+
+            {
+                let mut run = pin!(async {
+                    queue().run(&mut flash).await;
+                });
+                step(run.as_mut());
+
+                /* Download block 0 (command), erase page 0x08000040 */
+                let vec = dev
+                    .download(&mut dfu, 0, &[0x41, 0x40, 0x00, 0x00, 0x08])
+                    .expect("vec");
+                assert_eq!(vec, []);
+
+                // erase completes after three rounds
+                for _ in 0..3 {
+                    /* Get Status */
+                    let vec = dev.get_status(&mut dfu).expect("vec");
+                    assert_eq!(vec, status(DFUStatusCode::OK, 50, DFUState::DfuDnBusy));
+                    assert_eq!(
+                        queue().pending(),
+                        Some(Operation::Erase { from: 64, to: 128 })
+                    );
+
+                    step(run.as_mut());
+                }
+                assert_eq!(queue().pending(), None);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            assert_eq!(flash.events, ["erase 64 128"]);
+            assert_eq!(flash.memory[64..128], [0xff; 64]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_async_program() {
+    MkAsync {}
+        .with_usb(|mut dfu, mut dev| {
+            let mut flash = MockAsyncFlash::new();
+
+            {
+                let mut run = pin!(async {
+                    queue().run(&mut flash).await;
+                });
+
+                /* Download block 2 (offset 0) */
+                let vec = dev.download(&mut dfu, 2, &[0x55; 10]).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+                step(run.as_mut());
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+                /* Upload block 2 (offset 0), not supported */
+                let e = dev.upload(&mut dfu, 2, 16).expect_err("stall");
+                assert_eq!(e, AnyUsbError::EP0Stalled);
+            }
+
+            assert_eq!(flash.events, ["write 0 12"]);
+            assert_eq!(flash.memory[..10], [0x55; 10]);
+            assert_eq!(flash.memory[10..12], [0xff; 2]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_async_error() {
+    MkAsync {}
+        .with_usb(|mut dfu, mut dev| {
+            let mut flash = MockAsyncFlash::new();
+            flash.fail = Some(NorFlashErrorKind::Other);
+
+            let mut run = pin!(async {
+                queue().run(&mut flash).await;
+            });
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 16]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            step(run.as_mut());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,204 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use embedded_storage::nor_flash::{
+    check_erase, check_read, check_write, ErrorType, NorFlash, NorFlashErrorKind, ReadNorFlash,
+};
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::nor_flash::*;
+
+const FLASH_SIZE: usize = 1024;
+const FLASH_BASE: u32 = 0x0800_0000;
+
+/// NOR flash that checks alignment and records operations.
+pub struct MockNorFlash {
+    memory: Vec<u8>,
+    events: Vec<String>,
+    fail: Option<NorFlashErrorKind>,
+}
+
+impl MockNorFlash {
+    fn new() -> Self {
+        Self {
+            memory: vec![0xff; FLASH_SIZE],
+            events: Vec::new(),
+            fail: None,
+        }
+    }
+}
+
+impl ErrorType for MockNorFlash {
+    type Error = NorFlashErrorKind;
+}
+
+impl ReadNorFlash for MockNorFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), NorFlashErrorKind> {
+        check_read(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        bytes.copy_from_slice(&self.memory[offset..offset + bytes.len()]);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        FLASH_SIZE
+    }
+}
+
+impl NorFlash for MockNorFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 64;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), NorFlashErrorKind> {
+        self.events.push(format!("erase {} {}", from, to));
+        if let Some(e) = self.fail {
+            return Err(e);
+        }
+        check_erase(self, from, to)?;
+        self.memory[from as usize..to as usize].fill(0xff);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), NorFlashErrorKind> {
+        self.events
+            .push(format!("write {} {}", offset, bytes.len()));
+        if let Some(e) = self.fail {
+            return Err(e);
+        }
+        check_write(self, offset, bytes.len())?;
+        let offset = offset as usize;
+        // programming only clears bits
+        for (m, b) in self.memory[offset..offset + bytes.len()]
+            .iter_mut()
+            .zip(bytes)
+        {
+            *m &= b;
+        }
+        Ok(())
+    }
+}
+
+type NorMem = NorFlashMemIO<MockNorFlash, FLASH_BASE>;
+
+struct MkNor {}
+
+impl UsbDeviceCtx for MkNor {
+    type C<'c> = DFUClass<EmulatedUsbBus, NorMem>;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, NorMem>> {
+        Ok(DFUClass::new(alloc, NorMem::new(MockNorFlash::new())))
+    }
+}
+
+#[test]
+fn test_nor_flash_layout() {
+    let mem = NorMem::new(MockNorFlash::new());
+    assert_eq!(mem.mem_info_string(), "@Flash/0x08000000/16*64 g");
+
+    assert!(matches!(mem.offset(FLASH_BASE + 16, 8), Ok(16)));
+    assert!(matches!(mem.offset(FLASH_BASE + 1016, 8), Ok(1016)));
+    assert!(matches!(
+        mem.offset(FLASH_BASE + 1016, 9),
+        Err(DFUMemError::Address)
+    ));
+    assert!(matches!(
+        mem.offset(FLASH_BASE - 1, 1),
+        Err(DFUMemError::Address)
+    ));
+}
+
+#[test]
+fn test_nor_flash_program_padding() {
+    let mut mem = NorMem::new(MockNorFlash::new());
+
+    // aligned part is written from the block, the tail is padded
+    mem.program_block(FLASH_BASE + 4, &[1, 2, 3, 4, 5, 6])
+        .expect("program_block");
+
+    mem.store_write_buffer(&[7, 8, 9]).expect("store");
+    mem.program(FLASH_BASE + 12, 3).expect("program");
+
+    let flash = mem.into_inner();
+    assert_eq!(flash.events, ["write 4 4", "write 8 4", "write 12 4"]);
+    assert_eq!(
+        flash.memory[..16],
+        [0xff, 0xff, 0xff, 0xff, 1, 2, 3, 4, 5, 6, 0xff, 0xff, 7, 8, 9, 0xff]
+    );
+}
+
+#[test]
+fn test_nor_flash_erase() {
+    let mut mem = NorMem::new(MockNorFlash::new());
+
+    mem.erase(FLASH_BASE + 70).expect("erase");
+    mem.erase_all().expect("erase_all");
+    assert!(matches!(
+        mem.erase(FLASH_BASE + FLASH_SIZE as u32),
+        Err(DFUMemError::Address)
+    ));
+
+    assert_eq!(mem.flash().events, ["erase 64 128", "erase 0 1024"]);
+}
+
+#[test]
+fn test_nor_flash_errors() {
+    let mut mem = NorMem::new(MockNorFlash::new());
+
+    mem.flash_mut().fail = Some(NorFlashErrorKind::Other);
+    assert!(matches!(mem.erase(FLASH_BASE), Err(DFUMemError::Erase)));
+    assert!(matches!(
+        mem.program_block(FLASH_BASE, &[0; 8]),
+        Err(DFUMemError::Prog)
+    ));
+
+    mem.flash_mut().fail = Some(NorFlashErrorKind::NotAligned);
+    assert!(matches!(
+        mem.program_block(FLASH_BASE, &[0; 8]),
+        Err(DFUMemError::Write)
+    ));
+
+    mem.flash_mut().fail = Some(NorFlashErrorKind::OutOfBounds);
+    assert!(matches!(mem.erase_all(), Err(DFUMemError::Address)));
+}
+
+#[test]
+fn test_nor_flash_download_upload() {
+    MkNor {}
+        .with_usb(|mut dfu, mut dev| {
+            let data: Vec<u8> = (0..10).collect();
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &data).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 16).expect("vec");
+            assert_eq!(vec[..10], data);
+            assert_eq!(vec[10..], [0xff; 6]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.flash().events, ["write 0 12"]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,274 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const RAMMEM_BASE: u32 = 0x0800_0000;
+
+pub struct RamMem {
+    mem: MockMem,
+}
+
+impl RamMem {
+    /// The first two 64-byte blocks are erased, other bytes are their offsets
+    fn new() -> Self {
+        let mut mem = MockMem::new(RAMMEM_BASE, 1, 1024);
+        for (i, b) in mem.memory_mut().iter_mut().enumerate().skip(128) {
+            *b = i as u8;
+        }
+        Self { mem }
+    }
+}
+
+impl DFUMemIO for RamMem {
+    const INITIAL_ADDRESS_POINTER: u32 = RAMMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const MANIFESTATION_TOLERANT: bool = false;
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const DETACH_TIMEOUT: u16 = 0x1122;
+    const TRANSFER_SIZE: u16 = 128;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+const OPTIONS: DfuOptions = DfuOptions {
+    has_download: true,
+    has_upload: false,
+    will_detach: true,
+    manifestation_tolerant: true,
+    detach_timeout: 100,
+    transfer_size: 64,
+};
+
+struct MkOptions {
+    options: Option<DfuOptions>,
+}
+
+impl UsbDeviceCtx for MkOptions {
+    type C<'c> = DFUClass<EmulatedUsbBus, RamMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RamMem>> {
+        Ok(match self.options {
+            Some(options) => DFUClass::new_with_options(alloc, RamMem::new(), options),
+            None => DFUClass::new(alloc, RamMem::new()),
+        })
+    }
+}
+
+#[test]
+fn test_options_defaults() {
+    assert_eq!(
+        DfuOptions::new::<RamMem>(),
+        DfuOptions {
+            has_download: true,
+            has_upload: true,
+            will_detach: true,
+            manifestation_tolerant: false,
+            detach_timeout: 0x1122,
+            transfer_size: 128,
+        }
+    );
+}
+
+#[test]
+fn test_options_descriptor() {
+    MkOptions { options: None }
+        .with_usb(|mut dfu, mut dev| {
+            let vec = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 130)
+                .expect("vec");
+            assert_eq!(vec[18..], [9, 0x21, 0b1011, 0x22, 0x11, 128, 0, 0x1a, 1]);
+        })
+        .expect("with_usb");
+
+    MkOptions {
+        options: Some(OPTIONS),
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = dev
+            .device_get_descriptor(&mut dfu, 2, 0, 0, 130)
+            .expect("vec");
+        assert_eq!(vec[18..], [9, 0x21, 0b1101, 100, 0, 64, 0, 0x1a, 1]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_options_transfer_size() {
+    MkOptions {
+        options: Some(DfuOptions {
+            transfer_size: 64,
+            ..DfuOptions::new::<RamMem>()
+        }),
+    }
+    .with_usb(|mut dfu, mut dev| {
+        /* Upload block 3 (offset 64), longer request is truncated */
+        let vec = dev.upload(&mut dfu, 3, 128).expect("vec");
+        assert_eq!(vec, [0xff; 64]);
+
+        /* Get Status, full block, upload continues */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+
+        /* Abort */
+        let vec = dev.abort(&mut dfu).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Download block 3 (offset 64) */
+        let vec = dev.download(&mut dfu, 3, &[0x55; 64]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        /* Abort */
+        let vec = dev.abort(&mut dfu).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Upload block 2 (offset 0), not changed */
+        let vec = dev.upload(&mut dfu, 2, 64).expect("vec");
+        assert_eq!(vec, [0xff; 64]);
+
+        /* Upload block 3 (offset 64) */
+        let vec = dev.upload(&mut dfu, 3, 64).expect("vec");
+        assert_eq!(vec, [0x55; 64]);
+
+        /* Upload block 4 (offset 128), not changed */
+        let vec = dev.upload(&mut dfu, 4, 64).expect("vec");
+        assert_eq!(vec[0..4], [128, 129, 130, 131]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_options_manifestation_tolerant() {
+    MkOptions {
+        options: Some(OPTIONS),
+    }
+    .with_usb(|mut dfu, mut dev| {
+        /* Download block 2 (offset 0) */
+        let vec = dev.download(&mut dfu, 2, &[0x55; 64]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        /* Download 0 length */
+        let vec = dev.download(&mut dfu, 0, &[]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+
+        /* Get Status, manifestation tolerant, not dfuMANIFEST-WAIT-RESET */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+    })
+    .expect("with_usb");
+}
+
+#[test]
+#[should_panic(expected = "transfer_size")]
+fn test_options_transfer_size_too_large() {
+    MkOptions {
+        options: Some(DfuOptions {
+            transfer_size: 256,
+            ..OPTIONS
+        }),
+    }
+    .with_usb(|dfu, dev| {})
+    .ok();
+}
+
+#[test]
+fn test_options_download_too_large() {
+    MkOptions {
+        options: Some(DfuOptions {
+            transfer_size: 64,
+            ..DfuOptions::new::<RamMem>()
+        }),
+    }
+    .with_usb(|mut dfu, mut dev| {
+        /* Download block 2 (offset 0), longer than wTransferSize */
+        let vec = dev.download(&mut dfu, 2, &[0x55; 128]);
+        assert!(vec.is_err());
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(
+            vec,
+            status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+        );
+
+        let mem = dfu.release();
+        assert_eq!(mem.mem.calls, []);
+        assert_eq!(mem.mem.memory()[..64], [0xff; 64]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+#[cfg(feature = "control-buffer-256")]
+fn test_options_download_larger_than_buffer() {
+    MkOptions { options: None }
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0), twice TRANSFER_SIZE */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 256]);
+            assert!(vec.is_err());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, []);
+        })
+        .expect("with_usb");
+}
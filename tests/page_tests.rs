@@ -0,0 +1,155 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const PAGEMEMSIZE: usize = 1024;
+const PAGEMEM_BASE: u32 = 0x0800_0000;
+const PAGEMEM_PAGE: u32 = 256;
+
+/// Memory with 256 byte pages, all bits are programmed to 0 initially.
+pub struct PageMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for PageMem {
+    const INITIAL_ADDRESS_POINTER: u32 = PAGEMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/4*256 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkPage {}
+
+impl UsbDeviceCtx for MkPage {
+    type C<'c> = DFUClass<EmulatedUsbBus, PageMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, PageMem>> {
+        let mut mem = PageMem {
+            mem: MockMem::new(PAGEMEM_BASE, 4, PAGEMEM_PAGE),
+        };
+        mem.mem.memory_mut().fill(0);
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Send Erase Page command, returns the status after it is executed
+fn erase_page<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, address: u32) -> Vec<u8> {
+    let b = address.to_le_bytes();
+    let vec = dev
+        .download(dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+        .expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    dev.get_status(dfu).expect("vec")
+}
+
+#[test]
+fn test_page_erase_aligned() {
+    MkPage {}
+        .with_usb(|mut dfu, mut dev| {
+            let vec = erase_page(&mut dfu, &mut dev, PAGEMEM_BASE + 0x100);
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let vec = erase_page(&mut dfu, &mut dev, PAGEMEM_BASE + 0x300);
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Erase(PAGEMEM_BASE + 0x100),
+                    MockCall::Erase(PAGEMEM_BASE + 0x300)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[0xff..0x101], [0, 0xff]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_page_erase_unaligned() {
+    MkPage {}
+        .with_usb(|mut dfu, mut dev| {
+            let vec = erase_page(&mut dfu, &mut dev, PAGEMEM_BASE + 0x180);
+            assert_eq!(vec, status(DFUStatusCode::ErrErase, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, []);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_page_erase_out_of_range() {
+    MkPage {}
+        .with_usb(|mut dfu, mut dev| {
+            let vec = erase_page(&mut dfu, &mut dev, PAGEMEM_BASE + PAGEMEMSIZE as u32);
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            let vec = erase_page(&mut dfu, &mut dev, PAGEMEM_BASE - PAGEMEM_PAGE);
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, []);
+        })
+        .expect("with_usb");
+}
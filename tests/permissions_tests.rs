@@ -0,0 +1,262 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const PERMMEM_BASE: u32 = 0x0800_0000;
+/// Readable only
+const READ_ONLY: u32 = PERMMEM_BASE;
+/// Erasable only
+const ERASE_ONLY: u32 = PERMMEM_BASE + 0x100;
+/// Readable, erasable, and writable
+const FULL: u32 = PERMMEM_BASE + 0x200;
+
+/// Mock memory, `C` is `CHECK_PERMISSIONS` value. If `MULTI` is set,
+/// the same areas are described by two layout regions.
+pub struct PermMem<const C: bool, const MULTI: bool = false> {
+    mem: MockMem,
+}
+
+impl<const C: bool, const MULTI: bool> DFUMemIO for PermMem<C, MULTI> {
+    const INITIAL_ADDRESS_POINTER: u32 = PERMMEM_BASE;
+    const MEM_INFO_STRING: &'static str = if MULTI {
+        "@Boot/0x08000000/1*256 a@Flash/0x08000100/1*256 b,2*256 g"
+    } else {
+        "@Flash/0x08000000/1*256 a,1*256 b,2*256 g"
+    };
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 128;
+    const CHECK_PERMISSIONS: bool = C;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkPerm<const C: bool, const MULTI: bool = false> {}
+
+impl<const C: bool, const MULTI: bool> UsbDeviceCtx for MkPerm<C, MULTI> {
+    type C<'c> = DFUClass<EmulatedUsbBus, PermMem<C, MULTI>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, PermMem<C, MULTI>>> {
+        let mut mem = PermMem {
+            mem: MockMem::new(PERMMEM_BASE, 4, 256),
+        };
+        mem.mem.memory_mut().fill(0x11);
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Send a DfuSe command with an address, expect the device to return to dfuDNLOAD-IDLE
+/// or fail with `err`
+fn command<C>(
+    dfu: &mut C,
+    dev: &mut impl DeviceExt<C>,
+    cmd: u8,
+    address: u32,
+    err: Option<DFUStatusCode>,
+) {
+    let b = address.to_le_bytes();
+
+    /* Download block 0 (command) */
+    let vec = dev
+        .download(dfu, 0, &[cmd, b[0], b[1], b[2], b[3]])
+        .expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    match err {
+        None => assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle)),
+        Some(e) => {
+            assert_eq!(vec, status(e, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(dfu).expect("vec");
+            assert_eq!(vec, []);
+        }
+    }
+}
+
+/// Download block 2 at `address`, expect the device to return to dfuDNLOAD-IDLE
+/// or fail with `err`
+fn download<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, address: u32, err: Option<DFUStatusCode>) {
+    /* Set Address Pointer */
+    command(dfu, dev, 0x21, address, None);
+
+    /* Download block 2 (offset 0) */
+    let vec = dev.download(dfu, 2, &[0x55; 128]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    match err {
+        None => assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle)),
+        Some(e) => {
+            assert_eq!(vec, status(e, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(dfu).expect("vec");
+            assert_eq!(vec, []);
+        }
+    }
+}
+
+#[test]
+fn test_perms_upload() {
+    MkPerm::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            // the block ends before the area that is not readable
+            dfu.set_address_pointer(ERASE_ONLY - 64);
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec, [0x11; 64]);
+
+            /* Get Status, dfuIdle after short frame */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            dfu.set_address_pointer(ERASE_ONLY);
+
+            /* Upload block 2 (offset 0), not readable */
+            let e = dev.upload(&mut dfu, 2, 128).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::Read(ERASE_ONLY - 64, 64)]);
+        })
+        .expect("with_usb");
+}
+
+/// Program and erase areas with different permissions
+fn program_erase<const MULTI: bool>() {
+    MkPerm::<true, MULTI> {}
+        .with_usb(|mut dfu, mut dev| {
+            command(
+                &mut dfu,
+                &mut dev,
+                0x41,
+                READ_ONLY,
+                Some(DFUStatusCode::ErrErase),
+            );
+            command(&mut dfu, &mut dev, 0x41, ERASE_ONLY, None);
+            command(&mut dfu, &mut dev, 0x41, FULL, None);
+
+            download(&mut dfu, &mut dev, READ_ONLY, Some(DFUStatusCode::ErrWrite));
+            download(
+                &mut dfu,
+                &mut dev,
+                ERASE_ONLY,
+                Some(DFUStatusCode::ErrWrite),
+            );
+
+            // partially overlaps an area that is not writable
+            download(&mut dfu, &mut dev, FULL - 1, Some(DFUStatusCode::ErrWrite));
+
+            download(&mut dfu, &mut dev, FULL, None);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Erase(ERASE_ONLY),
+                    MockCall::Erase(FULL),
+                    MockCall::Program(FULL, 128)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[..0x100], [0x11; 0x100]);
+            assert_eq!(mem.mem.memory()[0x100..0x200], [0xff; 0x100]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_perms_program_erase() {
+    program_erase::<false>();
+}
+
+#[test]
+fn test_perms_multi_region() {
+    program_erase::<true>();
+}
+
+#[test]
+fn test_perms_disabled() {
+    MkPerm::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            command(&mut dfu, &mut dev, 0x41, READ_ONLY, None);
+            download(&mut dfu, &mut dev, READ_ONLY, None);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            dfu.set_address_pointer(ERASE_ONLY);
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec, [0x11; 128]);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Erase(READ_ONLY),
+                    MockCall::Program(READ_ONLY, 128),
+                    MockCall::Read(ERASE_ONLY, 128)
+                ]
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,132 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::Cell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const SLOWMEM_BASE: u32 = 0x0800_0000;
+
+thread_local! {
+    /// Fake cycle counter
+    static TICKS: Cell<u32> = const { Cell::new(0) };
+}
+
+fn spend(ticks: u32) {
+    TICKS.with(|c| c.set(c.get().wrapping_add(ticks)));
+}
+
+/// Memory with a slow program operation.
+pub struct SlowMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for SlowMem {
+    const INITIAL_ADDRESS_POINTER: u32 = SLOWMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        spend(20);
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        spend(10);
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        spend(5000);
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn profile_ticks(&mut self) -> u32 {
+        TICKS.with(|c| c.get())
+    }
+}
+
+struct MkSlow {}
+
+impl UsbDeviceCtx for MkSlow {
+    type C<'c> = DFUClass<EmulatedUsbBus, SlowMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, SlowMem>> {
+        let mem = SlowMem {
+            mem: MockMem::new(SLOWMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_profile() {
+    MkSlow {}
+        .with_usb(|mut dfu, mut dev| {
+            dfu.reset_profile();
+
+            for block in 2..4 {
+                /* Download block */
+                let vec = dev.download(&mut dfu, block, &[0x55; 32]).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [0x55; 32]);
+
+            let profile = *dfu.profile();
+
+            // program is called from poll
+            assert_eq!(profile.poll.max, 5000);
+            assert_eq!(profile.poll.total, 10000);
+            assert!(profile.poll.count >= 4);
+
+            assert_eq!(profile.control_in.max, 20);
+            assert_eq!(profile.control_out.max, 10);
+            assert_eq!(profile.control_out.total, 20);
+            assert!(profile.control_in.count >= 5);
+            assert!(profile.control_out.count >= 3);
+
+            dfu.reset_profile();
+            assert_eq!(dfu.profile().poll.count, 0);
+            assert_eq!(dfu.profile().poll.max, 0);
+        })
+        .expect("with_usb");
+}
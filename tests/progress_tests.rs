@@ -0,0 +1,170 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::RefCell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const PROGMEM_BASE: u32 = 0x0800_0000;
+
+thread_local! {
+    /// Events of on_progress() calls
+    static EVENTS: RefCell<Vec<DfuProgress>> = const { RefCell::new(Vec::new()) };
+}
+
+fn events() -> Vec<DfuProgress> {
+    EVENTS.with(|e| e.take())
+}
+
+/// Memory that records progress events, fails to program the block at offset 512.
+pub struct ProgMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for ProgMem {
+    const INITIAL_ADDRESS_POINTER: u32 = PROGMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn on_progress(&mut self, progress: DfuProgress) {
+        EVENTS.with(|e| e.borrow_mut().push(progress));
+    }
+}
+
+struct MkProg {}
+
+impl UsbDeviceCtx for MkProg {
+    type C<'c> = DFUClass<EmulatedUsbBus, ProgMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, ProgMem>> {
+        let mut mem = ProgMem {
+            mem: MockMem::new(PROGMEM_BASE, 1, 1024),
+        };
+        mem.mem.faults.program = Some((PROGMEM_BASE + 512, DFUMemError::Prog));
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_progress_download() {
+    MkProg {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Erase page at 0x08000000 */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, 0x00, 0x00, 0x00, 0x08])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download blocks 2, 3, 4 (offsets 0, 32, 64), the last one is short */
+            for (block, len) in [(2, 32), (3, 32), (4, 16)] {
+                let vec = dev
+                    .download(&mut dfu, block, &[0x55; 32][..len])
+                    .expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            /* Download 0 length */
+            let vec = dev.download(&mut dfu, 0, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+
+    let progress = |phase, block_num, address, bytes_written| DfuProgress {
+        phase,
+        block_num,
+        address,
+        bytes_written,
+    };
+    assert_eq!(
+        events(),
+        [
+            progress(DfuPhase::Erase, 0, 0x0800_0000, 0),
+            progress(DfuPhase::Program, 0, 0x0800_0000, 32),
+            progress(DfuPhase::Program, 1, 0x0800_0020, 64),
+            progress(DfuPhase::Program, 2, 0x0800_0040, 80),
+            progress(DfuPhase::Manifest, 0, 0x0800_0000, 80),
+        ]
+    );
+}
+
+#[test]
+fn test_progress_failed() {
+    MkProg {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 18 (offset 512), program fails */
+            let vec = dev.download(&mut dfu, 18, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+        })
+        .expect("with_usb");
+
+    // failed operations are not reported
+    assert_eq!(events(), []);
+}
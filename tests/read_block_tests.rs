@@ -0,0 +1,223 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const BLOCKMEMSIZE: usize = 80;
+const BLOCKMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that implements `read_block()` only, the last block is short.
+pub struct BlockMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for BlockMem {
+    const INITIAL_ADDRESS_POINTER: u32 = BLOCKMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*80 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        self.mem.read_block(address, dest)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkBlock {}
+
+impl UsbDeviceCtx for MkBlock {
+    type C<'c> = DFUClass<EmulatedUsbBus, BlockMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, BlockMem>> {
+        let mut mem = BlockMem {
+            mem: MockMem::new(BLOCKMEM_BASE, 1, BLOCKMEMSIZE as u32),
+        };
+        for (i, b) in mem.mem.memory_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_read_block_upload() {
+    MkBlock {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, (0..32).collect::<Vec<u8>>());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+
+            /* Upload block 3 (offset 1) */
+            let vec = dev.upload(&mut dfu, 3, 32).expect("vec");
+            assert_eq!(vec, (32..64).collect::<Vec<u8>>());
+
+            /* Upload block 4 (offset 2), short frame */
+            let vec = dev.upload(&mut dfu, 4, 32).expect("vec");
+            assert_eq!(vec, (64..80).collect::<Vec<u8>>());
+
+            /* Get Status, upload is complete */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Read(BLOCKMEM_BASE, 32),
+                    MockCall::Read(BLOCKMEM_BASE + 32, 32),
+                    MockCall::Read(BLOCKMEM_BASE + 64, 32)
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_read_block_err() {
+    MkBlock {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 5 (offset 3), out of memory */
+            let e = dev.upload(&mut dfu, 5, 32).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
+
+const CODEWORD: usize = 16;
+
+/// Memory that is read by 16-byte codewords, the last block is short.
+pub struct EccMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for EccMem {
+    const INITIAL_ADDRESS_POINTER: u32 = BLOCKMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*80 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read_block(&mut self, address: u32, dest: &mut [u8]) -> Result<usize, DFUMemError> {
+        let data = self.mem.read(address, dest.len())?;
+        let len = data.len().min(CODEWORD);
+        dest[..len].copy_from_slice(&data[..len]);
+        Ok(len)
+    }
+
+    fn is_end_of_region(&self, address: u32) -> bool {
+        address.wrapping_sub(BLOCKMEM_BASE) as usize >= BLOCKMEMSIZE
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkEcc {}
+
+impl UsbDeviceCtx for MkEcc {
+    type C<'c> = DFUClass<EmulatedUsbBus, EccMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, EccMem>> {
+        let mut mem = EccMem {
+            mem: MockMem::new(BLOCKMEM_BASE, 1, BLOCKMEMSIZE as u32),
+        };
+        for (i, b) in mem.mem.memory_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_read_block_codewords() {
+    MkEcc {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 2 (offset 0), two reads */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, (0..32).collect::<Vec<u8>>());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+
+            /* Upload block 3 (offset 1) */
+            let vec = dev.upload(&mut dfu, 3, 32).expect("vec");
+            assert_eq!(vec, (32..64).collect::<Vec<u8>>());
+
+            /* Upload block 4 (offset 2), short frame at the end of memory */
+            let vec = dev.upload(&mut dfu, 4, 32).expect("vec");
+            assert_eq!(vec, (64..80).collect::<Vec<u8>>());
+
+            /* Get Status, upload is complete */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Read(BLOCKMEM_BASE, 32),
+                    MockCall::Read(BLOCKMEM_BASE + 16, 16),
+                    MockCall::Read(BLOCKMEM_BASE + 32, 32),
+                    MockCall::Read(BLOCKMEM_BASE + 48, 16),
+                    MockCall::Read(BLOCKMEM_BASE + 64, 32),
+                ]
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,178 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const RBMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that allows read-back in dfuDNLOAD-IDLE state.
+pub struct RbMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for RbMem {
+    const INITIAL_ADDRESS_POINTER: u32 = RBMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const ALLOW_UPLOAD_DURING_DNLOAD_IDLE: bool = true;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkRb {}
+
+impl UsbDeviceCtx for MkRb {
+    type C<'c> = DFUClass<EmulatedUsbBus, RbMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RbMem>> {
+        let mem = RbMem {
+            mem: MockMem::new(RBMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_readback_write_verify() {
+    MkRb {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), address pointer = base + 0x100 */
+            let b = (RBMEM_BASE + 0x100).to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            for block in 2..5 {
+                let data = [block as u8; 32];
+
+                /* Download block */
+                let vec = dev.download(&mut dfu, block, &data).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+                /* Upload the same block, verify */
+                let vec = dev.upload(&mut dfu, block, 32).expect("vec");
+                assert_eq!(vec, data);
+
+                /* Get State, download session continues */
+                let vec = dev.get_state(&mut dfu).expect("vec");
+                assert_eq!(vec, [DFUState::DfuDnloadIdle as u8]);
+            }
+
+            /* Upload block 2 again */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [2; 32]);
+            assert!(dfu.download_in_progress());
+            assert_eq!(dfu.downloaded_bytes(), 96);
+
+            /* Upload block 0 (get commands), not allowed */
+            let e = dev.upload(&mut dfu, 0, 3).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[0x100..0x120], [2; 32]);
+            assert_eq!(mem.mem.memory()[0x120..0x140], [3; 32]);
+            assert_eq!(mem.mem.memory()[0x140..0x160], [4; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_readback_then_manifest() {
+    MkRb {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Upload block 2, verify */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [0x55; 32]);
+
+            /* Download block 3 (offset 1) len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 3 (offset 1), a regular upload session */
+            let vec = dev.upload(&mut dfu, 3, 32).expect("vec");
+            assert_eq!(vec, [0xff; 32]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+        })
+        .expect("with_usb");
+}
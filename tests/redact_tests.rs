@@ -0,0 +1,199 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use core::ops::Range;
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const REDACTMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory filled with a pattern, with a few redacted ranges.
+pub struct RedactMem {
+    mem: MockMem,
+}
+
+impl RedactMem {
+    fn new() -> Self {
+        let mut mem = MockMem::new(REDACTMEM_BASE, 1, 1024);
+        for (i, b) in mem.memory_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        // reads past the end of memory fail
+        mem.faults.read = Some((REDACTMEM_BASE + 1024, DFUMemError::Address));
+        Self { mem }
+    }
+}
+
+impl DFUMemIO for RedactMem {
+    const INITIAL_ADDRESS_POINTER: u32 = REDACTMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 64;
+    const REDACTED_RANGES: &'static [Range<u32>] = &[
+        // inside block 1
+        REDACTMEM_BASE + 0x50..REDACTMEM_BASE + 0x60,
+        // crosses blocks 3 and 4
+        REDACTMEM_BASE + 0xf0..REDACTMEM_BASE + 0x110,
+        // near the end of memory
+        REDACTMEM_BASE + 0x3f0..REDACTMEM_BASE + 0x3f8,
+    ];
+    const REDACTED_FILL: u8 = 0x00;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkRedact {}
+
+impl UsbDeviceCtx for MkRedact {
+    type C<'c> = DFUClass<EmulatedUsbBus, RedactMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RedactMem>> {
+        Ok(DFUClass::new(alloc, RedactMem::new()))
+    }
+}
+
+fn expected(range: Range<usize>) -> Vec<u8> {
+    range
+        .map(|i| match i {
+            0x50..=0x5f | 0xf0..=0x10f | 0x3f0..=0x3f7 => 0x00,
+            _ => i as u8,
+        })
+        .collect()
+}
+
+#[test]
+fn test_upload_redacted() {
+    MkRedact {}
+        .with_usb(|mut dfu, mut dev| {
+            for block in 0..6u16 {
+                let offset = block as usize * 64;
+
+                /* Upload block */
+                let vec = dev.upload(&mut dfu, 2 + block, 64).expect("vec");
+                assert_eq!(vec, expected(offset..offset + 64));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+            }
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Read(REDACTMEM_BASE, 64),
+                    MockCall::Read(REDACTMEM_BASE + 0x40, 0x10),
+                    MockCall::Read(REDACTMEM_BASE + 0x60, 0x20),
+                    MockCall::Read(REDACTMEM_BASE + 0x80, 64),
+                    MockCall::Read(REDACTMEM_BASE + 0xc0, 0x30),
+                    // block 4 starts in a redacted range
+                    MockCall::Read(REDACTMEM_BASE + 0x110, 0x30),
+                    MockCall::Read(REDACTMEM_BASE + 0x140, 64),
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_upload_redacted_short() {
+    MkRedact {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 3 (offset 1), shorter than transfer size */
+            let vec = dev.upload(&mut dfu, 3, 40).expect("vec");
+            assert_eq!(vec, expected(0x40..0x68));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Read(REDACTMEM_BASE + 0x40, 0x10),
+                    MockCall::Read(REDACTMEM_BASE + 0x60, 0x08)
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_upload_redacted_err() {
+    MkRedact {}
+        .with_usb(|mut dfu, mut dev| {
+            let b = (REDACTMEM_BASE + 0x3e0).to_le_bytes();
+
+            /* Download block 0 (command), set address pointer, block crosses the end of memory */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0), data after the redacted range is out of memory */
+            let b = dev.upload(&mut dfu, 2, 64);
+            assert_eq!(b, Err(AnyUsbError::EP0Stalled));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Read(REDACTMEM_BASE + 0x3e0, 0x10),
+                    MockCall::Read(REDACTMEM_BASE + 0x3f8, 0x28)
+                ]
+            );
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,183 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const BOOTMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that records USB reset contexts and `on_manifest_reset()` calls.
+pub struct BootMem {
+    mem: MockMem,
+    resets: Vec<ResetContext>,
+    manifest_resets: u32,
+}
+
+impl DFUMemIO for BootMem {
+    const INITIAL_ADDRESS_POINTER: u32 = BOOTMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const MANIFESTATION_TOLERANT: bool = false;
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const MANIFESTATION_TIME_MS: u32 = 40;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn on_usb_reset(&mut self, ctx: ResetContext) {
+        self.resets.push(ctx);
+    }
+
+    fn on_manifest_reset(&mut self) {
+        self.manifest_resets += 1;
+    }
+}
+
+struct MkBoot {}
+
+impl UsbDeviceCtx for MkBoot {
+    type C<'c> = DFUClass<EmulatedUsbBus, BootMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, BootMem>> {
+        let mem = BootMem {
+            mem: MockMem::new(BOOTMEM_BASE, 1, 1024),
+            resets: Vec::new(),
+            manifest_resets: 0,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Download a block, then wait for it to be programmed
+fn download_block<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>) {
+    /* Download block 2 (offset 0) */
+    let vec = dev.download(dfu, 2, &[0x55; 32]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+}
+
+#[test]
+fn test_reset_context_idle() {
+    MkBoot {}
+        .with_usb(|mut dfu, mut dev| {
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            let mem = dfu.release();
+            let idle = ResetContext {
+                state: DFUState::DfuIdle,
+                manifested: false,
+                downloaded: false,
+            };
+            assert_eq!(mem.resets, [idle]);
+            assert_eq!(mem.manifest_resets, 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_reset_context_manifested() {
+    MkBoot {}
+        .with_usb(|mut dfu, mut dev| {
+            download_block(&mut dfu, &mut dev);
+
+            /* Download 0 length */
+            let vec = dev.download(&mut dfu, 0, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 40, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::OK, 0, DFUState::DfuManifestWaitReset)
+            );
+
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            // device stays in DFU mode, flags are cleared
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.resets,
+                [
+                    ResetContext {
+                        state: DFUState::DfuManifestWaitReset,
+                        manifested: true,
+                        downloaded: true,
+                    },
+                    ResetContext {
+                        state: DFUState::DfuIdle,
+                        manifested: false,
+                        downloaded: false,
+                    },
+                ]
+            );
+            // only the first reset starts the application
+            assert_eq!(mem.manifest_resets, 1);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_reset_context_interrupted() {
+    MkBoot {}
+        .with_usb(|mut dfu, mut dev| {
+            download_block(&mut dfu, &mut dev);
+
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.resets,
+                [ResetContext {
+                    state: DFUState::DfuDnloadIdle,
+                    manifested: false,
+                    downloaded: true,
+                }]
+            );
+            assert_eq!(mem.manifest_resets, 0);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,179 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const RPMEM_BASE: u32 = 0x0800_0000;
+
+const KEEP: u8 = 0;
+const ERROR: u8 = 1;
+const IDLE: u8 = 2;
+
+/// Memory with `ON_USB_RESET` policy selected by `P`.
+pub struct RpMem<const P: u8> {
+    mem: MockMem,
+}
+
+impl<const P: u8> DFUMemIO for RpMem<P> {
+    const INITIAL_ADDRESS_POINTER: u32 = RPMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const ON_USB_RESET: UsbResetPolicy = match P {
+        KEEP => UsbResetPolicy::KeepState,
+        ERROR => UsbResetPolicy::ErrorUsbr,
+        _ => UsbResetPolicy::ReturnToIdle,
+    };
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkRp<const P: u8> {}
+
+impl<const P: u8> UsbDeviceCtx for MkRp<P> {
+    type C<'c> = DFUClass<EmulatedUsbBus, RpMem<P>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RpMem<P>>> {
+        let mem = RpMem {
+            mem: MockMem::new(RPMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Download block `block_num`, then wait for it to be programmed
+fn download_block<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, block_num: u16, data: &[u8]) {
+    let vec = dev.download(dfu, block_num, data).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+}
+
+#[test]
+fn test_reset_policy_keep() {
+    MkRp::<KEEP> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            download_block(&mut dfu, &mut dev, 2, &[0x55; 32]);
+
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 32), the session continues */
+            download_block(&mut dfu, &mut dev, 3, &[0xaa; 32]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..32], [0x55; 32]);
+            assert_eq!(mem.mem.memory()[32..64], [0xaa; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_reset_policy_keep_busy() {
+    MkRp::<KEEP> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            // the block is not programmed yet, it's cancelled
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrUsbr, 0, DFUState::DfuError));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..32], [0xff; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_reset_policy_error() {
+    MkRp::<ERROR> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            download_block(&mut dfu, &mut dev, 2, &[0x55; 32]);
+
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrUsbr, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_reset_policy_idle() {
+    MkRp::<IDLE> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            download_block(&mut dfu, &mut dev, 2, &[0x55; 32]);
+
+            dev.bus_reset(&mut dfu).expect("reset");
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Download block 2 (offset 0), a new session, clears more bits of the first block */
+            download_block(&mut dfu, &mut dev, 2, &[0x05; 32]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..32], [0x05; 32]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,254 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::retry::RetryMem;
+
+const FLAKYMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that fails a configured number of program and erase attempts.
+pub struct FlakyMem {
+    mem: MockMem,
+    program_failures: u8,
+    erase_failures: u8,
+    error: fn() -> DFUMemError,
+    program_calls: u32,
+    erase_calls: u32,
+    backoffs: u32,
+}
+
+impl FlakyMem {
+    fn new(program_failures: u8, erase_failures: u8, error: fn() -> DFUMemError) -> Self {
+        Self {
+            mem: MockMem::new(FLAKYMEM_BASE, 4, 1024),
+            program_failures,
+            erase_failures,
+            error,
+            program_calls: 0,
+            erase_calls: 0,
+            backoffs: 0,
+        }
+    }
+}
+
+impl DFUMemIO for FlakyMem {
+    const INITIAL_ADDRESS_POINTER: u32 = FLAKYMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/4*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 128;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.erase_calls += 1;
+        if self.erase_failures > 0 {
+            self.erase_failures -= 1;
+            return Err((self.error)());
+        }
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.program_calls += 1;
+        if self.program_failures > 0 {
+            self.program_failures -= 1;
+            return Err((self.error)());
+        }
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+fn backoff(mem: &mut FlakyMem, attempt: u8) {
+    mem.backoffs += 1;
+}
+
+/// Program fails once with ErrProg, erase fails twice with ErrErase
+struct MkRetryTransient {}
+
+impl UsbDeviceCtx for MkRetryTransient {
+    type C<'c> = DFUClass<EmulatedUsbBus, RetryMem<FlakyMem, 2>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RetryMem<FlakyMem, 2>>> {
+        let mem = FlakyMem::new(1, 2, || DFUMemError::Prog);
+        Ok(DFUClass::new(alloc, RetryMem::with_backoff(mem, backoff)))
+    }
+}
+
+#[test]
+fn test_retry_transient_failures() {
+    MkRetryTransient {}
+        .with_usb(|mut dfu, mut dev| {
+            let b = FLAKYMEM_BASE.to_le_bytes();
+
+            /* Download block 0 (command), erase = base, fails twice */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 2 (offset 0), program fails once */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec, [0x55; 128]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.retries(), 3);
+            assert_eq!(mem.inner().erase_calls, 3);
+            assert_eq!(mem.inner().program_calls, 2);
+            assert_eq!(mem.inner().backoffs, 3);
+        })
+        .expect("with_usb");
+}
+
+/// Program always fails with ErrProg
+struct MkRetryExhausted {}
+
+impl UsbDeviceCtx for MkRetryExhausted {
+    type C<'c> = DFUClass<EmulatedUsbBus, RetryMem<FlakyMem, 2>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RetryMem<FlakyMem, 2>>> {
+        let mem = FlakyMem::new(u8::MAX, 0, || DFUMemError::Prog);
+        Ok(DFUClass::new(alloc, RetryMem::new(mem)))
+    }
+}
+
+#[test]
+fn test_retry_exhausted() {
+    MkRetryExhausted {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+
+            let mem = dfu.release();
+            assert_eq!(mem.retries(), 2);
+            assert_eq!(mem.inner().program_calls, 3);
+        })
+        .expect("with_usb");
+}
+
+/// Program and erase always fail with ErrAddress
+struct MkRetryAddress {}
+
+impl UsbDeviceCtx for MkRetryAddress {
+    type C<'c> = DFUClass<EmulatedUsbBus, RetryMem<FlakyMem, 2>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RetryMem<FlakyMem, 2>>> {
+        let mem = FlakyMem::new(u8::MAX, u8::MAX, || DFUMemError::Address);
+        Ok(DFUClass::new(alloc, RetryMem::new(mem)))
+    }
+}
+
+#[test]
+fn test_retry_not_transient() {
+    MkRetryAddress {}
+        .with_usb(|mut dfu, mut dev| {
+            let b = FLAKYMEM_BASE.to_le_bytes();
+
+            /* Download block 0 (command), erase = base */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrAddress, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(mem.retries(), 0);
+            assert_eq!(mem.inner().erase_calls, 1);
+            assert_eq!(mem.inner().program_calls, 1);
+        })
+        .expect("with_usb");
+}
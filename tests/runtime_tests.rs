@@ -0,0 +1,189 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::Cell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usbd_dfu::runtime::*;
+use usbd_dfu::{DFUState, DFUStatusCode};
+
+thread_local! {
+    /// Fake clock, in milliseconds
+    static CLOCK: Cell<u32> = const { Cell::new(1000) };
+}
+
+fn advance_clock(ms: u32) {
+    CLOCK.with(|c| c.set(c.get() + ms));
+}
+
+/// Application that records detach requests.
+pub struct App {
+    detaches: u32,
+    resets: u32,
+}
+
+impl DFURuntime for App {
+    const DETACH_TIMEOUT: u16 = 0x1122;
+    const TRANSFER_SIZE: u16 = 64;
+    const WILL_DETACH: bool = false;
+
+    fn detach(&mut self) {
+        self.detaches += 1;
+    }
+
+    fn usb_reset(&mut self) {
+        self.resets += 1;
+    }
+
+    fn now_ms(&mut self) -> u32 {
+        CLOCK.with(|c| c.get())
+    }
+}
+
+struct MkApp {}
+
+impl UsbDeviceCtx for MkApp {
+    type C<'c> = DFURuntimeClass<EmulatedUsbBus, App>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFURuntimeClass<EmulatedUsbBus, App>> {
+        let app = App {
+            detaches: 0,
+            resets: 0,
+        };
+        Ok(DFURuntimeClass::new(alloc, app))
+    }
+}
+
+#[test]
+fn test_runtime_descriptors() {
+    MkApp {}
+        .with_usb(|mut rt, mut dev| {
+            let desc = dev
+                .device_get_descriptor(&mut rt, 2, 0, 0, 255)
+                .expect("desc");
+
+            // DFU interface, run-time protocol
+            assert_eq!(desc[9..18], [9, 4, 0, 0, 0, 0xfe, 0x01, 0x01, 0]);
+
+            // DFU Functional descriptor
+            assert_eq!(desc[18..27], [9, 0x21, 0x07, 0x22, 0x11, 64, 0, 0x1a, 0x01]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_runtime_detach() {
+    MkApp {}
+        .with_usb(|mut rt, mut dev| {
+            /* Get Status */
+            let vec = dev.get_status(&mut rt).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::AppIdle));
+
+            /* Detach */
+            let vec = dev.detach(&mut rt, 1000).expect("vec");
+            assert_eq!(vec, []);
+            assert!(rt.detach_pending());
+
+            /* Get State */
+            let vec = dev.get_state(&mut rt).expect("vec");
+            assert_eq!(vec, [DFUState::AppDetach as u8]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut rt).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::AppDetach));
+
+            // host resets the device to enter DFU mode
+            dev.bus_reset(&mut rt).expect("reset");
+            assert!(!rt.detach_pending());
+
+            /* Get State */
+            let vec = dev.get_state(&mut rt).expect("vec");
+            assert_eq!(vec, [DFUState::AppIdle as u8]);
+
+            let app = rt.release();
+            assert_eq!(app.detaches, 1);
+            assert_eq!(app.resets, 1);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_runtime_detach_timeout() {
+    MkApp {}
+        .with_usb(|mut rt, mut dev| {
+            /* Detach */
+            let vec = dev.detach(&mut rt, 500).expect("vec");
+            assert_eq!(vec, []);
+
+            advance_clock(499);
+            rt.poll();
+            assert!(rt.detach_pending());
+
+            advance_clock(1);
+            rt.poll();
+            assert!(!rt.detach_pending());
+
+            /* Get State */
+            let vec = dev.get_state(&mut rt).expect("vec");
+            assert_eq!(vec, [DFUState::AppIdle as u8]);
+
+            // wDetachTimeOut limits a longer request timeout
+            /* Detach */
+            let vec = dev.detach(&mut rt, 0xffff).expect("vec");
+            assert_eq!(vec, []);
+
+            advance_clock(0x1122);
+            /* Get State */
+            let vec = dev.get_state(&mut rt).expect("vec");
+            assert_eq!(vec, [DFUState::AppIdle as u8]);
+
+            // USB reset after timeout does not enter DFU mode
+            dev.bus_reset(&mut rt).expect("reset");
+
+            let app = rt.release();
+            assert_eq!(app.detaches, 2);
+            assert_eq!(app.resets, 0);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_runtime_unsupported() {
+    MkApp {}
+        .with_usb(|mut rt, mut dev| {
+            /* Download block 2, not supported in run-time mode */
+            let e = dev.download(&mut rt, 2, &[0x55; 32]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Upload block 2 */
+            let e = dev.upload(&mut rt, 2, 32).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status, no error state in run-time mode */
+            let vec = dev.get_status(&mut rt).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::AppIdle));
+
+            /* Detach */
+            let vec = dev.detach(&mut rt, 1000).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Detach again, unexpected in appDETACH state */
+            let e = dev.detach(&mut rt, 1000).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+            assert!(!rt.detach_pending());
+
+            /* Get State */
+            let vec = dev.get_state(&mut rt).expect("vec");
+            assert_eq!(vec, [DFUState::AppIdle as u8]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,184 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use core::ops::Range;
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const SEGMEM_BASE: u32 = 0x0800_0000;
+const FLASH: Range<u32> = SEGMEM_BASE..SEGMEM_BASE + 0x200;
+const EEPROM: Range<u32> = SEGMEM_BASE + 0x300..SEGMEM_BASE + 0x400;
+
+/// Internal flash and an external EEPROM in one address space.
+pub struct SegMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for SegMem {
+    const INITIAL_ADDRESS_POINTER: u32 = SEGMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/2*256 g,1*256 c,16*16 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 50;
+    const TRANSFER_SIZE: u16 = 64;
+    const LAYOUT_SEGMENTS: &'static [Range<u32>] = &[FLASH, EEPROM];
+    const SEGMENT_LIMITS: &'static [SegmentLimits] = &[
+        SegmentLimits {
+            max_block: 64,
+            program_time_ms: 30,
+            erase_time_ms: 40,
+        },
+        SegmentLimits {
+            max_block: 16,
+            program_time_ms: 5,
+            erase_time_ms: 6,
+        },
+    ];
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkSeg {}
+
+impl UsbDeviceCtx for MkSeg {
+    type C<'c> = DFUClass<EmulatedUsbBus, SegMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, SegMem>> {
+        let mem = SegMem {
+            mem: MockMem::new(SEGMEM_BASE, 4, 256),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Send a command block, expect the device to return to dfuDNLOAD-IDLE
+fn command(
+    dfu: &mut DFUClass<EmulatedUsbBus, SegMem>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, SegMem>, MkSeg>,
+    code: u8,
+    address: u32,
+    timeout: u32,
+) {
+    let b = address.to_le_bytes();
+    let vec = dev
+        .download(dfu, 0, &[code, b[0], b[1], b[2], b[3]])
+        .expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, timeout, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+}
+
+#[test]
+fn test_segment_limits() {
+    MkSeg {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Set Address Pointer to EEPROM */
+            command(&mut dfu, &mut dev, 0x21, EEPROM.start, 0);
+
+            /* Download block 2, larger than EEPROM allows */
+            let e = dev.download(&mut dfu, 2, &[0x11; 64]).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EPStalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Erase EEPROM page */
+            command(&mut dfu, &mut dev, 0x41, EEPROM.start, 6);
+
+            /* Set Address Pointer to EEPROM */
+            command(&mut dfu, &mut dev, 0x21, EEPROM.start, 0);
+
+            /* Download block 2, 16 bytes */
+            let vec = dev.download(&mut dfu, 2, &[0x22; 16]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status, EEPROM program time */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            // switch to flash in the same session
+            /* Erase flash page */
+            command(&mut dfu, &mut dev, 0x41, FLASH.start, 40);
+
+            /* Set Address Pointer to flash */
+            command(&mut dfu, &mut dev, 0x21, FLASH.start, 0);
+
+            /* Download block 2, 64 bytes */
+            let vec = dev.download(&mut dfu, 2, &[0x33; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status, flash program time */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 30, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Erase outside of segments, global erase time */
+            command(&mut dfu, &mut dev, 0x41, SEGMEM_BASE + 0x200, 20);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.mem.calls,
+                [
+                    MockCall::Erase(EEPROM.start),
+                    MockCall::Program(EEPROM.start, 16),
+                    MockCall::Erase(FLASH.start),
+                    MockCall::Program(FLASH.start, 64),
+                    MockCall::Erase(SEGMEM_BASE + 0x200)
+                ]
+            );
+            assert_eq!(mem.mem.memory()[0x300..0x310], [0x22; 16]);
+            assert_eq!(mem.mem.memory()[..0x40], [0x33; 64]);
+        })
+        .expect("with_usb");
+}
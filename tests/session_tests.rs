@@ -0,0 +1,271 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const SESSIONMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that fails to program blocks starting with `0xee`.
+pub struct SessionMem {
+    mem: MockMem,
+    /// The stored block starts with `0xee`
+    bad_block: bool,
+}
+
+impl DFUMemIO for SessionMem {
+    const INITIAL_ADDRESS_POINTER: u32 = SESSIONMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.bad_block = src.first() == Some(&0xee);
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        if self.bad_block {
+            return Err(DFUMemError::Prog);
+        }
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkSession {}
+
+impl UsbDeviceCtx for MkSession {
+    type C<'c> = DFUClass<EmulatedUsbBus, SessionMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, SessionMem>> {
+        let mem = SessionMem {
+            mem: MockMem::new(SESSIONMEM_BASE, 1, 1024),
+            bad_block: false,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_session_complete() {
+    MkSession {}
+        .with_usb(|mut dfu, mut dev| {
+            assert!(!dfu.download_in_progress());
+
+            /* Download block 0 (command), erase page */
+            let b = SESSIONMEM_BASE.to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+            assert!(dfu.download_in_progress());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            for block in 2..4 {
+                /* Download block */
+                let vec = dev.download(&mut dfu, block, &[0x11; 32]).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+                assert!(dfu.download_in_progress());
+            }
+            assert_eq!(dfu.downloaded_bytes(), 64);
+
+            /* Download len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 4, &[]).expect("vec");
+            assert_eq!(vec, []);
+            assert!(dfu.download_in_progress());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+            assert!(!dfu.download_in_progress());
+            assert_eq!(dfu.downloaded_bytes(), 0);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+            assert!(!dfu.download_in_progress());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_session_aborted() {
+    MkSession {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), set address pointer */
+            let b = SESSIONMEM_BASE.to_le_bytes();
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+                .expect("vec");
+            assert_eq!(vec, []);
+            assert!(!dfu.download_in_progress());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x11; 20]).expect("vec");
+            assert_eq!(vec, []);
+            assert!(dfu.download_in_progress());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            assert_eq!(dfu.downloaded_bytes(), 20);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+            assert!(!dfu.download_in_progress());
+            assert_eq!(dfu.downloaded_bytes(), 0);
+
+            /* Upload block 2, uploads are not download sessions */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec.len(), 32);
+            assert!(!dfu.download_in_progress());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_session_error() {
+    MkSession {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x11; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1), program fails */
+            let vec = dev.download(&mut dfu, 3, &[0xee; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+            assert!(dfu.download_in_progress());
+            assert_eq!(dfu.downloaded_bytes(), 32);
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+            assert!(!dfu.download_in_progress());
+
+            /* Download block 2 (offset 0), a new session */
+            let vec = dev.download(&mut dfu, 2, &[0x22; 32]).expect("vec");
+            assert_eq!(vec, []);
+            assert!(dfu.download_in_progress());
+
+            /* USB reset and enumeration */
+            dev.bus_reset(&mut dfu).expect("reset");
+            assert!(!dfu.download_in_progress());
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_session_poll_activity() {
+    MkSession {}
+        .with_usb(|mut dfu, mut dev| {
+            assert_eq!(dfu.take_poll_activity(), PollActivity::None);
+            assert!(!dfu.is_busy());
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+            // waits for Get Status
+            assert!(!dfu.is_busy());
+            assert_eq!(dfu.take_poll_activity(), PollActivity::None);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+            assert!(!dfu.is_busy());
+            assert_eq!(dfu.take_poll_activity(), PollActivity::ExecutedCommand);
+            // cleared on read
+            assert_eq!(dfu.take_poll_activity(), PollActivity::None);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            assert_eq!(dfu.take_poll_activity(), PollActivity::None);
+
+            /* Download block 3 (offset 1) len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
+            assert_eq!(vec, []);
+            assert_eq!(dfu.take_poll_activity(), PollActivity::None);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+            assert_eq!(dfu.take_poll_activity(), PollActivity::ExecutedCommand);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+            assert_eq!(dfu.take_poll_activity(), PollActivity::None);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,190 @@
+#![allow(unused_variables)]
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const SLOWMEM_BASE: u32 = 0x0800_0000;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Event {
+    /// Device has handled a setup packet, a reply is not read by the host yet
+    Setup,
+    /// `update_pending()` result just after a setup packet
+    Update(bool),
+    Erase,
+    Manifestation,
+}
+
+type Log = Rc<RefCell<Vec<Event>>>;
+
+/// Mock memory with operations that take a long time, calls are logged
+pub struct SlowMem<const IN_IRQ: bool> {
+    mem: MockMem,
+    log: Log,
+}
+
+impl<const IN_IRQ: bool> DFUMemIO for SlowMem<IN_IRQ> {
+    const INITIAL_ADDRESS_POINTER: u32 = SLOWMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/4*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 3000;
+    const FULL_ERASE_TIME_MS: u32 = 3000;
+    const MANIFESTATION_TIME_MS: u32 = 500;
+    const MEMIO_IN_USB_INTERRUPT: bool = IN_IRQ;
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.log.borrow_mut().push(Event::Erase);
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.log.borrow_mut().push(Event::Manifestation);
+        self.mem.manifestation()
+    }
+}
+
+struct MkSlow<const IN_IRQ: bool> {
+    log: Log,
+}
+
+impl<const IN_IRQ: bool> UsbDeviceCtx for MkSlow<IN_IRQ> {
+    type C<'c> = DFUClass<EmulatedUsbBus, SlowMem<IN_IRQ>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, SlowMem<IN_IRQ>>> {
+        let mem = SlowMem {
+            mem: MockMem::new(SLOWMEM_BASE, 4, 1024),
+            log: self.log.clone(),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+
+    fn hook(&mut self, cls: &mut Self::C<'_>, when: HookWhen) -> HookAction {
+        if let HookWhen::AfterSetup(_) = when {
+            self.log.borrow_mut().push(Event::Setup);
+            if !IN_IRQ {
+                // main loop runs before the reply is sent
+                let pending = cls.update_pending();
+                self.log.borrow_mut().push(Event::Update(pending));
+            }
+        }
+        if !IN_IRQ {
+            cls.update();
+        }
+        HookAction::Default
+    }
+}
+
+/// Erase a page, then finish the download and manifest
+fn erase_and_manifest<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>) {
+    let b = SLOWMEM_BASE.to_le_bytes();
+
+    /* Erase Page */
+    let vec = dev
+        .download(dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+        .expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status, erase starts after the reply is sent */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 3000, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+    /* Download 0 length, manifestation */
+    let vec = dev.download(dfu, 0, &[]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status, manifestation starts after the reply is sent */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 500, DFUState::DfuManifest));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+}
+
+#[test]
+fn test_status_reply_before_operation() {
+    let log = Log::default();
+    MkSlow::<true> { log: log.clone() }
+        .with_usb(|mut dfu, mut dev| {
+            erase_and_manifest(&mut dfu, &mut dev);
+        })
+        .expect("with_usb");
+
+    let log = log.borrow();
+    let start = log.iter().position(|e| *e == Event::Erase).expect("erase");
+    assert_eq!(
+        log[start - 2..],
+        [
+            // Erase Page download
+            Event::Setup,
+            // the first Get Status reply is produced without the erase
+            Event::Setup,
+            Event::Erase,
+            Event::Setup,
+            // manifestation download
+            Event::Setup,
+            Event::Setup,
+            Event::Manifestation,
+            Event::Setup,
+        ]
+    );
+}
+
+#[test]
+fn test_status_reply_before_update() {
+    let log = Log::default();
+    MkSlow::<false> { log: log.clone() }
+        .with_usb(|mut dfu, mut dev| {
+            erase_and_manifest(&mut dfu, &mut dev);
+
+            /* Nothing is left pending */
+            assert!(!dfu.update_pending());
+        })
+        .expect("with_usb");
+
+    let log = log.borrow();
+    let start = log.iter().position(|e| *e == Event::Erase).expect("erase");
+    assert_eq!(
+        log[start - 4..],
+        [
+            // Erase Page download
+            Event::Setup,
+            Event::Update(false),
+            // the first Get Status, update() has nothing to do yet
+            Event::Setup,
+            Event::Update(false),
+            // the reply is sent, the second Get Status runs the erase
+            Event::Erase,
+            Event::Setup,
+            Event::Update(false),
+            // manifestation download
+            Event::Setup,
+            Event::Update(false),
+            Event::Setup,
+            Event::Update(false),
+            Event::Manifestation,
+            Event::Setup,
+            Event::Update(false),
+        ]
+    );
+}
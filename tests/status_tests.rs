@@ -0,0 +1,27 @@
+use usbd_dfu::{DFUState, DFUStatusCode};
+
+#[test]
+fn test_state_conversion() {
+    for v in 0..=10u8 {
+        let state = DFUState::try_from(v).expect("state");
+        assert_eq!(u8::from(state), v);
+    }
+    assert_eq!(DFUState::try_from(2), Ok(DFUState::DfuIdle));
+    assert_eq!(DFUState::try_from(10), Ok(DFUState::DfuError));
+    assert_eq!(DFUState::try_from(11), Err(11));
+    assert_eq!(DFUState::try_from(0xff), Err(0xff));
+}
+
+#[test]
+fn test_status_code_conversion() {
+    for v in 0..=0x0fu8 {
+        let status = DFUStatusCode::try_from(v).expect("status");
+        assert_eq!(u8::from(status), v);
+    }
+    assert_eq!(DFUStatusCode::try_from(0), Ok(DFUStatusCode::OK));
+    assert_eq!(
+        DFUStatusCode::try_from(0x0f),
+        Ok(DFUStatusCode::ErrStalledPkt)
+    );
+    assert_eq!(DFUStatusCode::try_from(0x10), Err(0x10));
+}
@@ -0,0 +1,240 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::stm32h7::*;
+
+const BANK_SIZE: u32 = 2 * SECTOR_SIZE;
+
+/// Flash interface that records register operations.
+pub struct MockFlash {
+    memory: Vec<u8>,
+    events: Vec<String>,
+}
+
+impl MockFlash {
+    fn new() -> Self {
+        Self {
+            memory: vec![0xff; 2 * BANK_SIZE as usize],
+            events: Vec::new(),
+        }
+    }
+
+    fn offset(address: u32) -> usize {
+        if address >= BANK2_BASE {
+            (address - BANK2_BASE + BANK_SIZE) as usize
+        } else {
+            (address - BANK1_BASE) as usize
+        }
+    }
+}
+
+impl H7Flash for MockFlash {
+    const BANK_SIZE: u32 = BANK_SIZE;
+    const DUAL_BANK: bool = true;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/02*128Kg,/0x08100000/02*128Kg";
+
+    fn unlock(&mut self) {
+        self.events.push("unlock".into());
+    }
+
+    fn lock(&mut self) {
+        self.events.push("lock".into());
+    }
+
+    fn erase_sector(&mut self, bank: Bank, sector: u8) -> Result<(), DFUMemError> {
+        self.events
+            .push(format!("erase_sector {:?} {}", bank, sector));
+        Ok(())
+    }
+
+    fn erase_bank(&mut self, bank: Bank) -> Result<(), DFUMemError> {
+        self.events.push(format!("erase_bank {:?}", bank));
+        Ok(())
+    }
+
+    fn program_flash_word(
+        &mut self,
+        address: u32,
+        data: &[u8; FLASH_WORD_SIZE],
+    ) -> Result<(), DFUMemError> {
+        self.events.push(format!("program {:#010x}", address));
+        let offset = Self::offset(address);
+        self.memory[offset..offset + FLASH_WORD_SIZE].copy_from_slice(data);
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> &[u8] {
+        let offset = Self::offset(address);
+        &self.memory[offset..offset + length]
+    }
+}
+
+type H7Mem = Stm32h7Flash<MockFlash>;
+
+struct MkH7 {}
+
+impl UsbDeviceCtx for MkH7 {
+    type C<'c> = DFUClass<EmulatedUsbBus, H7Mem>;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, H7Mem>> {
+        Ok(DFUClass::new(alloc, H7Mem::new(MockFlash::new())))
+    }
+}
+
+#[test]
+fn test_h7_sectors() {
+    assert!(matches!(H7Mem::sector(0x0800_0000), Ok((Bank::Bank1, 0))));
+    assert!(matches!(H7Mem::sector(0x0801_ffff), Ok((Bank::Bank1, 0))));
+    assert!(matches!(H7Mem::sector(0x0802_0000), Ok((Bank::Bank1, 1))));
+    assert!(matches!(H7Mem::sector(0x0812_0010), Ok((Bank::Bank2, 1))));
+    // gap between banks
+    assert!(matches!(
+        H7Mem::sector(0x0804_0000),
+        Err(DFUMemError::Address)
+    ));
+    assert!(matches!(
+        H7Mem::sector(0x0814_0000),
+        Err(DFUMemError::Address)
+    ));
+
+    let mut mem = H7Mem::new(MockFlash::new());
+    mem.erase(0x0812_0010).expect("erase");
+    mem.erase_all().expect("erase_all");
+    assert_eq!(
+        mem.flash().events,
+        [
+            "unlock",
+            "erase_sector Bank2 1",
+            "lock",
+            "unlock",
+            "erase_bank Bank1",
+            "erase_bank Bank2",
+            "lock"
+        ]
+    );
+}
+
+#[test]
+fn test_h7_coalescing() {
+    let mut mem = H7Mem::new(MockFlash::new());
+
+    // unaligned blocks
+    let data: Vec<u8> = (0..100).collect();
+    mem.store_write_buffer(&data[..40]).expect("store");
+    mem.program(0x0800_0010, 40).expect("program");
+    assert_eq!(mem.pending_word(), Some(0x0800_0020));
+
+    mem.store_write_buffer(&data[40..]).expect("store");
+    mem.program(0x0800_0038, 60).expect("program");
+    assert_eq!(mem.pending_word(), Some(0x0800_0060));
+
+    // tail is programmed at manifestation
+    mem.manifestation().expect("manifestation");
+    assert_eq!(mem.pending_word(), None);
+
+    let flash = mem.into_inner();
+    assert_eq!(
+        flash.events,
+        [
+            "unlock",
+            "program 0x08000000",
+            "lock",
+            "unlock",
+            "program 0x08000020",
+            "program 0x08000040",
+            "lock",
+            "unlock",
+            "program 0x08000060",
+            "lock"
+        ]
+    );
+    assert_eq!(flash.memory[..0x10], [0xff; 0x10]);
+    assert_eq!(flash.memory[0x10..0x74], data[..]);
+    assert_eq!(flash.memory[0x74..0x80], [0xff; 0x0c]);
+}
+
+#[test]
+fn test_h7_reprogram() {
+    let mut mem = H7Mem::new(MockFlash::new());
+
+    mem.store_write_buffer(&[0x55; 32]).expect("store");
+    mem.program(0x0800_0100, 32).expect("program");
+
+    // the same flash word again, without erase
+    mem.store_write_buffer(&[0xaa; 32]).expect("store");
+    assert!(matches!(
+        mem.program(0x0800_0100, 32),
+        Err(DFUMemError::CheckErased)
+    ));
+    assert_eq!(mem.pending_word(), None);
+
+    // partially received word is refused at manifestation
+    mem.store_write_buffer(&[0xaa; 8]).expect("store");
+    mem.program(0x0800_0108, 8).expect("program");
+    assert!(matches!(
+        mem.manifestation(),
+        Err(DFUManifestationError::Unknown)
+    ));
+
+    let flash = mem.into_inner();
+    assert_eq!(flash.memory[0x100..0x120], [0x55; 32]);
+}
+
+#[test]
+fn test_h7_download() {
+    MkH7 {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), erase sector */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, 0x00, 0x00, 0x02, 0x08])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 2000, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 2 (offset 0), 100 bytes */
+            let vec = dev.download(&mut dfu, 2, &[0x77; 100]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 2, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3, len 0, trigger manifestation */
+            let vec = dev.download(&mut dfu, 3, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 2, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let flash = dfu.release().into_inner();
+            assert_eq!(flash.events[1], "erase_sector Bank1 1");
+            assert_eq!(flash.memory[0x0..0x64], [0x77; 100]);
+            assert_eq!(flash.memory[0x64..0x80], [0xff; 0x1c]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,293 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::stm32l4::*;
+
+const BANK_SIZE: u32 = 4 * PAGE_SIZE;
+
+/// Flash interface that records register operations.
+pub struct MockFlash {
+    active: Bank,
+    memory: Vec<u8>,
+    events: Vec<String>,
+    option_error: bool,
+}
+
+impl MockFlash {
+    fn new(active: Bank) -> Self {
+        Self {
+            active,
+            memory: vec![0xff; 2 * BANK_SIZE as usize],
+            events: Vec::new(),
+            option_error: false,
+        }
+    }
+}
+
+impl L4Flash for MockFlash {
+    const BANK_SIZE: u32 = BANK_SIZE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/04*002Kg";
+
+    fn active_bank(&mut self) -> Bank {
+        self.active
+    }
+
+    fn unlock(&mut self) {
+        self.events.push("unlock".into());
+    }
+
+    fn lock(&mut self) {
+        self.events.push("lock".into());
+    }
+
+    fn erase_page(&mut self, bank: Bank, page: u16) -> Result<(), DFUMemError> {
+        self.events.push(format!("erase_page {:?} {}", bank, page));
+        Ok(())
+    }
+
+    fn erase_bank(&mut self, bank: Bank) -> Result<(), DFUMemError> {
+        self.events.push(format!("erase_bank {:?}", bank));
+        Ok(())
+    }
+
+    fn program_double_word(&mut self, address: u32, data: u64) -> Result<(), DFUMemError> {
+        self.events.push(format!("program {:#010x}", address));
+        let offset = (address - FLASH_BASE) as usize;
+        self.memory[offset..offset + 8].copy_from_slice(&data.to_le_bytes());
+        Ok(())
+    }
+
+    fn read(&mut self, address: u32, length: usize) -> &[u8] {
+        let offset = (address - FLASH_BASE) as usize;
+        &self.memory[offset..offset + length]
+    }
+
+    fn option_unlock(&mut self) {
+        self.events.push("option_unlock".into());
+    }
+
+    fn set_bfb2(&mut self, bfb2: bool) {
+        self.events.push(format!("set_bfb2 {}", bfb2));
+    }
+
+    fn option_start(&mut self) -> Result<(), DFUManifestationError> {
+        self.events.push("option_start".into());
+        if self.option_error {
+            return Err(DFUManifestationError::Unknown);
+        }
+        Ok(())
+    }
+
+    fn option_launch(&mut self) {
+        self.events.push("option_launch".into());
+    }
+}
+
+type L4Mem = Stm32l4DualBank<MockFlash>;
+
+struct MkL4 {}
+
+impl UsbDeviceCtx for MkL4 {
+    type C<'c> = DFUClass<EmulatedUsbBus, L4Mem>;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, L4Mem>> {
+        Ok(DFUClass::new(
+            alloc,
+            L4Mem::new(MockFlash::new(Bank::Bank1)),
+        ))
+    }
+}
+
+#[test]
+fn test_l4_address_mapping() {
+    assert!(matches!(
+        L4Mem::inactive_address(0x0800_0000, 128),
+        Ok(0x0800_2000)
+    ));
+    assert!(matches!(
+        L4Mem::inactive_address(0x0800_1f80, 128),
+        Ok(0x0800_3f80)
+    ));
+    assert!(matches!(
+        L4Mem::inactive_address(0x0800_0000, 0x2000),
+        Ok(0x0800_2000)
+    ));
+
+    // crosses the end of the bank
+    assert!(matches!(
+        L4Mem::inactive_address(0x0800_1f88, 128),
+        Err(DFUMemError::Address)
+    ));
+    // inactive bank itself is not addressable
+    assert!(matches!(
+        L4Mem::inactive_address(0x0800_2000, 8),
+        Err(DFUMemError::Address)
+    ));
+    assert!(matches!(
+        L4Mem::inactive_address(0x07ff_fff8, 8),
+        Err(DFUMemError::Address)
+    ));
+    assert!(matches!(
+        L4Mem::inactive_address(0xffff_fff8, 128),
+        Err(DFUMemError::Address)
+    ));
+}
+
+#[test]
+fn test_l4_page_math() {
+    let mut mem = L4Mem::new(MockFlash::new(Bank::Bank1));
+    assert!(matches!(
+        mem.inactive_page(0x0800_0000),
+        Ok((Bank::Bank2, 0))
+    ));
+    assert!(matches!(
+        mem.inactive_page(0x0800_07ff),
+        Ok((Bank::Bank2, 0))
+    ));
+    assert!(matches!(
+        mem.inactive_page(0x0800_0800),
+        Ok((Bank::Bank2, 1))
+    ));
+    assert!(matches!(
+        mem.inactive_page(0x0800_1fff),
+        Ok((Bank::Bank2, 3))
+    ));
+    assert!(matches!(
+        mem.inactive_page(0x0800_2000),
+        Err(DFUMemError::Address)
+    ));
+
+    mem.erase(0x0800_1000).expect("erase");
+    mem.erase_all().expect("erase_all");
+    assert_eq!(
+        mem.flash().events,
+        [
+            "unlock",
+            "erase_page Bank2 2",
+            "lock",
+            "unlock",
+            "erase_bank Bank2",
+            "lock"
+        ]
+    );
+
+    // booted from bank 2, bank 1 is mapped after it
+    let mem = L4Mem::new(MockFlash::new(Bank::Bank2));
+    assert!(matches!(
+        mem.inactive_page(0x0800_0800),
+        Ok((Bank::Bank1, 1))
+    ));
+}
+
+#[test]
+fn test_l4_program() {
+    let mut mem = L4Mem::new(MockFlash::new(Bank::Bank1));
+
+    // not aligned to a double word
+    assert!(matches!(
+        mem.program(0x0800_0004, 8),
+        Err(DFUMemError::Address)
+    ));
+
+    let data: Vec<u8> = (1..=12).collect();
+    mem.store_write_buffer(&data).expect("store");
+    mem.program(0x0800_0010, 12).expect("program");
+
+    let flash = mem.into_inner();
+    assert_eq!(
+        flash.events,
+        ["unlock", "program 0x08002010", "program 0x08002018", "lock"]
+    );
+    // last double word is padded
+    assert_eq!(flash.memory[0x2010..0x201c], data[..]);
+    assert_eq!(flash.memory[0x201c..0x2020], [0xff; 4]);
+    // active bank is not touched
+    assert_eq!(flash.memory[..0x2000], [0xff; 0x2000]);
+}
+
+#[test]
+fn test_l4_option_sequence() {
+    let mut mem = L4Mem::new(MockFlash::new(Bank::Bank1));
+    mem.manifestation().expect("manifestation");
+    assert_eq!(
+        mem.flash().events,
+        [
+            "unlock",
+            "option_unlock",
+            "set_bfb2 true",
+            "option_start",
+            "option_launch"
+        ]
+    );
+
+    let mut mem = L4Mem::new(MockFlash::new(Bank::Bank2));
+    mem.manifestation().expect("manifestation");
+    assert_eq!(
+        mem.flash().events,
+        [
+            "unlock",
+            "option_unlock",
+            "set_bfb2 false",
+            "option_start",
+            "option_launch"
+        ]
+    );
+
+    let mut flash = MockFlash::new(Bank::Bank1);
+    flash.option_error = true;
+    let mut mem = L4Mem::new(flash);
+    assert!(matches!(
+        mem.manifestation(),
+        Err(DFUManifestationError::Unknown)
+    ));
+    assert_eq!(
+        mem.flash().events,
+        [
+            "unlock",
+            "option_unlock",
+            "set_bfb2 true",
+            "option_start",
+            "lock"
+        ]
+    );
+}
+
+#[test]
+fn test_l4_download() {
+    MkL4 {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 3 (offset 1) */
+            let vec = dev.download(&mut dfu, 3, &[0x5a; 128]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 2, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 3 (offset 1), reads the inactive bank */
+            let vec = dev.upload(&mut dfu, 3, 128).expect("vec");
+            assert_eq!(vec, [0x5a; 128]);
+
+            let flash = dfu.release().into_inner();
+            assert_eq!(flash.memory[0x2080..0x2100], [0x5a; 128]);
+            assert_eq!(flash.memory[0x0080..0x0100], [0xff; 128]);
+        })
+        .expect("with_usb");
+}
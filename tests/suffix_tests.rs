@@ -0,0 +1,272 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use std::cell::RefCell;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::crc::crc32_update;
+use usbd_dfu::{DfuSuffix, RetryMem};
+
+const SUFMEM_BASE: u32 = 0x0800_0000;
+
+const VID: u16 = 0x0483;
+const PID: u16 = 0xdf11;
+
+thread_local! {
+    /// Arguments of validate_suffix() calls
+    static SUFFIXES: RefCell<Vec<Option<DfuSuffix>>> = const { RefCell::new(Vec::new()) };
+}
+
+fn suffixes() -> Vec<Option<DfuSuffix>> {
+    SUFFIXES.with(|s| s.take())
+}
+
+/// Memory that accepts images for `VID`:`PID`, or without a suffix.
+pub struct SufMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for SufMem {
+    const INITIAL_ADDRESS_POINTER: u32 = SUFMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const CHECK_SUFFIX: bool = true;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn validate_suffix(&mut self, suffix: Option<DfuSuffix>) -> Result<(), DFUManifestationError> {
+        SUFFIXES.with(|s| s.borrow_mut().push(suffix));
+        match suffix {
+            None => Ok(()),
+            Some(s) if !s.matches_device(VID, PID, 0x0200) => Err(DFUManifestationError::Target),
+            Some(s) if !s.crc_matches() => Err(DFUManifestationError::File),
+            Some(_) => Ok(()),
+        }
+    }
+}
+
+struct MkSuf {}
+
+impl UsbDeviceCtx for MkSuf {
+    type C<'c> = DFUClass<EmulatedUsbBus, SufMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, SufMem>> {
+        let mem = SufMem {
+            mem: MockMem::new(SUFMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+struct MkRetrySuf {}
+
+impl UsbDeviceCtx for MkRetrySuf {
+    type C<'c> = DFUClass<EmulatedUsbBus, RetryMem<SufMem, 2>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, RetryMem<SufMem, 2>>> {
+        let mem = SufMem {
+            mem: MockMem::new(SUFMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, RetryMem::new(mem)))
+    }
+}
+
+/// Image of `len` bytes with a DFU suffix for `vid`:`pid`
+fn file(len: usize, vid: u16, pid: u16) -> Vec<u8> {
+    let mut file: Vec<u8> = (0..len).map(|i| i as u8).collect();
+    file.extend_from_slice(&0x0200u16.to_le_bytes());
+    file.extend_from_slice(&pid.to_le_bytes());
+    file.extend_from_slice(&vid.to_le_bytes());
+    file.extend_from_slice(&0x0100u16.to_le_bytes());
+    file.extend_from_slice(b"UFD");
+    file.push(16);
+    let crc = crc32_update(0xffff_ffff, &file);
+    file.extend_from_slice(&crc.to_le_bytes());
+    file
+}
+
+/// Erases the memory page, downloads `file` in 32 byte blocks and finishes the download,
+/// returns `DFU_GETSTATUS` reply after the final request
+fn download_file<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, file: &[u8]) -> Vec<u8> {
+    let b = SUFMEM_BASE.to_le_bytes();
+
+    /* Download block 0 (command), Erase */
+    let vec = dev
+        .download(dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+        .expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+    for (i, block) in file.chunks(32).enumerate() {
+        let vec = dev.download(dfu, 2 + i as u16, block).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+    }
+
+    /* Download 0 length, may be rejected */
+    dev.download(dfu, 0, &[]).ok();
+
+    /* Get Status */
+    dev.get_status(dfu).expect("vec")
+}
+
+#[test]
+fn test_suffix_valid() {
+    MkSuf {}
+        .with_usb(|mut dfu, mut dev| {
+            // suffix is split between the last two blocks
+            let file = file(20, VID, PID);
+            let vec = download_file(&mut dfu, &mut dev, &file);
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..36], file[..]);
+        })
+        .expect("with_usb");
+
+    let s = suffixes();
+    assert_eq!(s.len(), 1);
+    let s = s[0].expect("suffix");
+    assert_eq!(
+        (s.id_vendor, s.id_product, s.bcd_device),
+        (VID, PID, 0x0200)
+    );
+    assert_eq!(s.bcd_dfu, 0x0100);
+    assert!(s.crc_matches());
+}
+
+#[test]
+fn test_suffix_full_blocks() {
+    MkSuf {}
+        .with_usb(|mut dfu, mut dev| {
+            // exactly two full blocks, suffix in the last one
+            let file = file(48, VID, 0xffff);
+            assert_eq!(file.len(), 64);
+            let vec = download_file(&mut dfu, &mut dev, &file);
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+        })
+        .expect("with_usb");
+
+    let s = suffixes();
+    assert!(s[0].expect("suffix").crc_matches());
+}
+
+#[test]
+fn test_suffix_none() {
+    MkSuf {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Image without suffix */
+            let vec = download_file(&mut dfu, &mut dev, &[0x55; 40]);
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Image shorter than suffix */
+            let vec = download_file(&mut dfu, &mut dev, &[0x55; 8]);
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+        })
+        .expect("with_usb");
+
+    assert_eq!(suffixes(), [None, None]);
+}
+
+#[test]
+fn test_suffix_rejected() {
+    MkSuf {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Image for another device */
+            let vec = download_file(&mut dfu, &mut dev, &file(40, VID, 0x1234));
+            assert_eq!(vec, status(DFUStatusCode::ErrTarget, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Image with a broken CRC */
+            let mut file = file(40, VID, PID);
+            file[0] ^= 1;
+            let vec = download_file(&mut dfu, &mut dev, &file);
+            assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+        })
+        .expect("with_usb");
+
+    let s = suffixes();
+    assert_eq!(s.len(), 2);
+    assert!(s[0].expect("suffix").crc_matches());
+    assert!(!s[1].expect("suffix").crc_matches());
+}
+
+#[test]
+fn test_suffix_rejected_wrapped() {
+    MkRetrySuf {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Image for another device */
+            let vec = download_file(&mut dfu, &mut dev, &file(40, VID, 0x1234));
+            assert_eq!(vec, status(DFUStatusCode::ErrTarget, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Image for this device */
+            let vec = download_file(&mut dfu, &mut dev, &file(40, VID, PID));
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+        })
+        .expect("with_usb");
+
+    assert_eq!(suffixes().len(), 2);
+}
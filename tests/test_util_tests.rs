@@ -0,0 +1,214 @@
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+use usbd_dfu::test_util::*;
+
+const MOCK_BASE: u32 = 0x0800_0000;
+
+struct MkMock {
+    faults: MockFaults,
+}
+
+impl UsbDeviceCtx for MkMock {
+    type C<'c> = DFUClass<EmulatedUsbBus, MockMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, MockMem>> {
+        let mut mem = MockMem::new(MOCK_BASE, 4, 256);
+        mem.faults = self.faults;
+        mem.memory_mut()[..4].copy_from_slice(&[1, 2, 3, 4]);
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_mock_layout() {
+    let mem = MockMem::new(MOCK_BASE, 4, 256);
+    assert_eq!(mem.layout(), "@Flash/0x08000000/4*256 g");
+    assert_eq!(mem.memory(), [0xff; 1024]);
+    assert_eq!(mem.page_size_at(MOCK_BASE + 0x3ff), Some(256));
+    assert_eq!(mem.page_size_at(MOCK_BASE + 0x400), None);
+
+    let mem = MockMem::new(0x2000_0000, 2, 2048);
+    assert_eq!(mem.layout(), "@Flash/0x20000000/2*2Kg");
+    assert_eq!(mem.initial_address_pointer(), 0x2000_0000);
+}
+
+#[test]
+fn test_mock_download() {
+    MkMock {
+        faults: MockFaults::default(),
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let b = (MOCK_BASE + 0x100).to_le_bytes();
+
+        /* Erase Page */
+        let vec = dev
+            .download(&mut dfu, 0, &[0x41, b[0], b[1], b[2], b[3]])
+            .expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 20, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        /* Download block 4 (offset 256) */
+        let vec = dev.download(&mut dfu, 4, &[0x55; 128]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        /* Download 0 length, manifestation */
+        let vec = dev.download(&mut dfu, 0, &[]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+        /* Upload block 2 (offset 0) */
+        let vec = dev.upload(&mut dfu, 2, 4).expect("vec");
+        assert_eq!(vec, [1, 2, 3, 4]);
+
+        /* Abort */
+        let vec = dev.abort(&mut dfu).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get State */
+        let vec = dev.get_state(&mut dfu).expect("vec");
+        assert_eq!(vec, [DFUState::DfuIdle as u8]);
+
+        let mem = dfu.release();
+        assert_eq!(
+            mem.calls,
+            [
+                MockCall::Erase(MOCK_BASE + 0x100),
+                MockCall::Program(MOCK_BASE + 0x100, 128),
+                MockCall::Manifestation,
+                MockCall::Read(MOCK_BASE, 4),
+            ]
+        );
+        assert_eq!(mem.memory()[0x100..0x180], [0x55; 128]);
+        assert_eq!(mem.memory()[0x180..0x200], [0xff; 128]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_mock_not_erased() {
+    MkMock {
+        faults: MockFaults::default(),
+    }
+    .with_usb(|mut dfu, mut dev| {
+        /* Download block 2 (offset 0), over data that is not erased */
+        let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::ErrVerify, 0, DFUState::DfuError));
+
+        /* Clear Status */
+        let vec = dev.clear_status(&mut dfu).expect("vec");
+        assert_eq!(vec, []);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_mock_faults() {
+    MkMock {
+        faults: MockFaults {
+            program: Some((MOCK_BASE + 0x2ff, DFUMemError::Prog)),
+            manifestation: Some(DFUManifestationError::Firmware),
+            ..Default::default()
+        },
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let b = (MOCK_BASE + 0x200).to_le_bytes();
+
+        /* Set Address Pointer */
+        let vec = dev
+            .download(&mut dfu, 0, &[0x21, b[0], b[1], b[2], b[3]])
+            .expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec[4], DFUState::DfuDnBusy as u8);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        /* Download block 2 (offset 0), fault is not covered */
+        let vec = dev.download(&mut dfu, 2, &[0x55; 128]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        /* Download block 3 (offset 128), the last byte fails */
+        let vec = dev.download(&mut dfu, 3, &[0xaa; 128]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::ErrProg, 0, DFUState::DfuError));
+
+        /* Clear Status */
+        let vec = dev.clear_status(&mut dfu).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Download 0 length, manifestation fails */
+        let vec = dev.download(&mut dfu, 0, &[]).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(
+            vec,
+            status(DFUStatusCode::ErrFirmware, 0, DFUState::DfuError)
+        );
+
+        let mem = dfu.release();
+        assert_eq!(mem.memory()[0x200..0x280], [0x55; 128]);
+        assert_eq!(mem.memory()[0x280..0x300], [0xff; 128]);
+    })
+    .expect("with_usb");
+}
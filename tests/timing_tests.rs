@@ -0,0 +1,181 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const TIMEMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that programs 4 bytes per millisecond, the first 512 bytes
+/// are small pages that erase faster.
+pub struct TimeMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for TimeMem {
+    const INITIAL_ADDRESS_POINTER: u32 = TIMEMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn program_time_ms(&self, length: usize) -> u32 {
+        1 + length.div_ceil(4) as u32
+    }
+
+    fn erase_time_ms(&self, address: u32) -> u32 {
+        if address < TIMEMEM_BASE + 512 {
+            5
+        } else if address < TIMEMEM_BASE + 768 {
+            40
+        } else {
+            // doesn't fit in bwPollTimeout
+            u32::MAX
+        }
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkTime {}
+
+impl UsbDeviceCtx for MkTime {
+    type C<'c> = DFUClass<EmulatedUsbBus, TimeMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, TimeMem>> {
+        let mem = TimeMem {
+            mem: MockMem::new(TIMEMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_program_time_by_length() {
+    MkTime {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0), full block */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 9, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 1), short block */
+            let vec = dev.download(&mut dfu, 3, &[0xaa; 5]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 3, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..32], [0x55; 32]);
+            assert_eq!(mem.mem.memory()[32..37], [0xaa; 5]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_erase_time_by_address() {
+    MkTime {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), erase a small page */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, 0x00, 0x01, 0x00, 0x08])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 0 (command), erase a large page */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, 0x00, 0x02, 0x00, 0x08])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 40, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 0 (command), erase all is not affected */
+            let vec = dev.download(&mut dfu, 0, &[0x41]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 30, DFUState::DfuDnBusy));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_erase_time_saturated() {
+    MkTime {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), erase with a huge time */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x41, 0x00, 0x03, 0x00, 0x08])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec[1..4], [0xff, 0xff, 0xff]);
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::OK, 0xff_ffff, DFUState::DfuDnBusy)
+            );
+        })
+        .expect("with_usb");
+}
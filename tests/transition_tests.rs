@@ -0,0 +1,215 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const LEDMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory that records state transitions.
+pub struct LedMem {
+    mem: MockMem,
+    transitions: Vec<(DFUState, DFUState, DFUStatusCode)>,
+}
+
+impl DFUMemIO for LedMem {
+    const INITIAL_ADDRESS_POINTER: u32 = LEDMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn on_state_change(&mut self, old: DFUState, new: DFUState, status: DFUStatusCode) {
+        self.transitions.push((old, new, status));
+    }
+}
+
+struct MkLed {}
+
+impl UsbDeviceCtx for MkLed {
+    type C<'c> = DFUClass<EmulatedUsbBus, LedMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, LedMem>> {
+        let mem = LedMem {
+            mem: MockMem::new(LEDMEM_BASE, 1, 1024),
+            transitions: Vec::new(),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_transitions_download() {
+    MkLed {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Get Status, no change */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            // program completes in dfuDNBUSY, device returns to dfuDNLOAD-SYNC
+            // and then reports dfuDNLOAD-IDLE in the same request
+            let mem = dfu.release();
+            assert_eq!(
+                mem.transitions,
+                [
+                    (
+                        DFUState::DfuIdle,
+                        DFUState::DfuDnloadSync,
+                        DFUStatusCode::OK
+                    ),
+                    (
+                        DFUState::DfuDnloadSync,
+                        DFUState::DfuDnBusy,
+                        DFUStatusCode::OK
+                    ),
+                    (
+                        DFUState::DfuDnBusy,
+                        DFUState::DfuDnloadSync,
+                        DFUStatusCode::OK
+                    ),
+                    (
+                        DFUState::DfuDnloadSync,
+                        DFUState::DfuDnloadIdle,
+                        DFUStatusCode::OK
+                    ),
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_transitions_error() {
+    MkLed {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2, longer than wTransferSize */
+            let vec = dev.download(&mut dfu, 2, &[0; 64]);
+            assert!(vec.is_err());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.transitions,
+                [
+                    (
+                        DFUState::DfuIdle,
+                        DFUState::DfuError,
+                        DFUStatusCode::ErrStalledPkt
+                    ),
+                    (DFUState::DfuError, DFUState::DfuIdle, DFUStatusCode::OK),
+                ]
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_transitions_clear_status_idle() {
+    MkLed {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Clear Status in dfuIDLE */
+            let e = dev.clear_status(&mut dfu).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.transitions, []);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_transitions_clear_status_dnload_idle() {
+    MkLed {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Clear Status in dfuDNLOAD-IDLE */
+            let e = dev.clear_status(&mut dfu).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 3 (offset 32), the session continues */
+            let vec = dev.download(&mut dfu, 3, &[0xaa; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            let mem = dfu.release();
+            assert_eq!(
+                mem.transitions.last().map(|t| t.1),
+                Some(DFUState::DfuDnloadSync)
+            );
+            assert!(mem.transitions.iter().all(|t| t.1 != DFUState::DfuError));
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,159 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const UNPMEM_BASE: u32 = 0x0800_0000;
+
+/// Read-protected memory, unprotect erases it.
+pub struct UnpMem {
+    mem: MockMem,
+    protected: bool,
+}
+
+impl DFUMemIO for UnpMem {
+    const INITIAL_ADDRESS_POINTER: u32 = UNPMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const HAS_READ_UNPROTECT: bool = true;
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const READ_UNPROTECT_TIME_MS: u32 = 0x1234;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        if self.protected {
+            return Err(DFUMemError::Target);
+        }
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn read_unprotect(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()?;
+        self.protected = false;
+        Ok(())
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkUnp {
+    /// `read_unprotect()` fails
+    fails: bool,
+}
+
+impl UsbDeviceCtx for MkUnp {
+    type C<'c> = DFUClass<EmulatedUsbBus, UnpMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, UnpMem>> {
+        let mut mem = UnpMem {
+            mem: MockMem::new(UNPMEM_BASE, 1, 1024),
+            protected: true,
+        };
+        mem.mem.memory_mut().fill(0x55);
+        if self.fails {
+            mem.mem.faults.erase_all = Some(DFUMemError::ErrVendor);
+        }
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_unprotect_get_commands() {
+    MkUnp { fails: false }
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 0 (Get Commands) */
+            let vec = dev.upload(&mut dfu, 0, 32).expect("vec");
+            assert_eq!(vec, [0x00, 0x21, 0x41, 0x92]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_unprotect() {
+    MkUnp { fails: false }
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 2, read protected */
+            let e = dev.upload(&mut dfu, 2, 32).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrTarget, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 0 (command), Read Unprotect */
+            let vec = dev.download(&mut dfu, 0, &[0x92]).expect("vec");
+            assert_eq!(vec, []);
+            assert!(dfu.download_in_progress());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0x1234, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Upload block 2 */
+            let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+            assert_eq!(vec, [0xff; 32]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_unprotect_error() {
+    MkUnp { fails: true }
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), Read Unprotect */
+            let vec = dev.download(&mut dfu, 0, &[0x92]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0x1234, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrVendor, 0, DFUState::DfuError));
+
+            let mem = dfu.release();
+            assert!(mem.protected);
+        })
+        .expect("with_usb");
+}
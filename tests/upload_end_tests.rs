@@ -0,0 +1,163 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const ENDMEM_BASE: u32 = 0x0800_0000;
+
+/// Memory with an image that ends at `end`.
+pub struct EndMem {
+    mem: MockMem,
+    end: u32,
+}
+
+impl DFUMemIO for EndMem {
+    const INITIAL_ADDRESS_POINTER: u32 = ENDMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*256 g";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn upload_end_address(&self) -> Option<u32> {
+        Some(self.end)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkEnd {
+    end: u32,
+}
+
+impl UsbDeviceCtx for MkEnd {
+    type C<'c> = DFUClass<EmulatedUsbBus, EndMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, EndMem>> {
+        let mut mem = EndMem {
+            mem: MockMem::new(ENDMEM_BASE, 1, 256),
+            end: self.end,
+        };
+        for (i, b) in mem.mem.memory_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_upload_end_mid_block() {
+    MkEnd {
+        end: ENDMEM_BASE + 40,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        /* Upload block 2 (offset 0) */
+        let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+        assert_eq!(vec, (0..32).collect::<Vec<u8>>());
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+
+        /* Upload block 3 (offset 1), short frame */
+        let vec = dev.upload(&mut dfu, 3, 32).expect("vec");
+        assert_eq!(vec, (32..40).collect::<Vec<u8>>());
+
+        /* Get Status, upload is complete */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+        let mem = dfu.release();
+        assert_eq!(
+            mem.mem.calls,
+            [
+                MockCall::Read(ENDMEM_BASE, 32),
+                MockCall::Read(ENDMEM_BASE + 32, 8)
+            ]
+        );
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_upload_end_block_boundary() {
+    MkEnd {
+        end: ENDMEM_BASE + 64,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        /* Upload block 2 (offset 0) */
+        let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+        assert_eq!(vec, (0..32).collect::<Vec<u8>>());
+
+        /* Upload block 3 (offset 1), full block */
+        let vec = dev.upload(&mut dfu, 3, 32).expect("vec");
+        assert_eq!(vec, (32..64).collect::<Vec<u8>>());
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+
+        /* Upload block 4 (offset 2), empty frame */
+        let vec = dev.upload(&mut dfu, 4, 32).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status, upload is complete */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+        let mem = dfu.release();
+        assert_eq!(
+            mem.mem.calls,
+            [
+                MockCall::Read(ENDMEM_BASE, 32),
+                MockCall::Read(ENDMEM_BASE + 32, 32)
+            ]
+        );
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_upload_end_below_address() {
+    MkEnd {
+        end: ENDMEM_BASE + 64,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        dfu.set_address_pointer(ENDMEM_BASE + 128);
+
+        /* Upload block 2 (offset 0), empty frame */
+        let vec = dev.upload(&mut dfu, 2, 32).expect("vec");
+        assert_eq!(vec, []);
+
+        /* Get Status */
+        let vec = dev.get_status(&mut dfu).expect("vec");
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+        let mem = dfu.release();
+        assert_eq!(mem.mem.calls, []);
+    })
+    .expect("with_usb");
+}
@@ -0,0 +1,183 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const OTPMEM_BASE: u32 = 0x0800_0000;
+
+/// Set OTP word, executed immediately
+const CMD_SET_OTP: u8 = 0xe1;
+/// Store serial number, deferred
+const CMD_STORE_SERIAL: u8 = 0xe2;
+
+/// Memory with vendor commands.
+pub struct OtpMem {
+    mem: MockMem,
+    otp: Option<u32>,
+    /// Serial number waiting for `vendor_command_execute()`
+    pending_serial: Option<[u8; 4]>,
+    serial: Option<[u8; 4]>,
+}
+
+impl DFUMemIO for OtpMem {
+    const INITIAL_ADDRESS_POINTER: u32 = OTPMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn vendor_commands(&self) -> &[u8] {
+        &[CMD_SET_OTP, CMD_STORE_SERIAL]
+    }
+
+    fn vendor_dnload_command(
+        &mut self,
+        cmd: u8,
+        payload: &[u8],
+    ) -> Result<VendorCommandOutcome, DFUMemError> {
+        let word: [u8; 4] = match cmd {
+            CMD_SET_OTP | CMD_STORE_SERIAL => payload.try_into().map_err(|_| DFUMemError::File)?,
+            _ => return Ok(VendorCommandOutcome::Unsupported),
+        };
+        if cmd == CMD_SET_OTP {
+            self.otp = Some(u32::from_le_bytes(word));
+            Ok(VendorCommandOutcome::Done)
+        } else {
+            self.pending_serial = Some(word);
+            Ok(VendorCommandOutcome::Deferred(200))
+        }
+    }
+
+    fn vendor_command_execute(&mut self, cmd: u8) -> Result<(), DFUMemError> {
+        assert_eq!(cmd, CMD_STORE_SERIAL);
+        self.serial = self.pending_serial.take();
+        Ok(())
+    }
+}
+
+struct MkOtp {}
+
+impl UsbDeviceCtx for MkOtp {
+    type C<'c> = DFUClass<EmulatedUsbBus, OtpMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, OtpMem>> {
+        let mem = OtpMem {
+            mem: MockMem::new(OTPMEM_BASE, 1, 1024),
+            otp: None,
+            pending_serial: None,
+            serial: None,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_vendor_command_immediate() {
+    MkOtp {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), set OTP word */
+            let vec = dev
+                .download(&mut dfu, 0, &[CMD_SET_OTP, 0x78, 0x56, 0x34, 0x12])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.otp, Some(0x1234_5678));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_vendor_command_deferred() {
+    MkOtp {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), store serial number */
+            let vec = dev
+                .download(&mut dfu, 0, &[CMD_STORE_SERIAL, 1, 2, 3, 4])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 200, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.serial, Some([1, 2, 3, 4]));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_vendor_command_errors() {
+    MkOtp {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), invalid argument */
+            let vec = dev.download(&mut dfu, 0, &[CMD_SET_OTP, 0x78]);
+            assert!(vec.is_err());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrFile, 0, DFUState::DfuError));
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Download block 0 (command), unknown command */
+            let vec = dev.download(&mut dfu, 0, &[0xe3]);
+            assert!(vec.is_err());
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+
+            let mem = dfu.release();
+            assert_eq!(mem.otp, None);
+        })
+        .expect("with_usb");
+}
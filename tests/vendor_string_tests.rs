@@ -0,0 +1,144 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const VNDMEM_BASE: u32 = 0x0800_0000;
+
+/// Index of the vendor error string, after the interface string
+const VENDOR_STRING: u8 = 5;
+
+/// Memory that can't program 0x00 bytes and explains why.
+pub struct VndMem {
+    mem: MockMem,
+    /// The stored block has 0x00 bytes
+    zeros: bool,
+    error: Option<&'static str>,
+}
+
+impl DFUMemIO for VndMem {
+    const INITIAL_ADDRESS_POINTER: u32 = VNDMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const HAS_VENDOR_ERROR_STRING: bool = true;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        Err(DFUMemError::ErrVendor)
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.zeros = src.contains(&0);
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        if self.zeros {
+            self.error = Some("Zero bytes are not allowed");
+            return Err(DFUMemError::ErrVendor);
+        }
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+
+    fn vendor_error_string(&self) -> Option<&str> {
+        self.error
+    }
+}
+
+struct MkVnd {}
+
+impl UsbDeviceCtx for MkVnd {
+    type C<'c> = DFUClass<EmulatedUsbBus, VndMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, VndMem>> {
+        let mem = VndMem {
+            mem: MockMem::new(VNDMEM_BASE, 1, 1024),
+            zeros: false,
+            error: None,
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_vendor_string() {
+    MkVnd {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0x00; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            let mut expected = status(DFUStatusCode::ErrVendor, 0, DFUState::DfuError);
+            expected[5] = VENDOR_STRING;
+            assert_eq!(vec, expected);
+
+            let istr = dev
+                .device_get_string(&mut dfu, VENDOR_STRING, 0x409)
+                .expect("str");
+            assert_eq!(istr, "Zero bytes are not allowed");
+
+            // interface string is not changed
+            let istr = dev.device_get_string(&mut dfu, 4, 0x409).expect("str");
+            assert_eq!(istr, VndMem::MEM_INFO_STRING);
+
+            /* Clear Status */
+            let vec = dev.clear_status(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status, not a vendor error */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_vendor_string_not_set() {
+    MkVnd {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (command), Mass Erase */
+            let vec = dev.download(&mut dfu, 0, &[0x41]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 30, DFUState::DfuDnBusy));
+
+            /* Get Status, no description */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::ErrVendor, 0, DFUState::DfuError));
+
+            dev.device_get_string(&mut dfu, VENDOR_STRING, 0x409)
+                .expect_err("stall");
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,288 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::buffered::BufferedMem;
+use usbd_dfu::class::*;
+
+const SPIMEM_BASE: u32 = 0x9000_0000;
+
+#[derive(Clone, Copy)]
+enum Fault {
+    None,
+    /// Write of the byte at the offset is silently lost
+    LostWrite(usize),
+    /// Memory can't be read
+    NoRead,
+}
+
+/// External flash-like mock memory, programmed with `program_block()`.
+pub struct SpiMem<const VERIFY: bool> {
+    mem: MockMem,
+    fault: Fault,
+    async_write: bool,
+    /// Background write: address, data, and polls until it completes
+    in_flight: Option<(u32, Vec<u8>, u8)>,
+}
+
+impl<const VERIFY: bool> SpiMem<VERIFY> {
+    fn write(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        self.mem.store_write_buffer(data).unwrap();
+        self.mem.program(address, data.len())?;
+        if let Fault::LostWrite(lost) = self.fault {
+            let offset = (address - SPIMEM_BASE) as usize;
+            if (offset..offset + data.len()).contains(&lost) {
+                self.mem.memory_mut()[lost] = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads of the memory, in order
+    fn reads(&self) -> Vec<MockCall> {
+        let reads = self.mem.calls.iter().copied();
+        reads.filter(|c| matches!(c, MockCall::Read(..))).collect()
+    }
+}
+
+impl<const VERIFY: bool> DFUMemIO for SpiMem<VERIFY> {
+    const INITIAL_ADDRESS_POINTER: u32 = SPIMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@SPI Flash/0x90000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 64;
+    const VERIFY_AFTER_PROGRAM: bool = VERIFY;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn program_block(&mut self, address: u32, data: &[u8]) -> Result<(), DFUMemError> {
+        if self.async_write {
+            self.in_flight = Some((address, data.to_vec(), 2));
+            Ok(())
+        } else {
+            self.write(address, data)
+        }
+    }
+
+    fn operation_busy(&mut self) -> bool {
+        match &mut self.in_flight {
+            Some((_, _, 0)) => {
+                let (address, data, _) = self.in_flight.take().unwrap();
+                self.write(address, &data).expect("write");
+                false
+            }
+            Some((_, _, polls)) => {
+                *polls -= 1;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkVerify<const VERIFY: bool> {
+    fault: Fault,
+    async_write: bool,
+}
+
+impl<const VERIFY: bool> UsbDeviceCtx for MkVerify<VERIFY> {
+    type C<'c> = DFUClass<EmulatedUsbBus, BufferedMem<SpiMem<VERIFY>, 64>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, BufferedMem<SpiMem<VERIFY>, 64>>> {
+        let mut mem = SpiMem {
+            mem: MockMem::new(SPIMEM_BASE, 1, 1024),
+            fault: self.fault,
+            async_write: self.async_write,
+            in_flight: None,
+        };
+        if let Fault::NoRead = self.fault {
+            mem.mem.faults.read = Some((SPIMEM_BASE, DFUMemError::Unknown));
+        }
+        Ok(DFUClass::new(alloc, BufferedMem::new(mem)))
+    }
+}
+
+/// Download block 2 (offset 0), and return the status after it's programmed
+fn download<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>) -> Vec<u8> {
+    /* Download block 2 (offset 0) */
+    let vec = dev.download(dfu, 2, &[0x55; 64]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    dev.get_status(dfu).expect("vec")
+}
+
+#[test]
+fn test_verify_match() {
+    MkVerify::<true> {
+        fault: Fault::None,
+        async_write: false,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = download(&mut dfu, &mut dev);
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        let mem = dfu.release().into_inner();
+        assert_eq!(
+            mem.reads(),
+            [
+                MockCall::Read(SPIMEM_BASE, 32),
+                MockCall::Read(SPIMEM_BASE + 32, 32)
+            ]
+        );
+        assert_eq!(mem.mem.memory()[..64], [0x55; 64]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_verify_mismatch() {
+    MkVerify::<true> {
+        fault: Fault::LostWrite(40),
+        async_write: false,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = download(&mut dfu, &mut dev);
+        assert_eq!(vec, status(DFUStatusCode::ErrVerify, 0, DFUState::DfuError));
+
+        /* Clear Status */
+        let vec = dev.clear_status(&mut dfu).expect("vec");
+        assert_eq!(vec, []);
+
+        let mem = dfu.release().into_inner();
+        assert_eq!(
+            mem.reads(),
+            [
+                MockCall::Read(SPIMEM_BASE, 32),
+                MockCall::Read(SPIMEM_BASE + 32, 32)
+            ]
+        );
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_verify_no_read() {
+    MkVerify::<true> {
+        fault: Fault::NoRead,
+        async_write: false,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = download(&mut dfu, &mut dev);
+        assert_eq!(vec, status(DFUStatusCode::ErrVerify, 0, DFUState::DfuError));
+
+        /* Clear Status */
+        let vec = dev.clear_status(&mut dfu).expect("vec");
+        assert_eq!(vec, []);
+
+        let mem = dfu.release().into_inner();
+        assert_eq!(mem.reads(), [MockCall::Read(SPIMEM_BASE, 32)]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_verify_disabled() {
+    MkVerify::<false> {
+        fault: Fault::LostWrite(40),
+        async_write: false,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = download(&mut dfu, &mut dev);
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        let mem = dfu.release().into_inner();
+        assert_eq!(mem.reads(), []);
+        assert_eq!(mem.mem.memory()[40], 0);
+    })
+    .expect("with_usb");
+}
+
+/// Download block 2 (offset 0) to a memory that writes in background,
+/// and return the status after it's programmed
+fn download_async<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>) -> Vec<u8> {
+    /* Download block 2 (offset 0) */
+    let vec = dev.download(dfu, 2, &[0x55; 64]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status until the write completes */
+    for _ in 0..8 {
+        let vec = dev.get_status(dfu).expect("vec");
+        if vec[4] != DFUState::DfuDnBusy as u8 {
+            return vec;
+        }
+    }
+    panic!("write did not complete");
+}
+
+#[test]
+fn test_verify_async_write() {
+    MkVerify::<true> {
+        fault: Fault::None,
+        async_write: true,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = download_async(&mut dfu, &mut dev);
+        assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+        let mem = dfu.release().into_inner();
+        assert_eq!(
+            mem.reads(),
+            [
+                MockCall::Read(SPIMEM_BASE, 32),
+                MockCall::Read(SPIMEM_BASE + 32, 32)
+            ]
+        );
+        assert_eq!(mem.mem.memory()[..64], [0x55; 64]);
+    })
+    .expect("with_usb");
+}
+
+#[test]
+fn test_verify_async_write_mismatch() {
+    MkVerify::<true> {
+        fault: Fault::LostWrite(40),
+        async_write: true,
+    }
+    .with_usb(|mut dfu, mut dev| {
+        let vec = download_async(&mut dfu, &mut dev);
+        assert_eq!(vec, status(DFUStatusCode::ErrVerify, 0, DFUState::DfuError));
+
+        let mem = dfu.release().into_inner();
+        assert_eq!(
+            mem.reads(),
+            [
+                MockCall::Read(SPIMEM_BASE, 32),
+                MockCall::Read(SPIMEM_BASE + 32, 32)
+            ]
+        );
+    })
+    .expect("with_usb");
+}
@@ -0,0 +1,253 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const VERMEM_BASE: u32 = 0x0800_0000;
+
+const VERMEM_PAGE: u32 = 256;
+
+/// Memory with `V` as `DFU_VERSION` value, erases a page before programming
+/// its first block.
+pub struct VerMem<const V: u16> {
+    mem: MockMem,
+}
+
+impl<const V: u16> DFUMemIO for VerMem<V> {
+    const INITIAL_ADDRESS_POINTER: u32 = VERMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 32;
+    const DFU_VERSION: u16 = V;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        if (address - VERMEM_BASE).is_multiple_of(VERMEM_PAGE) {
+            self.mem.erase(address)?;
+        }
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkVer<const V: u16> {}
+
+impl<const V: u16> UsbDeviceCtx for MkVer<V> {
+    type C<'c> = DFUClass<EmulatedUsbBus, VerMem<V>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, VerMem<V>>> {
+        let mut mem = VerMem {
+            mem: MockMem::new(VERMEM_BASE, 4, VERMEM_PAGE),
+        };
+        for (i, b) in mem.mem.memory_mut().iter_mut().enumerate() {
+            *b = i as u8;
+        }
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+#[test]
+fn test_version_descriptor() {
+    MkVer::<DFU_VERSION_DFUSE> {}
+        .with_usb(|mut dfu, mut dev| {
+            let vec = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 130)
+                .expect("vec");
+            assert_eq!(vec[18..], [9, 0x21, 0b1111, 250, 0, 32, 0, 0x1a, 0x01]);
+        })
+        .expect("with_usb");
+
+    MkVer::<DFU_VERSION_1_1> {}
+        .with_usb(|mut dfu, mut dev| {
+            let vec = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 130)
+                .expect("vec");
+            assert_eq!(vec[18..], [9, 0x21, 0b1111, 250, 0, 32, 0, 0x10, 0x01]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_version_get_commands() {
+    MkVer::<DFU_VERSION_DFUSE> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 0, Get Commands */
+            let vec = dev.upload(&mut dfu, 0, 32).expect("vec");
+            assert_eq!(vec, [0x00, 0x21, 0x41]);
+        })
+        .expect("with_usb");
+
+    MkVer::<DFU_VERSION_1_1> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 0, data at offset 0, no command list */
+            let vec = dev.upload(&mut dfu, 0, 32).expect("vec");
+            assert_eq!(vec[..4], [0, 1, 2, 3]);
+
+            /* Upload block 1 (offset 32) */
+            let vec = dev.upload(&mut dfu, 1, 32).expect("vec");
+            assert_eq!(vec[..4], [32, 33, 34, 35]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_version_1_1_download() {
+    MkVer::<DFU_VERSION_1_1> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Download block 0 (offset 0), not a DfuSe command */
+            let vec = dev.download(&mut dfu, 0, &[0x55; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 1 (offset 32) */
+            let vec = dev.download(&mut dfu, 1, &[0xaa; 32]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download 0 length, with the next block number */
+            let vec = dev.download(&mut dfu, 2, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..32], [0x55; 32]);
+            assert_eq!(mem.mem.memory()[32..64], [0xaa; 32]);
+            // the rest of the page is erased
+            assert_eq!(mem.mem.memory()[64], 0xff);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_version_1_1_block_limit() {
+    MkVer::<DFU_VERSION_1_1> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Upload block 0xfffe, does not fit in DfuSe numbering */
+            let e = dev.upload(&mut dfu, 0xfffe, 32).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(
+                vec,
+                status(DFUStatusCode::ErrStalledPkt, 0, DFUState::DfuError)
+            );
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_version_1_1_round_trip() {
+    MkVer::<DFU_VERSION_1_1> {}
+        .with_usb(|mut dfu, mut dev| {
+            let image: Vec<u8> = (0..100).map(|i| 0x80 ^ i as u8).collect();
+
+            /* Download blocks 0 to 3, the last one is short */
+            for (block, data) in image.chunks(32).enumerate() {
+                let vec = dev.download(&mut dfu, block as u16, data).expect("vec");
+                assert_eq!(vec, []);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+            }
+
+            /* Download 0 length */
+            let vec = dev.download(&mut dfu, 4, &[]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload blocks 0 to 3 */
+            let mut uploaded = Vec::new();
+            for block in 0..4 {
+                let vec = dev.upload(&mut dfu, block, 32).expect("vec");
+                assert_eq!(vec.len(), 32);
+                uploaded.extend_from_slice(&vec);
+            }
+            assert_eq!(uploaded[..100], image[..]);
+            assert_eq!(uploaded[100..], [0xff; 28]);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuUploadIdle));
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            let mem = dfu.release();
+            let erased: Vec<_> = mem
+                .mem
+                .calls
+                .iter()
+                .filter(|c| matches!(c, MockCall::Erase(_)))
+                .collect();
+            assert_eq!(erased, [&MockCall::Erase(VERMEM_BASE)]);
+        })
+        .expect("with_usb");
+}
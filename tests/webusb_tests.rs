@@ -0,0 +1,248 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usbd_dfu::class::*;
+use usbd_dfu::webusb::{LANDING_PAGE_INDEX, WEBUSB_GET_URL};
+
+const WEBMEM_BASE: u32 = 0x0800_0000;
+
+const VENDOR_CODE: u8 = 0x37;
+
+const WEBUSB_UUID: [u8; 16] = [
+    0x38, 0xb6, 0x08, 0x34, 0xa9, 0x09, 0xa0, 0x47, 0x8b, 0xfd, 0xa0, 0x76, 0x88, 0x15, 0xb6, 0x65,
+];
+
+/// Memory with WebUSB descriptors, `L` selects landing page URL.
+pub struct WebMem<const L: u8> {
+    mem: MockMem,
+}
+
+impl<const L: u8> DFUMemIO for WebMem<L> {
+    const INITIAL_ADDRESS_POINTER: u32 = WEBMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 128;
+    const WEBUSB_VENDOR_CODE: u8 = VENDOR_CODE;
+    const WEBUSB_LANDING_PAGE: Option<&'static str> = match L {
+        0 => None,
+        1 => Some("https://example.com/dfu"),
+        _ => Some("ftp://example.com/"),
+    };
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkWeb<const L: u8> {}
+
+impl<const L: u8> UsbDeviceCtx for MkWeb<L> {
+    type C<'c> = DFUClass<EmulatedUsbBus, WebMem<L>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, WebMem<L>>> {
+        let mem = WebMem {
+            mem: MockMem::new(WEBMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Vendor device request
+fn vendor_read<'a, C, X>(
+    cls: &mut C,
+    dev: &mut Device<'a, C, X>,
+    req: u8,
+    value: u16,
+    index: u16,
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    dev.control_read(
+        cls,
+        CtrRequestType::to_host().vendor().device(),
+        req,
+        value,
+        index,
+        255,
+    )
+}
+
+/// WebUSB platform capability descriptor in BOS descriptor `bos`
+fn webusb_capability(bos: &[u8]) -> &[u8] {
+    assert_eq!(bos[..2], [5, 0x0f]);
+    assert_eq!(u16::from_le_bytes([bos[2], bos[3]]) as usize, bos.len());
+
+    let mut caps = &bos[5..];
+    while !caps.is_empty() {
+        let (cap, rest) = caps.split_at(caps[0] as usize);
+        if cap[1..3] == [0x10, 0x05] && cap[4..20] == WEBUSB_UUID {
+            return cap;
+        }
+        caps = rest;
+    }
+    panic!("no WebUSB capability");
+}
+
+#[test]
+fn test_webusb_bos() {
+    MkWeb::<1> {}
+        .with_usb(|mut dfu, mut dev| {
+            let bos = dev
+                .device_get_descriptor(&mut dfu, 0x0f, 0, 0, 255)
+                .expect("bos");
+
+            // bcdVersion, bVendorCode, iLandingPage
+            let cap = webusb_capability(&bos);
+            assert_eq!(cap.len(), 24);
+            assert_eq!(cap[20..24], [0x00, 0x01, VENDOR_CODE, 1]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_webusb_url() {
+    MkWeb::<1> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* GET_URL */
+            let vec = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE,
+                LANDING_PAGE_INDEX,
+                WEBUSB_GET_URL,
+            )
+            .expect("vec");
+            let mut expect = vec![3 + 15, 3, 1];
+            expect.extend_from_slice(b"example.com/dfu");
+            assert_eq!(vec, expect);
+
+            /* GET_URL, unknown URL index */
+            let e =
+                vendor_read(&mut dfu, &mut dev, VENDOR_CODE, 2, WEBUSB_GET_URL).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Another bRequest */
+            let e = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE + 1,
+                LANDING_PAGE_INDEX,
+                WEBUSB_GET_URL,
+            )
+            .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Another wIndex */
+            let e = vendor_read(&mut dfu, &mut dev, VENDOR_CODE, LANDING_PAGE_INDEX, 1)
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_webusb_url_scheme() {
+    MkWeb::<2> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* GET_URL, the whole URL with bScheme 255 */
+            let vec = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE,
+                LANDING_PAGE_INDEX,
+                WEBUSB_GET_URL,
+            )
+            .expect("vec");
+            let mut expect = vec![3 + 18, 3, 255];
+            expect.extend_from_slice(b"ftp://example.com/");
+            assert_eq!(vec, expect);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_webusb_no_landing_page() {
+    MkWeb::<0> {}
+        .with_usb(|mut dfu, mut dev| {
+            let bos = dev
+                .device_get_descriptor(&mut dfu, 0x0f, 0, 0, 255)
+                .expect("bos");
+
+            // iLandingPage
+            let cap = webusb_capability(&bos);
+            assert_eq!(cap[23], 0);
+
+            /* GET_URL */
+            let e = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE,
+                LANDING_PAGE_INDEX,
+                WEBUSB_GET_URL,
+            )
+            .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_webusb_dfu_intact() {
+    MkWeb::<1> {}
+        .with_usb(|mut dfu, mut dev| {
+            let desc = dev
+                .device_get_descriptor(&mut dfu, 2, 0, 0, 255)
+                .expect("desc");
+
+            // DFU interface and functional descriptors
+            assert_eq!(desc.len(), 9 + 9 + 9);
+            assert_eq!(desc[9..17], [9, 4, 0, 0, 0, 0xfe, 0x01, 0x02]);
+            assert_eq!(desc[18..27], [9, 0x21, 0x0f, 250, 0, 128, 0, 0x1a, 0x01]);
+
+            /* GET_URL */
+            let vec = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE,
+                LANDING_PAGE_INDEX,
+                WEBUSB_GET_URL,
+            )
+            .expect("vec");
+            assert_eq!(vec[2], 1);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            /* Upload block 2 (offset 0) */
+            let vec = dev.upload(&mut dfu, 2, 128).expect("vec");
+            assert_eq!(vec, [0xff; 128]);
+        })
+        .expect("with_usb");
+}
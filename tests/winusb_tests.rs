@@ -0,0 +1,267 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usb_device::class::UsbClass;
+use usbd_dfu::class::*;
+use usbd_dfu::winusb::{descriptor_set_len, MS_OS_20_DESCRIPTOR_INDEX};
+
+const WINMEM_BASE: u32 = 0x0800_0000;
+
+const GUID: &str = "{6b6c7a2e-1f34-4d6b-9d0a-3c5e8f7b2a10}";
+const VENDOR_CODE: u8 = 0x42;
+
+/// Memory with WinUSB descriptors, `C` is `WINUSB_COMPOSITE` value.
+pub struct WinMem<const C: bool> {
+    mem: MockMem,
+}
+
+impl<const C: bool> DFUMemIO for WinMem<C> {
+    const INITIAL_ADDRESS_POINTER: u32 = WINMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/1*1Kg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = 128;
+    const WINUSB_VENDOR_CODE: u8 = VENDOR_CODE;
+    const WINUSB_GUID: &'static str = GUID;
+    const WINUSB_COMPOSITE: bool = C;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkWin<const C: bool> {}
+
+impl<const C: bool> UsbDeviceCtx for MkWin<C> {
+    type C<'c> = DFUClass<EmulatedUsbBus, WinMem<C>>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, WinMem<C>>> {
+        if C {
+            // interface 0 belongs to another function of a composite device
+            alloc.interface();
+        }
+        let mem = WinMem {
+            mem: MockMem::new(WINMEM_BASE, 1, 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Vendor device request
+fn vendor_read<'a, C, X>(
+    cls: &mut C,
+    dev: &mut Device<'a, C, X>,
+    req: u8,
+    index: u16,
+    length: u16,
+) -> AnyResult<Vec<u8>>
+where
+    C: UsbClass<EmulatedUsbBus>,
+    X: UsbDeviceCtx<C<'a> = C>,
+{
+    dev.control_read(
+        cls,
+        CtrRequestType::to_host().vendor().device(),
+        req,
+        0,
+        index,
+        length,
+    )
+}
+
+/// MS OS 2.0 platform capability descriptor in BOS descriptor `bos`
+fn platform_capability(bos: &[u8]) -> &[u8] {
+    assert_eq!(bos[..2], [5, 0x0f]);
+    assert_eq!(u16::from_le_bytes([bos[2], bos[3]]) as usize, bos.len());
+
+    let mut caps = &bos[5..];
+    while !caps.is_empty() {
+        let (cap, rest) = caps.split_at(caps[0] as usize);
+        if cap[1..3] == [0x10, 0x05] {
+            return cap;
+        }
+        caps = rest;
+    }
+    panic!("no platform capability");
+}
+
+fn utf16(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+/// Checks feature descriptors at the end of descriptor set `desc`
+fn check_features(desc: &[u8]) {
+    assert_eq!(desc[..4], [20, 0, 3, 0]);
+    assert_eq!(desc[4..12], *b"WINUSB\0\0");
+    assert_eq!(desc[12..20], [0; 8]);
+
+    let prop = &desc[20..];
+    assert_eq!(u16::from_le_bytes([prop[0], prop[1]]) as usize, prop.len());
+    // MS_OS_20_FEATURE_REG_PROPERTY, REG_MULTI_SZ
+    assert_eq!(prop[2..6], [4, 0, 7, 0]);
+
+    let name = utf16("DeviceInterfaceGUIDs\0");
+    assert_eq!(u16::from_le_bytes([prop[6], prop[7]]) as usize, name.len());
+    assert_eq!(prop[8..8 + name.len()], name);
+
+    let data = &prop[8 + name.len()..];
+    let guids = utf16(&format!("{GUID}\0\0"));
+    assert_eq!(u16::from_le_bytes([data[0], data[1]]) as usize, guids.len());
+    assert_eq!(data[2..], guids);
+}
+
+#[test]
+fn test_winusb_bos() {
+    MkWin::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            let bos = dev
+                .device_get_descriptor(&mut dfu, 0x0f, 0, 0, 255)
+                .expect("bos");
+
+            let cap = platform_capability(&bos);
+            assert_eq!(cap.len(), 28);
+            assert_eq!(
+                cap[4..20],
+                [
+                    0xdf, 0x60, 0xdd, 0xd8, 0x89, 0x45, 0xc7, 0x4c, 0x9c, 0xd2, 0x65, 0x9d, 0x9e,
+                    0x64, 0x8a, 0x9f
+                ]
+            );
+            // dwWindowsVersion
+            assert_eq!(cap[20..24], [0, 0, 3, 6]);
+            // wMSOSDescriptorSetTotalLength, bMS_VendorCode, bAltEnumCode
+            let len = descriptor_set_len(false) as u16;
+            assert_eq!(cap[24..28], [len as u8, (len >> 8) as u8, VENDOR_CODE, 0]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_winusb_descriptor_set() {
+    MkWin::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            let desc = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE,
+                MS_OS_20_DESCRIPTOR_INDEX,
+                255,
+            )
+            .expect("desc");
+            assert_eq!(desc.len(), descriptor_set_len(false));
+
+            // MS_OS_20_SET_HEADER_DESCRIPTOR
+            let len = desc.len() as u16;
+            assert_eq!(
+                desc[..10],
+                [10, 0, 0, 0, 0, 0, 3, 6, len as u8, (len >> 8) as u8]
+            );
+            check_features(&desc[10..]);
+
+            /* Descriptor set header only */
+            let vec = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE,
+                MS_OS_20_DESCRIPTOR_INDEX,
+                10,
+            )
+            .expect("vec");
+            assert_eq!(vec, desc[..10]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_winusb_composite() {
+    MkWin::<true> {}
+        .with_usb(|mut dfu, mut dev| {
+            assert_eq!(u8::from(dfu.interface_number()), 1);
+
+            let bos = dev
+                .device_get_descriptor(&mut dfu, 0x0f, 0, 0, 255)
+                .expect("bos");
+            let cap = platform_capability(&bos);
+            let len = descriptor_set_len(true) as u16;
+            assert_eq!(cap[24..26], len.to_le_bytes());
+
+            let desc = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE,
+                MS_OS_20_DESCRIPTOR_INDEX,
+                255,
+            )
+            .expect("desc");
+            assert_eq!(desc.len(), len as usize);
+            assert_eq!(desc[8..10], len.to_le_bytes());
+
+            // configuration subset header, index 0
+            let sub = (len - 10).to_le_bytes();
+            assert_eq!(desc[10..18], [8, 0, 1, 0, 0, 0, sub[0], sub[1]]);
+
+            // function subset header, the DFU interface
+            let sub = (len - 18).to_le_bytes();
+            assert_eq!(desc[18..26], [8, 0, 2, 0, 1, 0, sub[0], sub[1]]);
+
+            check_features(&desc[26..]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_winusb_other_requests() {
+    MkWin::<false> {}
+        .with_usb(|mut dfu, mut dev| {
+            /* Another bRequest */
+            let e = vendor_read(
+                &mut dfu,
+                &mut dev,
+                VENDOR_CODE + 1,
+                MS_OS_20_DESCRIPTOR_INDEX,
+                255,
+            )
+            .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* MS_OS_20_SET_ALT_ENUMERATION is not supported */
+            let e = vendor_read(&mut dfu, &mut dev, VENDOR_CODE, 8, 255).expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+
+            /* Vendor request to the interface */
+            let e = dev
+                .control_read(
+                    &mut dfu,
+                    CtrRequestType::to_host().vendor().interface(),
+                    VENDOR_CODE,
+                    0,
+                    MS_OS_20_DESCRIPTOR_INDEX,
+                    255,
+                )
+                .expect_err("stall");
+            assert_eq!(e, AnyUsbError::EP0Stalled);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,204 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const BLOCK: usize = 8;
+const BIGMEM_BASE: u32 = 0x9000_0000;
+
+/// Large external memory, more blocks than `wBlockNum` can address.
+pub struct BigMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for BigMem {
+    const INITIAL_ADDRESS_POINTER: u32 = BIGMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@QSPI/0x90000000/1*1Mg";
+    const PROGRAM_TIME_MS: u32 = 10;
+    const ERASE_TIME_MS: u32 = 20;
+    const FULL_ERASE_TIME_MS: u32 = 30;
+    const TRANSFER_SIZE: u16 = BLOCK as u16;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkBig {}
+
+impl UsbDeviceCtx for MkBig {
+    type C<'c> = DFUClass<EmulatedUsbBus, BigMem>;
+    const EP0_SIZE: u8 = 8;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, BigMem>> {
+        let mem = BigMem {
+            mem: MockMem::new(BIGMEM_BASE, 1, 1024 * 1024),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Data of logical block `n`
+fn block_data(n: usize) -> [u8; BLOCK] {
+    let mut data = [0x5a; BLOCK];
+    data[..4].copy_from_slice(&(n as u32).to_le_bytes());
+    data
+}
+
+/// `wBlockNum` of logical block `n`, data blocks are numbered from 2 to 0xFFFF
+fn block_value(n: usize) -> u16 {
+    (2 + n % 0xfffe) as u16
+}
+
+/// Download a block, expect it to be programmed
+fn download_ok(
+    dfu: &mut DFUClass<EmulatedUsbBus, BigMem>,
+    dev: &mut Device<DFUClass<EmulatedUsbBus, BigMem>, MkBig>,
+    value: u16,
+    data: &[u8],
+) {
+    /* Download block */
+    let vec = dev.download(dfu, value, data).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 10, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+}
+
+/// Logical block of a download session starting with `wBlockNum` 0xFFFC
+const NEAR_WRAP: usize = 0xfffa;
+
+#[test]
+fn test_wrap_download() {
+    MkBig {}
+        .with_usb(|mut dfu, mut dev| {
+            // 0xFFFC..=0xFFFF, then 2..=4
+            for n in NEAR_WRAP..NEAR_WRAP + 7 {
+                download_ok(&mut dfu, &mut dev, block_value(n), &block_data(n));
+            }
+
+            /* Download len 0, trigger manifestation */
+            let vec = dev
+                .download(&mut dfu, block_value(NEAR_WRAP + 7), &[])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 1, DFUState::DfuManifest));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuIdle));
+
+            let mem = dfu.release();
+            for n in NEAR_WRAP..NEAR_WRAP + 7 {
+                assert_eq!(
+                    mem.mem.memory()[n * BLOCK..(n + 1) * BLOCK],
+                    block_data(n),
+                    "block {n}"
+                );
+            }
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_wrap_retransmit_last_block() {
+    MkBig {}
+        .with_usb(|mut dfu, mut dev| {
+            let n = NEAR_WRAP + 3;
+            assert_eq!(block_value(n), 0xffff);
+
+            download_ok(&mut dfu, &mut dev, 0xfffe, &block_data(n - 1));
+            download_ok(&mut dfu, &mut dev, 0xffff, &block_data(n));
+
+            // host didn't get the status and sends the last block before wraparound again
+            download_ok(&mut dfu, &mut dev, 0xffff, &block_data(n));
+
+            // the next block follows the retransmitted one
+            download_ok(&mut dfu, &mut dev, 2, &block_data(n + 1));
+
+            let mem = dfu.release();
+            for n in n - 1..n + 2 {
+                assert_eq!(
+                    mem.mem.memory()[n * BLOCK..(n + 1) * BLOCK],
+                    block_data(n),
+                    "block {n}"
+                );
+            }
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_wrap_reset() {
+    MkBig {}
+        .with_usb(|mut dfu, mut dev| {
+            for n in 0..4 {
+                download_ok(&mut dfu, &mut dev, block_value(n), &block_data(n));
+            }
+
+            /* Download block 0 (command), Set Address Pointer */
+            let vec = dev
+                .download(&mut dfu, 0, &[0x21, 0x00, 0x01, 0x00, 0x90])
+                .expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            // block numbers start over from the address pointer
+            download_ok(&mut dfu, &mut dev, 2, &[0xa5; BLOCK]);
+
+            /* Abort */
+            let vec = dev.abort(&mut dfu).expect("vec");
+            assert_eq!(vec, []);
+
+            download_ok(&mut dfu, &mut dev, 3, &[0x96; BLOCK]);
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.memory()[..BLOCK], block_data(0));
+            assert_eq!(mem.mem.memory()[0x100..0x100 + BLOCK], [0xa5; BLOCK]);
+            assert_eq!(mem.mem.memory()[0x108..0x108 + BLOCK], [0x96; BLOCK]);
+        })
+        .expect("with_usb");
+}
@@ -0,0 +1,189 @@
+#![allow(unused_variables)]
+
+mod helpers;
+use helpers::*;
+
+use usbd_class_tester::prelude::*;
+
+use usb_device::bus::UsbBusAllocator;
+use usbd_dfu::class::*;
+
+const WPMEM_BASE: u32 = 0x0800_0000;
+const PAGE_SIZE: u32 = 256;
+/// Bootloader area
+const PROTECTED_END: u32 = WPMEM_BASE + 2 * PAGE_SIZE;
+
+/// Mock memory, the first two pages are write-protected
+pub struct WpMem {
+    mem: MockMem,
+}
+
+impl DFUMemIO for WpMem {
+    const INITIAL_ADDRESS_POINTER: u32 = WPMEM_BASE;
+    const MEM_INFO_STRING: &'static str = "@Flash/0x08000000/8*256 g";
+    const PROGRAM_TIME_MS: u32 = 5;
+    const ERASE_TIME_MS: u32 = 10;
+    const FULL_ERASE_TIME_MS: u32 = 20;
+    const TRANSFER_SIZE: u16 = 64;
+
+    fn read(&mut self, address: u32, length: usize) -> Result<&[u8], DFUMemError> {
+        self.mem.read(address, length)
+    }
+
+    fn page_size_at(&self, address: u32) -> Option<u32> {
+        self.mem.page_size_at(address)
+    }
+
+    fn is_write_protected(&self, address: u32, length: usize) -> bool {
+        address < PROTECTED_END && address as u64 + length as u64 > WPMEM_BASE as u64
+    }
+
+    fn erase(&mut self, address: u32) -> Result<(), DFUMemError> {
+        self.mem.erase(address)
+    }
+
+    fn erase_all(&mut self) -> Result<(), DFUMemError> {
+        self.mem.erase_all()
+    }
+
+    fn store_write_buffer(&mut self, src: &[u8]) -> Result<(), ()> {
+        self.mem.store_write_buffer(src)
+    }
+
+    fn program(&mut self, address: u32, length: usize) -> Result<(), DFUMemError> {
+        self.mem.program(address, length)
+    }
+
+    fn manifestation(&mut self) -> Result<(), DFUManifestationError> {
+        self.mem.manifestation()
+    }
+}
+
+struct MkWp {}
+
+impl UsbDeviceCtx for MkWp {
+    type C<'c> = DFUClass<EmulatedUsbBus, WpMem>;
+    const EP0_SIZE: u8 = 32;
+
+    fn create_class(
+        &mut self,
+        alloc: &UsbBusAllocator<EmulatedUsbBus>,
+    ) -> AnyResult<DFUClass<EmulatedUsbBus, WpMem>> {
+        let mem = WpMem {
+            mem: MockMem::new(WPMEM_BASE, 8, PAGE_SIZE),
+        };
+        Ok(DFUClass::new(alloc, mem))
+    }
+}
+
+/// Send a DfuSe command with an address, the command is processed
+fn dfuse_command<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, cmd: u8, address: u32) {
+    let b = address.to_le_bytes();
+
+    /* Download block 0 (command) */
+    let vec = dev
+        .download(dfu, 0, &[cmd, b[0], b[1], b[2], b[3]])
+        .expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(DFUState::try_from(vec[4]), Ok(DFUState::DfuDnBusy));
+}
+
+/// Download a block to the Address Pointer, it's rejected as write-protected
+fn download_protected<C>(dfu: &mut C, dev: &mut impl DeviceExt<C>, address: u32) {
+    /* Set Address Pointer */
+    dfuse_command(dfu, dev, 0x21, address);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+    /* Download block 2 (offset 0) */
+    let vec = dev.download(dfu, 2, &[0x55; 64]).expect("vec");
+    assert_eq!(vec, []);
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+    /* Get Status */
+    let vec = dev.get_status(dfu).expect("vec");
+    assert_eq!(vec, status(DFUStatusCode::ErrWrite, 0, DFUState::DfuError));
+
+    /* Clear Status */
+    let vec = dev.clear_status(dfu).expect("vec");
+    assert_eq!(vec, []);
+}
+
+#[test]
+fn test_write_protect_erase() {
+    MkWp {}
+        .with_usb(|mut dfu, mut dev| {
+            for page in [WPMEM_BASE, WPMEM_BASE + PAGE_SIZE] {
+                /* Erase a protected page */
+                dfuse_command(&mut dfu, &mut dev, 0x41, page);
+
+                /* Get Status */
+                let vec = dev.get_status(&mut dfu).expect("vec");
+                assert_eq!(vec, status(DFUStatusCode::ErrErase, 0, DFUState::DfuError));
+
+                /* Clear Status */
+                let vec = dev.clear_status(&mut dfu).expect("vec");
+                assert_eq!(vec, []);
+            }
+
+            /* Erase the first page after the protected area */
+            dfuse_command(&mut dfu, &mut dev, 0x41, PROTECTED_END);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::Erase(PROTECTED_END)]);
+        })
+        .expect("with_usb");
+}
+
+#[test]
+fn test_write_protect_program() {
+    MkWp {}
+        .with_usb(|mut dfu, mut dev| {
+            // inside of the protected area
+            download_protected(&mut dfu, &mut dev, WPMEM_BASE);
+            download_protected(&mut dfu, &mut dev, WPMEM_BASE + 0x100);
+
+            // partially overlaps the end
+            download_protected(&mut dfu, &mut dev, PROTECTED_END - 1);
+            download_protected(&mut dfu, &mut dev, PROTECTED_END - 32);
+
+            // partially overlaps the start
+            download_protected(&mut dfu, &mut dev, WPMEM_BASE - 32);
+
+            /* Set Address Pointer, right after the protected area */
+            dfuse_command(&mut dfu, &mut dev, 0x21, PROTECTED_END);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            /* Download block 2 (offset 0) */
+            let vec = dev.download(&mut dfu, 2, &[0xaa; 64]).expect("vec");
+            assert_eq!(vec, []);
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 5, DFUState::DfuDnBusy));
+
+            /* Get Status */
+            let vec = dev.get_status(&mut dfu).expect("vec");
+            assert_eq!(vec, status(DFUStatusCode::OK, 0, DFUState::DfuDnloadIdle));
+
+            let mem = dfu.release();
+            assert_eq!(mem.mem.calls, [MockCall::Program(PROTECTED_END, 64)]);
+            assert_eq!(mem.mem.memory()[..2 * PAGE_SIZE as usize], [0xff; 512]);
+        })
+        .expect("with_usb");
+}